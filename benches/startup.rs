@@ -0,0 +1,133 @@
+//startup.rs
+
+// What a user waits on: the shell starting up to its first prompt, the redraw after every
+// key and starting programs. The first two should stay within a few milliseconds, `cargo
+// bench` tells
+
+use anyhow::Result;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use rshell::{
+    context::{Context, Mode},
+    editor::Editor,
+    prompt::Prompt,
+    shell::{Config, Shell},
+    terminal::{Backend, Terminal},
+};
+use std::{env, fs, hint::black_box, time::Duration};
+
+// A big history, like the one of a shell used for years
+const HISTORY_ENTRIES: usize = 200_000;
+
+// Programs started by each run of the spawn benchmark
+const SPAWNS: usize = 100;
+
+// Keys typed for each line of the keystroke benchmark, the last one is Enter
+const LINE_KEYS: usize = 41;
+
+// A terminal that throws the output away and types the same line over and over
+struct Typist {
+    typed: usize,
+}
+
+impl Backend for Typist {
+    fn enable_raw_mode(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn disable_raw_mode(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn write(&mut self, output: &str) -> Result<()> {
+        black_box(output);
+        Ok(())
+    }
+    fn move_to(&mut self, _: u16, _: u16) -> Result<()> {
+        Ok(())
+    }
+    fn move_by(&mut self, _: i16, _: i16) -> Result<()> {
+        Ok(())
+    }
+    fn clear_screen(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn clear_current_line(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn size(&mut self) -> Result<(u16, u16)> {
+        Ok((120, 40))
+    }
+    fn cursor_position(&mut self) -> Result<(u16, u16)> {
+        Ok((0, 0))
+    }
+    fn read_key(&mut self, _: Duration) -> Result<Option<KeyEvent>> {
+        self.typed = (self.typed + 1) % LINE_KEYS;
+        let code = match self.typed {
+            0 => KeyCode::Enter,
+            typed => KeyCode::Char((b'a' + (typed % 26) as u8) as char),
+        };
+        Ok(Some(KeyEvent::new(code, KeyModifiers::NONE)))
+    }
+}
+
+// An interactive shell in a home of its own, with a big history and no startup files
+fn context(home: &std::path::Path) -> Context {
+    let mode = Mode {
+        interactive: true,
+        ..Mode::default()
+    };
+    unsafe {
+        env::set_var("HOME", home);
+        env::set_var("HISTFILE", home.join("history"));
+        env::remove_var("XDG_CONFIG_HOME");
+        env::remove_var("XDG_DATA_HOME");
+    }
+    Context::new(mode).expect("the shell should start")
+}
+
+fn benchmarks(c: &mut Criterion) {
+    let home = tempfile::tempdir().expect("tempdir");
+    let history: String = (0..HISTORY_ENTRIES)
+        .map(|n| {
+            format!(
+                "{}\t0:12:abc\t/tmp\tcargo build --bin tool{}\n",
+                1_700_000_000 + n,
+                n % 500
+            )
+        })
+        .collect();
+    fs::write(home.path().join("history"), history).expect("history");
+
+    c.bench_function("startup", |b| b.iter(|| context(home.path())));
+
+    let mut context = context(home.path());
+    context.update_cwd();
+    let mut prompt = Prompt::new();
+    c.bench_function("prompt", |b| b.iter(|| prompt.update(&context)));
+
+    let mut group = c.benchmark_group("editor");
+    group.throughput(Throughput::Elements(LINE_KEYS as u64));
+    let mut terminal = Terminal::with_backend(Box::new(Typist { typed: 0 }));
+    let mut editor = Editor::new();
+    group.bench_function("keystroke", |b| {
+        b.iter(|| {
+            editor
+                .read_line(&mut context, &mut terminal, &mut prompt)
+                .expect("a line")
+        })
+    });
+    group.finish();
+
+    // A script loop, where starting each program is most of the time taken
+    let mut group = c.benchmark_group("spawn");
+    group.throughput(Throughput::Elements(SPAWNS as u64));
+    group.sample_size(20);
+    let mut shell = Shell::new(Config::default()).expect("the shell should start");
+    let script = format!("for ((i = 0; i < {SPAWNS}; i++)); do /bin/true | /bin/true; done");
+    group.bench_function("pipeline", |b| {
+        b.iter(|| shell.eval(&script).expect("the loop should run"))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
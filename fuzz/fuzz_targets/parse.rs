@@ -0,0 +1,30 @@
+//parse.rs
+
+// Any line goes through the tokenizer and the parser without a panic, and a command that
+// parses prints as a line that parses back to the same command
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rshell::{parser::Parser, tokenizer::Tokenizer};
+
+fn parse(line: &str) -> Option<String> {
+    let tokens = Tokenizer::tokenize(line).ok()?;
+    let command = Parser::parse(&tokens).ok()?;
+    Some(command.to_string())
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Some(printed) = parse(line) else {
+        return;
+    };
+    let reparsed = parse(&printed);
+    assert_eq!(
+        reparsed.as_deref(),
+        Some(printed.as_str()),
+        "{line:?} prints as {printed:?}, which doesn't parse back the same"
+    );
+});
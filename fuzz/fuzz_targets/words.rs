@@ -0,0 +1,155 @@
+//words.rs
+
+// Words made of plain text, escapes, single and double quotes and variables, glued together
+// in any order. Whatever the mix, the word stays one argument, expands to the text it
+// stands for, and as a pattern matches that text, since what's quoted matches itself
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rshell::{
+    expander,
+    parser::{Command, Parser},
+    shell::{Config, Shell},
+    tokenizer::Tokenizer,
+};
+use std::cell::RefCell;
+
+// The variables words can use, unquoted ones are kept free of spaces and patterns
+const WORD: (&str, &str) = ("FUZZ_WORD", "a-b");
+const SENTENCE: (&str, &str) = ("FUZZ_SENTENCE", "two  [words]*");
+
+#[derive(Arbitrary, Debug)]
+enum Piece {
+    Plain(String),
+    Escaped(char),
+    SingleQuoted(String),
+    DoubleQuoted(Vec<Quoted>),
+    Variable,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Quoted {
+    Text(String),
+    Escaped(Special),
+    Variable,
+}
+
+// What a backslash escapes between double quotes
+#[derive(Arbitrary, Debug)]
+enum Special {
+    Dollar,
+    Backslash,
+    Quote,
+    Backtick,
+}
+
+// The word as typed and what it stands for
+fn write(pieces: &[Piece]) -> (String, String) {
+    let mut source = String::new();
+    let mut value = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Plain(text) => {
+                let text: String = text.chars().filter(|&c| plain(c)).collect();
+                source.push_str(&text);
+                value.push_str(&text);
+            }
+            // A backslash before a newline joins lines instead
+            Piece::Escaped('\n' | '\0') => {}
+            Piece::Escaped(c) => {
+                source.push('\\');
+                source.push(*c);
+                value.push(*c);
+            }
+            Piece::SingleQuoted(text) => {
+                let text: String = text.chars().filter(|&c| c != '\'' && c != '\0').collect();
+                source.push_str(&format!("'{text}'"));
+                value.push_str(&text);
+            }
+            Piece::DoubleQuoted(parts) => {
+                source.push('"');
+                for part in parts {
+                    match part {
+                        Quoted::Text(text) => {
+                            let text: String = text
+                                .chars()
+                                .filter(|&c| !matches!(c, '"' | '\\' | '$' | '`' | '\0'))
+                                .collect();
+                            source.push_str(&text);
+                            value.push_str(&text);
+                        }
+                        Quoted::Escaped(special) => {
+                            let c = match special {
+                                Special::Dollar => '$',
+                                Special::Backslash => '\\',
+                                Special::Quote => '"',
+                                Special::Backtick => '`',
+                            };
+                            source.push('\\');
+                            source.push(c);
+                            value.push(c);
+                        }
+                        Quoted::Variable => {
+                            source.push_str(&format!("${{{}}}", SENTENCE.0));
+                            value.push_str(SENTENCE.1);
+                        }
+                    }
+                }
+                source.push('"');
+            }
+            Piece::Variable => {
+                source.push_str(&format!("${{{}}}", WORD.0));
+                value.push_str(WORD.1);
+            }
+        }
+    }
+    (source, value)
+}
+
+// Characters that mean nothing special outside of quotes
+fn plain(c: char) -> bool {
+    c.is_alphanumeric() || "-_.,:/@%+=^".contains(c)
+}
+
+thread_local! {
+    static SHELL: RefCell<Shell> = RefCell::new({
+        let mut shell = Shell::new(Config::default()).expect("the shell should start");
+        for (name, value) in [WORD, SENTENCE] {
+            shell.context.variables.set(name, value);
+        }
+        shell
+    });
+}
+
+fuzz_target!(|pieces: Vec<Piece>| {
+    let (source, value) = write(&pieces);
+    if source.is_empty() {
+        return;
+    }
+
+    let line = format!("echo {source}");
+    let tokens = Tokenizer::tokenize(&line).unwrap_or_else(|e| panic!("{line:?}: {e:#}"));
+    let command = Parser::parse(&tokens).unwrap_or_else(|e| panic!("{line:?}: {e:#}"));
+    let Command::Simple { args, .. } = command else {
+        panic!("{line:?} isn't a simple command");
+    };
+    let [arg] = args.as_slice() else {
+        panic!("{source:?} isn't a single word: {args:?}");
+    };
+
+    SHELL.with_borrow_mut(|shell| {
+        let Shell { context, terminal } = shell;
+        let expanded = expander::expand_word(context, terminal, arg.clone())
+            .unwrap_or_else(|e| panic!("{source:?}: {e:#}"));
+        assert_eq!(expanded, value, "{source:?} expands wrong");
+
+        let pattern = expander::expand_pattern(context, terminal, arg.clone())
+            .unwrap_or_else(|e| panic!("{source:?}: {e:#}"));
+        assert!(
+            expander::matches_pattern(&pattern, &value),
+            "{source:?} as the pattern {pattern:?} doesn't match {value:?}"
+        );
+    });
+});
@@ -0,0 +1,49 @@
+/*
+ * An example plugin: a `greet` builtin, a {greeting} prompt segment, a
+ * completion spec for greet and a precmd hook. Build it with
+ *
+ *     cc -shared -fPIC -I plugins -o libgreet.so plugins/greet.c
+ *
+ * and load it with `plugin load ./libgreet.so`.
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "rshell_plugin.h"
+
+static int greetings;
+
+static int greet(void *data, int argc, const char *const *argv) {
+    const char *greeting = data;
+    printf("%s, %s!\n", greeting, argc > 1 ? argv[1] : "world");
+    greetings++;
+    return 0;
+}
+
+static long greeting(void *data, char *buffer, size_t size) {
+    (void)data;
+    if (greetings == 0) {
+        return -1;
+    }
+    return snprintf(buffer, size, "greeted %d", greetings);
+}
+
+int rshell_plugin_init(const struct rshell_host *host) {
+    if (host->abi_version < RSHELL_PLUGIN_ABI) {
+        return 1;
+    }
+    if (host->register_builtin(host->registry, "greet", greet, "Hello") != 0 ||
+        host->register_segment(host->registry, "greeting", greeting, NULL) != 0 ||
+        host->register_completion(host->registry, "greet",
+                                  "values = [\"world\", \"there\"]\nargs = \"none\"\n") != 0 ||
+        host->register_hook(host->registry, "precmd", "GREET_HOOK=ran") != 0) {
+        return 1;
+    }
+    return 0;
+}
+
+void rshell_plugin_exit(void) {
+    printf("Goodbye after %d greetings\n", greetings);
+    fflush(stdout);
+}
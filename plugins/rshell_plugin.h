@@ -0,0 +1,65 @@
+/*
+ * rshell plugin ABI, version 1
+ *
+ * A plugin is a shared library exporting rshell_plugin_init, loaded with
+ * `plugin load path/to/libname.so` (or `plugin load name` for one in
+ * ~/.config/rshell/plugins/). While rshell_plugin_init runs, it registers what
+ * it brings through the functions of the host it's given. Strings are UTF-8
+ * and copied, the plugin keeps ownership of them. Every register function
+ * returns 0, or -1 when what it was given is invalid.
+ *
+ * New versions only add fields at the end of struct rshell_host and raise
+ * abi_version, so a plugin built against this header keeps working as long as
+ * abi_version is at least RSHELL_PLUGIN_ABI.
+ */
+
+#ifndef RSHELL_PLUGIN_H
+#define RSHELL_PLUGIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RSHELL_PLUGIN_ABI 1
+
+/*
+ * Runs a builtin: argv[0] is its name and argv[argc] is NULL, like main. What
+ * it returns is the status of the command. It runs in the shell itself, its
+ * output goes to the shell's stdout and stderr, and C's stdout is flushed
+ * once it's done.
+ */
+typedef int (*rshell_builtin)(void *data, int argc, const char *const *argv);
+
+/*
+ * Renders a prompt segment, used in templates as {name}: writes at most size
+ * bytes of text in buffer, without a NUL, and returns how many. A negative
+ * number leaves the segment out. The text can hold escape sequences for
+ * colors.
+ */
+typedef long (*rshell_segment)(void *data, char *buffer, size_t size);
+
+struct rshell_host {
+    uint32_t abi_version;
+    /* Passed back as the first argument of every register function */
+    void *registry;
+
+    /* A builtin named name, data is given back to it on every call */
+    int (*register_builtin)(void *registry, const char *name,
+                            rshell_builtin builtin, void *data);
+    /* A prompt segment named name, data is given back to it on every call */
+    int (*register_segment)(void *registry, const char *name,
+                            rshell_segment segment, void *data);
+    /* A completion spec for command, the TOML of a file in completions/ */
+    int (*register_completion)(void *registry, const char *command,
+                               const char *spec);
+    /* A command run on a hook, "precmd", "preexec" or "chpwd", like `hook` does */
+    int (*register_hook)(void *registry, const char *hook,
+                         const char *command);
+};
+
+/* Called once when the plugin is loaded, anything but 0 fails the load */
+int rshell_plugin_init(const struct rshell_host *host);
+
+/* Optional, called when the plugin is unloaded */
+void rshell_plugin_exit(void);
+
+#endif
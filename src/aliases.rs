@@ -1,4 +1,13 @@
-use std::collections::HashMap;
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+// Lines between these in the rc file belong to `alias --save`
+const SECTION_START: &str = "# >>> rshell aliases >>>";
+const SECTION_END: &str = "# <<< rshell aliases <<<";
 
 #[derive(Clone)]
 pub struct Aliases {
@@ -28,3 +37,74 @@ impl Aliases {
         &self.aliases
     }
 }
+
+/// Saves an alias in the section of the rc file the shell manages, replacing an older
+/// definition of it, or removes it from there when the value is None. The rest of the file
+/// is left alone and it isn't touched at all when there's nothing to change
+pub fn save(rc_file: &Path, name: &str, value: Option<&str>) -> Result<()> {
+    let content = match fs::read_to_string(rc_file) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(error).context("Failed to read the rc file"),
+    };
+    let lines: Vec<&str> = content.lines().collect();
+
+    let start = lines.iter().position(|line| line.trim() == SECTION_START);
+    let end = start.and_then(|start| {
+        lines[start..]
+            .iter()
+            .position(|line| line.trim() == SECTION_END)
+            .map(|end| start + end)
+    });
+
+    let mut saved: Vec<String> = match (start, end) {
+        (Some(start), Some(end)) => lines[start + 1..end]
+            .iter()
+            .map(|line| line.to_string())
+            .collect(),
+        _ => Vec::new(),
+    };
+    let prefix = format!("alias {name}=");
+    let position = saved.iter().position(|line| line.starts_with(&prefix));
+    let definition = value.map(|value| format!("{prefix}{}", quote(value)));
+    match (position, definition) {
+        (Some(i), Some(definition)) if saved[i] == definition => return Ok(()),
+        (Some(i), Some(definition)) => saved[i] = definition,
+        (Some(i), None) => {
+            saved.remove(i);
+        }
+        (None, Some(definition)) => saved.push(definition),
+        (None, None) => return Ok(()),
+    }
+
+    let mut section = vec![SECTION_START.to_string()];
+    section.extend(saved);
+    section.push(SECTION_END.to_string());
+
+    let mut output: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+    match (start, end) {
+        (Some(start), Some(end)) => {
+            output.splice(start..=end, section);
+        }
+        _ => {
+            if output.last().is_some_and(|line| !line.is_empty()) {
+                output.push(String::new());
+            }
+            output.extend(section);
+        }
+    }
+
+    // Written next to the rc file and renamed over it, so a crash can't leave it half written
+    let temporary = PathBuf::from(format!("{}.tmp", rc_file.display()));
+    fs::write(&temporary, output.join("\n") + "\n").context("Failed to write the rc file")?;
+    fs::rename(&temporary, rc_file).context("Failed to write the rc file")?;
+    Ok(())
+}
+
+// Single quotes keep the value as it is, unless it has single quotes itself
+fn quote(value: &str) -> String {
+    match value.contains('\'') {
+        true => format!("\"{value}\""),
+        false => format!("'{value}'"),
+    }
+}
@@ -0,0 +1,365 @@
+//arithmetic.rs
+
+use crate::{
+    error::{ShellError, ShellPhase},
+    variables::Variables,
+};
+use anyhow::Result;
+
+// From the loosest to the tightest, all left associative. Assignments, `?:` and `**` are
+// handled on their own since they group to the right
+const BINARY_OPERATORS: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+const ASSIGNMENTS: [&str; 11] = [
+    "=", "+=", "-=", "*=", "/=", "%=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+// Longest first so `<<=` isn't read as `<` `<=`
+const OPERATORS: [&str; 33] = [
+    "<<=", ">>=", "**", "++", "--", "+=", "-=", "*=", "/=", "%=", "&=", "^=", "|=", "<<", ">>",
+    "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "|",
+];
+
+const SINGLE_OPERATORS: [&str; 6] = ["^", "?", ":", ",", "(", ")"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+/// Evaluates an arithmetic expression like the ones in `((...))`, with C's operators and
+/// precedence on 64 bit integers. Variables can be written with or without `$`, unset or
+/// non numeric ones count as 0. Assignments like `i++` or `x += 2` set them in the environment
+pub fn eval(expression: &str) -> Result<i64> {
+    eval_in(&mut Variables::new(), expression)
+}
+
+/// Like `eval`, with the shell's variables so those that aren't exported count too.
+/// Assignments set them like `name=value` would
+pub fn eval_in(variables: &mut Variables, expression: &str) -> Result<i64> {
+    let tokens = tokenize(expression)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+
+    let mut evaluator = Evaluator {
+        tokens,
+        position: 0,
+        skip: false,
+        variables,
+    };
+    let value = evaluator.comma()?;
+    match evaluator.tokens.get(evaluator.position) {
+        Some(token) => error(&format!("Unexpected {}", describe(token))),
+        None => Ok(value),
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = expression;
+    while let Some(character) = rest.chars().next() {
+        if character.is_whitespace() {
+            rest = &rest[character.len_utf8()..];
+            continue;
+        }
+
+        if character.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            match parse_number(&rest[..end]) {
+                Some(number) => tokens.push(Token::Number(number)),
+                None => return error(&format!("Invalid number '{}'", &rest[..end])),
+            }
+            rest = &rest[end..];
+        } else if character.is_ascii_alphabetic() || character == '_' || character == '$' {
+            // `$x` is the same as `x`
+            let name = rest.strip_prefix('$').unwrap_or(rest);
+            let end = name
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(name.len());
+            if end == 0 {
+                return error("Expected a variable name after '$'");
+            }
+            tokens.push(Token::Name(name[..end].to_string()));
+            rest = &name[end..];
+        } else if let Some(operator) = OPERATORS
+            .iter()
+            .chain(SINGLE_OPERATORS.iter())
+            .find(|operator| rest.starts_with(**operator))
+        {
+            tokens.push(Token::Operator(operator));
+            rest = &rest[operator.len()..];
+        } else {
+            return error(&format!("Unexpected character '{}'", character));
+        }
+    }
+    Ok(tokens)
+}
+
+// Like C, `0x` starts a hexadecimal number and a leading 0 an octal one
+fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if text.len() > 1
+        && let Some(octal) = text.strip_prefix('0')
+    {
+        i64::from_str_radix(octal, 8).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(number) => format!("'{number}'"),
+        Token::Name(name) => format!("'{name}'"),
+        Token::Operator(operator) => format!("'{operator}'"),
+    }
+}
+
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    // Set while going through the side of `&&`, `||` or `?:` that isn't taken, which is
+    // parsed but must not assign anything or fail on a division by zero
+    skip: bool,
+    variables: &'a mut Variables,
+}
+
+impl Evaluator<'_> {
+    fn comma(&mut self) -> Result<i64> {
+        let mut value = self.assignment()?;
+        while self.eat(",") {
+            value = self.assignment()?;
+        }
+        Ok(value)
+    }
+
+    fn assignment(&mut self) -> Result<i64> {
+        if let Some(Token::Name(name)) = self.tokens.get(self.position).cloned()
+            && let Some(Token::Operator(operator)) = self.tokens.get(self.position + 1)
+            && ASSIGNMENTS.contains(operator)
+        {
+            let operator = *operator;
+            self.position += 2;
+            let right = self.assignment()?;
+            let value = match operator {
+                "=" => right,
+                _ => self.apply(&operator[..operator.len() - 1], self.variable(&name), right)?,
+            };
+            return self.assign(&name, value);
+        }
+        self.ternary()
+    }
+
+    fn ternary(&mut self) -> Result<i64> {
+        let condition = self.binary(0)?;
+        if !self.eat("?") {
+            return Ok(condition);
+        }
+
+        let skip = self.skip;
+        self.skip = skip || condition == 0;
+        let when_true = self.comma()?;
+        self.expect(":")?;
+        self.skip = skip || condition != 0;
+        let when_false = self.ternary()?;
+        self.skip = skip;
+
+        Ok(if condition != 0 {
+            when_true
+        } else {
+            when_false
+        })
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64> {
+        let Some(operators) = BINARY_OPERATORS.get(level) else {
+            return self.power();
+        };
+
+        let mut left = self.binary(level + 1)?;
+        while let Some(Token::Operator(operator)) = self.tokens.get(self.position)
+            && operators.contains(operator)
+        {
+            let operator = *operator;
+            self.position += 1;
+
+            let skip = self.skip;
+            self.skip |= (operator == "||" && left != 0) || (operator == "&&" && left == 0);
+            let right = self.binary(level + 1)?;
+            self.skip = skip;
+
+            left = self.apply(operator, left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn power(&mut self) -> Result<i64> {
+        let base = self.unary()?;
+        if !self.eat("**") {
+            return Ok(base);
+        }
+
+        let exponent = self.power()?;
+        self.apply("**", base, exponent)
+    }
+
+    fn unary(&mut self) -> Result<i64> {
+        let Some(Token::Operator(operator)) = self.tokens.get(self.position) else {
+            return self.postfix();
+        };
+
+        let operator = *operator;
+        match operator {
+            "!" | "~" | "-" | "+" => {
+                self.position += 1;
+                let value = self.unary()?;
+                Ok(match operator {
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    "-" => value.wrapping_neg(),
+                    _ => value,
+                })
+            }
+            "++" | "--" => {
+                self.position += 1;
+                let name = self.name(operator)?;
+                let step = if operator == "++" { 1 } else { -1 };
+                self.assign(&name, self.variable(&name).wrapping_add(step))
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<i64> {
+        let Some(Token::Name(name)) = self.tokens.get(self.position).cloned() else {
+            return self.primary();
+        };
+
+        self.position += 1;
+        let value = self.variable(&name);
+        let step = match () {
+            _ if self.eat("++") => 1,
+            _ if self.eat("--") => -1,
+            _ => return Ok(value),
+        };
+        self.assign(&name, value.wrapping_add(step))?;
+        Ok(value)
+    }
+
+    fn primary(&mut self) -> Result<i64> {
+        match self.tokens.get(self.position).cloned() {
+            Some(Token::Number(number)) => {
+                self.position += 1;
+                Ok(number)
+            }
+            Some(Token::Operator("(")) => {
+                self.position += 1;
+                let value = self.comma()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(token) => error(&format!("Unexpected {}", describe(&token))),
+            None => error("Unexpected end of the expression"),
+        }
+    }
+
+    fn apply(&self, operator: &str, left: i64, right: i64) -> Result<i64> {
+        if matches!(operator, "/" | "%") && right == 0 {
+            return match self.skip {
+                true => Ok(0),
+                false => error("Division by zero"),
+            };
+        }
+
+        Ok(match operator {
+            "||" => (left != 0 || right != 0) as i64,
+            "&&" => (left != 0 && right != 0) as i64,
+            "|" => left | right,
+            "^" => left ^ right,
+            "&" => left & right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 && !self.skip => return error("Negative exponent"),
+            "**" => left.wrapping_pow(right.clamp(0, u32::MAX as i64) as u32),
+            _ => return error(&format!("Unknown operator '{operator}'")),
+        })
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> Result<i64> {
+        if !self.skip {
+            self.variables.set(name, &value.to_string());
+        }
+        Ok(value)
+    }
+
+    fn variable(&self, name: &str) -> i64 {
+        self.variables
+            .get(name)
+            .and_then(|value| parse_number(value.trim()))
+            .unwrap_or(0)
+    }
+
+    fn name(&mut self, operator: &str) -> Result<String> {
+        match self.tokens.get(self.position).cloned() {
+            Some(Token::Name(name)) => {
+                self.position += 1;
+                Ok(name)
+            }
+            _ => error(&format!("Expected a variable after '{operator}'")),
+        }
+    }
+
+    fn eat(&mut self, operator: &str) -> bool {
+        let found = matches!(
+            self.tokens.get(self.position),
+            Some(Token::Operator(current)) if *current == operator
+        );
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, operator: &str) -> Result<()> {
+        match self.eat(operator) {
+            true => Ok(()),
+            false => error(&format!("Expected '{operator}'")),
+        }
+    }
+}
+
+fn error<T>(message: &str) -> Result<T> {
+    Err(anyhow::Error::new(ShellError {
+        phase: ShellPhase::Arithmetic,
+        command: None,
+        message: message.into(),
+    }))
+}
@@ -1,14 +1,33 @@
 // builtins.rs
 
+#[cfg(unix)]
+use crate::jobs::{self, JobState};
 use crate::{
-    context::Context,
-    error::{ShellError, ShellPhase},
-    jobs::JobState,
-    parser::EnvVariable,
+    aliases,
+    context::{self, Context},
+    correction, encoding,
+    error::{LoopControl, Return, ShellError, ShellPhase},
+    executor, expander,
+    frecency::{self, Directories},
+    history::{self, ExportFormat, HistoryFilter},
+    hooks::{self, Hook},
+    parser::{Arg, Command, EnvVariable, is_name},
+    prompt,
+    settings::{OPTIONS, Settings},
     terminal::Terminal,
+    theme::Theme,
+    variables::Attributes,
 };
 use anyhow::{Context as AnyhowContext, Result};
-use std::{collections::HashMap, env, path::PathBuf};
+use crossterm::event::{KeyCode, KeyModifiers};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    io::{self, IsTerminal},
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 pub type Builtin = fn(&[&str], &mut Context, &mut Terminal) -> Result<i32>;
 
@@ -21,6 +40,7 @@ impl BuiltIns {
     pub fn new() -> Self {
         let mut programs: HashMap<String, Builtin> = HashMap::new();
         programs.insert("cd".to_string(), Self::cd);
+        programs.insert("z".to_string(), Self::z);
         programs.insert("exit".to_string(), Self::exit);
         programs.insert("jobs".to_string(), Self::jobs);
         programs.insert("fg".to_string(), Self::fg);
@@ -31,6 +51,29 @@ impl BuiltIns {
         programs.insert("export".to_string(), Self::export);
         programs.insert("unset".to_string(), Self::unset);
         programs.insert("pwd".to_string(), Self::pwd);
+        programs.insert("reload".to_string(), Self::reload);
+        programs.insert("shopt".to_string(), Self::shopt);
+        programs.insert("theme".to_string(), Self::theme);
+        programs.insert("source".to_string(), Self::source);
+        programs.insert(".".to_string(), Self::source);
+        programs.insert("set".to_string(), Self::set);
+        programs.insert("return".to_string(), Self::return_from);
+        programs.insert("break".to_string(), Self::break_loop);
+        programs.insert("continue".to_string(), Self::continue_loop);
+        programs.insert("shift".to_string(), Self::shift);
+        programs.insert("hook".to_string(), Self::hook);
+        programs.insert("declare".to_string(), Self::declare);
+        programs.insert("local".to_string(), Self::local);
+        programs.insert(":".to_string(), Self::colon);
+        programs.insert("envsave".to_string(), Self::envsave);
+        programs.insert("envrestore".to_string(), Self::envrestore);
+        programs.insert("watch".to_string(), Self::watch);
+        programs.insert("plugin".to_string(), Self::plugin);
+        programs.insert("private".to_string(), Self::private);
+        programs.insert("timeout".to_string(), Self::timeout);
+        programs.insert("disown".to_string(), Self::disown);
+        programs.insert("type".to_string(), Self::type_of);
+        programs.insert("repeat".to_string(), Self::repeat);
 
         Self { programs }
     }
@@ -39,15 +82,31 @@ impl BuiltIns {
         self.programs.get(name).copied()
     }
 
-    pub fn cd(args: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
+    pub fn insert(&mut self, name: &str, builtin: Builtin) {
+        self.programs.insert(name.to_string(), builtin);
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.programs.keys().map(String::as_str)
+    }
+
+    /// Changes directory keeping track of the path it was reached by in `PWD`, so `cd ..` out
+    /// of a symlink goes back where it came from. `-P` follows the symlinks instead
+    pub fn cd(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let (physical, args) = match args {
+            ["-P", rest @ ..] => (true, rest),
+            ["-L", rest @ ..] => (false, rest),
+            _ => (false, args),
+        };
+
         let target = if !args.is_empty() {
             if args[0] == "-" {
-                match env::var("OLDPWD") {
-                    Ok(old) => PathBuf::from(old),
-                    Err(_) => return Self::error("cd", "OLDPWD environment variable isn't set"),
+                match context.variables.get("OLDPWD") {
+                    Some(old) => PathBuf::from(old),
+                    None => return Self::error("cd", "OLDPWD environment variable isn't set"),
                 }
             } else {
-                PathBuf::from(&args[0])
+                PathBuf::from(encoding::to_os_string(args[0]))
             }
         } else {
             match env::var("HOME") {
@@ -56,22 +115,192 @@ impl BuiltIns {
             }
         };
 
-        if let Ok(current) = env::current_dir() {
-            unsafe {
-                env::set_var("OLDPWD", current);
+        let current = context
+            .variables
+            .get("PWD")
+            .map(PathBuf::from)
+            .or_else(|| env::current_dir().ok());
+        let logical = current
+            .as_deref()
+            .map(|current| logical_path(current, &target));
+
+        // When the path the directory was reached by doesn't work, like after `..` out of a
+        // directory that's gone, the target is tried as it is
+        let pwd = match logical.filter(|path| !physical && env::set_current_dir(path).is_ok()) {
+            Some(logical) => logical,
+            None => {
+                if let Err(error) = env::set_current_dir(&target) {
+                    // A typo at the prompt is offered the closest directory next to it
+                    if let Some(corrected) = correction::directory(context, terminal, &target)? {
+                        let corrected = encoding::from_os_str(corrected.as_os_str());
+                        let flag = if physical { "-P" } else { "-L" };
+                        return Self::cd(&[flag, &corrected], context, terminal);
+                    }
+                    return Err(error).with_context(|| {
+                        format!("cd: Failed to change directory to '{}'", target.display())
+                    });
+                }
+                env::current_dir().context("cd: Failed to read the directory")?
             }
+        };
+
+        if let Some(current) = &current {
+            context
+                .variables
+                .export("OLDPWD", Some(&encoding::from_os_str(current.as_os_str())));
         }
+        context
+            .variables
+            .export("PWD", Some(&encoding::from_os_str(pwd.as_os_str())));
+        context.update_cwd();
 
-        env::set_current_dir(&target)
-            .with_context(|| format!("cd: Failed to change directory to '{}'", target.display()))?;
+        // Only directories someone went to count for `z`, not the ones scripts move through
+        if context.mode.interactive
+            && !context.mode.private
+            && let Err(error) = frecency::record(&pwd)
+        {
+            tracing::warn!(error = %format!("{error:#}"), "Failed to record the directory");
+        }
 
+        Self::update_dir_env(context, terminal)?;
+        // Run once the directory's own environment is in, so hooks see it
+        if let Some(current) = current.filter(|current| *current != pwd) {
+            let old = encoding::from_os_str(current.as_os_str());
+            let new = encoding::from_os_str(pwd.as_os_str());
+            hooks::run(context, terminal, Hook::Chpwd, &[&old, &new])?;
+        }
         Ok(0)
     }
 
-    pub fn exit(_args: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
+    /// Jumps to the visited directory that best matches the fragments, by how often and how
+    /// recently it was visited. `-l` lists the matches with their score instead
+    pub fn z(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let (list, fragments) = match args {
+            ["-l", rest @ ..] => (true, rest),
+            _ => (false, args),
+        };
+        let now = frecency::now();
+        let directories = Directories::load(&frecency::database_path());
+
+        if list || fragments.is_empty() {
+            for entry in directories.matching(fragments, now) {
+                let score = entry.score(now);
+                terminal.println(&format!("{score:<10.1} {}", entry.path.display()))?;
+            }
+            return Ok(0);
+        }
+
+        // A path is just entered, like `cd` would
+        if let [path] = fragments
+            && (path.contains('/') || *path == "..")
+            && Path::new(path).is_dir()
+        {
+            return Self::cd(&[path], context, terminal);
+        }
+
+        let current = context.variables.get("PWD").map(PathBuf::from);
+        let best = directories
+            .matching(fragments, now)
+            .into_iter()
+            .find(|entry| Some(&entry.path) != current.as_ref() && entry.path.is_dir());
+        match best {
+            Some(entry) => {
+                let path = encoding::from_os_str(entry.path.as_os_str());
+                Self::cd(&[&path], context, terminal)
+            }
+            None => Self::error(
+                "z",
+                &format!("{}: No visited directory matches", fragments.join(" ")),
+            ),
+        }
+    }
+
+    // Loads the env file of the new directory with the `direnv` option, asking before loading
+    // a file for the first time. Without a terminal to ask on the file is left alone
+    fn update_dir_env(context: &mut Context, terminal: &mut Terminal) -> Result<()> {
+        let change = match context.settings.options.direnv {
+            true => {
+                let directory = env::current_dir().context("cd: Failed to read the directory")?;
+                let can_ask = context.mode.interactive && io::stdin().is_terminal();
+                let ask = |file: &Path| {
+                    can_ask
+                        && Self::confirm(
+                            terminal,
+                            &format!("Load the variables of {}?", file.display()),
+                        )
+                };
+                context.dir_env.update(&directory, ask)?
+            }
+            false => context.dir_env.unload(),
+        };
+
+        if let Some(file) = &change.loaded {
+            terminal.println(&format!("Loaded {}", file.display()))?;
+        }
+        for name in &change.variables {
+            context.env_changed(name)?;
+        }
+        Ok(())
+    }
+
+    /// Asks a yes or no question on the terminal, anything but `y` is a no
+    pub fn confirm(terminal: &mut Terminal, question: &str) -> bool {
+        if terminal.print(&format!("{question} [y/N] ")).is_err() {
+            return false;
+        }
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+    }
+
+    /// Leaves the shell with the given status, or with the status of the last command. With
+    /// jobs left at the prompt it only lists them the first time, unless forced with `-f`
+    pub fn exit(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let (force, args) = match args {
+            ["-f", rest @ ..] => (true, rest),
+            _ => (false, args),
+        };
+        match args {
+            [] => {}
+            [status] => match status.parse::<i64>() {
+                // Statuses are a byte, like `exit 256` being 0 in other shells
+                Ok(status) => context.last_exit_code = status.rem_euclid(256) as i32,
+                Err(_) => {
+                    return Self::error("exit", &format!("{}: Needs a number", status));
+                }
+            },
+            _ => return Self::error("exit", "Only takes the status"),
+        }
+
+        let prompt = context.mode.interactive && context.sources.is_empty();
+        if prompt && !force && !context.jobs.confirm_exit(terminal)? {
+            return Ok(1);
+        }
         Err(ShellError::exit())?
     }
 
+    /// Leaves the loop it's in, `break 2` the one around it too
+    pub fn break_loop(args: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
+        let levels = Self::loop_levels("break", args)?;
+        Err(LoopControl::Break(levels))?
+    }
+
+    /// Goes on with the next iteration of the loop it's in, or of a loop around it
+    pub fn continue_loop(args: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
+        let levels = Self::loop_levels("continue", args)?;
+        Err(LoopControl::Continue(levels))?
+    }
+
+    fn loop_levels(name: &str, args: &[&str]) -> Result<usize> {
+        match args {
+            [] => Ok(1),
+            [levels] => match levels.parse::<usize>() {
+                Ok(levels) if levels > 0 => Ok(levels),
+                _ => Self::error(name, &format!("{}: Needs a number above 0", levels)),
+            },
+            _ => Self::error(name, "Only takes how many loops"),
+        }
+    }
+
     pub fn jobs(_: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
         for (_, job) in &context.jobs.table {
             terminal.println(&job.to_string())?;
@@ -80,6 +309,7 @@ impl BuiltIns {
         Ok(0)
     }
 
+    #[cfg(unix)]
     pub fn fg(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
         let job_id = Self::job_id_from_args("fg", args, context)?;
 
@@ -98,12 +328,13 @@ impl BuiltIns {
             // We send the SIGCONT signal to all the child procceses in that gpid
             libc::kill(-pgid, libc::SIGCONT);
 
-            if libc::tcsetpgrp(libc::STDIN_FILENO, pgid) == -1 {
+            if !jobs::give_terminal(pgid) {
                 return Self::error("fg", "Failed to give terminal to job");
             }
 
-            let job = context.jobs.table.get_mut(&job_id).unwrap();
-            job.state = JobState::Running;
+            if let Some(job) = context.jobs.table.get_mut(&job_id) {
+                job.state = JobState::Running;
+            }
 
             exit_code = context.jobs.wait_foreground(
                 context.pgid,
@@ -119,6 +350,7 @@ impl BuiltIns {
         Ok(exit_code)
     }
 
+    #[cfg(unix)]
     pub fn bg(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
         let job_id = Self::job_id_from_args("bg", args, context)?;
         let job = match context.jobs.table.get_mut(&job_id) {
@@ -140,6 +372,65 @@ impl BuiltIns {
         Ok(0)
     }
 
+    // Windows can't stop a program and go on with it later, so there are no jobs to resume
+    #[cfg(windows)]
+    pub fn fg(_: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
+        Self::error("fg", "No job control on Windows")
+    }
+
+    #[cfg(windows)]
+    pub fn bg(_: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
+        Self::error("bg", "No job control on Windows")
+    }
+
+    /// Keeps a job, the last stopped one without an argument, from being hung up when the
+    /// shell is left, and leaving doesn't wait for it anymore
+    pub fn disown(args: &[&str], context: &mut Context, _: &mut Terminal) -> Result<i32> {
+        let job_id = match args {
+            ["-a"] => {
+                for job in context.jobs.table.values_mut() {
+                    job.disowned = true;
+                }
+                return Ok(0);
+            }
+            _ => Self::job_id_from_args("disown", args, context)?,
+        };
+        match context.jobs.table.get_mut(&job_id) {
+            Some(job) => job.disowned = true,
+            None => return Self::error("disown", &format!("%{}: No such job", job_id)),
+        }
+        Ok(0)
+    }
+
+    /// Tells what each name runs: an alias, a builtin or the program found in PATH. Names
+    /// that are none of them are told on stderr, and the status is 1
+    pub fn type_of(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        if args.is_empty() {
+            return Self::error("type", "Usage: type name...");
+        }
+        let path = context.variables.get("PATH").unwrap_or_default();
+        let mut status = 0;
+        for &name in args {
+            let found = if let Some(value) = context.aliases.get(name) {
+                format!("{} is aliased to `{}`", name, value)
+            } else if context.builtins.get(name).is_some()
+                || context.plugins.builtin(name).is_some()
+            {
+                format!("{} is a shell builtin", name)
+            } else if name.contains('/') && Path::new(name).is_file() {
+                format!("{} is {}", name, name)
+            } else if let Some(program) = context.path_index.find(&path, name) {
+                format!("{} is {}", name, program.display())
+            } else {
+                eprintln!("type: {}: not found", name);
+                status = 1;
+                continue;
+            };
+            terminal.println(&found)?;
+        }
+        Ok(status)
+    }
+
     fn job_id_from_args(command_name: &str, args: &[&str], context: &mut Context) -> Result<usize> {
         if !args.is_empty() && args.len() != 1 {
             return Self::error(command_name, "Only one argument is expected");
@@ -175,13 +466,257 @@ impl BuiltIns {
         Ok(job_id)
     }
 
-    pub fn history(_: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
-        for (n, line) in context.history.current.iter().enumerate() {
-            terminal.println(&format!("{} {}", n, line))?;
+    pub fn history(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        context.history.load_all()?;
+        match args.first() {
+            Some(&"search") => return Self::history_search(&args[1..], context, terminal),
+            Some(&"import") => return Self::history_import(&args[1..], context, terminal),
+            Some(&"export") => return Self::history_export(&args[1..], context, terminal),
+            Some(&"stats") => return Self::history_stats(&args[1..], context, terminal),
+            Some(&"fuzzy") => return Self::history_fuzzy(&args[1..], context, terminal),
+            Some(subcommand) => {
+                return Self::error("history", &format!("Unknown subcommand: {subcommand}"));
+            }
+            None => {}
+        }
+
+        for (n, entry) in context.history.current.iter().enumerate() {
+            let duration = entry
+                .duration
+                .map(history::format_duration)
+                .unwrap_or_default();
+            terminal.println(&format!("{} {:>7} {}", n, duration, entry.command))?;
+        }
+        Ok(0)
+    }
+
+    fn history_export(
+        args: &[&str],
+        context: &mut Context,
+        terminal: &mut Terminal,
+    ) -> Result<i32> {
+        let mut format = ExportFormat::Plain;
+        let mut path = None;
+
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            if arg == "--format" {
+                let Some(name) = args.next() else {
+                    return Self::error("history", "--format expects jsonl, plain or csv");
+                };
+                format = match ExportFormat::from_name(name) {
+                    Some(format) => format,
+                    None => {
+                        return Self::error("history", &format!("Unknown export format: {name}"));
+                    }
+                };
+            } else if path.is_none() {
+                path = Some(arg);
+            } else {
+                return Self::error("history", "export expects at most one file");
+            }
+        }
+
+        let output = context.history.export(format);
+        match path {
+            Some(path) => fs::write(path, output)
+                .with_context(|| format!("history: Failed to write '{}'", path))?,
+            None => terminal.print(&output)?,
         }
+
         Ok(0)
     }
 
+    fn history_import(
+        args: &[&str],
+        context: &mut Context,
+        terminal: &mut Terminal,
+    ) -> Result<i32> {
+        let [path] = args else {
+            return Self::error(
+                "history",
+                "import expects the path of a bash or zsh history file",
+            );
+        };
+
+        // zsh escapes non ascii bytes in its history, so we can't assume valid UTF-8
+        let content =
+            fs::read(path).with_context(|| format!("history: Failed to read '{}'", path))?;
+        let count = context.history.import(&String::from_utf8_lossy(&content))?;
+
+        terminal.println(&format!("Imported {} commands from {}", count, path))?;
+        Ok(0)
+    }
+
+    fn history_fuzzy(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        if args.is_empty() {
+            return Self::error("history", "fuzzy expects a query");
+        }
+
+        let matches = context.history.fuzzy(&args.join(" "));
+        for (n, entry) in matches.iter().take(20) {
+            terminal.println(&format!("{} {}", n, entry.command))?;
+        }
+
+        Ok(if matches.is_empty() { 1 } else { 0 })
+    }
+
+    fn history_stats(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let mut filter = HistoryFilter::default();
+        let mut top = 10;
+
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            match arg {
+                "--top" => {
+                    let Some(n) = args.next().and_then(|s| s.parse().ok()) else {
+                        return Self::error("history", "--top expects a number");
+                    };
+                    top = n;
+                }
+                "--since" => {
+                    let Some(since) = args.next().and_then(|s| history::parse_since(s)) else {
+                        return Self::error(
+                            "history",
+                            "--since expects today, yesterday, a unix timestamp or an amount like 2h or 3d",
+                        );
+                    };
+                    filter.since = Some(since);
+                }
+                _ => return Self::error("history", &format!("Unknown stats option: {arg}")),
+            }
+        }
+
+        let stats = context.history.stats(&filter);
+        if stats.total == 0 {
+            terminal.println("No commands in history")?;
+            return Ok(1);
+        }
+
+        terminal.println(&format!("{} commands\n", stats.total))?;
+
+        terminal.println("Most used commands")?;
+        for command in stats.commands.iter().take(top) {
+            terminal.println(&format!("  {:>6}  {}", command.runs, command.name))?;
+        }
+
+        let mut failing: Vec<_> = stats.commands.iter().filter(|c| c.failures > 0).collect();
+        failing.sort_by(|a, b| {
+            b.failure_rate()
+                .total_cmp(&a.failure_rate())
+                .then_with(|| b.failures.cmp(&a.failures))
+        });
+        if !failing.is_empty() {
+            terminal.println("\nFailure rates")?;
+            terminal.println(&format!(
+                "  {:>6}  {:>6}  {:>5}  command",
+                "runs", "failed", "rate"
+            ))?;
+            for command in failing.iter().take(top) {
+                terminal.println(&format!(
+                    "  {:>6}  {:>6}  {:>4.0}%  {}",
+                    command.finished,
+                    command.failures,
+                    command.failure_rate() * 100.0,
+                    command.name
+                ))?;
+            }
+        }
+
+        if !stats.directories.is_empty() {
+            terminal.println("\nBusiest directories")?;
+            for (dir, count) in stats.directories.iter().take(top) {
+                terminal.println(&format!("  {:>6}  {}", count, dir.display()))?;
+            }
+        }
+
+        if !stats.days.is_empty() {
+            terminal.println("\nCommands per day")?;
+            let recent = &stats.days[stats.days.len().saturating_sub(top)..];
+            let busiest = recent.iter().map(|(_, count)| *count).max().unwrap_or(1);
+            for (day, count) in recent {
+                let bar = "#".repeat((count * 40).div_ceil(busiest));
+                terminal.println(&format!("  {}  {:>6}  {}", day, count, bar))?;
+            }
+        }
+
+        Ok(0)
+    }
+
+    fn history_search(
+        args: &[&str],
+        context: &mut Context,
+        terminal: &mut Terminal,
+    ) -> Result<i32> {
+        let mut filter = HistoryFilter::default();
+        let mut pattern = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(&arg) = args.next() {
+            match arg {
+                "--cwd" => {
+                    let Some(dir) = args.next() else {
+                        return Self::error("history", "--cwd expects a directory");
+                    };
+                    let dir = PathBuf::from(dir);
+                    filter.cwd = Some(dir.canonicalize().unwrap_or(dir));
+                }
+                "--failed" => filter.failed = true,
+                "--session" => {
+                    let Some(session) = args.next() else {
+                        return Self::error("history", "--session expects current or a session id");
+                    };
+                    filter.session = Some(match *session {
+                        "current" => history::session_id().to_string(),
+                        id => id.to_string(),
+                    });
+                }
+                "--slower-than" => {
+                    let Some(duration) = args.next().and_then(|s| history::parse_duration(s))
+                    else {
+                        return Self::error("history", "--slower-than expects a duration like 5s");
+                    };
+                    filter.slower_than = Some(duration);
+                }
+                "--since" => {
+                    let Some(since) = args.next().and_then(|s| history::parse_since(s)) else {
+                        return Self::error(
+                            "history",
+                            "--since expects today, yesterday, a unix timestamp or an amount like 2h or 3d",
+                        );
+                    };
+                    filter.since = Some(since);
+                }
+                _ => pattern.push(arg),
+            }
+        }
+
+        if !pattern.is_empty() {
+            filter.pattern = Some(pattern.join(" "));
+        }
+
+        let mut found = false;
+        for (n, entry) in context.history.search(&filter) {
+            let exit_code = entry.exit_code.map(|c| c.to_string()).unwrap_or_default();
+            let duration = entry
+                .duration
+                .map(history::format_duration)
+                .unwrap_or_default();
+            terminal.println(&format!(
+                "{} {:16} {:>3} {:>7} {} {}",
+                n,
+                history::format_timestamp(entry.timestamp),
+                exit_code,
+                duration,
+                entry.cwd.display(),
+                entry.command
+            ))?;
+            found = true;
+        }
+
+        Ok(if found { 0 } else { 1 })
+    }
+
     pub fn alias(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
         if args.is_empty() {
             for (name, value) in context.aliases.get_map() {
@@ -190,15 +725,39 @@ impl BuiltIns {
             return Ok(0);
         }
 
+        // `--save` also writes the alias to the rc file, always on with `savealiases`
+        let (save, args) = match args.split_first() {
+            Some((&"--save", rest)) => (true, rest),
+            _ => (context.settings.options.savealiases, args),
+        };
+
+        // `alias --save name` saves an alias that was already defined
+        if let [name] = args
+            && !name.contains('=')
+            && save
+        {
+            let Some(value) = context.aliases.get(name).cloned() else {
+                return Self::error("alias", &format!("No alias found for name: {name}"));
+            };
+            return Self::save_alias(name, Some(&value));
+        }
+
         let (name, mut value) = Self::check_env_var_args("alias", args)?;
 
         value = EnvVariable::strip_quotes_from_value(value);
         context.aliases.add(name.to_string(), value.to_string());
 
+        if save {
+            Self::save_alias(name, Some(value))?;
+        }
         Ok(0)
     }
 
     pub fn unalias(args: &[&str], context: &mut Context, _: &mut Terminal) -> Result<i32> {
+        let (save, args) = match args.split_first() {
+            Some((&"--save", rest)) => (true, rest),
+            _ => (context.settings.options.savealiases, args),
+        };
         if args.len() != 1 {
             return Self::error("unalias", "Only accepts 1 parameter");
         }
@@ -210,36 +769,805 @@ impl BuiltIns {
 
         context.aliases.remove(name);
 
+        // Otherwise it would be back in the next shell
+        if save {
+            Self::save_alias(name, None)?;
+        }
         Ok(0)
     }
 
-    pub fn export(args: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
-        let (name, value) = Self::check_env_var_args("export", args)?;
+    fn save_alias(name: &str, value: Option<&str>) -> Result<i32> {
+        match aliases::save(&context::rc_path(), name, value) {
+            Ok(()) => Ok(0),
+            Err(error) => Self::error("alias", &format!("{error:#}")),
+        }
+    }
 
-        unsafe {
-            env::set_var(name, value);
+    pub fn export(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        // Like sh, POSIX mode lists the exported variables in a form that can be run again
+        if context.mode.posix && matches!(args, [] | ["-p"]) {
+            for (name, value) in context.variables.all() {
+                if context.variables.is_exported(&name) {
+                    let value = value.replace('\'', "'\\''");
+                    terminal.println(&format!("export {}='{}'", name, value))?;
+                }
+            }
+            return Ok(0);
         }
 
+        // `export name` hands a variable of the shell to the commands it runs
+        if let [name] = args
+            && !name.contains('=')
+        {
+            if !context.variables.is_set(name) {
+                return Self::error("export", &format!("{}: Isn't set, use name='value'", name));
+            }
+            context.variables.export(name, None);
+            context.env_changed(name)?;
+            return Ok(0);
+        }
+
+        // Through the variable's attributes, so `declare -i n; export n=1+1` exports 2
+        let (name, value) = Self::check_env_var_args("export", args)?;
+        context.variables.assign(name, value)?;
+        context.variables.export(name, None);
+        context.env_changed(name)?;
+
         Ok(0)
     }
 
-    pub fn unset(args: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
+    pub fn unset(args: &[&str], context: &mut Context, _: &mut Terminal) -> Result<i32> {
         if args.len() != 1 {
             return Self::error("unset", "Only either none or 1 parameter");
         }
 
-        unsafe {
-            env::remove_var(args[0]);
+        context.variables.unset(args[0]);
+        context.env_changed(args[0])?;
+
+        Ok(0)
+    }
+
+    /// Runs a file in the current shell, the arguments after it are the positional parameters
+    /// while it runs
+    pub fn source(args: &[&str], context: &mut Context, _: &mut Terminal) -> Result<i32> {
+        let Some((file, arguments)) = args.split_first() else {
+            return Self::error("source", "Needs the file to run");
+        };
+        let Some(path) = Self::find_source_file(context, file) else {
+            return Self::error("source", &format!("{}: No such file", file));
+        };
+
+        let positional = match arguments.is_empty() {
+            true => None,
+            false => Some(std::mem::replace(
+                &mut context.positional,
+                arguments
+                    .iter()
+                    .map(|argument| argument.to_string())
+                    .collect(),
+            )),
+        };
+        // The file's `local` variables go away with it, however it ends
+        context.variables.push_scope();
+        let keep_running = Context::source_file(context, &path);
+        context.variables.pop_scope();
+        if let Some(positional) = positional {
+            context.positional = positional;
+        }
+
+        if !keep_running? {
+            Err(ShellError::exit())?
+        }
+        Ok(context.last_exit_code)
+    }
+
+    /// `set -- a b c` replaces the positional parameters, `set --` clears them. Without
+    /// arguments it lists the variables
+    pub fn set(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let parameters = match args {
+            [] => {
+                for (name, value) in context.variables.all() {
+                    let line = match context.mode.posix {
+                        // Quoted so the list can be run again
+                        true => format!("{}='{}'", name, value.replace('\'', "'\\''")),
+                        false => format!("{}={}", name, value),
+                    };
+                    terminal.println(&line)?;
+                }
+                return Ok(0);
+            }
+            ["--", parameters @ ..] => parameters,
+            [flag @ ("-o" | "+o"), "dryrun"] => {
+                context.mode.dry_run = *flag == "-o";
+                return Ok(0);
+            }
+            [option, ..] if option.starts_with('-') || option.starts_with('+') => {
+                return Self::error("set", &format!("Unknown option: {}", option));
+            }
+            parameters => parameters,
+        };
+
+        context.positional = parameters.iter().map(|value| value.to_string()).collect();
+        Ok(0)
+    }
+
+    /// Makes variables that only last until the sourced file running it is done, hiding
+    /// those with the same name meanwhile. Without names it lists them
+    pub fn local(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        if args.is_empty() {
+            for name in context.variables.locals() {
+                let value = context.variables.get(&name).unwrap_or_default();
+                terminal.println(&format!("{}={}", name, value))?;
+            }
+            return Ok(0);
+        }
+
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (*arg, None),
+            };
+            if !is_name(name) {
+                return Self::error("local", &format!("'{}' isn't a valid name", name));
+            }
+            if !context.variables.make_local(name, value)? {
+                return Self::error("local", "Can only be used in a sourced file");
+            }
+            context.env_changed(name)?;
+        }
+        Ok(0)
+    }
+
+    /// Saves the variables, exported or not, and the directory under a name for `envrestore`.
+    /// Without a name it lists the saved ones, `-d` forgets one
+    pub fn envsave(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        match args {
+            [] => {
+                let mut names: Vec<&String> = context.snapshots.keys().collect();
+                names.sort();
+                for name in names {
+                    terminal.println(name)?;
+                }
+            }
+            ["-d", name] => {
+                if context.snapshots.remove(*name).is_none() {
+                    return Self::error(
+                        "envsave",
+                        &format!("{}: Nothing saved with that name", name),
+                    );
+                }
+            }
+            [name] => {
+                let snapshot = context.snapshot();
+                context.snapshots.insert(name.to_string(), snapshot);
+            }
+            _ => return Self::error("envsave", "Use envsave [-d] [name]"),
+        }
+        Ok(0)
+    }
+
+    /// Puts back the variables and the directory saved with `envsave`, the snapshot is kept so
+    /// it can be restored again
+    pub fn envrestore(args: &[&str], context: &mut Context, _: &mut Terminal) -> Result<i32> {
+        let [name] = args else {
+            return Self::error("envrestore", "Needs the name it was saved with");
+        };
+        let Some(snapshot) = context.snapshots.get(*name).cloned() else {
+            return Self::error(
+                "envrestore",
+                &format!("{}: Nothing saved with that name", name),
+            );
+        };
+
+        if let Err(error) = context.restore(&snapshot) {
+            return Self::error("envrestore", &format!("{error:#}"));
+        }
+        Ok(0)
+    }
+
+    /// Gives variables attributes their values go through when they're assigned, `-i` works
+    /// them out as arithmetic and `-u` or `-l` makes them upper or lowercase. `+` takes an
+    /// attribute away and `-x` exports them. Without names, or with `-p`, it shows variables
+    pub fn declare(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let mut add = Attributes::default();
+        let mut remove = Attributes::default();
+        let (mut export, mut print) = (false, false);
+
+        let mut names = args;
+        while let [option, rest @ ..] = names {
+            if *option == "--" {
+                names = rest;
+                break;
+            }
+            let (adding, flags) = match option.split_at_checked(1) {
+                Some(("-", flags)) if !flags.is_empty() => (true, flags),
+                Some(("+", flags)) if !flags.is_empty() => (false, flags),
+                _ => break,
+            };
+            let attributes = match adding {
+                true => &mut add,
+                false => &mut remove,
+            };
+            for flag in flags.chars() {
+                match flag {
+                    'i' => attributes.integer = true,
+                    'u' => attributes.upper = true,
+                    'l' => attributes.lower = true,
+                    'x' if adding => export = true,
+                    'p' if adding => print = true,
+                    _ => return Self::error("declare", &format!("Unknown option: {}", option)),
+                }
+            }
+            names = rest;
+        }
+
+        if add.upper && add.lower {
+            return Self::error("declare", "Only one of -u and -l");
+        }
+
+        if names.is_empty() || print {
+            for (name, value) in context.variables.all() {
+                if !names.is_empty() && !names.contains(&name.as_str()) {
+                    continue;
+                }
+                let mut flags = context.variables.attributes(&name).flags();
+                if context.variables.is_exported(&name) {
+                    flags.push('x');
+                }
+                let flags = match flags.is_empty() {
+                    true => "--".to_string(),
+                    false => format!("-{}", flags),
+                };
+                terminal.println(&format!("declare {} {}=\"{}\"", flags, name, value))?;
+            }
+            return Ok(0);
+        }
+
+        for name in names {
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (*name, None),
+            };
+            if name.is_empty() {
+                return Self::error("declare", "Name can't be empty");
+            }
+
+            // Upper and lowercase can't both be on, asking for one turns the other off
+            let mut attributes = context.variables.attributes(name);
+            attributes.integer = (attributes.integer || add.integer) && !remove.integer;
+            attributes.upper = (attributes.upper || add.upper) && !add.lower && !remove.upper;
+            attributes.lower = (attributes.lower || add.lower) && !add.upper && !remove.lower;
+            context.variables.set_attributes(name, attributes);
+
+            if let Some(value) = value {
+                context.variables.assign(name, value)?;
+            }
+            if export && context.variables.is_set(name) {
+                context.variables.export(name, None);
+            }
+            context.env_changed(name)?;
         }
+        Ok(0)
+    }
 
+    /// Drops the first positional parameters, one unless told how many, so `$2` becomes `$1`
+    pub fn shift(args: &[&str], context: &mut Context, _: &mut Terminal) -> Result<i32> {
+        let count = match args {
+            [] => 1,
+            [count] => match count.parse::<usize>() {
+                Ok(count) => count,
+                Err(_) => return Self::error("shift", &format!("{}: Needs a number", count)),
+            },
+            _ => return Self::error("shift", "Only takes how many to drop"),
+        };
+
+        if count > context.positional.len() {
+            return Self::error(
+                "shift",
+                &format!(
+                    "Can't drop {}, there are {}",
+                    count,
+                    context.positional.len()
+                ),
+            );
+        }
+        context.positional.drain(..count);
         Ok(0)
     }
 
-    pub fn pwd(_: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
-        terminal.println(&context.directory.to_string_lossy())?;
+    // Relative paths are looked up in the current directory and then next to the script
+    // doing the sourcing, so scripts can source their neighbours wherever they're run from
+    fn find_source_file(context: &Context, file: &str) -> Option<PathBuf> {
+        let path = PathBuf::from(encoding::to_os_string(file));
+        if path.is_file() {
+            return Some(path);
+        }
+        if path.is_absolute() {
+            return None;
+        }
+
+        let script = context
+            .sources
+            .iter()
+            .rev()
+            .find_map(|source| source.file.as_ref())?;
+        Some(script.parent()?.join(&path)).filter(|path| path.is_file())
+    }
+
+    /// Does nothing and succeeds, for the side effects of expanding its arguments like in
+    /// `: $((count += 1))`
+    pub fn colon(_: &[&str], _: &mut Context, _: &mut Terminal) -> Result<i32> {
         Ok(0)
     }
 
+    /// Stops the sourced file it's in, with the given status or the one of the last command
+    pub fn return_from(args: &[&str], context: &mut Context, _: &mut Terminal) -> Result<i32> {
+        let status = match args {
+            [] => context.last_exit_code,
+            [status] => match status.parse::<i64>() {
+                Ok(status) => status.rem_euclid(256) as i32,
+                Err(_) => return Self::error("return", &format!("{}: Needs a number", status)),
+            },
+            _ => return Self::error("return", "Only takes the status"),
+        };
+        // At the prompt nothing catches it, which reports it as a mistake
+        Err(Return(status))?
+    }
+
+    /// Stops recording commands in the history and directories for `z` until `private off`,
+    /// for typing secrets. `private status` tells whether it's on
+    pub fn private(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        match args {
+            [] | ["on"] => context.mode.private = true,
+            ["off"] => context.mode.private = false,
+            ["status"] => {
+                let state = if context.mode.private { "on" } else { "off" };
+                terminal.println(&format!("Private mode is {}", state))?;
+            }
+            _ => return Self::error("private", "Use private [on | off | status]"),
+        }
+        Ok(0)
+    }
+
+    /// Runs a command every few seconds (`-n`, 2 by default) on a cleared screen until Ctrl-C
+    /// or `q`. The arguments are run as they are, aliases and builtins work too
+    pub fn watch(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let (interval, command) = match args {
+            ["-n", seconds, rest @ ..] => match seconds.parse::<f64>() {
+                Ok(seconds) if seconds > 0.0 && seconds.is_finite() => (seconds, rest),
+                _ => return Self::error("watch", &format!("Invalid interval '{}'", seconds)),
+            },
+            _ => (2.0, args),
+        };
+        if command.is_empty() {
+            return Self::error("watch", "Usage: watch [-n seconds] command");
+        }
+        let line = command.join(" ");
+        let command = Self::argv_command(command);
+        let interval = Duration::from_secs_f64(interval);
+        // Keys are read between runs to stop, without a terminal it's only a pause
+        let keys = io::stdin().is_terminal() && !terminal.is_dumb();
+
+        loop {
+            terminal.clear_screen()?;
+            terminal.println(&format!("Every {:?}: {}\n", interval, line))?;
+            let result = expander::expand(context, terminal, command.clone(), &[])
+                .and_then(|command| executor::execute(context, terminal, command, None));
+            let status = match result {
+                Ok((status, _)) => status,
+                Err(error)
+                    if error
+                        .downcast_ref::<ShellError>()
+                        .is_some_and(|error| error.is_exit()) =>
+                {
+                    return Err(error);
+                }
+                Err(error) => {
+                    terminal.println(&format!("{:#}", error))?;
+                    1
+                }
+            };
+            // Ctrl-C while it runs goes to the command, which stops the watch too
+            if status == 128 + libc::SIGINT {
+                return Ok(status);
+            }
+
+            if !keys {
+                std::thread::sleep(interval);
+                continue;
+            }
+            let deadline = Instant::now() + interval;
+            terminal.enter_raw_mode()?;
+            let stop = loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() || context.signals.ending().is_some() {
+                    break context.signals.ending().is_some();
+                }
+                match terminal.read_key(left) {
+                    Ok(Some(key))
+                        if key.modifiers.contains(KeyModifiers::CONTROL)
+                            && key.code == KeyCode::Char('c') =>
+                    {
+                        break true;
+                    }
+                    Ok(Some(key)) if key.code == KeyCode::Char('q') => break true,
+                    Ok(_) => {}
+                    Err(error) => {
+                        terminal.exit_raw_mode()?;
+                        return Err(error);
+                    }
+                }
+            };
+            terminal.exit_raw_mode()?;
+            if stop {
+                return Ok(0);
+            }
+        }
+    }
+
+    /// Runs a command, builtins and functions too, and stops it with a signal (SIGTERM unless
+    /// `-s` says otherwise) once the time is over, then with SIGKILL after `-k` whatever is
+    /// still running. Like coreutils' timeout the status is 124 when the time ran out, 137 when
+    /// `-k` had to kill something or the signal was KILL
+    pub fn timeout(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let usage = "Usage: timeout [-s signal] [-k duration] duration command";
+        let mut signal = libc::SIGTERM;
+        let mut kill_after = None;
+        let mut args = args;
+        loop {
+            match args {
+                ["-s", name, rest @ ..] => {
+                    signal = match prompt::signal_number(name) {
+                        Some(signal) => signal,
+                        None => {
+                            return Self::error("timeout", &format!("Unknown signal '{}'", name));
+                        }
+                    };
+                    args = rest;
+                }
+                ["-k", duration, rest @ ..] => {
+                    kill_after = match history::parse_duration(duration) {
+                        Some(millis) => Some(Duration::from_millis(millis)),
+                        None => {
+                            return Self::error(
+                                "timeout",
+                                &format!("Invalid duration '{}'", duration),
+                            );
+                        }
+                    };
+                    args = rest;
+                }
+                _ => break,
+            }
+        }
+        let [duration, command @ ..] = args else {
+            return Self::error("timeout", usage);
+        };
+        if command.is_empty() {
+            return Self::error("timeout", usage);
+        }
+        let Some(limit) = history::parse_duration(duration) else {
+            return Self::error("timeout", &format!("Invalid duration '{}'", duration));
+        };
+
+        let command = Self::argv_command(command);
+        // No limit at all, like coreutils
+        if limit == 0 {
+            return Ok(executor::execute(context, terminal, command, None)?.0);
+        }
+        let limit = Duration::from_millis(limit);
+        let (status, timed_out) =
+            executor::execute_timed(context, command, limit, signal, kill_after)?;
+        // Windows only ends a program outright, never by a signal that could be KILL
+        #[cfg(unix)]
+        let killed = status == 128 + libc::SIGKILL;
+        #[cfg(windows)]
+        let killed = false;
+        Ok(match timed_out && !killed {
+            true => 124,
+            false => status,
+        })
+    }
+
+    /// Runs a command a number of times, until Ctrl-C or with `-e` the first failure, then
+    /// tells how many runs failed and how long they took. Like `watch` the arguments are run as
+    /// they are. The status is the one of the last failure, 0 if none failed
+    pub fn repeat(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let (stop_on_failure, args) = match args {
+            ["-e", rest @ ..] => (true, rest),
+            _ => (false, args),
+        };
+        let [count, command @ ..] = args else {
+            return Self::error("repeat", "Usage: repeat [-e] count command");
+        };
+        if command.is_empty() {
+            return Self::error("repeat", "Usage: repeat [-e] count command");
+        }
+        let Ok(count) = count.parse::<usize>() else {
+            return Self::error("repeat", &format!("{}: Needs a number", count));
+        };
+        let command = Self::argv_command(command);
+
+        let started = Instant::now();
+        let mut runs = 0;
+        let mut failures: BTreeMap<i32, usize> = BTreeMap::new();
+        let mut last_failure = 0;
+        while runs < count {
+            let result = expander::expand(context, terminal, command.clone(), &[])
+                .and_then(|command| executor::execute(context, terminal, command, None));
+            let status = match result {
+                Ok((status, _)) => status,
+                Err(error)
+                    if error
+                        .downcast_ref::<ShellError>()
+                        .is_some_and(|error| error.is_exit()) =>
+                {
+                    return Err(error);
+                }
+                Err(error) => {
+                    terminal.println(&format!("{:#}", error))?;
+                    1
+                }
+            };
+            context.last_exit_code = status;
+            runs += 1;
+            if status != 0 {
+                *failures.entry(status).or_default() += 1;
+                last_failure = status;
+            }
+            // Ctrl-C goes to the command, the runs after it are left out
+            if status == 128 + libc::SIGINT || (stop_on_failure && status != 0) {
+                break;
+            }
+        }
+
+        let elapsed = started.elapsed().as_millis() as u64;
+        let failed: usize = failures.values().sum();
+        let mut summary = format!(
+            "{} {} in {} ({} each): {} passed, {} failed",
+            runs,
+            if runs == 1 { "run" } else { "runs" },
+            history::format_duration(elapsed),
+            history::format_duration(elapsed / runs.max(1) as u64),
+            runs - failed,
+            failed
+        );
+        if !failures.is_empty() {
+            let statuses: Vec<String> = failures
+                .iter()
+                .map(|(status, times)| format!("status {} ×{}", status, times))
+                .collect();
+            summary.push_str(&format!(" ({})", statuses.join(", ")));
+        }
+        terminal.println(&summary)?;
+        Ok(last_failure)
+    }
+
+    /// Loads plugins, shared libraries that bring builtins, prompt segments, completions and
+    /// hooks, unloads them, or lists them with what they registered
+    pub fn plugin(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        match args {
+            [] | ["list"] => {
+                for plugin in context.plugins.list() {
+                    terminal.println(&format!("{} {}", plugin.name, plugin.path.display()))?;
+                    let builtins: Vec<&str> = plugin
+                        .builtins
+                        .iter()
+                        .map(|builtin| builtin.name.as_str())
+                        .collect();
+                    let segments: Vec<&str> = plugin.segment_names().collect();
+                    let completions: Vec<&str> = plugin
+                        .completions
+                        .iter()
+                        .map(|(command, _)| command.as_str())
+                        .collect();
+                    for (kind, names) in [
+                        ("builtins", builtins),
+                        ("segments", segments),
+                        ("completions", completions),
+                    ] {
+                        if !names.is_empty() {
+                            terminal.println(&format!("  {}: {}", kind, names.join(" ")))?;
+                        }
+                    }
+                    for (hook, command) in &plugin.hooks {
+                        terminal.println(&format!("  {}: {}", hook.name(), command))?;
+                    }
+                }
+            }
+            ["load", targets @ ..] if !targets.is_empty() => {
+                for target in targets {
+                    let plugin = context
+                        .plugins
+                        .load(target)
+                        .with_context(|| format!("plugin: Failed to load {}", target))?;
+                    for (hook, command) in plugin.hooks.clone() {
+                        context.hooks.add(hook, &command);
+                    }
+                }
+            }
+            ["unload", names @ ..] if !names.is_empty() => {
+                for name in names {
+                    let Some(plugin) = context.plugins.unload(name) else {
+                        return Self::error("plugin", &format!("{}: No such plugin", name));
+                    };
+                    for (hook, command) in &plugin.hooks {
+                        context.hooks.remove(*hook, Some(command));
+                    }
+                }
+            }
+            _ => {
+                return Self::error(
+                    "plugin",
+                    "Use plugin [list | load path... | unload name...]",
+                );
+            }
+        }
+        Ok(0)
+    }
+
+    /// Adds a command to run before each prompt (`precmd`), before each command (`preexec`,
+    /// which gets the command as `$1`) or after `cd` changed the directory (`chpwd`, with the
+    /// old and the new one), `-d` removes one or all of them. Lists them without arguments
+    pub fn hook(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let hook = |name: &str| match Hook::from_name(name) {
+            Some(hook) => Ok(hook),
+            None => Self::error(
+                "hook",
+                &format!("Unknown hook '{}', use precmd, preexec or chpwd", name),
+            ),
+        };
+
+        match args {
+            [] => {
+                for hook in [Hook::Precmd, Hook::Preexec, Hook::Chpwd] {
+                    for command in context.hooks.get(hook) {
+                        terminal.println(&format!("{} {}", hook.name(), command))?;
+                    }
+                }
+            }
+            ["-d", name] => {
+                context.hooks.remove(hook(name)?, None);
+            }
+            ["-d", name, command] => {
+                if !context.hooks.remove(hook(name)?, Some(command)) {
+                    return Self::error("hook", &format!("'{}' isn't a {} hook", command, name));
+                }
+            }
+            [name, command] => context.hooks.add(hook(name)?, command),
+            _ => return Self::error("hook", "Use hook [-d] precmd|preexec|chpwd 'command'"),
+        }
+        Ok(0)
+    }
+
+    /// Prints the directory as it was reached, `-P` prints it with the symlinks resolved
+    pub fn pwd(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let directory = match args {
+            ["-P"] => env::current_dir().context("pwd: Failed to read the directory")?,
+            _ => context.update_cwd().clone(),
+        };
+        terminal.println(&directory.to_string_lossy())?;
+        Ok(0)
+    }
+
+    /// Reads the config file again and applies it, listing what changed
+    pub fn reload(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        if !args.is_empty() {
+            return Self::error("reload", "Doesn't take any parameter");
+        }
+
+        let (settings, problems) = match Settings::load() {
+            Ok(loaded) => loaded,
+            Err(error) => return Self::error("reload", &format!("{:#}", error)),
+        };
+
+        let changes = context.settings.changes(&settings);
+        context.apply_settings(settings);
+        if changes.is_empty() {
+            terminal.println("Nothing changed")?;
+        }
+        for change in changes {
+            terminal.println(&change)?;
+        }
+
+        // The rest was applied, these settings are back to their default
+        for problem in &problems {
+            terminal.println(problem)?;
+        }
+        Ok(if problems.is_empty() { 0 } else { 1 })
+    }
+
+    /// `theme` lists the themes marking the one in use, `theme <name>` switches to another
+    /// for the session. The config file's `colors.theme` picks the one new shells start with
+    pub fn theme(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let current =
+            env::var("PROMPT_THEME").unwrap_or_else(|_| context.settings.colors.theme.clone());
+
+        let name = match args {
+            [] => {
+                for name in Theme::available() {
+                    let marker = if name == current { "*" } else { " " };
+                    terminal.println(&format!("{marker} {name}"))?;
+                }
+                return Ok(0);
+            }
+            [name] => *name,
+            _ => return Self::error("theme", "Only either none or 1 parameter"),
+        };
+
+        match Theme::load(name) {
+            Ok(Some(_)) => {}
+            Ok(None) => return Self::error("theme", &format!("No theme found for name: {name}")),
+            Err(error) => return Self::error("theme", &format!("{:#}", error)),
+        }
+
+        context.settings.colors.theme = name.to_string();
+        // It would win over the setting otherwise
+        if env::var_os("PROMPT_THEME").is_some() {
+            unsafe {
+                env::set_var("PROMPT_THEME", name);
+            }
+        }
+        Ok(0)
+    }
+
+    /// `shopt` lists the interactive options, `-s`/`-u` turn them on and off, `-q` only
+    /// tells through the exit code whether they're all on and `-p` prints them as commands.
+    /// Changes last for the session, the `[options]` section of the config file keeps them
+    pub fn shopt(args: &[&str], context: &mut Context, terminal: &mut Terminal) -> Result<i32> {
+        let (flag, names) = match args.first() {
+            Some(&flag) if flag.starts_with('-') => (flag, &args[1..]),
+            _ => ("", args),
+        };
+
+        let options = &mut context.settings.options;
+        for name in names {
+            if options.get(name).is_none() {
+                return Self::error("shopt", &format!("Unknown option: {name}"));
+            }
+        }
+
+        match flag {
+            "-s" | "-u" => {
+                if names.is_empty() {
+                    return Self::error("shopt", "Needs the options to change");
+                }
+                for name in names {
+                    options.set(name, flag == "-s");
+                }
+                Ok(0)
+            }
+            "" | "-q" | "-p" => {
+                let listed: Vec<&str> = match names.is_empty() {
+                    true => OPTIONS.iter().map(|(name, _)| *name).collect(),
+                    false => names.to_vec(),
+                };
+
+                let mut all_on = true;
+                for name in listed {
+                    let on = options.get(name).unwrap_or_default();
+                    all_on &= on;
+                    let description = OPTIONS
+                        .iter()
+                        .find(|(option, _)| *option == name)
+                        .map_or("", |(_, description)| description);
+                    match flag {
+                        "-p" => terminal
+                            .println(&format!("shopt {} {name}", if on { "-s" } else { "-u" }))?,
+                        "" => terminal.println(&format!(
+                            "{name:<12} {:<3}  {description}",
+                            if on { "on" } else { "off" }
+                        ))?,
+                        _ => {}
+                    }
+                }
+                // Like bash, asking about options fails when one of them is off
+                Ok(if all_on || names.is_empty() { 0 } else { 1 })
+            }
+            _ => Self::error("shopt", &format!("Unknown flag: {flag}")),
+        }
+    }
+
     fn check_env_var_args<'a>(function_name: &str, args: &'a [&str]) -> Result<(&'a str, &'a str)> {
         if function_name == "export" && args.is_empty() {
             return Self::error(function_name, "Needs at least one parameter");
@@ -268,6 +1596,19 @@ impl BuiltIns {
         Ok((name, value))
     }
 
+    // The arguments are already expanded, each one is quoted so they're run as they are
+    fn argv_command(command: &[&str]) -> Command<'static> {
+        Command::Simple {
+            command: Cow::Owned(command[0].to_string()),
+            args: command[1..]
+                .iter()
+                .map(|arg| Arg::SingleQuoted(Cow::Owned(arg.to_string())))
+                .collect(),
+            redirects: Vec::new(),
+            env_vars: Vec::new(),
+        }
+    }
+
     fn error<T>(name: &str, message: &str) -> Result<T> {
         Err(anyhow::Error::new(ShellError {
             phase: ShellPhase::Executor,
@@ -276,3 +1617,19 @@ impl BuiltIns {
         }))
     }
 }
+
+// The target taken from the directory without looking at symlinks, `..` drops the last part of
+// the path like it was typed
+fn logical_path(current: &Path, target: &Path) -> PathBuf {
+    let mut path = PathBuf::from("/");
+    for component in current.join(target).components() {
+        match component {
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    path
+}
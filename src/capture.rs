@@ -0,0 +1,157 @@
+//capture.rs
+
+use crate::{
+    encoding,
+    error::{ShellError, ShellPhase},
+    sys::{RawFd, STDOUT_FILENO},
+};
+use anyhow::Result;
+use std::{
+    io::{self, Write},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver},
+    },
+    thread,
+    time::Duration,
+};
+
+/// How much of the output is kept, the rest still reaches the terminal
+pub const LIMIT: usize = 64 * 1024;
+
+// How long the copy is waited for once the command is over. A program it left behind, like a
+// stopped job, can hold the pipe for good, its output keeps going to the terminal then
+const DRAIN: Duration = Duration::from_millis(100);
+
+/// The output of a command copied on its way to the terminal, for `$LAST_OUTPUT`. Stdout of
+/// the shell is a pipe meanwhile, what's written into it goes to the terminal as it comes
+pub struct Capture {
+    saved: RawFd,
+    output: Arc<Mutex<Vec<u8>>>,
+    done: Receiver<()>,
+}
+
+impl Capture {
+    pub fn start() -> Result<Self> {
+        io::stdout().flush()?;
+        let mut fds = [0; 2];
+        #[cfg(unix)]
+        let (saved, terminal) = unsafe {
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) == -1 {
+                return os_error();
+            }
+            (
+                libc::fcntl(STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 3),
+                libc::fcntl(STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 3),
+            )
+        };
+        // Programs are given the handle behind stdout when they start, so the one of the pipe
+        // once it's put there
+        #[cfg(windows)]
+        let (saved, terminal) = unsafe {
+            if libc::pipe(
+                fds.as_mut_ptr(),
+                64 * 1024,
+                libc::O_BINARY | libc::O_NOINHERIT,
+            ) == -1
+            {
+                return os_error();
+            }
+            (libc::dup(STDOUT_FILENO), libc::dup(STDOUT_FILENO))
+        };
+        let [read_end, write_end] = fds;
+        if saved == -1 || terminal == -1 || unsafe { libc::dup2(write_end, STDOUT_FILENO) } == -1 {
+            unsafe {
+                for fd in [read_end, write_end, saved, terminal] {
+                    libc::close(fd);
+                }
+            }
+            return os_error();
+        }
+        unsafe { libc::close(write_end) };
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let (sender, done) = mpsc::channel();
+        let kept = Arc::clone(&output);
+        thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            loop {
+                let read = unsafe {
+                    libc::read(
+                        read_end,
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len() as _,
+                    )
+                };
+                if read == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                if read <= 0 {
+                    break;
+                }
+                let chunk = &buffer[..read as usize];
+                write_all(terminal, chunk);
+                let mut kept = kept.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                let room = LIMIT.saturating_sub(kept.len()).min(chunk.len());
+                kept.extend_from_slice(&chunk[..room]);
+            }
+            unsafe {
+                libc::close(read_end);
+                libc::close(terminal);
+            }
+            let _ = sender.send(());
+        });
+
+        Ok(Self {
+            saved,
+            output,
+            done,
+        })
+    }
+
+    /// Puts stdout back and gives the output, without the last newline like `$(...)`
+    pub fn finish(self) -> String {
+        let _ = io::stdout().flush();
+        unsafe {
+            libc::dup2(self.saved, STDOUT_FILENO);
+            libc::close(self.saved);
+        }
+        let _ = self.done.recv_timeout(DRAIN);
+
+        let output = self
+            .output
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut output = encoding::decode(&output);
+        if output.ends_with('\n') {
+            output.pop();
+            if output.ends_with('\r') {
+                output.pop();
+            }
+        }
+        output
+    }
+}
+
+// A terminal that went away only loses the copy, the command isn't bothered
+fn write_all(fd: RawFd, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        let written =
+            unsafe { libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len() as _) };
+        if written == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if written <= 0 {
+            return;
+        }
+        bytes = &bytes[written as usize..];
+    }
+}
+
+fn os_error<T>() -> Result<T> {
+    Err(anyhow::Error::new(ShellError {
+        phase: ShellPhase::Executor,
+        command: None,
+        message: io::Error::last_os_error().to_string(),
+    }))
+}
@@ -0,0 +1,103 @@
+//clipboard.rs
+
+use crate::terminal::Terminal;
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use std::{
+    env,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+// Programs that set and read the system clipboard, the first one found is used. Wayland and
+// X only count when there's a display to talk to
+const TOOLS: [(&str, &[&str], &[&str]); 4] = [
+    ("wl-copy", &[], &["wl-paste", "--no-newline"]),
+    (
+        "xclip",
+        &["-selection", "clipboard"],
+        &["xclip", "-selection", "clipboard", "-o"],
+    ),
+    (
+        "xsel",
+        &["--clipboard", "--input"],
+        &["xsel", "--clipboard", "--output"],
+    ),
+    ("pbcopy", &[], &["pbpaste"]),
+];
+
+/// Puts text on the clipboard. It's written with OSC 52, which terminals like kitty, WezTerm,
+/// iTerm2 and tmux turn into a copy even over SSH, and handed to the platform's clipboard
+/// tool too when there's one (`wl-copy`, `xclip`, `xsel` or `pbcopy`)
+pub fn copy(terminal: &mut Terminal, text: &str) -> Result<()> {
+    if !terminal.is_dumb() {
+        terminal.print(&osc52(text))?;
+    }
+    let Some((program, args, _)) = tool() else {
+        return Ok(());
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(text.as_bytes())
+            .with_context(|| format!("Failed to give the text to {}", program))?;
+    }
+    // The stdin is closed by now. Those that keep serving the clipboard, like wl-copy and
+    // xclip, do it from a process of their own, so this doesn't wait long
+    child
+        .wait()
+        .with_context(|| format!("Failed to run {}", program))?;
+    Ok(())
+}
+
+/// What's on the clipboard, read with the platform's clipboard tool. Terminals don't let
+/// OSC 52 read it back, so without a tool there's nothing to paste
+pub fn paste() -> Result<String> {
+    let Some((_, _, [program, args @ ..])) = tool() else {
+        return Err(anyhow!(
+            "No clipboard tool found, paste needs wl-paste, xclip, xsel or pbpaste"
+        ));
+    };
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        return Err(anyhow!("{} couldn't read the clipboard", program));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The escape sequence asking the terminal to put the text on the clipboard
+pub fn osc52(text: &str) -> String {
+    format!("\x1b]52;c;{}\x07", STANDARD.encode(text))
+}
+
+fn tool() -> Option<(
+    &'static str,
+    &'static [&'static str],
+    &'static [&'static str],
+)> {
+    TOOLS.into_iter().find(|(program, _, _)| {
+        let display = match *program {
+            "wl-copy" => env::var_os("WAYLAND_DISPLAY").is_some(),
+            "xclip" | "xsel" => env::var_os("DISPLAY").is_some(),
+            _ => true,
+        };
+        display && in_path(program)
+    })
+}
+
+fn in_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
@@ -0,0 +1,305 @@
+//completion.rs
+
+use crate::{
+    context::Context,
+    frecency::{self, Directories},
+    settings,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+// Specs that come with the shell, a file of the same name in the completions directory
+// takes their place
+const BUNDLED: [(&str, &str); 4] = [
+    ("git", include_str!("../completions/git.toml")),
+    ("cargo", include_str!("../completions/cargo.toml")),
+    ("docker", include_str!("../completions/docker.toml")),
+    ("z", include_str!("../completions/z.toml")),
+];
+
+// Characters that would split or change a file name if typed as they are
+const SPECIAL: &str = " \t\\'\"$`&|;<>()*?[]{}!#~";
+
+/// Where completion specs live, `completions` next to the config file. A spec completes the
+/// command named by its file without the `.toml`
+pub fn completions_dir() -> PathBuf {
+    let config = settings::config_path();
+    config
+        .parent()
+        .map(|dir| dir.join("completions"))
+        .unwrap_or_else(|| PathBuf::from("completions"))
+}
+
+/// What a command takes, read from a TOML file like
+///
+/// ```toml
+/// flags = ["--version", "--help"]
+///
+/// [subcommands.commit]
+/// flags = ["--amend", "--message"]
+///
+/// [subcommands.remote.subcommands.add]
+/// args = "none"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Spec {
+    /// Offered for a word starting with `-`
+    pub flags: Vec<String>,
+    /// Words always offered as arguments
+    pub values: Vec<String>,
+    /// What else the arguments are
+    pub args: Args,
+    pub subcommands: BTreeMap<String, Spec>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Args {
+    #[default]
+    Files,
+    Directories,
+    Commands,
+    /// The visited directory `z` would jump to for the word
+    Visited,
+    None,
+}
+
+/// The specs of every command that has one
+#[derive(Clone, Debug, Default)]
+pub struct Completions {
+    specs: HashMap<String, Spec>,
+}
+
+impl Completions {
+    /// The bundled specs and those in the completions directory. A spec that doesn't parse is
+    /// left out and reported in the problems
+    pub fn load() -> (Self, Vec<String>) {
+        let mut completions = Self::default();
+        let mut problems = Vec::new();
+        for (name, content) in BUNDLED {
+            if let Err(problem) = completions.add(name, content) {
+                problems.push(format!("Bundled completion for {}: {}", name, problem));
+            }
+        }
+
+        let Ok(entries) = fs::read_dir(completions_dir()) else {
+            return (completions, problems);
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            if path.extension().is_none_or(|extension| extension != "toml") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let added = fs::read_to_string(&path)
+                .map_err(|error| error.to_string())
+                .and_then(|content| completions.add(name, &content));
+            if let Err(problem) = added {
+                problems.push(format!("{}: {}", path.display(), problem));
+            }
+        }
+        (completions, problems)
+    }
+
+    /// Adds the spec of a command from the content of its file
+    pub fn add(&mut self, name: &str, content: &str) -> Result<(), String> {
+        let spec = toml::from_str(content).map_err(|error| error.message().to_string())?;
+        self.specs.insert(name.to_string(), spec);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Spec> {
+        self.specs.get(name)
+    }
+
+    /// What the last word of a line can become, sorted. The line ends where the cursor is,
+    /// only the command it's in counts
+    pub fn complete(&self, context: &Context, line: &str) -> Vec<String> {
+        let command = current_command(line);
+        let (before, word) = command.split_at(word_start(command));
+        let words: Vec<&str> = before.split_whitespace().collect();
+
+        let Some((name, args)) = words.split_first() else {
+            return sorted(commands(context, word));
+        };
+        let Some(mut spec) = self.get(name) else {
+            return sorted(files(word, false));
+        };
+        for arg in args.iter().filter(|arg| !arg.starts_with('-')) {
+            if let Some(subcommand) = spec.subcommands.get(*arg) {
+                spec = subcommand;
+            }
+        }
+
+        let mut candidates: Vec<String> = match word.starts_with('-') {
+            true => spec.flags.clone(),
+            false => spec
+                .subcommands
+                .keys()
+                .chain(spec.values.iter())
+                .cloned()
+                .collect(),
+        };
+        candidates.retain(|candidate| candidate.starts_with(word));
+        match spec.args {
+            Args::Files => candidates.extend(files(word, false)),
+            Args::Directories => candidates.extend(files(word, true)),
+            Args::Commands => candidates.extend(commands(context, word)),
+            Args::Visited => candidates.extend(visited(word)),
+            Args::None => {}
+        }
+        sorted(candidates)
+    }
+}
+
+/// The longest start all the candidates share
+pub fn common_prefix(candidates: &[String]) -> &str {
+    let Some((first, rest)) = candidates.split_first() else {
+        return "";
+    };
+    let mut prefix = first.as_str();
+    for candidate in rest {
+        let end = prefix
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(prefix.len().min(candidate.len()), |((index, _), _)| index);
+        prefix = &prefix[..end];
+    }
+    prefix
+}
+
+/// The word at the end of the line, the one `complete` gives candidates for
+pub fn current_word(line: &str) -> &str {
+    let command = current_command(line);
+    &command[word_start(command)..]
+}
+
+// The last command of the line, after the last operator
+fn current_command(line: &str) -> &str {
+    line.rfind(['|', ';', '&', '(', '`'])
+        .map_or(line, |operator| &line[operator + 1..])
+}
+
+// Where the word being typed starts, spaces escaped with a backslash are part of it
+fn word_start(command: &str) -> usize {
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, character) in command.char_indices() {
+        match character {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            space if space.is_whitespace() => start = index + space.len_utf8(),
+            _ => {}
+        }
+    }
+    start
+}
+
+fn sorted(mut candidates: Vec<String>) -> Vec<String> {
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+// Builtins, aliases and the programs in PATH starting with the word
+fn commands(context: &Context, word: &str) -> Vec<String> {
+    if word.contains('/') {
+        return files(word, false);
+    }
+    let mut commands: Vec<String> = context
+        .builtins
+        .names()
+        .chain(context.plugins.builtin_names())
+        .chain(context.aliases.get_map().keys().map(String::as_str))
+        .filter(|name| name.starts_with(word))
+        .map(str::to_string)
+        .collect();
+    commands.extend(programs(context, word));
+    commands
+}
+
+// The best visited directory for the word as a fragment, only that one since the others
+// don't start with the word to share a start with it
+fn visited(word: &str) -> Option<String> {
+    if word.is_empty() {
+        return None;
+    }
+    let directories = Directories::load(&frecency::database_path());
+    let best = directories
+        .matching(&[word], frecency::now())
+        .into_iter()
+        .find(|entry| entry.path.is_dir())?;
+    Some(format!("{}/", escape(&best.path.to_string_lossy())))
+}
+
+/// The programs in PATH starting with the word
+pub fn programs(context: &Context, word: &str) -> Vec<String> {
+    let path = context.variables.get("PATH").unwrap_or_default();
+    context.path_index.programs(&path, word)
+}
+
+// Files and directories the word is the start of, directories end in `/`. Hidden ones are
+// only offered when the word asks for them with a `.`
+fn files(word: &str, directories_only: bool) -> Vec<String> {
+    let (directory, prefix) = match word.rfind('/') {
+        Some(slash) => word.split_at(slash + 1),
+        None => ("", word),
+    };
+    let searched = match directory.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(env::var("HOME").unwrap_or_default()).join(rest),
+        None if directory.is_empty() => PathBuf::from("."),
+        None => PathBuf::from(directory),
+    };
+    let Ok(entries) = fs::read_dir(&searched) else {
+        return Vec::new();
+    };
+
+    let prefix = unescape(prefix);
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(&prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_directory = Path::new(&searched).join(&name).is_dir();
+            match (is_directory, directories_only) {
+                (true, _) => Some(format!("{}{}/", directory, escape(&name))),
+                (false, false) => Some(format!("{}{}", directory, escape(&name))),
+                (false, true) => None,
+            }
+        })
+        .collect()
+}
+
+// A file name as it has to be typed, with a backslash before what the shell would read
+// otherwise
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for character in name.chars() {
+        if SPECIAL.contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+fn unescape(word: &str) -> String {
+    let mut unescaped = String::with_capacity(word.len());
+    let mut chars = word.chars();
+    while let Some(character) = chars.next() {
+        match character {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(character),
+        }
+    }
+    unescaped
+}
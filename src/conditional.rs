@@ -0,0 +1,339 @@
+//conditional.rs
+
+#[cfg(windows)]
+use crate::sys;
+use crate::{
+    arithmetic,
+    context::Context,
+    error::{ShellError, ShellPhase},
+    expander,
+    parser::Arg,
+    terminal::Terminal,
+};
+use anyhow::Result;
+#[cfg(unix)]
+use std::{ffi::CString, os::unix::fs::PermissionsExt};
+use std::{fs, path::Path};
+
+const UNARY_OPERATORS: [&str; 12] = [
+    "-e", "-f", "-d", "-r", "-w", "-x", "-s", "-L", "-h", "-z", "-n", "-v",
+];
+
+const BINARY_OPERATORS: [&str; 14] = [
+    "==", "=", "!=", "=~", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot",
+];
+
+// Characters with a meaning in a regex, escaped in the quoted parts so they match themselves
+const REGEX_SPECIAL: &str = "\\^$.|?*+()[]{}";
+
+/// Evaluates the words of a `[[ ... ]]`. The words aren't split or globbed, the right side of
+/// `==` and `!=` is a glob pattern and the one of `=~` an extended regex, whose match and
+/// groups end up in the `BASH_REMATCH` array. Quoted parts of either only match themselves
+pub fn evaluate(context: &mut Context, terminal: &mut Terminal, args: &[Arg]) -> Result<bool> {
+    let mut evaluator = Evaluator {
+        context,
+        terminal,
+        args,
+        position: 0,
+        skip: false,
+    };
+    let value = evaluator.or()?;
+    match evaluator.args.get(evaluator.position) {
+        Some(arg) => error(&format!("Unexpected '{}'", arg)),
+        None => Ok(value),
+    }
+}
+
+struct Evaluator<'a, 'b> {
+    context: &'a mut Context,
+    terminal: &'a mut Terminal,
+    args: &'a [Arg<'b>],
+    position: usize,
+    // Set for the side of `&&` or `||` that doesn't count, its words aren't expanded so
+    // nothing in `$(...)` runs
+    skip: bool,
+}
+
+impl Evaluator<'_, '_> {
+    fn or(&mut self) -> Result<bool> {
+        let mut value = self.and()?;
+        while self.eat("||") {
+            let skip = self.skip;
+            self.skip |= value;
+            let right = self.and()?;
+            self.skip = skip;
+            value = value || right;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool> {
+        let mut value = self.not()?;
+        while self.eat("&&") {
+            let skip = self.skip;
+            self.skip |= !value;
+            let right = self.not()?;
+            self.skip = skip;
+            value = value && right;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<bool> {
+        if self.eat("!") {
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool> {
+        if self.eat("(") {
+            let value = self.or()?;
+            if !self.eat(")") {
+                return error("Expected ')'");
+            }
+            return Ok(value);
+        }
+
+        let Some(first) = self.args.get(self.position) else {
+            return error("Expected an expression");
+        };
+        self.position += 1;
+
+        if let Some(operator) = self.operator(self.position, &BINARY_OPERATORS) {
+            self.position += 1;
+            let Some(right) = self.args.get(self.position) else {
+                return error(&format!("Expected a word after '{}'", operator));
+            };
+            self.position += 1;
+            return self.binary(first, operator, right);
+        }
+
+        if let Some(operator) = self.operator(self.position - 1, &UNARY_OPERATORS)
+            && let Some(operand) = self.args.get(self.position)
+            && !self.is_operator(self.position, &["&&", "||", ")"])
+        {
+            self.position += 1;
+            return self.unary(operator, operand);
+        }
+
+        // A word on its own is true when it isn't empty
+        Ok(!self.word(first)?.is_empty())
+    }
+
+    fn unary(&mut self, operator: &str, operand: &Arg) -> Result<bool> {
+        let operand = self.word(operand)?;
+        if self.skip {
+            return Ok(false);
+        }
+
+        let path = Path::new(&operand);
+        let metadata = fs::metadata(path);
+        Ok(match operator {
+            "-z" => operand.is_empty(),
+            "-n" => !operand.is_empty(),
+            "-v" => self.context.variables.is_set(&operand),
+            "-e" => metadata.is_ok(),
+            "-f" => metadata.is_ok_and(|metadata| metadata.is_file()),
+            "-d" => metadata.is_ok_and(|metadata| metadata.is_dir()),
+            "-s" => metadata.is_ok_and(|metadata| metadata.len() > 0),
+            "-L" | "-h" => fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()),
+            #[cfg(unix)]
+            "-x" => metadata.is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0),
+            #[cfg(unix)]
+            "-r" => access(&operand, libc::R_OK),
+            #[cfg(unix)]
+            "-w" => access(&operand, libc::W_OK),
+            // Windows tells what runs by the extension, and has no other permission bits
+            #[cfg(windows)]
+            "-x" => metadata.is_ok_and(|metadata| sys::is_executable(path, &metadata)),
+            #[cfg(windows)]
+            "-r" => metadata.is_ok(),
+            #[cfg(windows)]
+            "-w" => metadata.is_ok_and(|metadata| !metadata.permissions().readonly()),
+            _ => return error(&format!("Unknown operator '{}'", operator)),
+        })
+    }
+
+    fn binary(&mut self, left: &Arg, operator: &str, right: &Arg) -> Result<bool> {
+        let left = self.word(left)?;
+        if self.skip {
+            return Ok(false);
+        }
+
+        match operator {
+            "==" | "=" | "!=" => {
+                let pattern = expander::expand_pattern(self.context, self.terminal, right.clone())?;
+                let matches = expander::matches_pattern(&pattern, &left);
+                Ok(matches == (operator != "!="))
+            }
+            "=~" => self.regex(&left, right),
+            "<" => Ok(left < self.word(right)?),
+            ">" => Ok(left > self.word(right)?),
+            "-nt" | "-ot" => {
+                let right = self.word(right)?;
+                let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+                let (left, right) = (modified(&left), modified(&right));
+                Ok(match operator {
+                    "-nt" => left.is_some() && (right.is_none() || left > right),
+                    _ => right.is_some() && (left.is_none() || left < right),
+                })
+            }
+            _ => {
+                // Both sides are arithmetic, like in other shells
+                let right = self.word(right)?;
+                let left = arithmetic::eval_in(&mut self.context.variables, &left)?;
+                let right = arithmetic::eval_in(&mut self.context.variables, &right)?;
+                Ok(match operator {
+                    "-eq" => left == right,
+                    "-ne" => left != right,
+                    "-lt" => left < right,
+                    "-le" => left <= right,
+                    "-gt" => left > right,
+                    _ => left >= right,
+                })
+            }
+        }
+    }
+
+    fn regex(&mut self, text: &str, regex: &Arg) -> Result<bool> {
+        // The quoted parts of the regex only match themselves
+        let regex =
+            expander::expand_escaped(self.context, self.terminal, regex.clone(), REGEX_SPECIAL)?;
+
+        let groups = regex_match(&regex, text)?;
+        let matched = groups.is_some();
+        self.context
+            .arrays
+            .insert("BASH_REMATCH".to_string(), groups.unwrap_or_default());
+        Ok(matched)
+    }
+
+    // Expanded without splitting or globbing, skipped words aren't expanded at all
+    fn word(&mut self, arg: &Arg) -> Result<String> {
+        if self.skip {
+            return Ok(String::new());
+        }
+        expander::expand_word(self.context, self.terminal, arg.clone())
+    }
+
+    // The unquoted word at the position if it's one of the operators
+    fn operator(&self, position: usize, operators: &[&'static str]) -> Option<&'static str> {
+        match self.args.get(position) {
+            Some(Arg::Word(word)) => operators.iter().find(|op| **op == word.as_ref()).copied(),
+            _ => None,
+        }
+    }
+
+    fn is_operator(&self, position: usize, operators: &[&'static str]) -> bool {
+        self.operator(position, operators).is_some()
+    }
+
+    fn eat(&mut self, operator: &'static str) -> bool {
+        let found = self.is_operator(self.position, &[operator]);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+}
+
+#[cfg(unix)]
+fn access(path: &str, mode: libc::c_int) -> bool {
+    CString::new(path).is_ok_and(|path| unsafe { libc::access(path.as_ptr(), mode) == 0 })
+}
+
+// The match followed by its groups, groups that took no part in the match are empty. None
+// when the text doesn't match
+#[cfg(unix)]
+fn regex_match(regex: &str, text: &str) -> Result<Option<Vec<String>>> {
+    let (Ok(pattern), Ok(subject)) = (CString::new(regex), CString::new(text)) else {
+        return Ok(None);
+    };
+
+    unsafe {
+        let mut compiled: libc::regex_t = std::mem::zeroed();
+        if libc::regcomp(&mut compiled, pattern.as_ptr(), libc::REG_EXTENDED) != 0 {
+            return error(&format!("Invalid regex '{}'", regex));
+        }
+
+        let groups = count_groups(regex);
+        let mut matches = vec![
+            libc::regmatch_t {
+                rm_so: -1,
+                rm_eo: -1
+            };
+            groups + 1
+        ];
+        let found = libc::regexec(
+            &compiled,
+            subject.as_ptr(),
+            matches.len(),
+            matches.as_mut_ptr(),
+            0,
+        ) == 0;
+        libc::regfree(&mut compiled);
+
+        if !found {
+            return Ok(None);
+        }
+        let groups = matches
+            .iter()
+            .map(|group| match group.rm_so {
+                -1 => String::new(),
+                start => text[start as usize..group.rm_eo as usize].to_string(),
+            })
+            .collect();
+        Ok(Some(groups))
+    }
+}
+
+// Windows has no POSIX regex in its C runtime
+#[cfg(windows)]
+fn regex_match(regex: &str, text: &str) -> Result<Option<Vec<String>>> {
+    let Ok(compiled) = regex::Regex::new(regex) else {
+        return error(&format!("Invalid regex '{}'", regex));
+    };
+    Ok(compiled.captures(text).map(|captures| {
+        captures
+            .iter()
+            .map(|group| group.map_or(String::new(), |group| group.as_str().to_string()))
+            .collect()
+    }))
+}
+
+// The `(` opening groups, those escaped or in a bracket expression like `[()]` don't count.
+// libc keeps the number to itself
+#[cfg(unix)]
+fn count_groups(regex: &str) -> usize {
+    let mut groups = 0;
+    let mut chars = regex.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '(' => groups += 1,
+            '[' => {
+                // A `]` right after the `[` or `[^` is part of the expression
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    groups
+}
+
+fn error<T>(message: &str) -> Result<T> {
+    Err(anyhow::Error::new(ShellError {
+        phase: ShellPhase::Executor,
+        command: Some("[[".to_string()),
+        message: message.into(),
+    }))
+}
@@ -1,69 +1,441 @@
 //context.rs
 
 use crate::{
-    aliases::Aliases, builtins::BuiltIns, history::History, jobs::Jobs, shell::Shell,
-    signals::SignalHandler, terminal::Terminal,
+    aliases::Aliases,
+    builtins::BuiltIns,
+    debugger::Debugger,
+    direnv::DirEnv,
+    encoding,
+    error::{Return, ShellError},
+    executor,
+    history::{self, History},
+    hooks::Hooks,
+    jobs::Jobs,
+    parser::Parser,
+    path_index::PathIndex,
+    plugins::Plugins,
+    record::Record,
+    settings::Settings,
+    shell::Shell,
+    signals::SignalHandler,
+    sys::{self, Pid},
+    terminal::Terminal,
+    tokenizer::Tokenizer,
+    variables::Variables,
 };
 use anyhow::{Context as AnyhowContext, Result, anyhow};
+#[cfg(unix)]
 use libc::{self};
-use std::{env, fs::OpenOptions, io::Read, path::PathBuf};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{io::IsTerminal, os::unix::fs::MetadataExt};
+
+/// How the shell was started, which decides the startup files it reads
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Mode {
+    /// Started by login (or with `-l`), reads `/etc/profile` and `~/.profile`
+    pub login: bool,
+    /// Reading commands from a user at a terminal, reads `~/.rshellrc`
+    pub interactive: bool,
+    /// Only checks the syntax of the commands without running them, `-n`
+    pub no_exec: bool,
+    /// Prints the syntax tree of every command checked, `--parse-only`
+    pub print_ast: bool,
+    /// Runs scripts in the debugger, `--debug`
+    pub debug: bool,
+    /// Sticks closer to POSIX sh where the shell's own behavior differs, `--posix`
+    pub posix: bool,
+    /// Writes a line of JSON about every command run to this file descriptor, `--json` for
+    /// stderr or `--json=FD`
+    pub json: Option<i32>,
+    /// Logs what the shell does at this level and above, `--log-level=debug`
+    pub log_level: Option<tracing::Level>,
+    /// Shows every command with the arguments, redirections and variables it would get
+    /// instead of running it, `--dry-run` or `set -o dryrun`
+    pub dry_run: bool,
+    /// Keeps commands out of the history and visited directories out of `z`, `--private` or
+    /// the `private` builtin
+    pub private: bool,
+}
+
+impl Mode {
+    /// Login shells get a name starting with `-` (`-rshell`) or are asked for with `-l` or
+    /// `--login`. The shell is interactive when stdin is a terminal and there's no script to
+    /// run, or with `-i`. `-n` and `--parse-only` check scripts without running them, like
+    /// in sh an interactive shell ignores them
+    pub fn from_args(args: &[String], stdin_is_terminal: bool) -> Self {
+        let (options, operands) = split_args(args);
+        let mut mode = Mode {
+            login: args.first().is_some_and(|name| name.starts_with('-')),
+            interactive: stdin_is_terminal && operands.is_empty(),
+            ..Mode::default()
+        };
+
+        for option in options {
+            match option.as_str() {
+                "--login" => mode.login = true,
+                "--debug" => mode.debug = true,
+                "--posix" => mode.posix = true,
+                "--dry-run" => mode.dry_run = true,
+                "--private" => mode.private = true,
+                "--json" => mode.json = Some(sys::STDERR_FILENO),
+                json if json.starts_with("--json=") => {
+                    mode.json = json["--json=".len()..].parse().ok();
+                }
+                level if level.starts_with("--log-level=") => {
+                    mode.log_level = level["--log-level=".len()..].parse().ok();
+                }
+                "--parse-only" => {
+                    mode.no_exec = true;
+                    mode.print_ast = true;
+                }
+                flags if !flags.starts_with("--") => {
+                    mode.login |= flags.contains('l');
+                    mode.interactive |= flags.contains('i');
+                    mode.no_exec |= flags.contains('n');
+                }
+                _ => {}
+            }
+        }
+        if mode.interactive {
+            mode.no_exec = false;
+            mode.print_ast = false;
+            mode.debug = false;
+            mode.json = None;
+        }
+        mode
+    }
+
+    /// Files to source at startup, in order. Like bash a login shell that's also interactive
+    /// reads the profiles first and then the rc file. In POSIX mode the file named by `ENV`
+    /// takes the place of the rc file
+    pub fn startup_files(&self, home: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        if self.login {
+            files.push(PathBuf::from("/etc/profile"));
+            files.push(home.join(".profile"));
+        }
+        match (self.interactive, self.posix) {
+            (true, false) => files.push(home.join(".rshellrc")),
+            (true, true) => files.extend(env::var_os("ENV").map(PathBuf::from)),
+            (false, _) => {}
+        }
+        files
+    }
+}
+
+/// Where the shell reads its commands from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Input {
+    /// Typed at the prompt, or read from stdin when it isn't a terminal
+    Stdin,
+    /// A script file, `rshell script.sh args...`
+    Script(PathBuf),
+    /// A command string, `rshell -c 'command' [name args...]`, the name is `$0`
+    Command {
+        command: String,
+        name: Option<String>,
+    },
+}
+
+impl Input {
+    /// The input named on the command line along with the positional parameters, the
+    /// arguments after the script. Like sh the first argument after a `-c` command string
+    /// names the shell and the rest are the positional parameters
+    pub fn from_args(args: &[String]) -> Result<(Self, Vec<String>)> {
+        let (options, operands) = split_args(args);
+        let command = options
+            .iter()
+            .any(|option| !option.starts_with("--") && option.contains('c'));
+
+        match (command, operands) {
+            (true, [command, rest @ ..]) => Ok((
+                Self::Command {
+                    command: command.clone(),
+                    name: rest.first().cloned(),
+                },
+                rest.get(1..).unwrap_or_default().to_vec(),
+            )),
+            (true, []) => Err(anyhow!("-c needs a command to run")),
+            (false, [script, rest @ ..]) => {
+                let script = PathBuf::from(encoding::to_os_string(script));
+                Ok((Self::Script(script), rest.to_vec()))
+            }
+            (false, []) => Ok((Self::Stdin, Vec::new())),
+        }
+    }
+}
+
+// Splits the command line, without the shell's name, into options and what follows them.
+// Options come first, anything after a script name belongs to the script
+fn split_args(args: &[String]) -> (&[String], &[String]) {
+    let args = args.get(1..).unwrap_or_default();
+    let end = args
+        .iter()
+        .position(|arg| !arg.starts_with('-') || arg == "-")
+        .unwrap_or(args.len());
+    match args[..end].iter().position(|arg| arg == "--") {
+        Some(dashes) => (&args[..dashes], &args[dashes + 1..]),
+        None => (&args[..end], &args[end..]),
+    }
+}
 
 #[derive(Clone)]
 pub struct Context {
     pub directory: PathBuf,
     pub name: String,
-    pub pid: libc::pid_t,
-    pub pgid: libc::pid_t,
+    pub pid: Pid,
+    pub pgid: Pid,
+    /// Commands started join `pgid` instead of getting a group of their own, in the copy of
+    /// the shell `timeout` runs so its signal reaches all of them
+    pub shared_group: bool,
     pub builtins: BuiltIns,
     pub jobs: Jobs,
     pub signals: SignalHandler,
     pub last_exit_code: i32,
-    pub last_job_pid: Option<libc::pid_t>,
+    /// How long the last command typed at the prompt took
+    pub last_duration: Option<Duration>,
+    pub last_job_pid: Option<Pid>,
     pub history: History,
     pub aliases: Aliases,
+    pub settings: Settings,
+    pub mode: Mode,
+    pub dir_env: DirEnv,
+    /// Positional parameters, `$1` and on
+    pub positional: Vec<String>,
+    /// Array variables set with `name=(...)`, they stay in the shell and aren't exported
+    pub arrays: HashMap<String, Vec<String>>,
+    /// Scripts, sourced files and command strings being run, the innermost last
+    pub sources: Vec<Source>,
+    /// Set with `--debug`, stops the script before its commands
+    pub debugger: Option<Debugger>,
+    /// Variables set with `name=value`, exported ones are in the environment
+    pub variables: Variables,
+    /// Commands run before each prompt and each command, added with `hook`
+    pub hooks: Hooks,
+    /// Loaded with `plugin load`
+    pub plugins: Plugins,
+    /// The programs in PATH, for completion, `type` and corrections
+    pub path_index: PathIndex,
+    /// Saved with `envsave`, by name
+    pub snapshots: HashMap<String, Snapshot>,
+}
+
+/// The variables and the directory of the shell at one point, `envrestore` puts them back
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    variables: Variables,
+    arrays: HashMap<String, Vec<String>>,
+    environment: HashMap<OsString, OsString>,
+    directory: PathBuf,
+}
+
+/// A script, sourced file or command string being run
+#[derive(Clone, Debug)]
+pub struct Source {
+    /// As it was given, used in messages
+    pub name: PathBuf,
+    /// Absolute path of the file, None for `-c` strings and stdin. Relative `source` paths
+    /// are also looked up next to it
+    pub file: Option<PathBuf>,
+    /// Line of the command being run
+    pub line: usize,
 }
 
 impl Context {
-    pub fn new() -> Result<Context> {
+    pub fn new(mode: Mode) -> Result<Context> {
+        // Mistakes in the config file are reported, not a reason to refuse to start
+        let settings = match Settings::load() {
+            Ok((settings, problems)) => {
+                for problem in problems {
+                    eprintln!("{problem}");
+                }
+                settings
+            }
+            Err(error) => {
+                eprintln!("{error:#}");
+                Settings::default()
+            }
+        };
+        // Only an interactive shell takes the terminal, for job control, when there's one
+        // Commands run by `-c`, scripts and piped input don't go in the history, so it isn't
+        // opened for them. An encrypted one would ask for its passphrase in cron or CI
+        let history = match mode.interactive {
+            true => History::open(&settings.history)?,
+            false => History::dummy(),
+        };
+        #[cfg(unix)]
+        let pgid = match mode.interactive && io::stdin().is_terminal() {
+            true => Self::setup_pgid()?,
+            false => unsafe { libc::getpgrp() },
+        };
+        // Windows has no process groups, the shell stands for its own
+        #[cfg(windows)]
+        let pgid = sys::pid();
         let mut context = Context {
             name: String::from("RShell"),
             directory: PathBuf::from("/"),
-            pgid: Self::setup_pgid()?,
-            pid: unsafe { libc::getpid() },
+            pgid,
+            shared_group: false,
+            pid: sys::pid(),
             jobs: Jobs::new(),
             builtins: BuiltIns::new(),
             signals: SignalHandler::new()?,
             last_exit_code: 0,
+            last_duration: None,
             last_job_pid: None,
-            history: History::new()?,
+            history,
             aliases: Aliases::new(),
+            settings,
+            mode,
+            dir_env: DirEnv::new(),
+            positional: Vec::new(),
+            arrays: HashMap::new(),
+            sources: Vec::new(),
+            debugger: None,
+            variables: Variables::new(),
+            hooks: Hooks::new(),
+            plugins: Plugins::default(),
+            path_index: PathIndex::new(),
+            snapshots: HashMap::new(),
         };
 
         Self::setup_home_directory(&mut context);
-        Self::exec_config_file(&mut context)?;
+        Self::setup_shell_level(&mut context);
+        Self::source_startup_files(&mut context)?;
+        // Ready by the time the first Tab is pressed
+        if mode.interactive {
+            let path = context.variables.get("PATH").unwrap_or_default();
+            context.path_index.refresh(&path);
+        }
+        // Only the script is debugged, not the startup files
+        context.debugger = mode.debug.then(Debugger::new);
 
         Ok(context)
     }
 
-    pub fn duplicate(self, pid: libc::pid_t) -> Result<Context> {
+    pub fn duplicate(self, pid: Pid) -> Result<Context> {
         Ok(Context {
             directory: self.directory,
             name: self.name,
             pid,
             pgid: pid,
-            builtins: BuiltIns::new(),
+            shared_group: false,
+            builtins: self.builtins,
             jobs: Jobs::new(),
             signals: SignalHandler::dummy(),
             last_exit_code: 0,
+            last_duration: None,
             last_job_pid: None,
             history: History::dummy(),
             aliases: self.aliases.clone(),
+            settings: self.settings,
+            mode: self.mode,
+            dir_env: self.dir_env,
+            positional: self.positional,
+            arrays: self.arrays,
+            sources: self.sources,
+            debugger: None,
+            variables: self.variables,
+            hooks: self.hooks,
+            plugins: self.plugins,
+            path_index: self.path_index,
+            snapshots: self.snapshots,
         })
     }
 
+    /// Switches to new settings, the prompt and the key bindings read them every time so only
+    /// the history needs to be told
+    pub fn apply_settings(&mut self, settings: Settings) {
+        self.history.configure(&settings.history);
+        self.settings = settings;
+    }
+
+    /// Applies a new value of one of the variables the shell follows itself, `export` and
+    /// `unset` call it so changes take effect straight away. `PROMPT` and friends are read
+    /// for every prompt and `EDITOR` whenever it's needed, so they don't need anything here
+    pub fn env_changed(&mut self, name: &str) -> Result<()> {
+        match name {
+            "HISTFILE" => self.history.reopen(&self.settings.history),
+            "HISTSIZE" => {
+                self.history
+                    .set_size(history::history_size(&self.settings.history));
+                Ok(())
+            }
+            "PATH" => {
+                let path = self.variables.get("PATH").unwrap_or_default();
+                self.path_index.refresh(&path);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn snapshot(&mut self) -> Snapshot {
+        Snapshot {
+            variables: self.variables.clone(),
+            arrays: self.arrays.clone(),
+            environment: env::vars_os().collect(),
+            directory: self.update_cwd().clone(),
+        }
+    }
+
+    /// Puts the variables and the directory back as they were in the snapshot. The variables
+    /// are put back even when the directory is gone
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let current: HashMap<OsString, OsString> = env::vars_os().collect();
+        let mut changed: Vec<OsString> = current
+            .iter()
+            .filter(|(name, value)| snapshot.environment.get(*name) != Some(*value))
+            .map(|(name, _)| name.clone())
+            .collect();
+        changed.extend(
+            snapshot
+                .environment
+                .keys()
+                .filter(|name| !current.contains_key(*name))
+                .cloned(),
+        );
+
+        for name in &changed {
+            match snapshot.environment.get(name) {
+                Some(value) => unsafe { env::set_var(name, value) },
+                None => unsafe { env::remove_var(name) },
+            }
+        }
+        self.variables.restore(&snapshot.variables);
+        self.arrays = snapshot.arrays.clone();
+        for name in changed.iter().filter_map(|name| name.to_str()) {
+            self.env_changed(name)?;
+        }
+
+        env::set_current_dir(&snapshot.directory)
+            .with_context(|| format!("Failed to go back to '{}'", snapshot.directory.display()))?;
+        self.update_cwd();
+        Ok(())
+    }
+
+    /// Reads the current directory, as the path in `PWD` when that still leads to it so
+    /// symlinks it went through are kept. Otherwise `PWD` is set to the real path
     pub fn update_cwd(&mut self) -> &PathBuf {
         if let Ok(cwd) = env::current_dir() {
-            self.directory = cwd;
+            let pwd = self.variables.get("PWD");
+            match pwd.map(|pwd| PathBuf::from(encoding::to_os_string(&pwd))) {
+                Some(pwd) if pwd.is_absolute() && same_file(&pwd, &cwd) => self.directory = pwd,
+                _ => {
+                    self.variables
+                        .export("PWD", Some(&encoding::from_os_str(cwd.as_os_str())));
+                    self.directory = cwd;
+                }
+            }
         } else {
             self.directory = env::var("HOME")
                 .map(PathBuf::from)
@@ -72,7 +444,8 @@ impl Context {
         &self.directory
     }
 
-    pub fn setup_pgid() -> Result<libc::pid_t> {
+    #[cfg(unix)]
+    pub fn setup_pgid() -> Result<Pid> {
         unsafe {
             let gpid = libc::getpid();
 
@@ -89,34 +462,51 @@ impl Context {
     }
 
     pub fn setup_home_directory(context: &mut Context) {
-        let home_directory = context.update_cwd();
-        unsafe {
-            env::set_var("OLDPWD", home_directory);
-        }
+        let home_directory = context.update_cwd().to_string_lossy().to_string();
+        context.variables.export("OLDPWD", Some(&home_directory));
     }
 
-    pub fn exec_config_file(context: &mut Context) -> Result<()> {
-        let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        let path = PathBuf::from(home_dir).join(".rshellrc");
+    /// Counts one more level of shell in `SHLVL`, for the shells and programs run by this one
+    pub fn setup_shell_level(context: &mut Context) {
+        let level = context
+            .variables
+            .get("SHLVL")
+            .and_then(|level| level.trim().parse::<i64>().ok())
+            .unwrap_or(0);
+        context
+            .variables
+            .export("SHLVL", Some(&(level.max(0) + 1).to_string()));
+    }
 
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&path)
-            .context("Failed to read config file")?;
+    /// Sources the profiles and the rc file that apply to how the shell was started.
+    /// `~/.rshellrc` is created if it's missing, profiles are only read when they exist
+    pub fn source_startup_files(context: &mut Context) -> Result<()> {
+        let home = PathBuf::from(env::var("HOME").unwrap_or_else(|_| ".".to_string()));
+        let rc_file = rc_path();
 
-        let mut content = String::new();
-        file.read_to_string(&mut content)
-            .context("Failed to read config file")?;
+        for path in context.mode.startup_files(&home) {
+            if path == rc_file {
+                OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(&path)
+                    .context("Failed to read config file")?;
+            }
 
-        let mut terminal = Terminal::new();
-        for line in content.lines() {
-            let command = Shell::parse_command(context, &mut terminal, &line, true)?;
-            if !Shell::execute_command(context, &mut terminal, command)?.0 {
+            // Scripts can have file names in them that aren't UTF-8
+            let content = match fs::read(&path) {
+                Ok(content) => encoding::decode(&content),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => {
+                    return Err(error)
+                        .with_context(|| format!("Failed to read {}", path.display()));
+                }
+            };
+            if !Self::source(context, &path, &content)? {
                 println!(
-                    "Exit command was found in rshellrc, it's suggested not to do that,
-                    the shell will not shutdown because otherewise you wouldn't be able to open it again"
+                    "Exit command was found in {}, it's suggested not to do that,
+                    the shell will not shutdown because otherewise you wouldn't be able to open it again",
+                    path.display()
                 );
                 break;
             }
@@ -124,4 +514,306 @@ impl Context {
 
         Ok(())
     }
+
+    /// Reads a file and sources it, keeping track of where it is for the `source` commands
+    /// it runs
+    pub fn source_file(context: &mut Context, path: &Path) -> Result<bool> {
+        let content = fs::read(path)
+            .map(|content| encoding::decode(&content))
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        // Made absolute so a `cd` in the script doesn't change where it's found
+        let absolute = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        Self::run_source(context, path, Some(absolute), content.lines())
+    }
+
+    /// Runs a file line by line skipping comments, returns false if it ran `exit`. A line
+    /// that doesn't parse or fails is reported with its line number and skipped with a status
+    /// of 1, profiles are often written for sh and a single unsupported line shouldn't keep the
+    /// shell from starting. Commands that go on for more than a line, like loops, are read
+    /// until they're complete
+    pub fn source(context: &mut Context, path: &Path, content: &str) -> Result<bool> {
+        Self::run_lines(context, path, content.lines())
+    }
+
+    /// Like `source`, for lines that come in one at a time like those read from a pipe
+    pub fn run_lines<S: AsRef<str>>(
+        context: &mut Context,
+        path: &Path,
+        lines: impl IntoIterator<Item = S>,
+    ) -> Result<bool> {
+        Self::run_source(context, path, None, lines)
+    }
+
+    /// Whether prompts and command output get OSC 133 marks, with the `shellintegration`
+    /// option or `SHELLINTEGRATION` set
+    pub fn shell_integration(&self) -> bool {
+        self.settings.options.shellintegration || env::var_os("SHELLINTEGRATION").is_some()
+    }
+
+    /// Whether the prompt is kept screen reader friendly, with the `accessible` option or
+    /// `ACCESSIBLE` set
+    pub fn accessible(&self) -> bool {
+        self.settings.options.accessible || env::var_os("ACCESSIBLE").is_some()
+    }
+
+    /// Whether a directory typed on its own is entered, with the `autocd` option or `AUTOCD`
+    /// set
+    pub fn autocd(&self) -> bool {
+        self.settings.options.autocd || env::var_os("AUTOCD").is_some()
+    }
+
+    /// Whether the output of commands typed at the prompt is kept in `LAST_OUTPUT`, with the
+    /// `captureoutput` option or `CAPTUREOUTPUT` set
+    pub fn capture_output(&self) -> bool {
+        self.settings.options.captureoutput || env::var_os("CAPTUREOUTPUT").is_some()
+    }
+
+    /// Where the command being run comes from, like `lib.sh:3 (sourced from main.sh:12)`
+    pub fn location(&self) -> String {
+        let mut sources = self
+            .sources
+            .iter()
+            .rev()
+            .map(|source| format!("{}:{}", source.name.display(), source.line));
+        let mut location = sources.next().unwrap_or_default();
+        for (index, outer) in sources.enumerate() {
+            let separator = if index == 0 {
+                " (sourced from"
+            } else {
+                ", from"
+            };
+            location.push_str(&format!("{} {}", separator, outer));
+        }
+        if self.sources.len() > 1 {
+            location.push(')');
+        }
+        location
+    }
+
+    // Runs the lines with the source on top of the stack, so messages and `source` know
+    // where they are. `return` stops the lines early
+    fn run_source<S: AsRef<str>>(
+        context: &mut Context,
+        path: &Path,
+        file: Option<PathBuf>,
+        lines: impl IntoIterator<Item = S>,
+    ) -> Result<bool> {
+        context.sources.push(Source {
+            name: path.to_path_buf(),
+            file,
+            line: 0,
+        });
+        let result = Self::run_source_lines(context, lines);
+        context.sources.pop();
+        result
+    }
+
+    fn run_source_lines<S: AsRef<str>>(
+        context: &mut Context,
+        lines: impl IntoIterator<Item = S>,
+    ) -> Result<bool> {
+        let mut terminal = Terminal::new();
+        // The command read so far and why it isn't complete, its first line is the one of the
+        // source on top of the stack
+        let mut pending = String::new();
+        let mut unfinished: Option<anyhow::Error> = None;
+        // Line numbers of the lines in `pending`, for the position of syntax errors
+        let mut pending_lines = Vec::new();
+
+        for (number, line) in lines.into_iter().enumerate() {
+            let line = line.as_ref();
+            let trimmed = line.trim();
+            // Blank lines and comments in the body of a here-document are part of it
+            let in_here_doc = !pending.is_empty()
+                && unfinished
+                    .as_ref()
+                    .and_then(|error| error.downcast_ref::<ShellError>())
+                    .is_some_and(|error| error.is_in_here_doc());
+            if (trimmed.is_empty() || trimmed.starts_with('#')) && !in_here_doc {
+                continue;
+            }
+            if pending.is_empty()
+                && let Some(source) = context.sources.last_mut()
+            {
+                source.line = number + 1;
+            }
+            pending.push_str(line);
+            pending.push('\n');
+            pending_lines.push(number + 1);
+
+            if context.mode.no_exec {
+                match check(&pending) {
+                    Err((error, _))
+                        if error
+                            .downcast_ref::<ShellError>()
+                            .is_some_and(|error| error.is_incomplete()) =>
+                    {
+                        unfinished = Some(error);
+                        continue;
+                    }
+                    Err((error, offset)) => {
+                        let (line, column) = position(&pending, &pending_lines, offset);
+                        eprintln!("{}:{}:{}: {}", context.location_name(), line, column, error);
+                        // The status sh gives syntax errors
+                        context.last_exit_code = 2;
+                    }
+                    Ok(ast) if context.mode.print_ast => {
+                        // `--parse-only | head` stops reading early, the rest has nowhere to go
+                        match writeln!(io::stdout().lock(), "{ast}") {
+                            Err(error) if error.kind() == io::ErrorKind::BrokenPipe => {
+                                return Ok(true);
+                            }
+                            result => result?,
+                        }
+                    }
+                    Ok(_) => {}
+                }
+                pending.clear();
+                pending_lines.clear();
+                continue;
+            }
+
+            let command = match Shell::parse_command(context, &mut terminal, &pending, true) {
+                Ok(command) => command,
+                Err(error)
+                    if error
+                        .downcast_ref::<ShellError>()
+                        .is_some_and(|error| error.is_incomplete()) =>
+                {
+                    unfinished = Some(error);
+                    continue;
+                }
+                Err(error) => {
+                    eprintln!("{}: {}", context.location(), error);
+                    context.last_exit_code = 1;
+                    pending.clear();
+                    pending_lines.clear();
+                    continue;
+                }
+            };
+            let mut record = context
+                .mode
+                .json
+                .map(|fd| (fd, Record::new(context, &pending, &command)));
+            pending.clear();
+            pending_lines.clear();
+
+            if let Some(mut debugger) = context.debugger.take() {
+                let resume = match debugger.should_stop(context, &command) {
+                    true => debugger.stop(context, &mut terminal, &command),
+                    false => Ok(true),
+                };
+                context.debugger = Some(debugger);
+                if !resume? {
+                    return Ok(false);
+                }
+            }
+
+            let started = Instant::now();
+            // Some when the lines stop early, with whether the shell keeps running
+            let stop = match executor::execute(context, &mut terminal, command, None) {
+                Ok((exit_code, _)) => {
+                    context.last_exit_code = exit_code;
+                    None
+                }
+                Err(error)
+                    if error
+                        .downcast_ref::<ShellError>()
+                        .is_some_and(|error| error.is_exit()) =>
+                {
+                    Some(false)
+                }
+                Err(error) => match error.downcast_ref::<Return>() {
+                    Some(Return(status)) => {
+                        context.last_exit_code = *status;
+                        Some(true)
+                    }
+                    None => {
+                        eprintln!("{}: {}", context.location(), error);
+                        context.last_exit_code = 1;
+                        None
+                    }
+                },
+            };
+
+            if let Some((fd, record)) = &mut record {
+                record.status = context.last_exit_code;
+                record.duration = started.elapsed();
+                if let Err(error) = record.write(*fd) {
+                    eprintln!("rshell: --json: {}", error);
+                }
+            }
+            if let Some(keep_running) = stop {
+                return Ok(keep_running);
+            }
+        }
+
+        if !pending.is_empty()
+            && let Some(error) = unfinished
+        {
+            if context.mode.no_exec {
+                let (line, column) = position(&pending, &pending_lines, pending.trim_end().len());
+                eprintln!("{}:{}:{}: {}", context.location_name(), line, column, error);
+                context.last_exit_code = 2;
+            } else {
+                eprintln!("{}: {}", context.location(), error);
+                context.last_exit_code = 1;
+            }
+        }
+        Ok(true)
+    }
+
+    // Name of the source being run, for messages that give their own line
+    fn location_name(&self) -> String {
+        self.sources
+            .last()
+            .map(|source| source.name.display().to_string())
+            .unwrap_or_default()
+    }
+}
+
+// Parses a command without expanding or running anything, giving back its syntax tree. An
+// error comes with the byte offset in the command where it was found
+fn check(command: &str) -> std::result::Result<String, (anyhow::Error, usize)> {
+    let end = command.trim_end().len();
+    let (tokens, offsets) = Tokenizer::tokenize_with_offsets(command).map_err(|e| (e, end))?;
+    match Parser::parse_with_position(&tokens) {
+        Ok(ast) => Ok(format!("{ast:#?}")),
+        Err((error, index)) => Err((error, offsets.get(index).copied().unwrap_or(end))),
+    }
+}
+
+// Line and column, counting from 1, of a byte offset in a command spanning the given lines
+fn position(command: &str, lines: &[usize], offset: usize) -> (usize, usize) {
+    let before = &command[..offset];
+    let line = lines
+        .get(before.matches('\n').count())
+        .or(lines.last())
+        .copied()
+        .unwrap_or(1);
+    let start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (line, before[start..].chars().count() + 1)
+}
+
+// Whether both paths lead to the same file or directory
+#[cfg(unix)]
+fn same_file(first: &Path, second: &Path) -> bool {
+    match (fs::metadata(first), fs::metadata(second)) {
+        (Ok(first), Ok(second)) => first.dev() == second.dev() && first.ino() == second.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
+fn same_file(first: &Path, second: &Path) -> bool {
+    match (fs::canonicalize(first), fs::canonicalize(second)) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => false,
+    }
+}
+
+/// The rc file interactive shells read, `~/.rshellrc`
+pub fn rc_path() -> PathBuf {
+    PathBuf::from(env::var("HOME").unwrap_or_else(|_| ".".to_string())).join(".rshellrc")
 }
@@ -0,0 +1,117 @@
+//correction.rs
+
+// Typos in a command name or a `cd` target, the closest match is offered and taken with a
+// single key. Only at the prompt, where someone is there to answer
+
+use crate::{completion, context::Context, settings, terminal::Terminal};
+use anyhow::Result;
+use crossterm::event::{KeyCode, KeyEvent};
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The builtin or program in PATH closest to a command that isn't there, once it's accepted
+pub fn command(
+    context: &Context,
+    terminal: &mut Terminal,
+    name: &str,
+    args: &[&str],
+) -> Result<Option<String>> {
+    if !can_ask(context, terminal) {
+        return Ok(None);
+    }
+    let programs = completion::programs(context, "");
+    let candidates = context
+        .builtins
+        .names()
+        .chain(programs.iter().map(String::as_str));
+    let Some(suggestion) = settings::suggest(name, candidates) else {
+        return Ok(None);
+    };
+
+    let line = |name: &str| {
+        [name]
+            .iter()
+            .chain(args)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let question = format!(
+        "{}: command not found, did you mean `{}`?",
+        name,
+        line(suggestion)
+    );
+    Ok(accept(terminal, &question)?.then(|| suggestion.to_string()))
+}
+
+/// The directory next to a `cd` target that doesn't exist closest to it, once it's accepted
+pub fn directory(
+    context: &Context,
+    terminal: &mut Terminal,
+    target: &Path,
+) -> Result<Option<PathBuf>> {
+    if !can_ask(context, terminal) || target.exists() {
+        return Ok(None);
+    }
+    let Some(name) = target.file_name().map(|name| name.to_string_lossy()) else {
+        return Ok(None);
+    };
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = fs::read_dir(parent) else {
+        return Ok(None);
+    };
+    let directories: Vec<String> = entries
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    let Some(suggestion) = settings::suggest(&name, directories.iter().map(String::as_str)) else {
+        return Ok(None);
+    };
+
+    let corrected = target.with_file_name(suggestion);
+    let question = format!(
+        "cd: no such directory {}, did you mean {}?",
+        target.display(),
+        corrected.display()
+    );
+    Ok(accept(terminal, &question)?.then_some(corrected))
+}
+
+fn can_ask(context: &Context, terminal: &Terminal) -> bool {
+    context.mode.interactive
+        && context.sources.is_empty()
+        && io::stdin().is_terminal()
+        && !terminal.is_dumb()
+}
+
+// Enter or `y` takes the suggestion, any other key doesn't
+fn accept(terminal: &mut Terminal, question: &str) -> Result<bool> {
+    // In raw mode before asking, a key typed straight away isn't read as a line
+    let raw = terminal.is_raw();
+    terminal.enter_raw_mode()?;
+    let key = terminal
+        .print(&format!("{question} [Y/n] "))
+        .and_then(|_| read_key(terminal));
+    if !raw {
+        terminal.exit_raw_mode()?;
+    }
+    let accepted = matches!(key?.code, KeyCode::Enter | KeyCode::Char('y' | 'Y'));
+    terminal.println(if accepted { "y" } else { "n" })?;
+    Ok(accepted)
+}
+
+fn read_key(terminal: &mut Terminal) -> Result<KeyEvent> {
+    loop {
+        if let Some(key) = terminal.read_key(Duration::from_millis(100))? {
+            return Ok(key);
+        }
+    }
+}
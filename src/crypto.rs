@@ -0,0 +1,212 @@
+// crypto.rs
+
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, KeyInit},
+};
+use pbkdf2::{pbkdf2_hmac_array, sha2::Sha256};
+use std::process::Command;
+#[cfg(unix)]
+use std::{
+    fs::OpenOptions,
+    io::{self, BufRead, BufReader, Write},
+    os::fd::AsRawFd,
+};
+
+const KDF_ROUNDS: u32 = 600_000;
+const NONCE_LEN: usize = 24;
+pub const SALT_LEN: usize = 16;
+
+/// Authenticated encryption of single lines, used to keep the history file unreadable at rest
+#[derive(Clone)]
+pub struct Cipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Derives the key from the passphrase with PBKDF2, the salt is stored next to the data
+    pub fn from_passphrase(passphrase: &[u8], salt: &[u8]) -> Result<Self> {
+        let key = pbkdf2_hmac_array::<Sha256, 32>(passphrase, salt, KDF_ROUNDS);
+        let cipher = XChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|_| anyhow!("Failed to initialize the history cipher"))?;
+        Ok(Self { cipher })
+    }
+
+    /// Encrypts a line with a fresh random nonce, returns base64 of nonce and ciphertext
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let nonce_bytes: [u8; NONCE_LEN] = random_bytes()?;
+        let nonce = XNonce::from(nonce_bytes);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt history entry"))?;
+
+        let mut data = nonce_bytes.to_vec();
+        data.extend(ciphertext);
+        Ok(STANDARD.encode(data))
+    }
+
+    /// Reverses `encrypt`, fails if the line was tampered with or the key is wrong
+    pub fn decrypt(&self, line: &str) -> Result<String> {
+        let data = STANDARD
+            .decode(line.trim())
+            .context("History entry isn't valid base64")?;
+        if data.len() < NONCE_LEN {
+            return Err(anyhow!("History entry is too short to be encrypted"));
+        }
+
+        let (nonce_bytes, ciphertext) = data.split_at(NONCE_LEN);
+        let nonce_bytes: [u8; NONCE_LEN] = nonce_bytes.try_into()?;
+        let plaintext = self
+            .cipher
+            .decrypt(&XNonce::from(nonce_bytes), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt history, is the passphrase right?"))?;
+
+        String::from_utf8(plaintext).context("Decrypted history entry isn't valid UTF-8")
+    }
+}
+
+#[cfg(unix)]
+pub fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut buffer = [0u8; N];
+    let mut filled = 0;
+    while filled < N {
+        let n = unsafe {
+            libc::getrandom(
+                buffer[filled..].as_mut_ptr() as *mut libc::c_void,
+                N - filled,
+                0,
+            )
+        };
+        if n < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error).context("Failed to gather random bytes");
+        }
+        filled += n as usize;
+    }
+    Ok(buffer)
+}
+
+#[cfg(windows)]
+pub fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut buffer = [0u8; N];
+    getrandom::fill(&mut buffer)
+        .map_err(|error| anyhow!("Failed to gather random bytes: {}", error))?;
+    Ok(buffer)
+}
+
+pub fn encode_salt(salt: &[u8]) -> String {
+    STANDARD.encode(salt)
+}
+
+pub fn decode_salt(salt: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(salt.trim())
+        .context("History salt isn't valid base64")
+}
+
+/// Obtains the secret the history key is derived from. `source` is either `passphrase`,
+/// which asks for it on the terminal, or `keyring`, which reads it from the OS keyring
+pub fn obtain_secret(source: &str) -> Result<String> {
+    match source {
+        "keyring" => keyring_secret(),
+        _ => prompt_passphrase("History passphrase: "),
+    }
+}
+
+fn keyring_secret() -> Result<String> {
+    let output = if cfg!(target_os = "macos") {
+        Command::new("security")
+            .args([
+                "find-generic-password",
+                "-s",
+                "rshell",
+                "-a",
+                "history",
+                "-w",
+            ])
+            .output()
+    } else {
+        Command::new("secret-tool")
+            .args(["lookup", "service", "rshell", "account", "history"])
+            .output()
+    }
+    .context("Failed to query the OS keyring for the history passphrase")?;
+
+    let secret = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || secret.is_empty() {
+        return Err(anyhow!(
+            "No history passphrase found in the OS keyring (service 'rshell', account 'history')"
+        ));
+    }
+    Ok(secret)
+}
+
+// Reads the passphrase from the controlling terminal with echo turned off
+#[cfg(unix)]
+fn prompt_passphrase(message: &str) -> Result<String> {
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .context("Failed to open the terminal to ask for the history passphrase")?;
+    let fd = tty.as_raw_fd();
+
+    let mut original: libc::termios = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::tcgetattr(fd, &mut original);
+        let mut silent = original;
+        silent.c_lflag &= !libc::ECHO;
+        libc::tcsetattr(fd, libc::TCSANOW, &silent);
+    }
+
+    let mut passphrase = String::new();
+    let result = (&tty)
+        .write_all(message.as_bytes())
+        .and_then(|_| BufReader::new(&tty).read_line(&mut passphrase));
+
+    unsafe {
+        libc::tcsetattr(fd, libc::TCSANOW, &original);
+    }
+    let _ = (&tty).write_all(b"\n");
+    result.context("Failed to read the history passphrase")?;
+
+    Ok(passphrase.trim_end_matches(['\n', '\r']).to_string())
+}
+
+// The console has no /dev/tty, the keys are read in raw mode so they don't show
+#[cfg(windows)]
+fn prompt_passphrase(message: &str) -> Result<String> {
+    use crossterm::{
+        event::{self, Event, KeyCode, KeyEventKind},
+        terminal,
+    };
+
+    eprint!("{}", message);
+    terminal::enable_raw_mode().context("Failed to ask for the history passphrase")?;
+    let mut passphrase = String::new();
+    let result = loop {
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind != KeyEventKind::Release => match key.code {
+                KeyCode::Enter => break Ok(()),
+                KeyCode::Backspace => {
+                    passphrase.pop();
+                }
+                KeyCode::Char(character) => passphrase.push(character),
+                _ => {}
+            },
+            Ok(_) => {}
+            Err(error) => break Err(error),
+        }
+    };
+    let _ = terminal::disable_raw_mode();
+    eprintln!();
+    result.context("Failed to read the history passphrase")?;
+
+    Ok(passphrase)
+}
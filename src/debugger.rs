@@ -0,0 +1,210 @@
+//debugger.rs
+
+use crate::{
+    context::{Context, Source},
+    expander,
+    parser::{Arg, Command},
+    terminal::Terminal,
+};
+use anyhow::Result;
+use std::{
+    borrow::Cow,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+};
+
+const HELP: &str = "\
+step, s, enter    run this command and stop at the next one
+continue, c       run until a breakpoint
+break, b LINE     stop at a line, FILE:LINE for one of a sourced file
+break, b NAME     stop before running the command NAME
+delete, d N       remove the breakpoint numbered N
+list, l           show the breakpoints
+print, p WORDS    expand words like $HOME or ${list[@]} and show them
+quit, q           stop the script";
+
+/// Where a script stops in the debugger
+#[derive(Clone, Debug, PartialEq)]
+pub enum Breakpoint {
+    /// A line of the script, or of the file with that name when there's one
+    Line { file: Option<String>, line: usize },
+    /// Any command running the program or builtin with that name
+    Command(String),
+}
+
+impl Breakpoint {
+    /// Reads `12`, `lib.sh:12` or a command name
+    pub fn parse(spec: &str) -> Self {
+        if let Ok(line) = spec.parse() {
+            return Self::Line { file: None, line };
+        }
+        match spec.rsplit_once(':') {
+            Some((file, line)) if !file.is_empty() && line.parse::<usize>().is_ok() => Self::Line {
+                file: Some(file.to_string()),
+                line: line.parse().unwrap_or_default(),
+            },
+            _ => Self::Command(spec.to_string()),
+        }
+    }
+
+    fn matches(&self, source: Option<&Source>, command: &Command) -> bool {
+        match self {
+            Self::Line { file, line } => source.is_some_and(|source| {
+                source.line == *line && file.as_ref().is_none_or(|file| source.name.ends_with(file))
+            }),
+            Self::Command(name) => runs(command, name),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Line { file: None, line } => write!(f, "line {}", line),
+            Self::Line {
+                file: Some(file),
+                line,
+            } => write!(f, "{}:{}", file, line),
+            Self::Command(name) => write!(f, "command {}", name),
+        }
+    }
+}
+
+/// Stops a script before its commands, with `--debug`. It starts out stepping, stopping
+/// before the first command, and at every stop shows the command as it's about to run and
+/// asks what to do at a `(debug)` prompt
+#[derive(Clone, Debug)]
+pub struct Debugger {
+    pub stepping: bool,
+    pub breakpoints: Vec<Breakpoint>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            stepping: true,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    /// Whether to stop before the command, the top of the source stack says where it is
+    pub fn should_stop(&self, context: &Context, command: &Command) -> bool {
+        self.stepping
+            || self
+                .breakpoints
+                .iter()
+                .any(|breakpoint| breakpoint.matches(context.sources.last(), command))
+    }
+
+    /// Shows the command and asks what to do until told to go on. Returns false to stop the
+    /// script. The prompt reads from the terminal, so the script's stdin is left alone
+    pub fn stop(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        command: &Command,
+    ) -> Result<bool> {
+        eprintln!("{}: {}", context.location(), command.to_string());
+        let mut input: Box<dyn BufRead> = match File::open("/dev/tty") {
+            Ok(tty) => Box::new(BufReader::new(tty)),
+            Err(_) => Box::new(io::stdin().lock()),
+        };
+
+        loop {
+            eprint!("(debug) ");
+            io::stderr().flush()?;
+            let mut line = String::new();
+            // The end of the input lets the script run to the end
+            if input.read_line(&mut line)? == 0 {
+                self.stepping = false;
+                return Ok(true);
+            }
+            if let Some(resume) = self.run(context, terminal, line.trim())? {
+                return Ok(resume);
+            }
+        }
+    }
+
+    /// Runs a command typed at the debug prompt. Some(false) stops the script, Some(true)
+    /// goes on with it and None asks for another command
+    pub fn run(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        line: &str,
+    ) -> Result<Option<bool>> {
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        match name {
+            "" | "s" | "step" => {
+                self.stepping = true;
+                return Ok(Some(true));
+            }
+            "c" | "continue" => {
+                self.stepping = false;
+                return Ok(Some(true));
+            }
+            "q" | "quit" => return Ok(Some(false)),
+            "b" | "break" if !rest.is_empty() => {
+                let breakpoint = Breakpoint::parse(rest);
+                eprintln!("Breakpoint {}: {}", self.breakpoints.len() + 1, breakpoint);
+                self.breakpoints.push(breakpoint);
+            }
+            "d" | "delete" => match rest.parse::<usize>() {
+                Ok(number) if (1..=self.breakpoints.len()).contains(&number) => {
+                    self.breakpoints.remove(number - 1);
+                }
+                _ => eprintln!("No breakpoint '{}'", rest),
+            },
+            "l" | "list" => {
+                for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+                    eprintln!("{}: {}", index + 1, breakpoint);
+                }
+            }
+            "p" | "print" => {
+                let words = rest
+                    .split_whitespace()
+                    .map(|word| {
+                        expander::expand_word(context, terminal, Arg::Word(Cow::Borrowed(word)))
+                    })
+                    .collect::<Result<Vec<_>>>();
+                match words {
+                    Ok(words) => eprintln!("{}", words.join(" ")),
+                    Err(error) => eprintln!("{}", error),
+                }
+            }
+            _ => eprintln!("{}", HELP),
+        }
+        Ok(None)
+    }
+}
+
+// Whether the command, or one of those it's made of, runs the program named
+fn runs(command: &Command, name: &str) -> bool {
+    match command {
+        Command::Simple { command, .. } => command == name,
+        Command::Pipeline(left, right)
+        | Command::And(left, right)
+        | Command::Or(left, right)
+        | Command::Sequence(left, right) => runs(left, name) || runs(right, name),
+        Command::Background(command) | Command::Subshell(command) => runs(command, name),
+        Command::For { body, .. }
+        | Command::ArithmeticFor { body, .. }
+        | Command::Select { body, .. } => runs(body, name),
+        Command::Case { items, .. } => items
+            .iter()
+            .filter_map(|item| item.body.as_ref())
+            .any(|body| runs(body, name)),
+        Command::Arithmetic(_)
+        | Command::Conditional(_)
+        | Command::Assignment(_)
+        | Command::ArrayAssignment { .. } => false,
+    }
+}
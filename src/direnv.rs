@@ -0,0 +1,190 @@
+// direnv.rs
+
+use crate::{history, parser::EnvVariable};
+use anyhow::{Context, Result};
+use pbkdf2::sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Files looked for in a directory and its parents, the first one found applies
+pub const ENV_FILES: [&str; 2] = [".shellenv", ".env"];
+
+/// Variables of the nearest `.shellenv` or `.env` file, set while inside its directory and
+/// put back as they were when leaving it. A file is only loaded once it's on the allow list,
+/// and it has to be allowed again whenever it changes
+#[derive(Clone, Debug, Default)]
+pub struct DirEnv {
+    loaded: Option<LoadedEnv>,
+    /// Files the user said no to, not asked about again this session
+    denied: HashSet<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+struct LoadedEnv {
+    file: PathBuf,
+    /// Values the variables had before the file was loaded
+    previous: Vec<(String, Option<OsString>)>,
+}
+
+/// What `DirEnv::update` did, with the names of the variables it touched
+#[derive(Debug, Default, PartialEq)]
+pub struct EnvChange {
+    pub unloaded: Option<PathBuf>,
+    pub loaded: Option<PathBuf>,
+    pub variables: Vec<String>,
+}
+
+impl DirEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// File currently loaded, if any
+    pub fn loaded(&self) -> Option<&Path> {
+        self.loaded.as_ref().map(|loaded| loaded.file.as_path())
+    }
+
+    /// Brings the environment in line with the directory: unloads the file of the directory
+    /// that was left and loads the one of the new directory. `ask` is called for files that
+    /// aren't allowed yet, saying yes puts them on the allow list
+    pub fn update(
+        &mut self,
+        directory: &Path,
+        ask: impl FnOnce(&Path) -> bool,
+    ) -> Result<EnvChange> {
+        let file = find_env_file(directory);
+        if let Some(loaded) = &self.loaded
+            && Some(&loaded.file) == file.as_ref()
+        {
+            return Ok(EnvChange::default());
+        }
+
+        let mut change = self.unload();
+        let Some(file) = file else {
+            return Ok(change);
+        };
+        let content = fs::read_to_string(&file)
+            .with_context(|| format!("Failed to read {}", file.display()))?;
+
+        if !is_allowed(&file, &content) {
+            if self.denied.contains(&file) || !ask(&file) {
+                self.denied.insert(file);
+                return Ok(change);
+            }
+            allow(&file, &content)?;
+        }
+
+        let mut previous = Vec::new();
+        for (name, value) in parse(&content) {
+            previous.push((name.clone(), env::var_os(&name)));
+            unsafe {
+                env::set_var(&name, value);
+            }
+            change.variables.push(name);
+        }
+        self.loaded = Some(LoadedEnv {
+            file: file.clone(),
+            previous,
+        });
+        change.loaded = Some(file);
+        Ok(change)
+    }
+
+    /// Puts back the variables the loaded file changed
+    pub fn unload(&mut self) -> EnvChange {
+        let mut change = EnvChange::default();
+        let Some(loaded) = self.loaded.take() else {
+            return change;
+        };
+
+        for (name, value) in loaded.previous.iter().rev() {
+            unsafe {
+                match value {
+                    Some(value) => env::set_var(name, value),
+                    None => env::remove_var(name),
+                }
+            }
+            change.variables.push(name.clone());
+        }
+        change.unloaded = Some(loaded.file);
+        change
+    }
+}
+
+/// Nearest env file walking up from the directory
+pub fn find_env_file(directory: &Path) -> Option<PathBuf> {
+    directory.ancestors().find_map(|dir| {
+        ENV_FILES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+    })
+}
+
+/// Reads `NAME=value` lines, with an optional `export` in front and quotes around the value.
+/// Nothing in the file is run, other lines are skipped
+pub fn parse(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (name, value) = line.split_once('=')?;
+            let name = name.trim();
+            let valid = !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            valid.then(|| {
+                let value = EnvVariable::strip_quotes_from_value(value.trim());
+                (name.to_string(), value.to_string())
+            })
+        })
+        .collect()
+}
+
+/// Where the allowed files are kept: `$XDG_DATA_HOME/rshell/allowed_env`, next to the history
+pub fn allow_list_path() -> PathBuf {
+    history::data_dir().join("allowed_env")
+}
+
+// The file's path and content together, so a changed or moved file has to be allowed again
+fn fingerprint(file: &Path, content: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(file.as_os_str().as_encoded_bytes());
+    hasher.update([0]);
+    hasher.update(content.as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+pub fn is_allowed(file: &Path, content: &str) -> bool {
+    let fingerprint = fingerprint(file, content);
+    fs::read_to_string(allow_list_path()).is_ok_and(|list| {
+        list.lines()
+            .any(|line| line.split_whitespace().next() == Some(&fingerprint))
+    })
+}
+
+/// Adds a file as it is now to the allow list
+pub fn allow(file: &Path, content: &str) -> Result<()> {
+    let path = allow_list_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("Failed to create the data directory")?;
+    }
+    let mut list = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&path)
+        .context("Failed to open the env allow list")?;
+    writeln!(list, "{} {}", fingerprint(file, content), file.display())
+        .context("Failed to write the env allow list")
+}
@@ -1,8 +1,29 @@
 //editor.rs
 
-use crate::{context::Context, prompt::Prompt, terminal::Terminal};
+#[cfg(unix)]
+use crate::signals::SignalHandler;
+use crate::{
+    clipboard,
+    completion::{self, Completions},
+    context::Context,
+    history::DirectoryMode,
+    keymap::{Action, Keymap},
+    notify,
+    prompt::Prompt,
+    terminal::{Mark, Terminal},
+};
 use anyhow::{Context as AnyhowContext, Ok, Result};
-use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+#[cfg(unix)]
+use std::os::unix::{fs::OpenOptionsExt, process::CommandExt};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    ops::ControlFlow,
+    process,
+};
+use unicode_width::UnicodeWidthStr;
 
 pub struct Buffer {
     pub data: String,
@@ -98,19 +119,44 @@ impl Buffer {
     }
 }
 
+// State of the Ctrl-R fuzzy history picker
+struct FuzzySearch {
+    query: String,
+    selected: usize,
+    matches: usize,
+    // What was typed before opening the picker, restored when it's cancelled
+    original: String,
+}
+
+// Kills kept for yanking, the oldest go first
+const KILL_RING_SIZE: usize = 16;
+
 pub struct Editor {
+    /// Keys bound to actions, plain characters that aren't bound get typed
+    pub keymap: Keymap,
     buffer: Buffer,
     row: u16,
+    search: Option<FuzzySearch>,
+    // Text removed by the kill actions, the last kill at the end
+    kill_ring: Vec<String>,
 }
 
 impl Editor {
     pub fn new() -> Self {
         Self {
+            keymap: Keymap::default(),
             buffer: Buffer::new(),
             row: 0,
+            search: None,
+            kill_ring: Vec::new(),
         }
     }
 
+    /// Fills the buffer with a line the user can still edit before submitting it again
+    pub fn preload(&mut self, line: &str) {
+        self.buffer.set(line);
+    }
+
     // Called by Shell before each read_line so the editor knows the current prompt
     pub fn set_prompt(&mut self, terminal: &mut Terminal) -> Result<()> {
         let (_, row) = terminal.cursor_pos()?;
@@ -118,69 +164,157 @@ impl Editor {
         Ok(())
     }
 
+    /// The line typed by the user, None when they end the session with Ctrl-D on an empty line
     pub fn read_line(
         &mut self,
         context: &mut Context,
         terminal: &mut Terminal,
-        prompt: &Prompt,
-    ) -> Result<String> {
-        self.redraw(context, terminal, prompt, false)?;
+        prompt: &mut Prompt,
+    ) -> Result<Option<String>> {
+        terminal.begin_batch();
+        let drawn = self.redraw(context, terminal, prompt, false);
+        terminal.end_batch()?;
+        drawn?;
 
         loop {
-            if context.signals.drain_child_pipe() {
-                self.redraw(context, terminal, prompt, true)?;
+            // What's drawn for a keystroke, or for output coming in, goes out in one write
+            terminal.begin_batch();
+            let refreshed = self.refresh(context, terminal, prompt);
+            terminal.end_batch()?;
+            refreshed?;
+
+            // A hangup ends the session like Ctrl-D, it's only seen between keys so a redraw
+            // is never cut off halfway
+            if context.signals.ending().is_some() {
+                terminal.println("")?;
+                return Ok(None);
             }
 
-            // Prints the current output of any background process
-            for line in context.jobs.get_background_stdout()? {
-                terminal.print(&line)?;
+            // Check for keyboard input with short timeout
+            let Some(KeyEvent {
+                code, modifiers, ..
+            }) = terminal.read_key(std::time::Duration::from_millis(50))?
+            else {
+                continue;
+            };
+            terminal.begin_batch();
+            let handled = self.key(code, modifiers, context, terminal, prompt);
+            terminal.end_batch()?;
+            if let ControlFlow::Break(line) = handled? {
+                return Ok(line);
             }
+        }
+    }
 
+    // Shows what happened while waiting for a key
+    fn refresh(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &mut Prompt,
+    ) -> Result<()> {
+        if context.signals.drain_child_pipe() {
+            self.redraw(context, terminal, prompt, true)?;
+        }
+
+        // The terminal may have moved the line while wrapping it to the new width
+        #[cfg(unix)]
+        if context.signals.received(libc::SIGWINCH) {
             let (_, row) = terminal.cursor_pos()?;
             self.row = row;
+            self.redraw(context, terminal, prompt, false)?;
+        }
 
-            // Check for keyboard input with short timeout
-            if event::poll(std::time::Duration::from_millis(50))? {
-                match event::read().context("Failed to read event")? {
-                    Event::Key(KeyEvent {
-                        code, modifiers, ..
-                    }) => {
-                        if modifiers.contains(KeyModifiers::CONTROL) {
-                            match code {
-                                KeyCode::Char('c') => self.ctrl_c(context, terminal, prompt)?,
-                                KeyCode::Char('l') => self.ctrl_l(context, terminal, prompt)?,
-                                _ => {}
-                            }
-                        } else if modifiers.contains(KeyModifiers::ALT) {
-                            match code {
-                                KeyCode::Left => self.alt_left(context, terminal, prompt)?,
-                                KeyCode::Right => self.alt_right(context, terminal, prompt)?,
-                                KeyCode::Backspace => {
-                                    self.alt_backspace(context, terminal, prompt)?
-                                }
-                                _ => {}
-                            }
-                        } else {
-                            match code {
-                                KeyCode::Char(c) => {
-                                    self.buffer.insert(c);
-                                    self.redraw(context, terminal, prompt, false)?;
-                                }
-                                KeyCode::Enter => return self.enter(context, terminal),
-                                KeyCode::Backspace => self.backspace(context, terminal, prompt)?,
-                                KeyCode::Up => self.up_arrow(context, terminal, prompt)?,
-                                KeyCode::Down => self.down_arrow(context, terminal, prompt)?,
-                                KeyCode::Left => self.left_arrow(terminal)?,
-                                KeyCode::Right => self.right_arrow(terminal)?,
-                                KeyCode::Home => self.home_key(terminal, prompt)?,
-                                KeyCode::End => self.end_key(terminal, prompt)?,
-                                _ => {}
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+        // A segment like `{git}` finished what it was slow to render
+        if prompt.poll() {
+            prompt.update(context);
+            self.redraw(context, terminal, prompt, false)?;
+        }
+
+        // Prints the current output of any background process
+        for line in context.jobs.get_background_stdout()? {
+            terminal.print(&line)?;
+        }
+
+        let (_, row) = terminal.cursor_pos()?;
+        self.row = row;
+        Ok(())
+    }
+
+    // Handles a key, breaking with what read_line gives back once the line is done
+    fn key(
+        &mut self,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<ControlFlow<Option<String>>> {
+        if self.search.is_some() {
+            if self.search_key(code, modifiers, context, terminal, prompt)? {
+                return self
+                    .enter(context, terminal, prompt)
+                    .map(Some)
+                    .map(ControlFlow::Break);
+            }
+        } else if let Some(action) = self.keymap.get(code, modifiers) {
+            if action == Action::AcceptLine {
+                return self
+                    .enter(context, terminal, prompt)
+                    .map(Some)
+                    .map(ControlFlow::Break);
+            }
+            if action == Action::EndOfFile && self.buffer.len() == 0 {
+                terminal.println("")?;
+                return Ok(ControlFlow::Break(None));
             }
+            self.run(action, context, terminal, prompt)?;
+        } else if let KeyCode::Char(c) = code
+            && !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+        {
+            self.buffer.insert(c);
+            self.redraw(context, terminal, prompt, false)?;
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    // Everything but AcceptLine, which ends read_line
+    fn run(
+        &mut self,
+        action: Action,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<()> {
+        match action {
+            // Only ends the session on an empty line, see read_line
+            Action::AcceptLine | Action::EndOfFile => Ok(()),
+            Action::CancelLine => self.ctrl_c(context, terminal, prompt),
+            Action::ClearScreen => self.ctrl_l(context, terminal, prompt),
+            Action::FuzzySearch => self.ctrl_r(context, terminal, prompt),
+            Action::BackwardChar => self.left_arrow(terminal),
+            Action::ForwardChar => self.right_arrow(terminal),
+            Action::BackwardWord => self.alt_left(context, terminal, prompt),
+            Action::ForwardWord => self.alt_right(context, terminal, prompt),
+            Action::BeginningOfLine => self.home_key(terminal, prompt),
+            Action::EndOfLine => self.end_key(terminal, prompt),
+            Action::BackwardDeleteChar => self.backspace(context, terminal, prompt),
+            Action::BackwardKillWord => self.alt_backspace(context, terminal, prompt),
+            Action::PreviousHistory => self.up_arrow(context, terminal, prompt),
+            Action::NextHistory => self.down_arrow(context, terminal, prompt),
+            Action::EditCommandLine => self.alt_e(context, terminal, prompt),
+            Action::Complete => self.tab(context, terminal, prompt),
+            Action::KillLine => self.ctrl_k(context, terminal, prompt),
+            Action::Yank => self.ctrl_y(context, terminal, prompt),
+            Action::CopyLine => {
+                let line = self.buffer.content();
+                self.copy(context, terminal, prompt, &line)
+            }
+            Action::CopyKill => {
+                let killed = self.kill_ring.last().cloned().unwrap_or_default();
+                self.copy(context, terminal, prompt, &killed)
+            }
+            Action::PasteClipboard => self.alt_v(context, terminal, prompt),
         }
     }
 
@@ -207,6 +341,73 @@ impl Editor {
         self.redraw(context, terminal, prompt, false)
     }
 
+    fn ctrl_r(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<()> {
+        if context.settings.options.histshare || env::var_os("HISTSHARE").is_some() {
+            context.history.reload()?;
+        }
+        context.history.load_all()?;
+
+        self.search = Some(FuzzySearch {
+            query: String::new(),
+            selected: 0,
+            matches: 0,
+            original: self.buffer.content(),
+        });
+        self.redraw(context, terminal, prompt, false)
+    }
+
+    /// Handles a key while the fuzzy picker is open, returns true when the selected
+    /// command should be run straight away
+    fn search_key(
+        &mut self,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<bool> {
+        let Some(search) = self.search.as_mut() else {
+            return Ok(false);
+        };
+
+        let control = modifiers.contains(KeyModifiers::CONTROL);
+        match code {
+            // Ctrl-R again walks down the list of matches
+            KeyCode::Char('r') if control => search.selected += 1,
+            KeyCode::Char('c') | KeyCode::Char('g') if control => {
+                self.buffer.set(&search.original);
+                self.search = None;
+            }
+            KeyCode::Esc => {
+                self.buffer.set(&search.original);
+                self.search = None;
+            }
+            KeyCode::Char(c) if !control => {
+                search.query.push(c);
+                search.selected = 0;
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                search.selected = 0;
+            }
+            KeyCode::Enter => {
+                self.search = None;
+                self.redraw(context, terminal, prompt, false)?;
+                return Ok(true);
+            }
+            // Any movement key keeps the selected command in the buffer to edit it
+            _ => self.search = None,
+        }
+
+        self.redraw(context, terminal, prompt, false)?;
+        Ok(false)
+    }
+
     fn alt_left(
         &mut self,
         context: &mut Context,
@@ -235,19 +436,229 @@ impl Editor {
     ) -> Result<()> {
         let start = self.buffer.prev_word();
         if start < self.buffer.index {
-            self.buffer.data.drain(start..self.buffer.index);
+            let killed = self.buffer.data.drain(start..self.buffer.index).collect();
+            self.kill(killed);
             self.buffer.index = start;
         }
         self.redraw(context, terminal, prompt, false)
     }
 
-    fn enter(&mut self, context: &mut Context, terminal: &mut Terminal) -> Result<String> {
+    // Kills from the cursor to the end of the line
+    fn ctrl_k(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<()> {
+        if self.buffer.index < self.buffer.len() {
+            let killed = self.buffer.data.split_off(self.buffer.index);
+            self.kill(killed);
+        }
+        self.redraw(context, terminal, prompt, false)
+    }
+
+    // Puts back the last kill at the cursor
+    fn ctrl_y(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<()> {
+        if let Some(killed) = self.kill_ring.last().cloned() {
+            self.insert(&killed);
+        }
+        self.redraw(context, terminal, prompt, false)
+    }
+
+    fn kill(&mut self, killed: String) {
+        if self.kill_ring.len() == KILL_RING_SIZE {
+            self.kill_ring.remove(0);
+        }
+        self.kill_ring.push(killed);
+    }
+
+    fn insert(&mut self, text: &str) {
+        self.buffer.data.insert_str(self.buffer.index, text);
+        self.buffer.index += text.len();
+    }
+
+    fn copy(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+        text: &str,
+    ) -> Result<()> {
+        if let Err(error) = clipboard::copy(terminal, text) {
+            self.show(terminal, prompt, &[format!("{:#}", error)])?;
+        }
+        self.forget_tool(context, terminal)?;
+        self.redraw(context, terminal, prompt, false)
+    }
+
+    // Pastes the clipboard at the cursor, separate lines become a sequence like with alt_e
+    fn alt_v(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<()> {
+        match clipboard::paste() {
+            Err(error) => self.show(terminal, prompt, &[format!("{:#}", error)])?,
+            pasted => {
+                let text = pasted?;
+                let lines: Vec<&str> = text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .collect();
+                self.insert(&lines.join("; "));
+            }
+        }
+        self.forget_tool(context, terminal)?;
+        self.redraw(context, terminal, prompt, false)
+    }
+
+    // The clipboard tool isn't a job, its end shouldn't look like one finishing
+    fn forget_tool(&mut self, context: &mut Context, terminal: &mut Terminal) -> Result<()> {
+        if context.signals.drain_child_pipe() {
+            context.jobs.update_table(terminal)?;
+        }
+        Ok(())
+    }
+
+    // Prints lines under the one being edited, which is drawn again after them
+    fn show(&mut self, terminal: &mut Terminal, prompt: &Prompt, lines: &[String]) -> Result<()> {
+        self.end_key(terminal, prompt)?;
+        terminal.println("")?;
+        for line in lines {
+            terminal.println(line)?;
+        }
+        let (_, row) = terminal.cursor_pos()?;
+        self.row = row;
+        Ok(())
+    }
+
+    // Opens the line in $VISUAL or $EDITOR (vi if neither is set) and puts back what was
+    // saved, for when a command gets too long to edit comfortably here
+    fn alt_e(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<()> {
+        // The name is predictable, so the file is created from scratch and only readable by us
+        let path = env::temp_dir().join(format!("rshell-{}-line.sh", context.pid));
+        let _ = fs::remove_file(&path);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", self.buffer.data))
+            .context("Failed to write the line for the editor")?;
+
+        let editor = env::var("VISUAL")
+            .or_else(|_| env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string());
+        let mut words = editor.split_whitespace();
+        let program = words.next().unwrap_or("vi");
+        let mut command = process::Command::new(program);
+        command.args(words).arg(&path);
+        #[cfg(unix)]
+        unsafe {
+            command.pre_exec(|| {
+                SignalHandler::restore_defaults();
+                std::io::Result::Ok(())
+            });
+        }
+
         terminal.println("")?;
-        let line = self.buffer.content();
-        if !line.is_empty() && context.history.current.last() != Some(&line) {
-            context.history.push(line)?;
+        terminal.exit_raw_mode()?;
+        let status = command.status();
+        terminal.enter_raw_mode()?;
+
+        let edited = fs::read_to_string(&path);
+        let _ = fs::remove_file(&path);
+        if let Err(error) = &status {
+            terminal.println(&format!("{}: {}", program, error))?;
+        }
+        // Quitting the editor with an error keeps the line as it was
+        if status.is_ok_and(|status| status.success()) {
+            let edited = edited.context("Failed to read the edited line")?;
+            // The line editor is single line, so separate lines become a sequence
+            let lines: Vec<&str> = edited
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect();
+            self.buffer.set(&lines.join("; "));
         }
-        context.history.row = context.history.current.len();
+
+        if context.signals.drain_child_pipe() {
+            context.jobs.update_table(terminal)?;
+        }
+        let (_, row) = terminal.cursor_pos()?;
+        self.row = row;
+        self.redraw(context, terminal, prompt, false)
+    }
+
+    // Completes the word before the cursor as far as the candidates agree, a single one gets
+    // a space after it. When they don't agree on anything more they're listed under the line
+    fn tab(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<()> {
+        // Read every time, so new and edited specs apply straight away
+        let (mut completions, problems) = Completions::load();
+        for (command, spec) in context.plugins.completions() {
+            // Checked when the plugin registered it
+            let _ = completions.add(command, spec);
+        }
+        let line = &self.buffer.data[..self.buffer.index];
+        let start = self.buffer.index - completion::current_word(line).len();
+        let candidates = completions.complete(context, line);
+
+        let replacement = match candidates.as_slice() {
+            [only] if only.ends_with('/') => only.clone(),
+            [only] => format!("{} ", only),
+            _ => completion::common_prefix(&candidates).to_string(),
+        };
+        let mut listed = problems;
+        if replacement.len() > self.buffer.index - start {
+            self.buffer
+                .data
+                .replace_range(start..self.buffer.index, &replacement);
+            self.buffer.index = start + replacement.len();
+        } else if candidates.len() > 1 {
+            let (columns, _) = terminal.size()?;
+            listed.extend(in_columns(&candidates, columns as usize));
+        }
+
+        if !listed.is_empty() {
+            self.show(terminal, prompt, &listed)?;
+        }
+        self.redraw(context, terminal, prompt, false)
+    }
+
+    // The line gets recorded in the history by the shell, after history expansion
+    fn enter(
+        &mut self,
+        context: &mut Context,
+        terminal: &mut Terminal,
+        prompt: &Prompt,
+    ) -> Result<String> {
+        // With a transient prompt the submitted line keeps only a short marker in the scrollback
+        if let Some(marker) = &prompt.transient {
+            terminal.clear_line(self.row)?;
+            terminal.print(marker)?;
+            terminal.print(&self.buffer.data)?;
+        }
+        terminal.println("")?;
+        context.history.reset_row();
         Ok(self.buffer.take())
     }
 
@@ -269,10 +680,15 @@ impl Editor {
         terminal: &mut Terminal,
         prompt: &Prompt,
     ) -> Result<()> {
-        if context.history.row > 0 {
-            context.history.row -= 1;
-            self.buffer
-                .set(&context.history.current[context.history.row]);
+        // With histshare on, starting to browse the history also shows what other sessions ran
+        let share = context.settings.options.histshare || env::var_os("HISTSHARE").is_some();
+        if !context.history.is_browsing() && share {
+            context.history.reload()?;
+        }
+
+        let mode = DirectoryMode::from_env();
+        if let Some(command) = context.history.older(&context.directory, mode)? {
+            self.buffer.set(command);
             self.redraw(context, terminal, prompt, false)?;
         }
         Ok(())
@@ -284,14 +700,8 @@ impl Editor {
         terminal: &mut Terminal,
         prompt: &Prompt,
     ) -> Result<()> {
-        if context.history.row < context.history.current.len() {
-            context.history.row += 1;
-            let val = if context.history.row == context.history.current.len() {
-                ""
-            } else {
-                &context.history.current[context.history.row]
-            };
-            self.buffer.set(val);
+        if let Some(command) = context.history.newer() {
+            self.buffer.set(command);
             self.redraw(context, terminal, prompt, false)?;
         }
         Ok(())
@@ -334,10 +744,45 @@ impl Editor {
             self.handle_child_finished(context, terminal)?;
         }
 
+        if let Some(search) = self.search.as_mut() {
+            let matches = context.history.fuzzy(&search.query);
+            search.matches = matches.len();
+            search.selected = search.selected.min(matches.len().saturating_sub(1));
+            match matches.get(search.selected) {
+                Some((_, entry)) => self.buffer.set(&entry.command),
+                None => self.buffer.set(""),
+            }
+
+            let label = format!(
+                "(fuzzy {}/{}) {}",
+                (search.selected + 1).min(search.matches),
+                search.matches,
+                search.query
+            );
+            terminal.clear_line(self.row)?;
+            terminal.print(&format!("{}: {}", label, self.buffer.data))?;
+            terminal.move_to(label.len() as u16, self.row)?;
+            return Ok(());
+        }
+
+        let marks = context.shell_integration();
         terminal.clear_line(self.row)?;
+        if marks {
+            terminal.mark(Mark::PromptStart)?;
+        }
         terminal.print(&prompt.message)?;
+        if marks {
+            terminal.mark(Mark::CommandStart)?;
+        }
         terminal.print(&self.buffer.data)?;
 
+        // The right prompt disappears once the line grows into it, clear_line took care of it
+        let (columns, _) = terminal.size()?;
+        if let Some(column) = prompt.right_column(self.buffer.data.width(), columns as usize) {
+            terminal.move_to(column as u16, self.row)?;
+            terminal.print(&prompt.right)?;
+        }
+
         let cursor_col = prompt.len() + self.buffer.index;
         terminal.move_to(cursor_col as u16, self.row)?;
 
@@ -349,19 +794,44 @@ impl Editor {
         context: &mut Context,
         terminal: &mut Terminal,
     ) -> Result<()> {
-        terminal.println("")?;
         context.signals.drain_child_pipe();
 
         context.jobs.update_table(terminal)?;
+        notify::jobs_finished(context, terminal)?;
 
+        // The child may not have been a job, or a job with more processes left
+        if terminal.notifications.is_empty() {
+            return Ok(());
+        }
+        terminal.println("")?;
         let notifications: Vec<String> = terminal.notifications.drain(..).collect();
         for notification in notifications {
             terminal.println(&notification)?;
         }
 
+        // The job may have written to the terminal itself, on stderr
+        terminal.forget_cursor();
         let (_, row) = terminal.cursor_pos()?;
         self.row = row;
 
         Ok(())
     }
 }
+
+// Lays out the words in as many columns as fit the width, going down each column first
+fn in_columns(words: &[String], width: usize) -> Vec<String> {
+    let column = words.iter().map(|word| word.width()).max().unwrap_or(0) + 2;
+    let columns = (width / column).max(1);
+    let rows = words.len().div_ceil(columns);
+    (0..rows)
+        .map(|row| {
+            let line: String = words
+                .iter()
+                .skip(row)
+                .step_by(rows)
+                .map(|word| format!("{}{}", word, " ".repeat(column - word.width())))
+                .collect();
+            line.trim_end().to_string()
+        })
+        .collect()
+}
@@ -0,0 +1,74 @@
+//encoding.rs
+
+use std::ffi::{CString, NulError, OsStr, OsString};
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+// Bytes that aren't UTF-8 are kept as the characters from here on, the last 128 of the
+// private use plane 16, so they go back to the same bytes when given to the system
+const ESCAPED: u32 = 0x10FF80;
+// Comes before the characters of the text that are themselves in that range, or this one, so
+// they aren't taken for bytes
+const MARK: char = '\u{10FF7F}';
+
+/// Reads bytes into a string without losing any, file names and arguments don't have to be
+/// UTF-8. A byte that isn't part of a UTF-8 character becomes a private use character, which
+/// `encode` turns back into the byte
+pub fn decode(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for character in chunk.valid().chars() {
+            if character >= MARK {
+                text.push(MARK);
+            }
+            text.push(character);
+        }
+        for byte in chunk.invalid() {
+            text.extend(char::from_u32(ESCAPED + (*byte as u32 - 0x80)));
+        }
+    }
+    text
+}
+
+/// The bytes a string read with `decode` came from
+pub fn encode(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        let character = match character {
+            MARK => characters.next().unwrap_or(MARK),
+            code if code as u32 >= ESCAPED => {
+                bytes.push((code as u32 - ESCAPED + 0x80) as u8);
+                continue;
+            }
+            _ => character,
+        };
+        bytes.extend_from_slice(character.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    bytes
+}
+
+#[cfg(unix)]
+pub fn from_os_str(text: &OsStr) -> String {
+    decode(text.as_bytes())
+}
+
+#[cfg(unix)]
+pub fn to_os_string(text: &str) -> OsString {
+    OsString::from_vec(encode(text))
+}
+
+// Windows names are UTF-16, only a lone surrogate can't be kept and becomes U+FFFD
+#[cfg(windows)]
+pub fn from_os_str(text: &OsStr) -> String {
+    decode(text.to_string_lossy().as_bytes())
+}
+
+#[cfg(windows)]
+pub fn to_os_string(text: &str) -> OsString {
+    OsString::from(String::from_utf8_lossy(&encode(text)).into_owned())
+}
+
+pub fn to_cstring(text: &str) -> Result<CString, NulError> {
+    CString::new(encode(text))
+}
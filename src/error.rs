@@ -9,6 +9,7 @@ pub enum ShellPhase {
     Expander,
     Executor,
     SignalHandler,
+    Arithmetic,
 }
 
 impl fmt::Display for ShellPhase {
@@ -19,6 +20,7 @@ impl fmt::Display for ShellPhase {
             ShellPhase::Expander => "Expander",
             ShellPhase::Executor => "Executor",
             ShellPhase::SignalHandler => "SignalHandler",
+            ShellPhase::Arithmetic => "Arithmetic",
         };
         write!(f, "{}", name)
     }
@@ -52,6 +54,54 @@ impl ShellError {
     pub fn is_exit(&self) -> bool {
         matches!(self.phase, ShellPhase::Executor) && self.message == "QUIT"
     }
+
+    /// A command that doesn't tokenize or parse, like sh the shell gives it status 2
+    pub fn is_syntax(&self) -> bool {
+        matches!(self.phase, ShellPhase::Tokenizer | ShellPhase::Parser)
+    }
+
+    /// The input ended before the command did, like after a `for` line without its `done`
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self.phase, ShellPhase::Tokenizer | ShellPhase::Parser)
+            && self.message.starts_with("Unexpected end of input")
+    }
+
+    /// The input ended in the body of a here-document, its lines are kept as they are
+    pub fn is_in_here_doc(&self) -> bool {
+        self.is_incomplete() && self.message.ends_with("closing the here-document")
+    }
 }
 
 impl std::error::Error for ShellError {}
+
+/// Raised by `break` and `continue` and caught by the loops around them, the number is how
+/// many loops it goes through
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopControl {
+    Break(usize),
+    Continue(usize),
+}
+
+impl fmt::Display for LoopControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LoopControl::Break(_) => "break",
+            LoopControl::Continue(_) => "continue",
+        };
+        write!(f, "{}: Only meaningful in a loop", name)
+    }
+}
+
+impl std::error::Error for LoopControl {}
+
+/// Raised by `return` and caught by the sourced file it's in, which stops there with the status
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Return(pub i32);
+
+impl fmt::Display for Return {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "return: Only meaningful in a sourced file")
+    }
+}
+
+impl std::error::Error for Return {}
@@ -1,38 +1,321 @@
 //executor.rs
 
 use crate::{
+    arithmetic, conditional,
     context::Context,
+    correction,
+    editor::Editor,
+    encoding,
     error::*,
-    jobs::{Job, JobState, Jobs},
-    parser::{Command, EnvVariable, Redirect, RedirectKind},
+    expander,
+    parser::{Arg, Command, EnvVariable, Redirect, RedirectKind, RedirectTarget},
+    prompt::Prompt,
+    safety, shell,
+    sys::{self, Pid, RawFd, STDIN_FILENO, STDOUT_FILENO},
     terminal::Terminal,
 };
+#[cfg(unix)]
+use crate::{
+    jobs::{self, Job, JobState, Jobs},
+    signals,
+    sys::STDERR_FILENO,
+};
 use anyhow::{Context as AnyhowContext, Ok, Result};
-use std::{collections::HashMap, env, ffi::CString, io, os::fd::RawFd};
+use std::{
+    borrow::Cow,
+    env,
+    fs::{self, OpenOptions},
+    io::{self, IsTerminal, Seek, SeekFrom, Write},
+    path::Path,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+};
+#[cfg(windows)]
+use std::{
+    ffi::OsString,
+    io::Read,
+    os::windows::{
+        fs::OpenOptionsExt,
+        io::{BorrowedHandle, IntoRawHandle, OwnedHandle},
+    },
+    thread,
+};
+#[cfg(windows)]
+use windows_sys::Win32::Storage::FileSystem::{
+    FILE_FLAG_DELETE_ON_CLOSE, FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE,
+};
+
+#[cfg(unix)]
+unsafe extern "C" {
+    // The environment of the shell, what `export` changes
+    static environ: *const *const libc::c_char;
+}
 
 pub fn execute(
     context: &mut Context,
     terminal: &mut Terminal,
     command: Command<'static>,
     stdout_fd: Option<RawFd>, // if this parameter here is present it means that we're calling this from a subcommand
-) -> Result<(i32, libc::pid_t)> {
+) -> Result<(i32, Pid)> {
+    // Only printed when the span is logged, running a builtin doesn't need the line otherwise
+    let _span = tracing::debug_span!("execute", command = %command.to_string()).entered();
+
+    // A dry run shows the commands instead of starting them, only `set` still runs so it can
+    // be turned off. Assignments and the like only change the shell, they run as usual
+    if context.mode.dry_run {
+        match command {
+            Command::Simple {
+                ref command,
+                ref args,
+                ref redirects,
+                ref env_vars,
+            } if command != "set" => {
+                show(terminal, command, args, redirects, env_vars)?;
+                return Ok((0, 0));
+            }
+            // Their commands are shown one after the other, nothing is forked
+            Command::Pipeline(left, right) => {
+                execute(context, terminal, *left, stdout_fd)?;
+                return execute(context, terminal, *right, stdout_fd);
+            }
+            Command::Background(command) | Command::Subshell(command) => {
+                return execute(context, terminal, *command, stdout_fd);
+            }
+            _ => {}
+        }
+    }
+
+    // Commands matching a `[safety] confirm` pattern only run once the user says so
+    if !safety::allow(context, terminal, &command) {
+        return Ok((1, 0));
+    }
+
+    let mut command = command;
     if let Command::Simple {
-        command: ref name,
+        command: ref mut name,
         ref args,
         ..
     } = command
     {
         if let Some(builtin) = context.builtins.get(name) {
             let str_args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
-            return Ok((builtin(&str_args, context, terminal)?, 0 as libc::pid_t));
+            let status = builtin(&str_args, context, terminal)?;
+            tracing::debug!(builtin = %name, status, "Ran builtin");
+            return Ok((status, 0 as Pid));
+        }
+        if let Some(builtin) = context.plugins.builtin(name) {
+            let str_args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            let status = builtin.call(&str_args)?;
+            tracing::debug!(builtin = %name, status, "Ran plugin builtin");
+            return Ok((status, 0));
+        }
+
+        // Like zsh's AUTO_CD, a directory that isn't the name of a program is entered
+        if args.is_empty()
+            && context.autocd()
+            && !in_path(name)
+            && Path::new(name.as_ref()).is_dir()
+            && let Some(cd) = context.builtins.get("cd")
+        {
+            let status = cd(&[name.as_ref()], context, terminal)?;
+            return Ok((status, 0));
+        }
+
+        // A typo at the prompt is offered the closest builtin or program instead
+        if stdout_fd.is_none() && !name.contains('/') && !in_path(name) {
+            let str_args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            if let Some(corrected) = correction::command(context, terminal, name, &str_args)? {
+                *name = Cow::Owned(corrected);
+                return execute(context, terminal, command, stdout_fd);
+            }
         }
     }
 
-    let stdout = stdout_fd.unwrap_or(libc::STDOUT_FILENO);
-    let command_str = command.to_string();
+    let stdout = stdout_fd.unwrap_or(STDOUT_FILENO);
     match command {
-        Command::Simple { .. } => {
-            let pgid = spawn_process(context, command, libc::STDIN_FILENO, stdout, None, true)?;
+        // In a subcommand the loop runs in a copy of the shell writing into the pipe, so the
+        // caller can wait for all of it
+        #[cfg(unix)]
+        Command::For { .. }
+        | Command::ArithmeticFor { .. }
+        | Command::Select { .. }
+        | Command::Case { .. }
+            if stdout_fd.is_some() =>
+        {
+            let pid = spawn_process(context, command, STDIN_FILENO, stdout, None, false, None)?;
+            Ok((0, pid))
+        }
+
+        Command::For {
+            variable,
+            words,
+            body,
+        } => {
+            let values = match words {
+                Some(words) => expander::expand_words(context, terminal, words)?,
+                None => context.positional.clone(),
+            };
+
+            let mut result = (0, 0);
+            for value in values {
+                context.variables.assign(&variable, &value)?;
+                if !execute_iteration(context, terminal, &body, &mut result)? {
+                    break;
+                }
+            }
+            Ok(result)
+        }
+
+        Command::ArithmeticFor {
+            init,
+            condition,
+            step,
+            body,
+        } => {
+            arithmetic::eval_in(&mut context.variables, &init)?;
+
+            // Like C an empty condition is always true
+            let mut result = (0, 0);
+            while condition.trim().is_empty()
+                || arithmetic::eval_in(&mut context.variables, &condition)? != 0
+            {
+                if !execute_iteration(context, terminal, &body, &mut result)? {
+                    break;
+                }
+                arithmetic::eval_in(&mut context.variables, &step)?;
+            }
+            Ok(result)
+        }
+
+        Command::Select {
+            variable,
+            words,
+            body,
+        } => {
+            let values = match words {
+                Some(words) => expander::expand_words(context, terminal, words)?,
+                None => context.positional.clone(),
+            };
+
+            let mut result = (0, 0);
+            let mut show_menu = true;
+            loop {
+                // Like other shells the menu goes to stderr, so it's seen even when the loop
+                // is piped somewhere
+                if show_menu {
+                    let width = values.len().to_string().len();
+                    for (number, value) in values.iter().enumerate() {
+                        eprintln!("{:>width$}) {}", number + 1, value);
+                    }
+                }
+
+                let prompt = context
+                    .variables
+                    .get("PS3")
+                    .unwrap_or_else(|| "#? ".to_string());
+                let Some(reply) = read_reply(context, terminal, &prompt)? else {
+                    break;
+                };
+                // An empty line shows the menu again
+                show_menu = reply.trim().is_empty();
+                if show_menu {
+                    continue;
+                }
+
+                // A reply that isn't one of the numbers leaves the variable empty
+                let picked = reply
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|number| values.get(number.checked_sub(1)?));
+                context.variables.set("REPLY", reply.trim());
+                context
+                    .variables
+                    .set(&variable, picked.map_or("", |value| value.as_str()));
+                if !execute_iteration(context, terminal, &body, &mut result)? {
+                    break;
+                }
+            }
+            Ok(result)
+        }
+
+        Command::Case { word, items } => {
+            let word = expander::expand_word(context, terminal, word)?;
+
+            let mut matched = false;
+            let mut result = (0, 0);
+            for item in items {
+                if !matched {
+                    for pattern in item.patterns {
+                        let pattern = expander::expand_pattern(context, terminal, pattern)?;
+                        if expander::matches_pattern(&pattern, &word) {
+                            matched = true;
+                            break;
+                        }
+                    }
+                }
+                if !matched {
+                    continue;
+                }
+
+                if let Some(body) = &item.body {
+                    result = execute_body(context, terminal, body)?;
+                }
+                if !item.fall_through {
+                    break;
+                }
+            }
+            Ok(result)
+        }
+
+        // Like C a value that isn't 0 is true, which is a status of 0
+        Command::Arithmetic(expression) => {
+            let value = expander::expand_arithmetic(context, terminal, &expression)?;
+            Ok(((value == 0) as i32, 0))
+        }
+
+        Command::Conditional(args) => {
+            let matched = conditional::evaluate(context, terminal, &args)?;
+            Ok(((!matched) as i32, 0))
+        }
+
+        Command::Assignment(variables) => {
+            for variable in variables {
+                context.variables.assign(&variable.name, &variable.value)?;
+            }
+            Ok((0, 0))
+        }
+
+        Command::ArrayAssignment {
+            name,
+            values,
+            append,
+        } => {
+            let array = context.arrays.entry(name.into_owned()).or_default();
+            if !append {
+                array.clear();
+            }
+            array.extend(values.into_iter().map(Into::<String>::into));
+            Ok((0, 0))
+        }
+
+        // A subshell is a copy of the shell, waited for like any other process
+        #[cfg(unix)]
+        Command::Simple { .. } | Command::Subshell(_) => {
+            let command_str = command.to_string();
+            let group = context.shared_group.then_some(context.pgid);
+            let pid = spawn_process(context, command, STDIN_FILENO, stdout, group, true, None)?;
+            let pgid = group.unwrap_or(pid);
 
             if stdout_fd.is_none() {
                 Ok((
@@ -41,7 +324,7 @@ pub fn execute(
                         terminal,
                         pgid,
                         command_str,
-                        &[pgid],
+                        &[pid],
                         true,
                         false,
                     )?,
@@ -53,32 +336,37 @@ pub fn execute(
         }
 
         Command::And(left, right) => {
-            let status = execute(context, terminal, *left, stdout_fd)?;
+            let status = execute_expanded(context, terminal, *left, stdout_fd)?;
+            context.last_exit_code = status.0;
             if status.0 == 0 {
-                execute(context, terminal, *right, stdout_fd)
+                execute_expanded(context, terminal, *right, stdout_fd)
             } else {
                 Ok(status)
             }
         }
 
         Command::Or(left, right) => {
-            let status = execute(context, terminal, *left, stdout_fd)?;
+            let status = execute_expanded(context, terminal, *left, stdout_fd)?;
+            context.last_exit_code = status.0;
             if status.0 != 0 {
-                execute(context, terminal, *right, stdout_fd)
+                execute_expanded(context, terminal, *right, stdout_fd)
             } else {
                 Ok(status)
             }
         }
 
         Command::Sequence(left, right) => {
-            execute(context, terminal, *left, stdout_fd)?;
-            execute(context, terminal, *right, stdout_fd)
+            // A bare `exit` further on leaves with this status
+            context.last_exit_code = execute_expanded(context, terminal, *left, stdout_fd)?.0;
+            execute_expanded(context, terminal, *right, stdout_fd)
         }
 
+        #[cfg(unix)]
         Command::Background(command) => {
-            if stdout != libc::STDOUT_FILENO {
+            if stdout != STDOUT_FILENO {
                 error("You cannot use a background command as a subcommand")?;
             }
+            let command_str = format!("{} &", command.to_string());
 
             let mut fds = [0; 2];
             unsafe {
@@ -99,14 +387,13 @@ pub fn execute(
                 let pid = spawn_process(
                     context,
                     *command,
-                    libc::STDIN_FILENO,
+                    STDIN_FILENO,
                     pipe_write,
                     None,
                     false,
+                    None,
                 )?;
 
-                unsafe { libc::close(pipe_write) };
-
                 let job_id = context.jobs.add(Job::new(
                     pid,
                     vec![pid],
@@ -120,18 +407,10 @@ pub fn execute(
 
                 Ok((0, pid))
             } else {
-                let (gpid, pids) = spawn_piped(
-                    context,
-                    *command,
-                    libc::STDIN_FILENO,
-                    pipe_write,
-                    None,
-                    false,
-                )?;
+                let (gpid, pids) =
+                    spawn_piped(context, *command, STDIN_FILENO, pipe_write, None, false)?;
 
-                unsafe { libc::close(pipe_write) };
-
-                context.last_job_pid = Some(*pids.last().unwrap());
+                context.last_job_pid = pids.last().copied();
 
                 let job_id = context.jobs.add(Job::new(
                     gpid,
@@ -151,9 +430,11 @@ pub fn execute(
             }
         }
 
+        #[cfg(unix)]
         Command::Pipeline(..) => {
-            let (gpid, pids) =
-                spawn_piped(context, command, libc::STDIN_FILENO, stdout, None, true)?;
+            let command_str = command.to_string();
+            let group = context.shared_group.then_some(context.pgid);
+            let (gpid, pids) = spawn_piped(context, command, STDIN_FILENO, stdout, group, true)?;
 
             if stdout_fd.is_none() {
                 Ok((
@@ -173,60 +454,116 @@ pub fn execute(
             }
         }
 
-        Command::Subshell(command) => {
-            let pid = unsafe { libc::fork() };
-            if pid == -1 {
-                return os_error();
-            }
-
-            if pid == 0 {
-                // ── CHILD ────────────────────────────────────────────────
-
-                unsafe { libc::setpgid(0, 0) };
-
-                let child_pid = unsafe { libc::getpid() };
-                let mut child_context = context.clone().duplicate(child_pid)?;
-                context.signals.reset();
-
-                execute(&mut child_context, terminal, *command, None)?;
+        // Windows can't copy the shell, programs are started and waited for and what would
+        // run in a copy runs here on a copy of the context
+        #[cfg(windows)]
+        Command::Simple { .. } | Command::Subshell(_) | Command::Pipeline(..) => {
+            Ok((run_pipeline(context, command, true)?.0, 0))
+        }
 
-                unsafe { libc::_exit(0) };
+        // Without job control it's only started, its output goes straight to the console
+        #[cfg(windows)]
+        Command::Background(command) => {
+            if stdout != STDOUT_FILENO {
+                error("You cannot use a background command as a subcommand")?;
             }
+            let (_, pids) = run_pipeline(context, *command, false)?;
+            context.last_job_pid = pids.last().copied();
+            let pids: Vec<String> = pids.iter().map(|pid| pid.to_string()).collect();
+            terminal.println(&pids.join(" "))?;
+            Ok((0, 0))
+        }
+    }
+}
 
-            // ── PARENT ────────────────────────────────────────────────────
-            unsafe {
-                libc::setpgid(pid, 0);
-
-                if libc::tcsetpgrp(libc::STDIN_FILENO, pid) == -1 {
-                    return os_error();
-                }
-            }
+// The sides of `;`, `&&` and `||` are left to expand until they run, so `x=1; echo $x` and
+// `cd dir && echo $PWD` see what the command before them did
+fn execute_expanded(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    command: Command<'static>,
+    stdout_fd: Option<RawFd>,
+) -> Result<(i32, Pid)> {
+    let command = expander::expand(context, terminal, command, &[])?;
+    execute(context, terminal, command, stdout_fd)
+}
 
-            let mut status = 0;
-            unsafe {
-                libc::waitpid(pid, &mut status, libc::WNOHANG);
-                libc::tcsetpgrp(libc::STDIN_FILENO, context.pgid);
-            }
+// Expands the body of a loop again for every iteration, so it sees the new value of the
+// loop variable. Case bodies go through here too so they're only expanded when they run
+fn execute_body(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    body: &Command<'static>,
+) -> Result<(i32, Pid)> {
+    let command = expander::expand(context, terminal, body.borrowed(), &[])?;
+    let result = execute(context, terminal, command, None)?;
+    context.last_exit_code = result.0;
+    Ok(result)
+}
 
-            let exit_code = if libc::WIFEXITED(status) {
-                libc::WEXITSTATUS(status)
-            } else {
-                1
-            };
+// Runs the body of a loop once, false when a `break` ends the loop. `break 2` and
+// `continue 2` go on to the loop around this one
+fn execute_iteration(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    body: &Command<'static>,
+    result: &mut (i32, Pid),
+) -> Result<bool> {
+    let error = match execute_body(context, terminal, body) {
+        Err(error) => error,
+        status => {
+            *result = status?;
+            return Ok(true);
+        }
+    };
 
-            Ok((exit_code, pid))
+    match error.downcast_ref::<LoopControl>() {
+        Some(LoopControl::Break(1)) => {
+            *result = (0, 0);
+            Ok(false)
         }
+        Some(LoopControl::Continue(1)) => Ok(true),
+        Some(LoopControl::Break(levels)) => Err(LoopControl::Break(levels - 1).into()),
+        Some(LoopControl::Continue(levels)) => Err(LoopControl::Continue(levels - 1).into()),
+        None => Err(error),
     }
 }
 
+// The line picked in a `select` menu, typed in the line editor when there's a terminal and
+// read from stdin otherwise. None once the input ends
+fn read_reply(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    prompt: &str,
+) -> Result<Option<String>> {
+    if !io::stdin().is_terminal() || terminal.is_dumb() {
+        eprint!("{}", prompt);
+        return Ok(shell::read_line());
+    }
+
+    let mut editor = Editor::new();
+    editor.keymap = context.settings.keymap()?;
+    let mut message = Prompt::new();
+    message.set(prompt.to_string());
+
+    terminal.enter_raw_mode()?;
+    let reply = editor
+        .set_prompt(terminal)
+        .and_then(|_| editor.read_line(context, terminal, &mut message));
+    terminal.exit_raw_mode()?;
+    reply
+}
+
+#[cfg(unix)]
 fn spawn_process(
     context: &mut Context,
     command: Command,
     stdin: RawFd,
     stdout: RawFd,
-    pgid: Option<libc::pid_t>,
+    pgid: Option<Pid>,
     is_foreground: bool,
-) -> Result<libc::pid_t> {
+    next_input: Option<RawFd>,
+) -> Result<Pid> {
     match command {
         Command::Simple {
             command,
@@ -237,36 +574,48 @@ fn spawn_process(
             let str_args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
             let (command, args) = to_cstring(&command, &str_args)?;
 
-            let mut env_map = HashMap::new();
-            for var in env::vars_os() {
-                env_map.insert(
-                    var.0.to_string_lossy().to_string(),
-                    var.1.to_string_lossy().to_string(),
-                );
-            }
-
-            for var in env_vars {
-                env_map.insert(
-                    var.name.as_ref().to_string(),
-                    var.value.as_ref().to_string(),
-                );
-            }
-
-            let env_vec = env_map
-                .iter()
-                .map(|(name, value)| EnvVariable::to_cstring(name, value))
-                .collect::<Result<Vec<CString>>>()?;
+            // The shell's own environment is passed as it is, unless the command sets variables
+            let env_vec = match env_vars.is_empty() {
+                true => None,
+                false => {
+                    let mut env_map = HashMap::new();
+                    for var in env::vars_os() {
+                        env_map
+                            .insert(encoding::from_os_str(&var.0), encoding::from_os_str(&var.1));
+                    }
+                    for var in env_vars {
+                        env_map.insert(
+                            var.name.as_ref().to_string(),
+                            var.value.as_ref().to_string(),
+                        );
+                    }
+                    Some(
+                        env_map
+                            .iter()
+                            .map(|(name, value)| EnvVariable::to_cstring(name, value))
+                            .collect::<Result<Vec<CString>>>()?,
+                    )
+                }
+            };
 
             unsafe {
                 // We do one final conversion from CString to const char*
                 let mut argv: Vec<*const libc::c_char> = args.iter().map(|s| s.as_ptr()).collect();
                 argv.push(std::ptr::null());
 
-                let mut envp: Vec<*const libc::c_char> =
-                    env_vec.iter().map(|v| v.as_ptr()).collect();
-                envp.push(std::ptr::null());
-
-                let pid = libc::fork();
+                let envp: Option<Vec<*const libc::c_char>> = env_vec.as_ref().map(|env_vec| {
+                    let mut envp: Vec<_> = env_vec.iter().map(|v| v.as_ptr()).collect();
+                    envp.push(std::ptr::null());
+                    envp
+                });
+                let envp = envp.as_ref().map_or(environ, |envp| envp.as_ptr());
+
+                // fork is only left for when posix_spawn fails, to tell why
+                let pid = match posix_spawn(&command, &argv, envp, stdin, stdout, &redirects, pgid)
+                {
+                    Some(pid) => pid,
+                    None => libc::fork(),
+                };
 
                 if pid == -1 {
                     return os_error();
@@ -278,33 +627,33 @@ fn spawn_process(
                     libc::setpgid(0, pgid.unwrap_or(0));
 
                     // Wire up stdin if it's coming from a pipe
-                    if stdin != libc::STDIN_FILENO {
-                        libc::dup2(stdin, libc::STDIN_FILENO);
+                    if stdin != STDIN_FILENO {
+                        libc::dup2(stdin, STDIN_FILENO);
                         libc::close(stdin);
                     }
 
                     // Wire up stdout if it's going into a pipe
-                    if stdout != libc::STDOUT_FILENO {
-                        libc::dup2(stdout, libc::STDOUT_FILENO);
+                    if stdout != STDOUT_FILENO {
+                        libc::dup2(stdout, STDOUT_FILENO);
                         libc::close(stdout);
                     }
 
-                    // Handle file redirections (>, <, >>, 2>, 2>&1)
-                    if let Err(_) = set_stdio(redirects) {
+                    // Handle file redirections (>, <, >>, 2>, 2>>, 2>&1, &>)
+                    if let Err(error) = set_stdio(redirects) {
+                        let message = format!("{error}\n");
+                        let _ =
+                            libc::write(STDERR_FILENO, message.as_ptr() as *const _, message.len());
                         libc::_exit(1);
                     }
 
                     // Reset signals to defaults (shell may have ignored some)
                     context.signals.reset();
 
-                    libc::execvpe(command.as_ptr(), argv.as_ptr(), envp.as_ptr());
+                    libc::execvpe(command.as_ptr(), argv.as_ptr(), envp);
 
                     // message to the parent the command was not found
-                    let _ = libc::write(
-                        libc::STDERR_FILENO,
-                        b"Command not found\n".as_ptr() as *const _,
-                        19,
-                    );
+                    let message = b"Command not found\n";
+                    let _ = libc::write(STDERR_FILENO, message.as_ptr() as *const _, message.len());
                     // execvp only returns on failure
                     libc::_exit(1);
                 } else {
@@ -316,85 +665,447 @@ fn spawn_process(
                     // If it's a foreground process and doesn't belong to a pipeline
                     // give him the terminal
                     if pgid.is_none() && is_foreground {
-                        if libc::tcsetpgrp(libc::STDIN_FILENO, pid) == -1 {
+                        if !jobs::give_terminal(pid) {
                             return os_error();
                         }
                     }
 
                     // Close the pipe ends we handed to the child — we don't need them
-                    if stdin != libc::STDIN_FILENO {
+                    if stdin != STDIN_FILENO {
                         libc::close(stdin);
                     }
-                    if stdout != libc::STDOUT_FILENO {
+                    if stdout != STDOUT_FILENO {
                         libc::close(stdout);
                     }
 
+                    tracing::debug!(pid, ?pgid, is_foreground, "Spawned");
                     Ok(pid)
                 }
             }
         }
 
-        _ => unreachable!(),
+        // Anything else, like a loop in a pipeline, runs in a copy of the shell
+        compound => {
+            let pid = unsafe { libc::fork() };
+            if pid == -1 {
+                return os_error();
+            }
+
+            if pid == 0 {
+                // ── CHILD ──────────────────────────────────────────────────
+                unsafe {
+                    libc::setpgid(0, pgid.unwrap_or(0));
+
+                    // Holding the end the next stage reads from, it would never get SIGPIPE
+                    // writing into a pipe nobody reads anymore
+                    if let Some(next_input) = next_input {
+                        libc::close(next_input);
+                    }
+                    // Builtins in it end on a closed pipe too, like programs do
+                    libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+
+                    if stdin != STDIN_FILENO {
+                        libc::dup2(stdin, STDIN_FILENO);
+                        libc::close(stdin);
+                    }
+                    if stdout != STDOUT_FILENO {
+                        libc::dup2(stdout, STDOUT_FILENO);
+                        libc::close(stdout);
+                    }
+                }
+
+                // The subshell already is the copy, what's inside it runs straight away
+                let compound = match compound {
+                    Command::Subshell(inner) => *inner,
+                    compound => compound,
+                };
+                let status = context
+                    .clone()
+                    .duplicate(unsafe { libc::getpid() })
+                    .map(|mut child_context| {
+                        // The commands it runs give the terminal back to its process group
+                        child_context.pgid = unsafe { libc::getpgrp() };
+                        run_copy(&mut child_context, compound.into_owned())
+                    })
+                    .unwrap_or(1);
+                unsafe { libc::_exit(status) };
+            }
+
+            // ── PARENT ─────────────────────────────────────────────────────
+            unsafe {
+                libc::setpgid(pid, pgid.unwrap_or(0));
+
+                if pgid.is_none() && is_foreground && !jobs::give_terminal(pid) {
+                    return os_error();
+                }
+
+                if stdin != STDIN_FILENO {
+                    libc::close(stdin);
+                }
+                if stdout != STDOUT_FILENO {
+                    libc::close(stdout);
+                }
+            }
+
+            tracing::debug!(pid, ?pgid, is_foreground, "Spawned a copy of the shell");
+            Ok(pid)
+        }
+    }
+}
+
+// What a copy of the shell runs, its status is what it exits with
+fn run_copy(context: &mut Context, command: Command<'static>) -> i32 {
+    execute(context, &mut Terminal::new(), command, None)
+        .map(|(status, _)| status)
+        .unwrap_or_else(|error| {
+            let exit = error
+                .downcast_ref::<ShellError>()
+                .is_some_and(|error| error.is_exit());
+            if exit {
+                return context.last_exit_code;
+            }
+            eprintln!("{}", error);
+            1
+        })
+}
+
+/// Runs a command in a copy of the shell with a process group every command in it joins, so
+/// builtins and functions can be stopped too. Once `limit` is over the group gets `signal`,
+/// then SIGKILL after `kill_after` if it's still there. The status comes with whether the
+/// limit was reached
+#[cfg(unix)]
+pub fn execute_timed(
+    context: &mut Context,
+    command: Command<'static>,
+    limit: Duration,
+    signal: libc::c_int,
+    kill_after: Option<Duration>,
+) -> Result<(i32, bool)> {
+    let foreground = io::stdin().is_terminal() && context.mode.interactive;
+    // What's killed may have left the terminal in raw mode, like a watch waiting for keys
+    let mut modes: libc::termios = unsafe { std::mem::zeroed() };
+    if foreground {
+        unsafe { libc::tcgetattr(STDIN_FILENO, &mut modes) };
+    }
+    let pid = unsafe { libc::fork() };
+    if pid == -1 {
+        return os_error();
+    }
+
+    if pid == 0 {
+        // ── CHILD ──────────────────────────────────────────────────────
+        unsafe {
+            libc::setpgid(0, 0);
+            // The signal has to end the copy too, not only the programs it started
+            signals::SignalHandler::restore_defaults();
+            for signal in signals::ENDING {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+        let status = context
+            .clone()
+            .duplicate(unsafe { libc::getpid() })
+            .map(|mut child_context| {
+                child_context.pgid = unsafe { libc::getpgrp() };
+                child_context.shared_group = true;
+                run_copy(&mut child_context, command)
+            })
+            .unwrap_or(1);
+        unsafe { libc::_exit(status) };
+    }
+
+    // ── PARENT ─────────────────────────────────────────────────────────
+    unsafe { libc::setpgid(pid, pid) };
+    if foreground && !jobs::give_terminal(pid) {
+        return os_error();
+    }
+    tracing::debug!(pid, ?limit, signal, "Spawned a timed copy of the shell");
+
+    let started = Instant::now();
+    let mut signalled: Option<Instant> = None;
+    let mut killed = false;
+    let mut exited = false;
+    let mut status: libc::c_int = 0;
+    loop {
+        if !exited {
+            let waited = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+            exited = waited == pid || waited == -1;
+        }
+        // The signal ends the copy first, what it started may have ignored it. With `-k` the
+        // group is watched until it's gone so SIGKILL gets to them too, without it they're
+        // left running like coreutils does
+        if exited
+            && (signalled.is_none()
+                || killed
+                || kill_after.is_none()
+                || unsafe { libc::kill(-pid, 0) } == -1)
+        {
+            break;
+        }
+        match signalled {
+            None if started.elapsed() >= limit => {
+                // A stopped group wouldn't get it until it goes on again
+                unsafe {
+                    libc::kill(-pid, signal);
+                    libc::kill(-pid, libc::SIGCONT);
+                }
+                signalled = Some(Instant::now());
+            }
+            Some(at) if !killed && kill_after.is_some_and(|after| at.elapsed() >= after) => {
+                unsafe { libc::kill(-pid, libc::SIGKILL) };
+                killed = true;
+            }
+            _ => {}
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    if foreground {
+        jobs::give_terminal(context.pgid);
+        if signalled.is_some() {
+            unsafe { libc::tcsetattr(STDIN_FILENO, libc::TCSANOW, &modes) };
+        }
+    }
+
+    let status = match libc::WIFSIGNALED(status) {
+        _ if killed => 128 + libc::SIGKILL,
+        true => 128 + libc::WTERMSIG(status),
+        false => libc::WEXITSTATUS(status),
+    };
+    tracing::debug!(pid, status, timed_out = signalled.is_some(), "Waited");
+    Ok((status, signalled.is_some()))
+}
+
+// Starts a program without copying the shell like fork does, which adds up in scripts
+// running thousands of commands. The child gets the same pipes, redirects, process group and
+// signals the fork path sets up. None when it couldn't be started, the caller forks then
+#[cfg(unix)]
+unsafe fn posix_spawn(
+    command: &CString,
+    argv: &[*const libc::c_char],
+    envp: *const *const libc::c_char,
+    stdin: RawFd,
+    stdout: RawFd,
+    redirects: &[Redirect],
+    pgid: Option<Pid>,
+) -> Option<Pid> {
+    let mut paths = Vec::new();
+    // Closed once the child has its own copy
+    let mut texts = Vec::new();
+    for redirect in redirects {
+        if let Some(path) = redirect.get_target_path() {
+            paths.push(encoding::to_cstring(path).ok()?);
+        }
+        if let RedirectTarget::Text { body, .. } = &redirect.target {
+            texts.push(here_doc(body).ok()?);
+        }
+    }
+
+    unsafe {
+        let mut actions: libc::posix_spawn_file_actions_t = std::mem::zeroed();
+        let mut attributes: libc::posix_spawnattr_t = std::mem::zeroed();
+        libc::posix_spawn_file_actions_init(&mut actions);
+        libc::posix_spawnattr_init(&mut attributes);
+
+        if stdin != STDIN_FILENO {
+            libc::posix_spawn_file_actions_adddup2(&mut actions, stdin, STDIN_FILENO);
+            libc::posix_spawn_file_actions_addclose(&mut actions, stdin);
+        }
+        if stdout != STDOUT_FILENO {
+            libc::posix_spawn_file_actions_adddup2(&mut actions, stdout, STDOUT_FILENO);
+            libc::posix_spawn_file_actions_addclose(&mut actions, stdout);
+        }
+
+        let mut paths = paths.iter();
+        let mut texts = texts.iter();
+        for redirect in redirects {
+            let (flags, target_fd) = match redirect.kind {
+                RedirectKind::ErrAndOut => {
+                    libc::posix_spawn_file_actions_adddup2(
+                        &mut actions,
+                        STDOUT_FILENO,
+                        STDERR_FILENO,
+                    );
+                    continue;
+                }
+                RedirectKind::HereDoc => {
+                    if let Some(text) = texts.next() {
+                        libc::posix_spawn_file_actions_adddup2(
+                            &mut actions,
+                            text.as_raw_fd(),
+                            STDIN_FILENO,
+                        );
+                    }
+                    continue;
+                }
+                RedirectKind::In => (libc::O_RDONLY, STDIN_FILENO),
+                RedirectKind::Out => (
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+                    STDOUT_FILENO,
+                ),
+                RedirectKind::Append => (
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND,
+                    STDOUT_FILENO,
+                ),
+                RedirectKind::Err => (
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+                    STDERR_FILENO,
+                ),
+                RedirectKind::ErrAppend => (
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND,
+                    STDERR_FILENO,
+                ),
+                RedirectKind::All => (
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+                    STDOUT_FILENO,
+                ),
+            };
+            if let Some(path) = paths.next() {
+                libc::posix_spawn_file_actions_addopen(
+                    &mut actions,
+                    target_fd,
+                    path.as_ptr(),
+                    flags,
+                    0o644,
+                );
+            }
+            // &> is > then 2>&1
+            if matches!(redirect.kind, RedirectKind::All) {
+                libc::posix_spawn_file_actions_adddup2(&mut actions, STDOUT_FILENO, STDERR_FILENO);
+            }
+        }
+
+        let mut defaults: libc::sigset_t = std::mem::zeroed();
+        let mut mask: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut defaults);
+        libc::sigemptyset(&mut mask);
+        for signal in signals::IGNORED {
+            libc::sigaddset(&mut defaults, signal);
+        }
+        libc::posix_spawnattr_setsigdefault(&mut attributes, &defaults);
+        libc::posix_spawnattr_setsigmask(&mut attributes, &mask);
+        libc::posix_spawnattr_setpgroup(&mut attributes, pgid.unwrap_or(0));
+        libc::posix_spawnattr_setflags(
+            &mut attributes,
+            (libc::POSIX_SPAWN_SETPGROUP
+                | libc::POSIX_SPAWN_SETSIGDEF
+                | libc::POSIX_SPAWN_SETSIGMASK) as libc::c_short,
+        );
+
+        let mut pid = 0;
+        let status = libc::posix_spawnp(
+            &mut pid,
+            command.as_ptr(),
+            &actions,
+            &attributes,
+            argv.as_ptr() as *const *mut libc::c_char,
+            envp as *const *mut libc::c_char,
+        );
+
+        libc::posix_spawn_file_actions_destroy(&mut actions);
+        libc::posix_spawnattr_destroy(&mut attributes);
+        (status == 0).then_some(pid)
     }
 }
 
+#[cfg(unix)]
 fn spawn_piped(
     context: &mut Context,
     command: Command,
     stdin: RawFd,
     stdout: RawFd,
-    pgid: Option<libc::pid_t>,
+    pgid: Option<Pid>,
     is_foreground: bool,
-) -> Result<(libc::pid_t, Vec<libc::pid_t>)> {
-    match command {
-        Command::Simple { .. } => {
-            let pid = spawn_process(context, command, stdin, stdout, pgid, is_foreground)?;
-            Ok((pid, vec![pid]))
-        }
-
-        Command::Pipeline(left, right) => {
-            let mut fds = [0; 2];
-            unsafe {
-                // We pass libc::O_CLOEXEC directly into the creation call
-                // this allows the pipe ends to be automatically closed when the process
-                // calls exec
-                if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) == -1 {
+) -> Result<(Pid, Vec<Pid>)> {
+    let mut stages = Vec::new();
+    pipeline_stages(command, &mut stages);
+
+    let last = stages.len() - 1;
+    let mut pgid = pgid;
+    let mut pids = Vec::with_capacity(stages.len());
+    let mut input = stdin;
+    for (i, stage) in stages.into_iter().enumerate() {
+        // Each pipe is made right before the stage writing into it starts, the parent closes
+        // its ends as soon as they're handed over so only the two stages hold them and the
+        // reader sees EOF, or the writer SIGPIPE, when the other one is gone
+        let (output, next_input) = match i == last {
+            true => (stdout, None),
+            false => {
+                let mut fds = [0; 2];
+                // O_CLOEXEC keeps the ends out of programs run by the other stages
+                if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+                    if i > 0 {
+                        unsafe { libc::close(input) };
+                    }
                     return os_error();
                 }
+                (fds[1], Some(fds[0]))
             }
+        };
 
-            let pipe_read = fds[0];
-            let pipe_write = fds[1];
-
-            // Left side writes into the pipe — it must close the read end it doesn't use
-            let (pgid, mut pids) =
-                spawn_piped(context, *left, stdin, pipe_write, None, is_foreground)?;
-
-            // Right side reads from the pipe — it must close the write end it doesn't use
-            let (_, right_pids) = spawn_piped(
-                context,
-                *right,
-                pipe_read,
-                stdout,
-                Some(pgid),
-                is_foreground,
-            )?;
-
-            pids.extend(right_pids);
-
-            // We still close the pipes in the parent because O_CLOEXEC works only
-            // when the child calles exec() since the parent never does
-            // we still have to perform this clean up
-            unsafe {
-                libc::close(pipe_read);
-                libc::close(pipe_write);
+        let pid = spawn_process(
+            context,
+            stage,
+            input,
+            output,
+            pgid,
+            is_foreground,
+            next_input,
+        )
+        .inspect_err(|_| unsafe {
+            // The stages already started see their pipe closed and finish
+            if i > 0 {
+                libc::close(input);
             }
+            if let Some(next_input) = next_input {
+                libc::close(output);
+                libc::close(next_input);
+            }
+        })?;
+
+        pgid.get_or_insert(pid);
+        pids.push(pid);
+        input = next_input.unwrap_or(stdin);
+    }
 
-            Ok((pgid, pids))
+    Ok((pgid.unwrap_or(pids[0]), pids))
+}
+
+// `a | b | c` is parsed as `(a | b) | c`, the stages come out in the order they're written
+fn pipeline_stages<'a>(command: Command<'a>, stages: &mut Vec<Command<'a>>) {
+    match command {
+        Command::Pipeline(left, right) => {
+            pipeline_stages(*left, stages);
+            pipeline_stages(*right, stages);
         }
+        command => stages.push(command),
+    }
+}
 
-        _ => unreachable!(),
+// What a dry run shows for a command: its arguments, then the variables and redirections it
+// would get, quoted so spaces and empty arguments can be seen
+fn show(
+    terminal: &mut Terminal,
+    command: &str,
+    args: &[Arg],
+    redirects: &[Redirect],
+    env_vars: &[EnvVariable],
+) -> Result<()> {
+    let argv: Vec<&str> = std::iter::once(command)
+        .chain(args.iter().map(|arg| arg.as_str()))
+        .collect();
+    terminal.println(&format!("{command}\n  argv: {argv:?}"))?;
+    for variable in env_vars {
+        terminal.println(&format!("  env: {}={:?}", variable.name, variable.value))?;
+    }
+    for redirect in redirects {
+        let line = match (&redirect.kind, &redirect.target) {
+            (RedirectKind::ErrAndOut, _) => redirect.to_string(),
+            (kind, RedirectTarget::File(path)) => format!("{kind} {path:?}"),
+            (kind, target) => format!("{kind} {target}"),
+        };
+        terminal.println(&format!("  redirect: {line}"))?;
     }
+    Ok(())
 }
 
 pub fn execute_and_get_stdout(
@@ -402,6 +1113,23 @@ pub fn execute_and_get_stdout(
     terminal: &mut Terminal,
     command: Command<'static>,
 ) -> Result<String> {
+    let mut output = encoding::decode(&command_output(context, terminal, command)?);
+    if output.ends_with('\n') {
+        output.pop();
+        if output.ends_with('\r') {
+            output.pop();
+        }
+    }
+
+    Ok(output)
+}
+
+#[cfg(unix)]
+fn command_output(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    command: Command<'static>,
+) -> Result<Vec<u8>> {
     let mut pipe_fds = [0; 2];
     unsafe {
         if libc::pipe2(pipe_fds.as_mut_ptr(), libc::O_CLOEXEC) == -1 {
@@ -416,7 +1144,8 @@ pub fn execute_and_get_stdout(
         libc::close(write_end);
     }
 
-    let mut output = String::with_capacity(4096);
+    // Kept as bytes until the end, a character can be split between two reads
+    let mut output = Vec::with_capacity(4096);
     let mut status = 0;
     loop {
         let ret = unsafe { libc::waitpid(-pgid, &mut status, libc::WNOHANG | libc::WUNTRACED) };
@@ -428,7 +1157,7 @@ pub fn execute_and_get_stdout(
         } else {
             false
         };
-        output.push_str(&Jobs::job_stdout_from_fd(read_end)?);
+        output.extend(Jobs::job_bytes_from_fd(read_end)?);
 
         if finished {
             break;
@@ -439,35 +1168,59 @@ pub fn execute_and_get_stdout(
 
     unsafe {
         libc::close(read_end);
-        if libc::tcsetpgrp(libc::STDIN_FILENO, context.pgid) == -1 {
+        if !jobs::give_terminal(context.pgid) {
             return os_error();
         }
     }
 
-    if output.ends_with('\n') {
-        output.pop();
-        if output.ends_with('\r') {
-            output.pop();
-        }
-    }
+    Ok(output)
+}
 
+// Like `$(...)` in other shells it all runs in a copy, here of the context. The pipe is read
+// meanwhile, what runs would wait for good on a full one otherwise
+#[cfg(windows)]
+fn command_output(
+    context: &mut Context,
+    _terminal: &mut Terminal,
+    command: Command<'static>,
+) -> Result<Vec<u8>> {
+    let (mut reader, writer) = io::pipe()?;
+    let reading = thread::spawn(move || {
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).map(|_| output)
+    });
+    let ran = with_stdio(None, Some(writer.into()), || run_in_copy(context, command));
+    let output = reading
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("The output got lost")))?;
+    ran?;
     Ok(output)
 }
 
+#[cfg(unix)]
 fn set_stdio(redirects: Vec<Redirect>) -> Result<()> {
     for redirect in redirects {
         match redirect.kind {
             RedirectKind::ErrAndOut => {
                 // 2>&1 - no file opening, just duplication
                 unsafe {
-                    if libc::dup2(libc::STDOUT_FILENO, libc::STDERR_FILENO) == -1 {
+                    if libc::dup2(STDOUT_FILENO, STDERR_FILENO) == -1 {
                         return os_error();
                     }
                 }
             }
+            RedirectKind::HereDoc => {
+                let RedirectTarget::Text { body, .. } = &redirect.target else {
+                    unreachable!("A here-document always has its text");
+                };
+                let text = here_doc(body)?;
+                if unsafe { libc::dup2(text.as_raw_fd(), STDIN_FILENO) } == -1 {
+                    return os_error();
+                }
+            }
             _ => {
                 let path = match redirect.get_target_path() {
-                    Some(path) => CString::new(path).with_context(|| {
+                    Some(path) => encoding::to_cstring(path).with_context(|| {
                         format!("Failed to convert target path to CString {}", path)
                     })?,
                     None => unreachable!(
@@ -476,18 +1229,26 @@ fn set_stdio(redirects: Vec<Redirect>) -> Result<()> {
                 };
 
                 let (flags, target_fd) = match redirect.kind {
-                    RedirectKind::In => (libc::O_RDONLY, libc::STDIN_FILENO),
+                    RedirectKind::In => (libc::O_RDONLY, STDIN_FILENO),
                     RedirectKind::Out => (
                         libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
-                        libc::STDOUT_FILENO,
+                        STDOUT_FILENO,
                     ),
                     RedirectKind::Append => (
                         libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND,
-                        libc::STDOUT_FILENO,
+                        STDOUT_FILENO,
                     ),
                     RedirectKind::Err => (
                         libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
-                        libc::STDERR_FILENO,
+                        STDERR_FILENO,
+                    ),
+                    RedirectKind::ErrAppend => (
+                        libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND,
+                        STDERR_FILENO,
+                    ),
+                    RedirectKind::All => (
+                        libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+                        STDOUT_FILENO,
                     ),
                     _ => unreachable!(),
                 };
@@ -495,12 +1256,23 @@ fn set_stdio(redirects: Vec<Redirect>) -> Result<()> {
                 unsafe {
                     let fd = libc::open(path.as_ptr(), flags, 0o644);
                     if fd == -1 {
-                        return os_error();
+                        // Like other shells, the file is named, `No such file or directory`
+                        // alone doesn't tell which one
+                        return error(&format!(
+                            "{}: {}",
+                            path.to_string_lossy(),
+                            io::Error::last_os_error()
+                        ));
                     }
                     if libc::dup2(fd, target_fd) == -1 {
                         return os_error();
                     }
                     libc::close(fd);
+                    if matches!(redirect.kind, RedirectKind::All)
+                        && libc::dup2(STDOUT_FILENO, STDERR_FILENO) == -1
+                    {
+                        return os_error();
+                    }
                 }
             }
         }
@@ -509,15 +1281,39 @@ fn set_stdio(redirects: Vec<Redirect>) -> Result<()> {
     Ok(())
 }
 
+// The body of a here-document in a file that's gone from the directory already, like bash
+// does, so a body of any size can be read without something writing it into a pipe
+#[cfg(unix)]
+fn here_doc(body: &str) -> Result<OwnedFd> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!(
+        "rshell-heredoc-{}-{}",
+        process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to make the here-document at {}", path.display()))?;
+    let _ = fs::remove_file(&path);
+    file.write_all(&encoding::encode(body))?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file.into())
+}
+
+#[cfg(unix)]
 fn to_cstring(raw_command: &str, raw_args: &[&str]) -> Result<(CString, Vec<CString>)> {
-    let command = CString::new(raw_command)
+    let command = encoding::to_cstring(raw_command)
         .with_context(|| format!("Failed to convert command '{}' to CString", raw_command))?;
 
     let mut args = vec![command.clone()];
 
     for arg in raw_args {
         args.push(
-            CString::new(*arg)
+            encoding::to_cstring(arg)
                 .with_context(|| format!("Failed to convert argument '{}' to CString", arg))?,
         );
     }
@@ -525,6 +1321,366 @@ fn to_cstring(raw_command: &str, raw_args: &[&str]) -> Result<(CString, Vec<CStr
     Ok((command, args))
 }
 
+// A copy of the file of a here-document, or of a pipe between two stages that both run in
+// the shell. Windows can't remove a file that's open, so it's gone once the last handle to
+// it is closed instead
+#[cfg(windows)]
+fn temp_file(kind: &str) -> Result<fs::File> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!(
+        "rshell-{}-{}-{}",
+        kind,
+        process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+        .custom_flags(FILE_FLAG_DELETE_ON_CLOSE)
+        .open(&path)
+        .with_context(|| format!("Failed to make the {} at {}", kind, path.display()))
+}
+
+#[cfg(windows)]
+fn here_doc(body: &str) -> Result<fs::File> {
+    let mut file = temp_file("heredoc")?;
+    file.write_all(&encoding::encode(body))?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+// Whether a stage is started as a program, everything else runs in the shell
+#[cfg(windows)]
+fn is_program(context: &Context, command: &Command) -> bool {
+    matches!(command, Command::Simple { command, .. }
+        if context.builtins.get(command).is_none() && context.plugins.builtin(command).is_none())
+}
+
+// The stages of a pipeline. Programs are all started first, then what runs in the shell goes
+// one stage after the other with its stdin and stdout put in place. Two such stages next to
+// each other are joined by a file, nothing would read a pipe while the first one fills it.
+// The status is the one of the last stage, unless it isn't waited for, which only programs
+// can do
+#[cfg(windows)]
+fn run_pipeline(
+    context: &mut Context,
+    command: Command<'static>,
+    wait: bool,
+) -> Result<(i32, Vec<Pid>)> {
+    let mut stages = Vec::new();
+    pipeline_stages(command, &mut stages);
+    let programs: Vec<bool> = stages
+        .iter()
+        .map(|stage| is_program(context, stage))
+        .collect();
+    if !wait && programs.contains(&false) {
+        return error("Only programs can run in the background on Windows");
+    }
+
+    let last = stages.len() - 1;
+    let mut inputs: Vec<Option<OwnedHandle>> = (0..=last).map(|_| None).collect();
+    let mut outputs: Vec<Option<OwnedHandle>> = (0..=last).map(|_| None).collect();
+    let mut spools: Vec<Option<fs::File>> = (0..=last).map(|_| None).collect();
+    for i in 0..last {
+        if programs[i] || programs[i + 1] {
+            let (reader, writer) = io::pipe()?;
+            outputs[i] = Some(writer.into());
+            inputs[i + 1] = Some(reader.into());
+        } else {
+            let spool = temp_file("pipe")?;
+            outputs[i] = Some(spool.try_clone()?.into());
+            inputs[i + 1] = Some(spool.try_clone()?.into());
+            spools[i + 1] = Some(spool);
+        }
+    }
+
+    let mut statuses = vec![0; stages.len()];
+    let mut children = Vec::new();
+    let mut here = Vec::new();
+    let mut pids = Vec::new();
+    for (i, stage) in stages.into_iter().enumerate() {
+        let (input, output) = (inputs[i].take(), outputs[i].take());
+        if !programs[i] {
+            here.push((i, stage, input, output));
+            continue;
+        }
+        match spawn_program(stage, input, output) {
+            std::result::Result::Ok(child) => {
+                tracing::debug!(pid = child.id(), "Spawned");
+                pids.push(child.id() as Pid);
+                children.push((i, child));
+            }
+            Err(error) => {
+                eprintln!("{:#}", error);
+                statuses[i] = 1;
+            }
+        }
+    }
+    if !wait {
+        return Ok((0, pids));
+    }
+
+    for (i, stage, input, output) in here {
+        if let Some(spool) = &mut spools[i] {
+            spool.seek(SeekFrom::Start(0))?;
+        }
+        let stage = match stage {
+            Command::Subshell(inner) => *inner,
+            stage => stage,
+        };
+        statuses[i] = with_stdio(input, output, || run_in_copy(context, stage))?;
+    }
+    for (i, mut child) in children {
+        statuses[i] = status_of(child.wait()?);
+    }
+    tracing::debug!(status = statuses[last], "Waited");
+    Ok((statuses[last], pids))
+}
+
+// Starts a program with the redirects of the command on top of the stdin and stdout it's
+// given, the ones of the shell when there's none
+#[cfg(windows)]
+fn spawn_program(
+    command: Command,
+    stdin: Option<OwnedHandle>,
+    stdout: Option<OwnedHandle>,
+) -> Result<process::Child> {
+    let Command::Simple {
+        command,
+        args,
+        redirects,
+        env_vars,
+    } = command
+    else {
+        unreachable!("Only simple commands are programs");
+    };
+
+    let mut program = process::Command::new(find_program(&command));
+    program.args(args.iter().map(|arg| encoding::to_os_string(arg.as_str())));
+    for var in env_vars {
+        program.env(var.name.as_ref(), encoding::to_os_string(&var.value));
+    }
+
+    let (mut stdin, mut stdout, mut stderr) = (stdin, stdout, None);
+    for redirect in &redirects {
+        match redirect.kind {
+            RedirectKind::ErrAndOut => {
+                stderr = Some(match &stdout {
+                    Some(stdout) => stdout.try_clone()?,
+                    None => fd_handle(STDOUT_FILENO)?,
+                });
+            }
+            RedirectKind::HereDoc => {
+                let RedirectTarget::Text { body, .. } = &redirect.target else {
+                    unreachable!("A here-document always has its text");
+                };
+                stdin = Some(here_doc(body)?.into());
+            }
+            ref kind => {
+                let Some(path) = redirect.get_target_path() else {
+                    unreachable!(
+                        "Target path should be always configured for redirects except ErrAndOut"
+                    );
+                };
+                let mut options = OpenOptions::new();
+                match kind {
+                    RedirectKind::In => options.read(true),
+                    RedirectKind::Append | RedirectKind::ErrAppend => {
+                        options.append(true).create(true)
+                    }
+                    _ => options.write(true).create(true).truncate(true),
+                };
+                // Like other shells, the file is named, `No such file or directory` alone
+                // doesn't tell which one
+                let file = options
+                    .open(encoding::to_os_string(path))
+                    .map_err(|error| anyhow::anyhow!("{}: {}", path, error))?;
+                match kind {
+                    RedirectKind::In => stdin = Some(file.into()),
+                    RedirectKind::Err | RedirectKind::ErrAppend => stderr = Some(file.into()),
+                    RedirectKind::All => {
+                        stderr = Some(file.try_clone()?.into());
+                        stdout = Some(file.into());
+                    }
+                    _ => stdout = Some(file.into()),
+                }
+            }
+        }
+    }
+    if let Some(stdin) = stdin {
+        program.stdin(stdin);
+    }
+    if let Some(stdout) = stdout {
+        program.stdout(stdout);
+    }
+    if let Some(stderr) = stderr {
+        program.stderr(stderr);
+    }
+
+    program.spawn().map_err(|error| match error.kind() {
+        io::ErrorKind::NotFound => anyhow::anyhow!("Command not found"),
+        _ => anyhow::anyhow!("{}: {}", command, error),
+    })
+}
+
+// The file a name runs. std only looks for .exe in PATH, .bat and the others of PATHEXT are
+// found here
+#[cfg(windows)]
+fn find_program(name: &str) -> OsString {
+    if name.contains(['/', '\\']) {
+        return encoding::to_os_string(name);
+    }
+    env::var_os("PATH")
+        .and_then(|path| env::split_paths(&path).find_map(|dir| sys::program_file(&dir, name)))
+        .map_or_else(|| encoding::to_os_string(name), Into::into)
+}
+
+// A copy of the handle behind a file descriptor of the C runtime
+#[cfg(windows)]
+fn fd_handle(fd: RawFd) -> Result<OwnedHandle> {
+    let handle = unsafe { libc::get_osfhandle(fd) };
+    if handle == -1 {
+        return os_error();
+    }
+    let handle = unsafe { BorrowedHandle::borrow_raw(handle as _) };
+    Ok(handle.try_clone_to_owned()?)
+}
+
+// Puts the handles in place of stdin and stdout while `run` goes, for what runs in the shell
+// itself. Programs it starts get them too
+#[cfg(windows)]
+fn with_stdio<T>(
+    stdin: Option<OwnedHandle>,
+    stdout: Option<OwnedHandle>,
+    run: impl FnOnce() -> T,
+) -> Result<T> {
+    io::stdout().flush()?;
+    let mut saved = Vec::new();
+    let mut failed = false;
+    for (handle, target) in [(stdin, STDIN_FILENO), (stdout, STDOUT_FILENO)] {
+        let Some(handle) = handle else {
+            continue;
+        };
+        unsafe {
+            let fd = libc::open_osfhandle(handle.into_raw_handle() as _, libc::O_BINARY);
+            let previous = libc::dup(target);
+            if fd == -1 || previous == -1 || libc::dup2(fd, target) == -1 {
+                for fd in [fd, previous] {
+                    if fd != -1 {
+                        libc::close(fd);
+                    }
+                }
+                failed = true;
+                break;
+            }
+            libc::close(fd);
+            saved.push((previous, target));
+        }
+    }
+    let result = match failed {
+        true => None,
+        false => Some(run()),
+    };
+
+    let _ = io::stdout().flush();
+    for (previous, target) in saved {
+        unsafe {
+            libc::dup2(previous, target);
+            libc::close(previous);
+        }
+    }
+    match result {
+        Some(result) => Ok(result),
+        None => os_error(),
+    }
+}
+
+// What a copy of the shell would run. The directory and the environment belong to the whole
+// process, they're put back after so what ran can't change them for the shell
+#[cfg(windows)]
+fn run_in_copy(context: &Context, command: Command<'static>) -> i32 {
+    let directory = env::current_dir();
+    let environment: Vec<(OsString, OsString)> = env::vars_os().collect();
+    let status = context
+        .clone()
+        .duplicate(sys::pid())
+        .map(|mut copy| run_copy(&mut copy, command))
+        .unwrap_or(1);
+
+    if let std::result::Result::Ok(directory) = directory {
+        let _ = env::set_current_dir(directory);
+    }
+    for (name, _) in env::vars_os() {
+        if !environment.iter().any(|(kept, _)| *kept == name) {
+            unsafe { env::remove_var(name) };
+        }
+    }
+    for (name, value) in environment {
+        unsafe { env::set_var(name, value) };
+    }
+    status
+}
+
+// A program ended by Ctrl-C exits with STATUS_CONTROL_C_EXIT, it's taken as SIGINT like on
+// Unix
+#[cfg(windows)]
+fn status_of(status: process::ExitStatus) -> i32 {
+    const STATUS_CONTROL_C_EXIT: i32 = 0xC000013A_u32 as i32;
+    match status.code() {
+        Some(STATUS_CONTROL_C_EXIT) => 128 + libc::SIGINT,
+        Some(code) => code,
+        None => 1,
+    }
+}
+
+/// Runs a program and ends it once `limit` is over. Windows has no signal to ask it first,
+/// so it's ended outright and `signal` and `kill_after` don't matter. Builtins and the like
+/// can't be stopped in the middle, only programs are timed. The status comes with whether the
+/// limit was reached
+#[cfg(windows)]
+pub fn execute_timed(
+    context: &mut Context,
+    command: Command<'static>,
+    limit: Duration,
+    _signal: libc::c_int,
+    _kill_after: Option<Duration>,
+) -> Result<(i32, bool)> {
+    if !is_program(context, &command) {
+        return error("Only programs can be timed on Windows");
+    }
+    let mut child = match spawn_program(command, None, None) {
+        std::result::Result::Ok(child) => child,
+        Err(error) => {
+            eprintln!("{:#}", error);
+            return Ok((1, false));
+        }
+    };
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status_of(status), false));
+        }
+        if started.elapsed() >= limit {
+            child.kill()?;
+            let status = child.wait()?;
+            tracing::debug!(pid = child.id(), ?limit, "Timed out");
+            return Ok((status_of(status), true));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Whether a program of that name is in PATH, a path with a slash is never looked up there
+fn in_path(program: &str) -> bool {
+    !program.contains('/')
+        && env::var_os("PATH").is_some_and(|path| {
+            env::split_paths(&path).any(|dir| sys::program_file(&dir, program).is_some())
+        })
+}
+
 fn os_error<T>() -> Result<T> {
     error(&io::Error::last_os_error().to_string())
 }
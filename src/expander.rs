@@ -1,20 +1,31 @@
 //expander.rs
 
 use crate::{
+    arithmetic,
     context::Context,
+    encoding,
     error::{ShellError, ShellPhase},
     executor,
-    parser::{Arg, Command, EnvVariable, Redirect, RedirectTarget},
+    parser::{Arg, Command, EnvVariable, Parser, Redirect, RedirectTarget, is_name},
     shell::Shell,
     terminal::Terminal,
+    tokenizer::Tokenizer,
 };
 use anyhow::{Context as AnyhowContext, Result};
+#[cfg(unix)]
+use std::ffi::CStr;
+#[cfg(windows)]
+use std::fs;
 use std::{
     borrow::Cow,
     env::{self},
-    ffi::{CStr, CString},
+    iter::Peekable,
+    str::CharIndices,
 };
 
+// What has a meaning in a glob pattern
+const GLOB_SPECIAL: &str = "*?[]\\";
+
 pub fn expand<'a>(
     context: &mut Context,
     terminal: &mut Terminal,
@@ -36,20 +47,12 @@ pub fn expand<'a>(
             Box::new(expand(context, terminal, *right, expanded)?),
         )),
 
-        Command::And(left, right) => Ok(Command::And(
-            Box::new(expand(context, terminal, *left, expanded)?),
-            Box::new(expand(context, terminal, *right, expanded)?),
-        )),
-
-        Command::Or(left, right) => Ok(Command::Or(
-            Box::new(expand(context, terminal, *left, expanded)?),
-            Box::new(expand(context, terminal, *right, expanded)?),
-        )),
-
-        Command::Sequence(left, right) => Ok(Command::Sequence(
-            Box::new(expand(context, terminal, *left, expanded)?),
-            Box::new(expand(context, terminal, *right, expanded)?),
-        )),
+        // A command after `;`, `&&` or `||` sees what the ones before it did, like a variable
+        // they set or the directory they went to, so the executor expands each one right
+        // before it runs. Only their aliases are replaced here
+        command @ (Command::And(..) | Command::Or(..) | Command::Sequence(..)) => {
+            resolve_aliases(context, command.into_owned(), expanded)
+        }
 
         Command::Background(cmd) => Ok(Command::Background(Box::new(expand(
             context, terminal, *cmd, expanded,
@@ -58,6 +61,34 @@ pub fn expand<'a>(
         Command::Subshell(cmd) => Ok(Command::Subshell(Box::new(expand(
             context, terminal, *cmd, expanded,
         )?))),
+
+        // The body of a loop sees the loop variable, so it's expanded on every iteration
+        // by the executor instead of here. A case only expands the body it runs, `((...))` and
+        // `[[ ... ]]` are expanded when they're evaluated so they see what ran before them
+        command @ (Command::For { .. }
+        | Command::ArithmeticFor { .. }
+        | Command::Select { .. }
+        | Command::Case { .. }
+        | Command::Arithmetic(_)
+        | Command::Conditional(_)) => Ok(command.into_owned()),
+
+        Command::Assignment(variables) => Ok(Command::Assignment(expand_env_vars(
+            context, terminal, variables,
+        )?)),
+
+        // The values are split and globbed like the words of a `for`
+        Command::ArrayAssignment {
+            name,
+            values,
+            append,
+        } => Ok(Command::ArrayAssignment {
+            name: Cow::Owned(name.into_owned()),
+            values: expand_words(context, terminal, values)?
+                .into_iter()
+                .map(|value| Arg::SingleQuoted(Cow::Owned(value)))
+                .collect(),
+            append,
+        }),
     }
 }
 
@@ -70,91 +101,91 @@ fn expand_simple_command<'a>(
     env_vars: Vec<EnvVariable>,
     expanded: &[String],
 ) -> Result<Command<'static>> {
-    // This function uses recursiona and might seems a bit complicated, but it's actually quite elegant
-    // We start by checking if the current command given is an alias, notice that this is a simple command
-    // that though, doesn't imply that the mapped alias can't be a composed command
-    // Example: the command give is 'Z' and it maps to -> 'a | b'
-    let command_ref = command.as_ref().to_string();
-    if let Some(alias) = context.aliases.get(&command_ref).cloned()
-        && !expanded.contains(&&command_ref)
-    {
-        // We need to parse the aliased command, because it was written in just string form
-        // So we currently have no way to check where are the arguments, what kind of argument they are etc...
-        let aliased_command = Shell::parse_command(context, terminal, &alias, false)
-            .context(format!("Failed to parse alias: {alias}"))?;
-
-        // We keep an expanded paramenter to this function that allows us to stop recursion by avoiding infinite loops
-        // For example if the user does ls='ls --color' it would try to expand ls infinitely, we avoid that with this array
-        // that keeps every alias we encoutered, and even if, we find an alias, if it's already present in the array
-        // we stop trying to see what it aliases to.
-
-        // We have to make this a vec, because we're actually passing just a slice, this is mainly done because
-        // Vec<String> gives so much hassle with the borrow checker, doing it like this avoids all this problem
-        // Just imagine that this part is just me adding the new found alias in the slice
-        let mut next_expanded = expanded.to_vec();
-        next_expanded.push(command_ref);
-
-        // Now we actually perform the checks on the alias to see what kind of command it was
-        match aliased_command {
-            Command::Simple {
-                command,
-                args: mut aliased_args,
-                redirects: mut aliased_redirects,
-                env_vars,
-            } => {
-                // This is the best case scenario, it's just a simple command so imagine like cdh='cd ~'
-                // We can just expand the args, so '~' can become /home/<user>
-                // and we call expand, which will come here once again, see that the command isn't alias,
-                // so it will not come at all inside this if statement, and go directly in the else case,
-                // where it will return the command with everything properly expanded
-                aliased_args.extend(expand_args(context, terminal, args)?);
-                aliased_redirects.extend(expanded_redirects(context, terminal, redirects)?);
-                expand(
-                    context,
-                    terminal,
-                    Command::Simple {
-                        command,
-                        args: aliased_args,
-                        redirects: aliased_redirects,
-                        env_vars,
-                    },
-                    &next_expanded,
-                )
-            }
+    // An alias can stand for a composed command too, like 'Z' mapping to 'a | b', so it's
+    // replaced first and what it stands for is expanded like any other command
+    let name = command.as_ref().to_string();
+    if context.aliases.get(&name).is_some() && !expanded.contains(&name) {
+        let simple = Command::Simple {
+            command,
+            args,
+            redirects,
+            env_vars,
+        };
+        let aliased = resolve_aliases(context, simple.into_owned(), expanded)?;
+        return expand(context, terminal, aliased, expanded);
+    }
+
+    let command = to_owned(context, terminal, command, args, redirects, env_vars)?;
+    tracing::trace!(command = %command.to_string(), "Expanded");
+    Ok(command)
+}
 
-            // This is the case which is a bit more troublesome, beacuse we have the aliased command,
-            // but the user aliased to something like a pipeline or a sequence. This is a bit of an hassle
-            // because we first of all have to expand the all the individual components of the composed command
-            // so imagine like a pipeline the first line calls expand, and it does that a bunch of times for all
-            // the simple command present, that's why i said at the start it was quite elegant, because it
-            // summarized a lot of work into just a single line of code. Now that have the alis fully expanded,
-            // we may have recived some external arguments from the user, image this scenario:
-            // alias gl='git log --oneline | head'  here's there wasn't much to expand, but, the user called this command like this
-            // gl -n $HOME/myfile   What happens now is that we now have to also pass the arguments, to this aliased command,
-            // but we also have to expand them, so we call expand_args. Once we expanded the arguments, we need to append them
-            // To the last command of the sequence, pipeline or whatever, in this example, it would've been to head.
-            // In order to do that we have to traverse the Command AST, and we do that in the append_args_to_composed
-            // That function recursively calls itself, only on the right side of the composed commands
-            // (which is the last we mentioned before), and once it reaches the simple command contained in the right side,
-            // it extends it's arguments with the extra arguments we expanded before.
-            // This is also applies to redirects
-            composed_command => {
-                let mut expanded_composed =
-                    expand(context, terminal, composed_command, &next_expanded)?;
-                let extra_args = expand_args(context, terminal, args)?;
-                let extra_redirects = expanded_redirects(context, terminal, redirects)?;
-                append_args_to_composed_command(
-                    &mut expanded_composed,
-                    extra_args,
-                    extra_redirects,
-                )?;
-                Ok(expanded_composed)
+// Replaces the aliases of a command with what they stand for, nothing else is expanded. The
+// arguments the alias got go to the last command of it, like `gl -n 5` with
+// gl='git log --oneline | head' giving `head -n 5`. `expanded` keeps the aliases already
+// replaced so ls='ls --color' doesn't go on forever, such a name is left escaped (`\ls`) so
+// the commands the executor expands later don't take it for the alias again
+fn resolve_aliases(
+    context: &Context,
+    command: Command<'static>,
+    expanded: &[String],
+) -> Result<Command<'static>> {
+    let resolve = |command: Box<Command<'static>>| -> Result<Box<Command<'static>>> {
+        Ok(Box::new(resolve_aliases(context, *command, expanded)?))
+    };
+    match command {
+        Command::Simple {
+            command,
+            args,
+            redirects,
+            env_vars,
+        } => {
+            let name = command.as_ref();
+            let Some(alias) = context.aliases.get(name).cloned() else {
+                return Ok(Command::Simple {
+                    command,
+                    args,
+                    redirects,
+                    env_vars,
+                });
+            };
+            if expanded.iter().any(|seen| seen == name) {
+                return Ok(Command::Simple {
+                    command: Cow::Owned(format!("\\{}", name)),
+                    args,
+                    redirects,
+                    env_vars,
+                });
+            }
+            tracing::debug!(alias = %name, expansion = %alias, "Expanding alias");
+
+            let tokens = Tokenizer::tokenize(&alias)
+                .with_context(|| format!("Failed to parse alias: {alias}"))?;
+            let mut aliased = Parser::parse(&tokens)
+                .with_context(|| format!("Failed to parse alias: {alias}"))?
+                .into_owned();
+            append_args_to_composed_command(&mut aliased, args, redirects)?;
+            // `NAME=value ll` still sets the variable when the alias is a simple command
+            if let Command::Simple {
+                env_vars: aliased_env_vars,
+                ..
+            } = &mut aliased
+            {
+                aliased_env_vars.extend(env_vars);
             }
+
+            let mut next_expanded = expanded.to_vec();
+            next_expanded.push(name.to_string());
+            resolve_aliases(context, aliased, &next_expanded)
         }
-    } else {
-        Ok(to_owned(
-            context, terminal, command, args, redirects, env_vars,
-        )?)
+        Command::Pipeline(left, right) => Ok(Command::Pipeline(resolve(left)?, resolve(right)?)),
+        Command::And(left, right) => Ok(Command::And(resolve(left)?, resolve(right)?)),
+        Command::Or(left, right) => Ok(Command::Or(resolve(left)?, resolve(right)?)),
+        Command::Sequence(left, right) => Ok(Command::Sequence(resolve(left)?, resolve(right)?)),
+        Command::Background(command) => Ok(Command::Background(resolve(command)?)),
+        Command::Subshell(command) => Ok(Command::Subshell(resolve(command)?)),
+        // Their commands are expanded when they run, aliases included
+        command => Ok(command),
     }
 }
 
@@ -189,115 +220,277 @@ fn append_args_to_composed_command(
         Command::Subshell(inner) => {
             append_args_to_composed_command(inner, extra_args, extra_redirects)
         }
+        Command::For { .. }
+        | Command::ArithmeticFor { .. }
+        | Command::Select { .. }
+        | Command::Case { .. }
+        | Command::Arithmetic(_)
+        | Command::Conditional(_)
+        | Command::Assignment(_)
+        | Command::ArrayAssignment { .. } => {
+            if extra_args.is_empty() && extra_redirects.is_empty() {
+                Ok(())
+            } else {
+                error("An alias for a loop, a case, an assignment or a test can't take arguments")
+            }
+        }
+    }
+}
+
+// A part of a word once expanded, quoted parts are neither split nor globbed
+struct Piece {
+    text: String,
+    quoted: bool,
+}
+
+fn push_piece(pieces: &mut Vec<Piece>, text: &str, quoted: bool) {
+    match pieces.last_mut() {
+        Some(last) if last.quoted == quoted => last.text.push_str(text),
+        _ => pieces.push(Piece {
+            text: text.to_string(),
+            quoted,
+        }),
     }
 }
 
+fn join(pieces: &[Piece]) -> String {
+    pieces.iter().map(|piece| piece.text.as_str()).collect()
+}
+
 fn expand_string<'a>(
     context: &mut Context,
     terminal: &mut Terminal,
     to_expand: Cow<'a, str>,
 ) -> Result<String> {
-    if !to_expand.contains(['$', '~']) {
+    if !to_expand.contains(['$', '~', '\'', '"', '\\']) {
         return Ok(to_expand.to_string());
     }
+    Ok(join(&expand_pieces(context, terminal, &to_expand, false)?))
+}
 
-    let mut expanded = String::new();
-    let mut chars = to_expand.char_indices().peekable();
+fn arg_pieces(context: &mut Context, terminal: &mut Terminal, arg: Arg) -> Result<Vec<Piece>> {
+    match arg {
+        Arg::Word(s) => expand_pieces(context, terminal, &s, false),
+        Arg::DoubleQuoted(s) => expand_pieces(context, terminal, &s, true),
+        Arg::SingleQuoted(s) => Ok(vec![Piece {
+            text: s.into_owned(),
+            quoted: true,
+        }]),
+    }
+}
+
+// Expands a word as it was written, quotes and backslashes included. Nothing is expanded
+// between single quotes, and between double quotes only `$` is, with a backslash escaping
+// just `$`, `"`, `\` and a backtick like other shells
+fn expand_pieces(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    word: &str,
+    in_double_quotes: bool,
+) -> Result<Vec<Piece>> {
+    let mut pieces = Vec::new();
+    let mut chars = word.char_indices().peekable();
 
     while let Some((index, character)) = chars.next() {
         match character {
-            '~' if index == 0 => {
+            '\'' if !in_double_quotes => {
+                let quoted: String = chars
+                    .by_ref()
+                    .map(|(_, c)| c)
+                    .take_while(|c| *c != '\'')
+                    .collect();
+                push_piece(&mut pieces, &quoted, true);
+            }
+
+            '"' if !in_double_quotes => {
+                let mut quoted = String::new();
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            quoted.push(c);
+                            if let Some((_, escaped)) = chars.next() {
+                                quoted.push(escaped);
+                            }
+                        }
+                        _ => quoted.push(c),
+                    }
+                }
+                let inner = expand_pieces(context, terminal, &quoted, true)?;
+                // `""` is still an empty word of its own
+                push_piece(&mut pieces, "", true);
+                for piece in inner {
+                    push_piece(&mut pieces, &piece.text, true);
+                }
+            }
+
+            '\\' => match chars.next() {
+                // A line continuation goes away
+                Some((_, '\n')) => {}
+                Some((_, escaped))
+                    if !in_double_quotes || matches!(escaped, '$' | '"' | '\\' | '`') =>
+                {
+                    push_piece(&mut pieces, escaped.encode_utf8(&mut [0; 4]), true)
+                }
+                Some((_, other)) => push_piece(&mut pieces, &format!("\\{}", other), true),
+                None => push_piece(&mut pieces, "\\", true),
+            },
+
+            '~' if index == 0 && !in_double_quotes => {
                 // This should be the proper implementation since POSIX
                 // doesn't specify the standard for this situation
                 match env::var("HOME") {
-                    Ok(home) => expanded.push_str(&home),
-                    Err(_) => {
-                        expanded.push('~');
-                    }
+                    Ok(home) => push_piece(&mut pieces, &home, true),
+                    Err(_) => push_piece(&mut pieces, "~", false),
                 }
             }
 
             '$' => {
-                let mut variable_name = String::new();
-
-                if let Some((_, paren)) = chars.peek() {
-                    // This expands variables
-                    if *paren != '(' {
-                        if let Some((_, next)) = chars.peek() {
-                            if *next == '{' {
-                                chars.next();
-
-                                let mut is_ok = false;
-                                while let Some((_, next)) = chars.next() {
-                                    if next == '}' {
-                                        is_ok = true;
-                                        break;
-                                    }
-                                    variable_name.push(next);
-                                }
-
-                                if !is_ok {
-                                    return error(&format!(
-                                        "Found unclosed variable expansion bracket '}}'"
-                                    ));
-                                }
-                            }
-                        }
+                let value = expand_parameter(context, terminal, &mut chars)?;
+                push_piece(&mut pieces, &value, in_double_quotes);
+            }
 
-                        while let Some(&(_, next)) = chars.peek() {
-                            if next.is_alphanumeric() || matches!(next, '_' | '?' | '$' | '!') {
-                                chars.next();
-                                variable_name.push(next);
-                            } else {
-                                break;
-                            }
-                        }
+            _ => push_piece(
+                &mut pieces,
+                character.encode_utf8(&mut [0; 4]),
+                in_double_quotes,
+            ),
+        }
+    }
 
-                        if !variable_name.is_empty() {
-                            match variable_name.as_str() {
-                                "$" => expanded.push_str(&context.pid.to_string()),
-                                "0" => expanded.push_str(&context.name.to_string()),
-                                "?" => expanded.push_str(&context.last_exit_code.to_string()),
-                                "!" => {
-                                    if let Some(pid) = context.last_job_pid {
-                                        expanded.push_str(&pid.to_string());
-                                    }
-                                }
-                                _ => {
-                                    let expanded_variable =
-                                        env::var(variable_name).unwrap_or_default();
-                                    expanded.push_str(&expanded_variable);
-                                }
-                            };
+    Ok(pieces)
+}
+
+// What the `$` just read stands for, a variable, a command substitution or arithmetic
+fn expand_parameter(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    chars: &mut Peekable<CharIndices>,
+) -> Result<String> {
+    let mut expanded = String::new();
+    let mut variable_name = String::new();
+
+    if let Some((_, paren)) = chars.peek() {
+        // This expands variables
+        if *paren != '(' {
+            if chars.next_if(|(_, next)| *next == '{').is_some() {
+                // The name ends at the brace, so `${name}text` is the variable followed by `text`
+                let mut is_ok = false;
+                for (_, next) in chars.by_ref() {
+                    if next == '}' {
+                        is_ok = true;
+                        break;
+                    }
+                    variable_name.push(next);
+                }
+
+                if !is_ok {
+                    return error("Found unclosed variable expansion bracket '}'");
+                }
+                if variable_name.is_empty() {
+                    return error("Bad substitution '${}'");
+                }
+            } else if let Some((_, next)) = chars.next_if(|(_, next)| {
+                next.is_ascii_digit() || matches!(next, '?' | '$' | '!' | '#' | '@' | '*')
+            }) {
+                // Special and positional parameters are a single character,
+                // `$1a` is `$1` followed by `a`
+                variable_name.push(next);
+            } else {
+                // Anything that can't be in a name ends it, like in `$HOME/projects`,
+                // `$PATH:/opt/bin` or `$name.txt`
+                while let Some((_, next)) =
+                    chars.next_if(|(_, next)| next.is_ascii_alphanumeric() || *next == '_')
+                {
+                    variable_name.push(next);
+                }
+            }
+
+            // Without a name after it, `$` is just a dollar sign
+            if variable_name.is_empty() {
+                expanded.push('$');
+            }
+
+            if !variable_name.is_empty() {
+                match variable_name.as_str() {
+                    "$" => expanded.push_str(&context.pid.to_string()),
+                    "0" => expanded.push_str(&context.name.to_string()),
+                    "?" => expanded.push_str(&context.last_exit_code.to_string()),
+                    "!" => {
+                        if let Some(pid) = context.last_job_pid {
+                            expanded.push_str(&pid.to_string());
                         }
                     }
-                    // This is for parsing subcommands
-                    else {
-                        chars.next();
-                        let mut sub_content = String::new();
-                        let mut depth = 1;
-
-                        while let Some((_, c)) = chars.next() {
-                            match c {
-                                '(' => depth += 1,
-                                ')' => depth -= 1,
-                                _ => {}
-                            }
-                            if depth == 0 {
-                                break;
-                            }
-                            sub_content.push(c);
+                    "#" => expanded.push_str(&context.positional.len().to_string()),
+                    "LINENO" => {
+                        let line = context.sources.last().map_or(0, |source| source.line);
+                        expanded.push_str(&line.to_string());
+                    }
+                    "@" | "*" => expanded.push_str(&context.positional.join(" ")),
+                    name if name.chars().all(|c| c.is_ascii_digit()) => {
+                        let value = name
+                            .parse::<usize>()
+                            .ok()
+                            .and_then(|index| index.checked_sub(1))
+                            .and_then(|index| context.positional.get(index));
+                        if let Some(value) = value {
+                            expanded.push_str(value);
                         }
-
-                        let command = Shell::parse_command(context, terminal, &sub_content, true)?;
-                        let output = executor::execute_and_get_stdout(context, terminal, command)?;
-                        expanded.push_str(&output.trim()); // Trim often needed for stdout
                     }
+                    // `${name[1]}`, `${#name[@]}` and the like
+                    name if name.contains('[') || name.len() > 1 && name.starts_with('#') => {
+                        expanded.push_str(&expand_array(context, name)?);
+                    }
+                    // An array on its own is its first value
+                    name if context.arrays.contains_key(name) => {
+                        expanded.push_str(&array_value(context, name, "0")?)
+                    }
+                    name => {
+                        let value = context.variables.get(name).unwrap_or_default();
+                        expanded.push_str(&value);
+                    }
+                };
+            }
+        }
+        // This is for parsing subcommands
+        else {
+            chars.next();
+            let mut sub_content = String::new();
+            let mut depth = 1;
+
+            while let Some((_, c)) = chars.next() {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    break;
                 }
+                sub_content.push(c);
+            }
+
+            // `$((...))` is arithmetic, its value takes the place of the expression
+            if let Some(expression) = sub_content
+                .strip_prefix('(')
+                .and_then(|content| content.strip_suffix(')'))
+            {
+                let value = expand_arithmetic(context, terminal, expression)?;
+                expanded.push_str(&value.to_string());
+                return Ok(expanded);
             }
 
-            _ => expanded.push(character),
+            // A dry run doesn't run anything, what would have been run is shown as it is
+            if context.mode.dry_run {
+                expanded.push_str(&format!("$({sub_content})"));
+                return Ok(expanded);
+            }
+            let command = Shell::parse_command(context, terminal, &sub_content, true)?;
+            let output = executor::execute_and_get_stdout(context, terminal, command)?;
+            expanded.push_str(&output.trim()); // Trim often needed for stdout
         }
+    } else {
+        expanded.push('$');
     }
 
     Ok(expanded)
@@ -310,23 +503,44 @@ fn expand_args(
 ) -> Result<Vec<Arg<'static>>> {
     let mut expanded_args = Vec::new();
     for arg in args {
+        // Each positional parameter or value of the array is an argument of its own, unquoted
+        // they're also split and globbed like the words of a `for`
+        if let Some(list) = list_name(&arg) {
+            match arg {
+                Arg::DoubleQuoted(_) => expanded_args.extend(
+                    list_values(context, list)
+                        .into_iter()
+                        .map(|value| Arg::DoubleQuoted(Cow::Owned(value))),
+                ),
+                _ => expanded_args.extend(
+                    expand_words(context, terminal, vec![arg])?
+                        .into_iter()
+                        .map(|value| Arg::Word(Cow::Owned(value))),
+                ),
+            }
+            continue;
+        }
+
         match arg {
-            Arg::Word(s) => {
-                // We first expand the variables and then we do globbing
-                let expanded_string = expand_string(context, terminal, s)?;
-                let matches = glob_word(&expanded_string)?;
-                if matches.is_empty() {
-                    expanded_args.push(Arg::Word(Cow::Owned(expanded_string)));
-                } else {
-                    for m in matches {
-                        expanded_args.push(Arg::Word(Cow::Owned(m)));
+            Arg::Word(_) => {
+                // We first expand the variables and then we do globbing, only the parts that
+                // weren't quoted can match files. Like sh, POSIX mode also splits what the
+                // unquoted variables expand to
+                let pieces = arg_pieces(context, terminal, arg)?;
+                let fields = match context.mode.posix {
+                    true => split_fields(pieces),
+                    false => vec![pieces],
+                };
+                for field in fields {
+                    for value in glob_pieces(&field)? {
+                        expanded_args.push(Arg::Word(Cow::Owned(value)));
                     }
                 }
             }
-            Arg::DoubleQuoted(s) => {
+            Arg::DoubleQuoted(_) => {
                 // We expand variable but not do globbing
-                let expanded_str = expand_string(context, terminal, s)?;
-                expanded_args.push(Arg::DoubleQuoted(Cow::Owned(expanded_str)));
+                let pieces = arg_pieces(context, terminal, arg)?;
+                expanded_args.push(Arg::DoubleQuoted(Cow::Owned(join(&pieces))));
             }
             Arg::SingleQuoted(s) => {
                 // Remains as it is
@@ -337,6 +551,265 @@ fn expand_args(
     Ok(expanded_args)
 }
 
+/// Expands the words of a `for` loop into the values it goes through. Unlike arguments,
+/// the unquoted parts of words are split on whitespace once expanded, so `for x in $LIST`
+/// goes through every item of the list while `"$LIST"` is a single value
+pub fn expand_words(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    words: Vec<Arg>,
+) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    for word in words {
+        if let Arg::DoubleQuoted(_) = word
+            && let Some(list) = list_name(&word)
+        {
+            values.extend(list_values(context, list));
+            continue;
+        }
+
+        match word {
+            Arg::Word(_) => {
+                for field in split_fields(arg_pieces(context, terminal, word)?) {
+                    values.extend(glob_pieces(&field)?);
+                }
+            }
+            Arg::DoubleQuoted(_) => values.push(join(&arg_pieces(context, terminal, word)?)),
+            Arg::SingleQuoted(s) => values.push(s.into_owned()),
+        }
+    }
+    Ok(values)
+}
+
+// Splits the unquoted parts of a word on whitespace, a quoted part makes a field even when
+// it's empty
+fn split_fields(pieces: Vec<Piece>) -> Vec<Vec<Piece>> {
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    for piece in pieces {
+        if piece.quoted {
+            push_piece(&mut field, &piece.text, true);
+            continue;
+        }
+        for character in piece.text.chars() {
+            match character.is_whitespace() {
+                true if !field.is_empty() => fields.push(std::mem::take(&mut field)),
+                true => {}
+                false => push_piece(&mut field, character.encode_utf8(&mut [0; 4]), false),
+            }
+        }
+    }
+    if !field.is_empty() {
+        fields.push(field);
+    }
+    fields
+}
+
+// The files matching a word, or the word itself when its unquoted parts have no pattern or
+// nothing matches
+fn glob_pieces(pieces: &[Piece]) -> Result<Vec<String>> {
+    let is_pattern = pieces
+        .iter()
+        .any(|piece| !piece.quoted && piece.text.contains(['*', '?', '[']));
+    if !is_pattern {
+        return Ok(vec![join(pieces)]);
+    }
+
+    let matches = glob_word(&escape_quoted(pieces, GLOB_SPECIAL))?;
+    match matches.is_empty() {
+        true => Ok(vec![join(pieces)]),
+        false => Ok(matches),
+    }
+}
+
+// The word with a backslash before the special characters of its quoted parts
+fn escape_quoted(pieces: &[Piece], special: &str) -> String {
+    let mut escaped = String::new();
+    for piece in pieces {
+        for c in piece.text.chars() {
+            if piece.quoted && special.contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// `$@` or `${name[@]}` on its own, which stand for all the positional parameters or all the
+// values of the array as separate words. Gives back `@` or the name of the array
+fn list_name<'a>(arg: &'a Arg) -> Option<&'a str> {
+    let (Arg::Word(s) | Arg::DoubleQuoted(s)) = arg else {
+        return None;
+    };
+    match s.as_ref() {
+        "$@" | "${@}" => Some("@"),
+        s => s
+            .strip_prefix("${")
+            .and_then(|s| s.strip_suffix("[@]}"))
+            .filter(|name| is_name(name)),
+    }
+}
+
+fn list_values(context: &Context, list: &str) -> Vec<String> {
+    match list {
+        "@" => context.positional.clone(),
+        name => context.arrays.get(name).cloned().unwrap_or_default(),
+    }
+}
+
+// `name[index]` or `name[@]`, with a `#` in front for the length of the value or how many
+// values there are. Indexes count from 0 and can be arithmetic, negative ones from the end
+fn expand_array(context: &mut Context, parameter: &str) -> Result<String> {
+    let (length, parameter) = match parameter.strip_prefix('#') {
+        Some(parameter) => (true, parameter),
+        None => (false, parameter),
+    };
+    let (name, index) = match parameter.split_once('[') {
+        Some((name, index)) => match index.strip_suffix(']') {
+            Some(index) => (name, index),
+            None => return error(&format!("Missing ']' in '{}'", parameter)),
+        },
+        None => (parameter, "0"),
+    };
+    if !is_name(name) {
+        return error(&format!("Bad substitution '{}'", parameter));
+    }
+
+    match (index, length) {
+        ("@" | "*", true) => Ok(list_values(context, name).len().to_string()),
+        ("@" | "*", false) => Ok(list_values(context, name).join(" ")),
+        (_, true) => Ok(array_value(context, name, index)?
+            .chars()
+            .count()
+            .to_string()),
+        (_, false) => array_value(context, name, index),
+    }
+}
+
+// A value of an array, variables that aren't arrays act like one with just their value
+fn array_value(context: &mut Context, name: &str, index: &str) -> Result<String> {
+    let index = arithmetic::eval_in(&mut context.variables, index)?;
+    let Some(values) = context.arrays.get(name) else {
+        let value = context.variables.get(name).filter(|_| index == 0);
+        return Ok(value.unwrap_or_default());
+    };
+
+    let index = match index < 0 {
+        true => values.len().checked_sub(index.unsigned_abs() as usize),
+        false => Some(index as usize),
+    };
+    Ok(index
+        .and_then(|index| values.get(index))
+        .cloned()
+        .unwrap_or_default())
+}
+
+/// Value of an arithmetic expression, the variables in it like `$x` or `${arr[1]}` are expanded
+/// first. Names without a `$` are left to the evaluator, which can also assign to them
+pub fn expand_arithmetic(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    expression: &str,
+) -> Result<i64> {
+    let expression = expand_string(context, terminal, Cow::Borrowed(expression))?;
+    arithmetic::eval_in(&mut context.variables, &expression)
+}
+
+/// Expands the word a `case` looks at, it's neither split nor globbed
+pub fn expand_word(context: &mut Context, terminal: &mut Terminal, word: Arg) -> Result<String> {
+    Ok(join(&arg_pieces(context, terminal, word)?))
+}
+
+/// Expands a `case` pattern, the quoted parts are escaped so they only match themselves
+pub fn expand_pattern(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    pattern: Arg,
+) -> Result<String> {
+    expand_escaped(context, terminal, pattern, GLOB_SPECIAL)
+}
+
+/// Expands a word with a backslash before the special characters of its quoted parts, so a
+/// pattern or a regex made of it only matches them as they are
+pub fn expand_escaped(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    word: Arg,
+    special: &str,
+) -> Result<String> {
+    Ok(escape_quoted(
+        &arg_pieces(context, terminal, word)?,
+        special,
+    ))
+}
+
+/// Whether the text matches a glob pattern like `*.rs` or `[a-c]?`
+#[cfg(unix)]
+pub fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let (Ok(pattern), Ok(text)) = (encoding::to_cstring(pattern), encoding::to_cstring(text))
+    else {
+        return false;
+    };
+    unsafe { libc::fnmatch(pattern.as_ptr(), text.as_ptr(), 0) == 0 }
+}
+
+// The C runtime of Windows has no fnmatch nor glob, they're done here the same way
+#[cfg(windows)]
+pub fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    wildcard_match(&pattern, &text)
+}
+
+#[cfg(windows)]
+fn wildcard_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = &pattern[pattern.iter().take_while(|&&c| c == '*').count()..];
+            (0..=text.len()).any(|skip| wildcard_match(rest, &text[skip..]))
+        }
+        _ if text.is_empty() => false,
+        Some('?') => wildcard_match(&pattern[1..], &text[1..]),
+        Some('[') => match bracket(&pattern[1..], text[0]) {
+            Some((matched, rest)) => matched && wildcard_match(rest, &text[1..]),
+            None => text[0] == '[' && wildcard_match(&pattern[1..], &text[1..]),
+        },
+        Some('\\') if pattern.len() > 1 => {
+            text[0] == pattern[1] && wildcard_match(&pattern[2..], &text[1..])
+        }
+        Some(&c) => text[0] == c && wildcard_match(&pattern[1..], &text[1..]),
+    }
+}
+
+// Whether the character is in the set of a `[...]`, and what's left of the pattern after it.
+// None when the bracket isn't closed, the `[` only matches itself then
+#[cfg(windows)]
+fn bracket(pattern: &[char], c: char) -> Option<(bool, &[char])> {
+    let negated = matches!(pattern.first(), Some('!' | '^'));
+    let mut i = negated as usize;
+    let mut found = false;
+    let mut first = true;
+    loop {
+        let &start = pattern.get(i)?;
+        if start == ']' && !first {
+            return Some((found != negated, &pattern[i + 1..]));
+        }
+        first = false;
+        match pattern.get(i + 2) {
+            Some(&end) if pattern[i + 1] == '-' && end != ']' => {
+                found |= (start..=end).contains(&c);
+                i += 3;
+            }
+            _ => {
+                found |= start == c;
+                i += 1;
+            }
+        }
+    }
+}
+
 fn expanded_redirects(
     context: &mut Context,
     terminal: &mut Terminal,
@@ -350,6 +823,15 @@ fn expanded_redirects(
                 RedirectTarget::File(Cow::Owned(expanded_path))
             }
             RedirectTarget::FileDescriptor(fd) => RedirectTarget::FileDescriptor(fd),
+            // Like between double quotes, only `$` is expanded, and the text isn't split
+            RedirectTarget::Text { body, expand: true } => RedirectTarget::Text {
+                body: Cow::Owned(join(&expand_pieces(context, terminal, &body, true)?)),
+                expand: false,
+            },
+            RedirectTarget::Text { body, expand } => RedirectTarget::Text {
+                body: Cow::Owned(body.into_owned()),
+                expand,
+            },
         };
 
         expanded_redirects.push(Redirect {
@@ -377,12 +859,13 @@ fn expand_env_vars<'a>(
     Ok(expanded_env_vars)
 }
 
+#[cfg(unix)]
 fn glob_word(pattern: &str) -> Result<Vec<String>> {
     if !pattern.contains(['*', '?', '[']) {
         return Ok(Vec::new());
     }
 
-    let pattern_c = CString::new(pattern)?;
+    let pattern_c = encoding::to_cstring(pattern)?;
     let mut glob_result: libc::glob_t = unsafe { std::mem::zeroed() };
     let result = unsafe {
         libc::glob(
@@ -398,7 +881,7 @@ fn glob_word(pattern: &str) -> Result<Vec<String>> {
         let mut matches = Vec::new();
         for i in 0..glob_result.gl_pathc {
             let path_cstr = unsafe { CStr::from_ptr(*glob_result.gl_pathv.offset(i as isize)) };
-            matches.push(path_cstr.to_string_lossy().into_owned());
+            matches.push(encoding::decode(path_cstr.to_bytes()));
         }
         unsafe { libc::globfree(&mut glob_result) };
         Ok(matches)
@@ -411,6 +894,58 @@ fn glob_word(pattern: &str) -> Result<Vec<String>> {
     }
 }
 
+// One directory at a time, like glob does it. Names starting with a dot are only matched by
+// a part that starts with one too
+#[cfg(windows)]
+fn glob_word(pattern: &str) -> Result<Vec<String>> {
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(Vec::new());
+    }
+
+    let mut paths = vec![String::new()];
+    for (index, part) in pattern.split('/').enumerate() {
+        let mut found = Vec::new();
+        for path in paths {
+            let join = |name: &str| match index {
+                0 => name.to_string(),
+                _ => format!("{}/{}", path, name),
+            };
+            if !part.contains(['*', '?', '[']) {
+                let mut literal = String::new();
+                let mut chars = part.chars();
+                while let Some(c) = chars.next() {
+                    literal.push(match c {
+                        '\\' => chars.next().unwrap_or('\\'),
+                        c => c,
+                    });
+                }
+                found.push(join(&literal));
+                continue;
+            }
+            let directory = match index {
+                0 => ".",
+                _ if path.is_empty() => "/",
+                _ => &path,
+            };
+            let Ok(entries) = fs::read_dir(encoding::to_os_string(directory)) else {
+                continue;
+            };
+            let mut names: Vec<String> = entries
+                .flatten()
+                .map(|entry| encoding::from_os_str(&entry.file_name()))
+                .filter(|name| !name.starts_with('.') || part.starts_with('.'))
+                .filter(|name| matches_pattern(part, name))
+                .collect();
+            names.sort();
+            found.extend(names.iter().map(|name| join(name)));
+        }
+        paths = found;
+    }
+
+    paths.retain(|path| fs::symlink_metadata(encoding::to_os_string(path)).is_ok());
+    Ok(paths)
+}
+
 pub fn to_owned<'a>(
     context: &mut Context,
     terminal: &mut Terminal,
@@ -419,8 +954,13 @@ pub fn to_owned<'a>(
     redirects: Vec<Redirect>,
     env_vars: Vec<EnvVariable<'a>>,
 ) -> Result<Command<'static>> {
+    // `\ls` runs the program even when there's an alias with its name
+    let command = match command.strip_prefix('\\') {
+        Some(name) => name.to_string(),
+        None => command.into_owned(),
+    };
     Ok(Command::Simple {
-        command: command.into_owned().into(),
+        command: command.into(),
         args: expand_args(context, terminal, args)?,
         redirects: expanded_redirects(context, terminal, redirects)?,
         env_vars: expand_env_vars(context, terminal, env_vars)?,
@@ -0,0 +1,178 @@
+//frecency.rs
+
+use crate::history;
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Once the visits add up to more than this they're all scaled down, forgetting directories
+// that were only seen a few times long ago
+const MAX_VISITS: f64 = 10_000.0;
+const AGING: f64 = 0.9;
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+const WEEK: u64 = 7 * DAY;
+
+/// Where the visited directories are kept: `$XDG_DATA_HOME/rshell/directories`, next to the
+/// history
+pub fn database_path() -> PathBuf {
+    history::data_dir().join("directories")
+}
+
+/// A directory `cd` went to, how many times and when last
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub visits: f64,
+    pub last: u64,
+}
+
+impl Entry {
+    /// The visits weighed by how long ago the last one was, like zoxide does
+    pub fn score(&self, now: u64) -> f64 {
+        let age = now.saturating_sub(self.last);
+        let recency = match age {
+            age if age < HOUR => 4.0,
+            age if age < DAY => 2.0,
+            age if age < WEEK => 0.5,
+            _ => 0.25,
+        };
+        self.visits * recency
+    }
+
+    // Every fragment shows up in the path in order, ignoring case unless one has capitals.
+    // The last one has to be in the last part, `z proj` is the project and not a directory
+    // somewhere under it
+    fn matches(&self, fragments: &[&str]) -> bool {
+        let sensitive = fragments
+            .iter()
+            .any(|fragment| fragment.chars().any(char::is_uppercase));
+        let fold = |text: &str| match sensitive {
+            true => text.to_string(),
+            false => text.to_lowercase(),
+        };
+
+        let path = fold(&self.path.to_string_lossy());
+        let mut rest = path.as_str();
+        for fragment in fragments {
+            let fragment = fold(fragment);
+            let Some(found) = rest.find(&fragment) else {
+                return false;
+            };
+            rest = &rest[found + fragment.len()..];
+        }
+
+        let last = fragments
+            .last()
+            .map_or(String::new(), |fragment| fold(fragment));
+        let last = last.rsplit('/').next().unwrap_or_default();
+        self.path
+            .file_name()
+            .is_some_and(|name| fold(&name.to_string_lossy()).contains(last))
+    }
+}
+
+/// The directories visited with `cd`, ranked by how often and how recently (frecency). Kept
+/// in a file of `path<TAB>visits<TAB>last visit` lines shared by every session, each visit
+/// reads and writes it again so sessions don't undo each other's visits
+#[derive(Clone, Debug, Default)]
+pub struct Directories {
+    pub entries: Vec<Entry>,
+}
+
+impl Directories {
+    /// Reads the database, a missing file is an empty one and broken lines are left out
+    pub fn load(path: &Path) -> Self {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        let entries = content
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.rsplitn(3, '\t');
+                let last = fields.next()?.parse().ok()?;
+                let visits = fields.next()?.parse().ok()?;
+                let path = PathBuf::from(fields.next()?);
+                Some(Entry { path, visits, last })
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// Writes the database in one go, a session reading it never sees half of it
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("Failed to create the data directory")?;
+        }
+        let content: String = self
+            .entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{}\t{}\t{}\n",
+                    entry.path.display(),
+                    entry.visits,
+                    entry.last
+                )
+            })
+            .collect();
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, content)
+            .and_then(|_| fs::rename(&temporary, path))
+            .context("Failed to write the directories database")
+    }
+
+    pub fn visit(&mut self, directory: &Path, now: u64) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.path == directory)
+        {
+            Some(entry) => {
+                entry.visits += 1.0;
+                entry.last = now;
+            }
+            None => self.entries.push(Entry {
+                path: directory.to_path_buf(),
+                visits: 1.0,
+                last: now,
+            }),
+        }
+
+        let total: f64 = self.entries.iter().map(|entry| entry.visits).sum();
+        if total > MAX_VISITS {
+            for entry in &mut self.entries {
+                entry.visits *= AGING;
+            }
+            self.entries.retain(|entry| entry.visits >= 1.0);
+        }
+    }
+
+    /// The directories matching every fragment, best first. Without fragments that's all of
+    /// them
+    pub fn matching(&self, fragments: &[&str], now: u64) -> Vec<&Entry> {
+        let mut matching: Vec<&Entry> = self
+            .entries
+            .iter()
+            .filter(|entry| fragments.is_empty() || entry.matches(fragments))
+            .collect();
+        matching.sort_by(|a, b| b.score(now).total_cmp(&a.score(now)));
+        matching
+    }
+}
+
+/// Counts a visit to the directory in the database
+pub fn record(directory: &Path) -> Result<()> {
+    let path = database_path();
+    let mut directories = Directories::load(&path);
+    directories.visit(directory, now());
+    directories.save(&path)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
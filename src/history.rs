@@ -1,23 +1,280 @@
 // history.rs
 
-use anyhow::{Context, Result};
+use crate::{
+    crypto::{self, Cipher},
+    error::{ShellError, ShellPhase},
+    settings::HistorySettings,
+};
+use anyhow::{Context, Result, anyhow};
+#[cfg(unix)]
+use libc::{mktime, strftime};
 use std::{
+    collections::{HashMap, HashSet},
     env,
-    fs::{File, OpenOptions},
-    io::{Read, Write},
-    path::PathBuf,
+    ffi::CStr,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+#[derive(Clone, Debug)]
+pub struct HistoryEntry {
+    pub command: String,
+    pub timestamp: u64,
+    pub cwd: PathBuf,
+    pub exit_code: Option<i32>,
+    /// Wall clock time the command took, in milliseconds
+    pub duration: Option<u64>,
+    /// Shell session that ran the command, see `session_id`
+    pub session: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn new(command: String) -> Self {
+        Self {
+            command,
+            timestamp: now(),
+            cwd: env::current_dir().unwrap_or_default(),
+            exit_code: None,
+            duration: None,
+            session: Some(session_id().to_string()),
+        }
+    }
+
+    /// Parses a line of the history file, lines written before entries carried
+    /// metadata are just the command, so they're kept as they are
+    pub fn parse(line: &str) -> Self {
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        if let [timestamp, status, cwd, command] = fields[..]
+            && let Ok(timestamp) = timestamp.parse::<u64>()
+        {
+            // The status field is '<exit code>:<duration>:<session>', older entries
+            // stop after the exit code or the duration
+            let mut status = status.splitn(3, ':');
+            let exit_code = status.next().unwrap_or_default();
+            let duration = status.next().unwrap_or_default();
+            let session = status.next().filter(|s| !s.is_empty());
+            return Self {
+                command: command.replace(LINE_BREAK, "\n"),
+                timestamp,
+                cwd: PathBuf::from(cwd),
+                exit_code: exit_code.parse().ok(),
+                duration: duration.parse().ok(),
+                session: session.map(str::to_string),
+            };
+        }
+
+        Self {
+            command: line.replace(LINE_BREAK, "\n"),
+            timestamp: 0,
+            cwd: PathBuf::new(),
+            exit_code: None,
+            duration: None,
+            session: None,
+        }
+    }
+
+    /// Serializes the entry as a single tab separated line, the command goes last so it can
+    /// contain anything. The lines of a command going on over more, like a here-document,
+    /// are kept apart by `LINE_BREAK`
+    pub fn to_line(&self) -> String {
+        let exit_code = self.exit_code.map(|c| c.to_string()).unwrap_or_default();
+        let duration = self.duration.map(|d| d.to_string()).unwrap_or_default();
+        let status = match &self.session {
+            Some(session) => format!("{}:{}:{}", exit_code, duration, session),
+            None if self.duration.is_some() => format!("{}:{}", exit_code, duration),
+            None => exit_code,
+        };
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.timestamp,
+            status,
+            self.cwd.display(),
+            self.command.replace('\n', LINE_BREAK)
+        )
+    }
+
+    pub fn failed(&self) -> bool {
+        matches!(self.exit_code, Some(code) if code != 0)
+    }
+}
+
+/// Whether browsing the history favours commands run in the current directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DirectoryMode {
+    Off,
+    Prefer,
+    Only,
+}
+
+impl DirectoryMode {
+    /// Reads the mode from `HISTDIR`, which can be `prefer` or `only`
+    pub fn from_env() -> Self {
+        match env::var("HISTDIR").as_deref() {
+            Ok("prefer") => Self::Prefer,
+            Ok("only") => Self::Only,
+            _ => Self::Off,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Jsonl,
+    Plain,
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "jsonl" | "json" => Some(Self::Jsonl),
+            "plain" | "text" => Some(Self::Plain),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct HistoryFilter {
+    pub cwd: Option<PathBuf>,
+    pub failed: bool,
+    pub since: Option<u64>,
+    /// Minimum duration in milliseconds
+    pub slower_than: Option<u64>,
+    pub session: Option<String>,
+    pub pattern: Option<String>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        if let Some(cwd) = &self.cwd
+            && &entry.cwd != cwd
+        {
+            return false;
+        }
+
+        if self.failed && !entry.failed() {
+            return false;
+        }
+
+        if let Some(since) = self.since
+            && entry.timestamp < since
+        {
+            return false;
+        }
+
+        if let Some(slower_than) = self.slower_than
+            && entry.duration.is_none_or(|duration| duration < slower_than)
+        {
+            return false;
+        }
+
+        if let Some(session) = &self.session
+            && entry.session.as_ref() != Some(session)
+        {
+            return false;
+        }
+
+        match &self.pattern {
+            Some(pattern) => entry.command.contains(pattern.as_str()),
+            None => true,
+        }
+    }
+}
+
+pub struct CommandStats {
+    pub name: String,
+    pub runs: usize,
+    /// Runs whose exit code was recorded, older entries don't have one
+    pub finished: usize,
+    pub failures: usize,
+}
+
+impl CommandStats {
+    pub fn failure_rate(&self) -> f64 {
+        if self.finished == 0 {
+            return 0.0;
+        }
+        self.failures as f64 / self.finished as f64
+    }
+}
+
+/// Aggregated view of the history, every list is sorted with the most relevant item first
+/// except `days` which is chronological
+pub struct HistoryStats {
+    pub total: usize,
+    pub commands: Vec<CommandStats>,
+    pub directories: Vec<(PathBuf, usize)>,
+    pub days: Vec<(String, usize)>,
+}
+
+/// Advisory lock on the history file, released when dropped
+struct FileLock<'a> {
+    file: &'a File,
+}
+
+impl<'a> FileLock<'a> {
+    // For writing, nobody else can hold the file meanwhile
+    fn exclusive(file: &'a File) -> Result<Self> {
+        file.lock().context("Failed to lock history file")?;
+        Ok(Self { file })
+    }
+
+    // For reading, other readers can hold it too
+    fn shared(file: &'a File) -> Result<Self> {
+        file.lock_shared().context("Failed to lock history file")?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for FileLock<'_> {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+// First line of an encrypted history file, followed by the salt of the key
+const ENCRYPTED_HEADER: &str = "#rshell-encrypted ";
+
+// Stands for the newlines of a command in the file, a command can't have a NUL byte
+const LINE_BREAK: &str = "\0";
+
 pub struct History {
     file: Option<File>,
+    cipher: Option<Cipher>,
+    // How far into the file we've read, anything past it was written by other sessions
+    offset: u64,
+    // Bytes at the start of the file that haven't been parsed yet, see `load_all`
+    unloaded: u64,
+    pending: bool,
+    // Entries parsed at startup and whether lines starting with a space are skipped
+    recent_entries: usize,
+    ignore_space: bool,
+    // Most entries kept, from HISTSIZE, the oldest ones go first
+    size: Option<usize>,
+    // Entries visited by Up/Down, from the most recent, and where we are among them
+    browsing: Option<(Vec<usize>, usize)>,
     pub row: usize,
-    pub current: Vec<String>,
+    pub current: Vec<HistoryEntry>,
 }
 
 impl Clone for History {
     fn clone(&self) -> Self {
         Self {
             file: None,
+            cipher: None,
+            offset: 0,
+            unloaded: 0,
+            pending: false,
+            recent_entries: self.recent_entries,
+            ignore_space: self.ignore_space,
+            size: self.size,
+            browsing: None,
             row: self.row,
             current: self.current.clone(),
         }
@@ -26,46 +283,1087 @@ impl Clone for History {
 
 impl History {
     pub fn new() -> Result<Self> {
-        let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        let path = PathBuf::from(home_dir).join(".rshell_history");
+        Self::open(&HistorySettings::default())
+    }
 
-        let mut file = OpenOptions::new()
+    pub fn open(settings: &HistorySettings) -> Result<Self> {
+        let path = history_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context("Failed to create history directory")?;
+        }
+        if env::var_os("HISTFILE").is_none() {
+            migrate_legacy_file(&path)?;
+        }
+
+        let file = OpenOptions::new()
             .read(true)
             .append(true)
             .create(true)
             .open(&path)
             .context("Failed to read history file")?;
 
-        let mut current = Vec::new();
+        let mut history = Self::dummy();
+        history.size = history_size(settings);
+        // Entries that would be dropped for HISTSIZE straight away aren't worth parsing
+        history.recent_entries = match history.size {
+            Some(size) => settings.recent_entries.min(size),
+            None => settings.recent_entries,
+        };
+        history.ignore_space = settings.ignore_space;
+        // HISTENCRYPT opts into encryption at rest, the key comes from a passphrase typed at
+        // startup or from the OS keyring depending on its value. It's asked for before taking
+        // the lock, other sessions writing their commands don't wait on someone typing it
+        let secret = match env::var("HISTENCRYPT") {
+            Ok(source) => Some(crypto::obtain_secret(&source)?),
+            Err(_) => None,
+        };
+        {
+            let _lock = FileLock::exclusive(&file)?;
+            let end = file.metadata()?.len();
+            let (start, mut content) = read_tail(&file, end, history.recent_entries)?;
+            history.unloaded = start;
+
+            // Every entry ends with a newline, a line without one was cut short by a crash
+            // mid-write, drop it so the next entry doesn't get glued to it
+            if !content.is_empty() && !content.ends_with('\n') {
+                let complete = content.rfind('\n').map_or(0, |i| i + 1);
+                file.set_len(start + complete as u64)
+                    .context("Failed to repair history file")?;
+                content.truncate(complete);
+            }
+
+            let header = read_header(&file)?;
+            let salt = header.strip_prefix(ENCRYPTED_HEADER);
+            match (&secret, salt) {
+                (Some(secret), Some(salt)) => {
+                    let salt = crypto::decode_salt(salt)?;
+                    history.cipher = Some(Cipher::from_passphrase(secret.as_bytes(), &salt)?);
+                }
+                (Some(secret), None) => {
+                    // Existing plain text history gets encrypted the first time
+                    let salt: [u8; crypto::SALT_LEN] = crypto::random_bytes()?;
+                    history.current = history.decode(&content)?;
+                    history.load_head(&file)?;
+                    history.cipher = Some(Cipher::from_passphrase(secret.as_bytes(), &salt)?);
+                    history.rewrite(&path, &salt)?;
+                    history.reset_row();
+                    return Ok(history);
+                }
+                (None, Some(_)) => {
+                    return Err(anyhow!(
+                        "The history file is encrypted, set HISTENCRYPT to 'passphrase' or 'keyring' to open it"
+                    ));
+                }
+                (None, None) => {}
+            }
+
+            history.current = history.decode(&content)?;
+            history.offset = start + content.len() as u64;
+        }
+
+        history.file = Some(file);
+        history.trim();
+        history.reset_row();
+        tracing::debug!(
+            path = %path.display(),
+            entries = history.current.len(),
+            unloaded = history.unloaded,
+            encrypted = history.cipher.is_some(),
+            "Opened history"
+        );
+        Ok(history)
+    }
+
+    /// Switches to the file `HISTFILE` now points to, called when it changes mid-session.
+    /// The command being run moves along, so it ends up in the new file
+    pub fn reopen(&mut self, settings: &HistorySettings) -> Result<()> {
+        let mut history = Self::open(settings)?;
+        if self.pending
+            && let Some(entry) = self.current.pop()
+        {
+            history.current.push(entry);
+            history.pending = true;
+            history.reset_row();
+        }
+        *self = history;
+        Ok(())
+    }
+
+    /// Applies settings changed at runtime, how many entries to load at startup no longer matters
+    pub fn configure(&mut self, settings: &HistorySettings) {
+        self.ignore_space = settings.ignore_space;
+        self.set_size(history_size(settings));
+    }
+
+    /// Changes how many entries are kept, None keeps them all
+    pub fn set_size(&mut self, size: Option<usize>) {
+        self.size = size;
+        self.trim();
+    }
+
+    // Drops the oldest entries past the size limit, keeping the browsing state on the same
+    // entries. The entry waiting for `finish` is never dropped
+    fn trim(&mut self) {
+        let Some(size) = self.size else {
+            return;
+        };
+        let keep = size + self.pending as usize;
+        let Some(extra) = self
+            .current
+            .len()
+            .checked_sub(keep)
+            .filter(|&extra| extra > 0)
+        else {
+            return;
+        };
+
+        self.current.drain(..extra);
+        // What's still in the file before these is even older, no need to ever load it
+        self.unloaded = 0;
+        self.row = self.row.saturating_sub(extra);
+        if let Some((order, position)) = self.browsing.take() {
+            let selected = order[position].checked_sub(extra);
+            let order: Vec<usize> = order
+                .into_iter()
+                .filter_map(|index| index.checked_sub(extra))
+                .collect();
+            let position = order
+                .iter()
+                .position(|&index| Some(index) == selected)
+                .unwrap_or(0);
+            if !order.is_empty() {
+                self.browsing = Some((order, position));
+            }
+        }
+    }
+
+    /// Parses the older part of the file `new` skipped to start quickly. Everything that
+    /// needs the whole history (listing, searching, `!n`) calls this first, it's a no-op
+    /// once everything is loaded
+    pub fn load_all(&mut self) -> Result<()> {
+        if self.unloaded == 0 {
+            return Ok(());
+        }
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+
+        let result = FileLock::shared(&file).and_then(|_lock| self.load_head(&file));
+        self.file = Some(file);
+        tracing::debug!(entries = self.current.len(), "Loaded the whole history");
+        result
+    }
+
+    // Prepends the entries before `unloaded`, keeping the browsing state pointed at the
+    // same entries
+    fn load_head(&mut self, mut file: &File) -> Result<()> {
+        file.seek(SeekFrom::Start(0))?;
         let mut content = String::new();
-        file.read_to_string(&mut content)
+        file.take(self.unloaded)
+            .read_to_string(&mut content)
             .context("Failed to read history file")?;
 
-        content.lines().for_each(|l| current.push(l.to_string()));
+        let entries = self.decode(&content)?;
+        let added = entries.len();
+        self.current.splice(0..0, entries);
+        self.unloaded = 0;
 
-        Ok(Self {
-            file: Some(file),
-            row: current.len(),
-            current,
-        })
+        self.row += added;
+        if let Some((order, _)) = self.browsing.as_mut() {
+            for index in order.iter_mut() {
+                *index += added;
+            }
+        }
+        self.trim();
+        Ok(())
     }
 
     pub fn dummy() -> Self {
         Self {
             file: None,
+            cipher: None,
+            offset: 0,
+            unloaded: 0,
+            pending: false,
+            recent_entries: HistorySettings::default().recent_entries,
+            ignore_space: true,
+            size: None,
+            browsing: None,
             current: Vec::new(),
             row: 0,
         }
     }
 
+    /// Records a command and writes it to the history file straight away
     pub fn push(&mut self, command: String) -> Result<()> {
-        if let Some(ref mut file) = self.file {
-            writeln!(file, "{}", command)?;
-            file.flush()?;
+        self.flush_pending()?;
+        let entry = HistoryEntry::new(command);
+        self.write(std::slice::from_ref(&entry))?;
+        self.current.push(entry);
+        self.trim();
+
+        Ok(())
+    }
+
+    /// Moves the navigation row back past the last entry, like a fresh prompt
+    pub fn reset_row(&mut self) {
+        self.row = self.current.len();
+        self.browsing = None;
+    }
+
+    pub fn is_browsing(&self) -> bool {
+        self.browsing.is_some()
+    }
+
+    /// Indices of the entries to browse, from the most recent one, honoring the directory mode
+    pub fn browse_order(&self, cwd: &Path, mode: DirectoryMode) -> Vec<usize> {
+        let newest_first = (0..self.current.len()).rev();
+        let in_cwd = |&index: &usize| self.current[index].cwd == cwd;
+        match mode {
+            DirectoryMode::Off => newest_first.collect(),
+            DirectoryMode::Only => newest_first.filter(in_cwd).collect(),
+            DirectoryMode::Prefer => {
+                let (mut order, others): (Vec<usize>, Vec<usize>) = newest_first.partition(in_cwd);
+                order.extend(others);
+                order
+            }
+        }
+    }
+
+    /// Moves one entry back in the history (Up arrow) and returns its command
+    pub fn older(&mut self, cwd: &Path, mode: DirectoryMode) -> Result<Option<&str>> {
+        // Past the oldest entry parsed at startup, the rest of the file is needed now
+        let exhausted = match &self.browsing {
+            Some((order, position)) => position + 1 >= order.len(),
+            None => false,
+        };
+        if exhausted && self.unloaded > 0 {
+            self.load_all()?;
+            if let Some((order, position)) = self.browsing.take() {
+                let index = order[position];
+                let order = self.browse_order(cwd, mode);
+                let position = order.iter().position(|&i| i == index).unwrap_or(0);
+                self.browsing = Some((order, position));
+            }
+        }
+
+        let (order, position) = match self.browsing.take() {
+            Some((order, position)) if position + 1 < order.len() => (order, position + 1),
+            Some((order, position)) => (order, position),
+            None => (self.browse_order(cwd, mode), 0),
+        };
+
+        let Some(&index) = order.get(position) else {
+            return Ok(None);
+        };
+        self.row = index;
+        self.browsing = Some((order, position));
+        Ok(Some(&self.current[index].command))
+    }
+
+    /// Moves one entry forward in the history (Down arrow), going past the most recent
+    /// entry gives back an empty line. Returns None when we weren't browsing at all
+    pub fn newer(&mut self) -> Option<&str> {
+        match self.browsing.take() {
+            Some((order, position)) if position > 0 => {
+                self.row = order[position - 1];
+                self.browsing = Some((order, position - 1));
+                Some(&self.current[self.row].command)
+            }
+            Some(_) => {
+                self.reset_row();
+                Some("")
+            }
+            None => None,
+        }
+    }
+
+    /// Records a line typed by the user, skipping empty lines and consecutive duplicates.
+    /// The entry is written to the file by `finish` once we know how the command went
+    pub fn add(&mut self, line: &str) -> Result<()> {
+        self.flush_pending()?;
+        // Like bash's ignorespace, a leading space keeps the command out of the history unless
+        // the config file turns it off
+        let ignored = self.ignore_space && line.starts_with(' ');
+        if !line.is_empty() && !ignored && self.last_command() != Some(line) {
+            self.current.push(HistoryEntry::new(line.to_string()));
+            self.pending = true;
+        }
+        self.reset_row();
+        Ok(())
+    }
+
+    /// Stores how the command recorded by `add` went and persists it
+    pub fn finish(&mut self, exit_code: i32, duration: Duration) -> Result<()> {
+        if self.pending
+            && let Some(entry) = self.current.last_mut()
+        {
+            entry.exit_code = Some(exit_code);
+            entry.duration = Some(duration.as_millis() as u64);
+        }
+        self.flush_pending()
+    }
+
+    /// Fuzzy matches the query against every distinct command, best match first.
+    /// Newer commands get a boost so that among similar matches the recent one wins
+    pub fn fuzzy(&self, query: &str) -> Vec<(usize, &HistoryEntry)> {
+        let mut seen = HashSet::new();
+        let mut matches = Vec::new();
+
+        for (n, entry) in self.current.iter().enumerate().rev() {
+            if !seen.insert(entry.command.as_str()) {
+                continue;
+            }
+            if let Some(score) = fuzzy_score(query, &entry.command) {
+                let recency = (n * 10 / self.current.len()) as i64;
+                matches.push((score + recency, n, entry));
+            }
+        }
+
+        matches.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| b.1.cmp(&a.1)));
+        matches
+            .into_iter()
+            .map(|(_, n, entry)| (n, entry))
+            .collect()
+    }
+
+    pub fn last_command(&self) -> Option<&str> {
+        self.current.last().map(|entry| entry.command.as_str())
+    }
+
+    /// Returns the entries matching the filter alongside their index in the history
+    pub fn search<'a>(
+        &'a self,
+        filter: &'a HistoryFilter,
+    ) -> impl Iterator<Item = (usize, &'a HistoryEntry)> + 'a {
+        self.current
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| filter.matches(entry))
+    }
+
+    /// Aggregates the entries matching the filter, commands are grouped by their first word
+    pub fn stats(&self, filter: &HistoryFilter) -> HistoryStats {
+        let mut commands: HashMap<&str, CommandStats> = HashMap::new();
+        let mut directories: HashMap<&Path, usize> = HashMap::new();
+        let mut days: HashMap<String, usize> = HashMap::new();
+        let mut total = 0;
+
+        for (_, entry) in self.search(filter) {
+            let Some(name) = entry.command.split_whitespace().next() else {
+                continue;
+            };
+            total += 1;
+
+            let stats = commands.entry(name).or_insert_with(|| CommandStats {
+                name: name.to_string(),
+                runs: 0,
+                finished: 0,
+                failures: 0,
+            });
+            stats.runs += 1;
+            if entry.exit_code.is_some() {
+                stats.finished += 1;
+            }
+            if entry.failed() {
+                stats.failures += 1;
+            }
+
+            if !entry.cwd.as_os_str().is_empty() {
+                *directories.entry(&entry.cwd).or_default() += 1;
+            }
+
+            if entry.timestamp != 0 {
+                let day = format_timestamp(entry.timestamp);
+                let day = day.split(' ').next().unwrap_or_default().to_string();
+                *days.entry(day).or_default() += 1;
+            }
+        }
+
+        let mut commands: Vec<CommandStats> = commands.into_values().collect();
+        commands.sort_by(|a, b| b.runs.cmp(&a.runs).then_with(|| a.name.cmp(&b.name)));
+
+        let mut directories: Vec<(PathBuf, usize)> = directories
+            .into_iter()
+            .map(|(dir, count)| (dir.to_path_buf(), count))
+            .collect();
+        directories.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        // Dates are formatted as %Y-%m-%d so sorting them as strings is chronological
+        let mut days: Vec<(String, usize)> = days.into_iter().collect();
+        days.sort();
+
+        HistoryStats {
+            total,
+            commands,
+            directories,
+            days,
+        }
+    }
+
+    fn flush_pending(&mut self) -> Result<()> {
+        if !self.pending {
+            return Ok(());
         }
+        self.pending = false;
 
-        self.current.push(command);
+        if let Some(entry) = self.current.last() {
+            let entry = entry.clone();
+            self.write(&[entry])?;
+        }
+        self.trim();
+        Ok(())
+    }
+
+    /// Dumps the whole history, plain only has the commands while the other formats
+    /// also carry the metadata of every entry
+    pub fn export(&self, format: ExportFormat) -> String {
+        let mut output = String::new();
+        if format == ExportFormat::Csv {
+            output.push_str("index,timestamp,session,cwd,exit_code,duration_ms,command\n");
+        }
+
+        for (n, entry) in self.current.iter().enumerate() {
+            let exit_code = entry.exit_code.map(|c| c.to_string());
+            let duration = entry.duration.map(|d| d.to_string());
+            let line = match format {
+                ExportFormat::Plain => entry.command.clone(),
+                ExportFormat::Jsonl => format!(
+                    "{{\"index\":{},\"timestamp\":{},\"session\":{},\"cwd\":{},\"exit_code\":{},\"duration_ms\":{},\"command\":{}}}",
+                    n,
+                    entry.timestamp,
+                    entry
+                        .session
+                        .as_deref()
+                        .map(json_string)
+                        .unwrap_or_else(|| "null".to_string()),
+                    json_string(&entry.cwd.to_string_lossy()),
+                    exit_code.as_deref().unwrap_or("null"),
+                    duration.as_deref().unwrap_or("null"),
+                    json_string(&entry.command)
+                ),
+                ExportFormat::Csv => format!(
+                    "{},{},{},{},{},{},{}",
+                    n,
+                    entry.timestamp,
+                    entry.session.as_deref().unwrap_or_default(),
+                    csv_field(&entry.cwd.to_string_lossy()),
+                    exit_code.unwrap_or_default(),
+                    duration.unwrap_or_default(),
+                    csv_field(&entry.command)
+                ),
+            };
+            output.push_str(&line);
+            output.push('\n');
+        }
+
+        output
+    }
+
+    /// Appends the commands of a bash or zsh history file, returns how many were imported
+    pub fn import(&mut self, content: &str) -> Result<usize> {
+        self.flush_pending()?;
+        let entries = parse_foreign(content);
+        self.write(&entries)?;
+        let count = entries.len();
+        self.current.extend(entries);
+        self.trim();
+        self.reset_row();
+        Ok(count)
+    }
+
+    /// Picks up the entries other sessions appended to the history file since we last looked
+    pub fn reload(&mut self) -> Result<()> {
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+
+        let before = self.current.len();
+        let result = FileLock::shared(&file).and_then(|_lock| self.merge(&file));
+        self.file = Some(file);
+        result?;
+        tracing::trace!(added = self.current.len() - before, "Reloaded history");
+        self.trim();
+
+        if !self.pending {
+            self.reset_row();
+        }
+        Ok(())
+    }
+
+    // Several shells can share the same file, so every write happens under an exclusive lock
+    // and first merges whatever the other sessions wrote, this way nobody clobbers anybody
+    fn write(&mut self, entries: &[HistoryEntry]) -> Result<()> {
+        let Some(file) = self.file.take() else {
+            return Ok(());
+        };
+
+        let result = FileLock::exclusive(&file).and_then(|_lock| {
+            self.merge(&file)?;
+            // Anything past the merged lines is a partial line left by a session that
+            // crashed mid-write, cut it off before appending
+            if file.metadata()?.len() > self.offset {
+                file.set_len(self.offset)?;
+            }
+
+            let mut lines = String::new();
+            for entry in entries {
+                lines.push_str(&self.encode(entry)?);
+                lines.push('\n');
+            }
+            // A single append followed by fsync, so a crash leaves at worst a partial last line
+            (&file).write_all(lines.as_bytes())?;
+            file.sync_data()?;
+            self.offset += lines.len() as u64;
+            Ok(())
+        });
+
+        self.file = Some(file);
+        match &result {
+            Ok(()) => tracing::trace!(entries = entries.len(), "Wrote history"),
+            Err(error) => tracing::warn!(error = %format!("{error:#}"), "Failed to write history"),
+        }
+        result
+    }
+
+    // Replaces the whole file with the current entries, encrypting them with a new salt
+    fn rewrite(&mut self, path: &Path, salt: &[u8]) -> Result<()> {
+        let mut content = format!("{}{}\n", ENCRYPTED_HEADER, crypto::encode_salt(salt));
+        for entry in &self.current {
+            content.push_str(&self.encode(entry)?);
+            content.push('\n');
+        }
+
+        // Written and synced aside then renamed over the old file, so a crash leaves
+        // either the old history or the new one but never half of it
+        let temporary = path.with_extension("tmp");
+        let mut replacement = File::create(&temporary).context("Failed to write history file")?;
+        replacement
+            .write_all(content.as_bytes())
+            .and_then(|_| replacement.sync_all())
+            .context("Failed to write history file")?;
+        fs::rename(&temporary, path).context("Failed to replace history file")?;
+
+        self.file = Some(
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .open(path)
+                .context("Failed to read history file")?,
+        );
+        self.offset = content.len() as u64;
+        Ok(())
+    }
+
+    fn encode(&self, entry: &HistoryEntry) -> Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&entry.to_line()),
+            None => Ok(entry.to_line()),
+        }
+    }
+
+    fn decode(&self, content: &str) -> Result<Vec<HistoryEntry>> {
+        let mut entries = Vec::new();
+        for line in content.lines() {
+            if line.starts_with(ENCRYPTED_HEADER) {
+                continue;
+            }
+            match &self.cipher {
+                Some(cipher) => entries.push(HistoryEntry::parse(&cipher.decrypt(line)?)),
+                None => entries.push(HistoryEntry::parse(line)),
+            }
+        }
+        Ok(entries)
+    }
+
+    // Must be called while holding the lock
+    fn merge(&mut self, mut file: &File) -> Result<()> {
+        let end = file.metadata()?.len();
+        if end <= self.offset {
+            self.offset = end;
+            return Ok(());
+        }
+
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .context("Failed to read history file")?;
+
+        // Only complete lines count, see `write` for what happens to a partial one
+        let complete = content.rfind('\n').map_or(0, |i| i + 1);
+        content.truncate(complete);
+        self.offset += complete as u64;
+
+        // The entry typed in this session but not written yet stays the most recent one
+        let position = if self.pending {
+            self.current.len().saturating_sub(1)
+        } else {
+            self.current.len()
+        };
+        let entries = self.decode(&content)?;
+        self.current.splice(position..position, entries);
 
         Ok(())
     }
+
+    /// Performs csh-style history expansion on a line before it gets parsed.
+    /// Supported designators are `!!` (last command), `!n` (entry n as shown by `history`),
+    /// `!-n` (n commands back), `!prefix` (last command starting with prefix) and `!$`
+    /// (last word of the last command). Returns None if the line didn't reference the history
+    pub fn expand(&self, line: &str) -> Result<Option<String>> {
+        if !line.contains('!') {
+            return Ok(None);
+        }
+
+        let mut expanded = String::with_capacity(line.len());
+        let mut chars = line.chars().peekable();
+        let mut inside_single_quote = false;
+        let mut previous = None;
+        let mut found = false;
+
+        while let Some(character) = chars.next() {
+            // '$!' is the pid of the last job, '\!' is an escaped bang and inside
+            // single quotes nothing gets expanded
+            if character != '!' || inside_single_quote || matches!(previous, Some('$' | '\\')) {
+                if character == '\'' {
+                    inside_single_quote = !inside_single_quote;
+                }
+                expanded.push(character);
+                previous = Some(character);
+                continue;
+            }
+
+            let designator = match chars.peek() {
+                Some('!') => {
+                    chars.next();
+                    "!".to_string()
+                }
+                Some('$') => {
+                    chars.next();
+                    "$".to_string()
+                }
+                Some(&next) if !next.is_whitespace() && !matches!(next, '=' | '(' | '"') => {
+                    let mut designator = String::new();
+                    while let Some(&next) = chars.peek() {
+                        if next.is_whitespace() || matches!(next, ';' | '|' | '&' | '"' | '\'') {
+                            break;
+                        }
+                        designator.push(next);
+                        chars.next();
+                    }
+                    designator
+                }
+                _ => {
+                    expanded.push(character);
+                    previous = Some(character);
+                    continue;
+                }
+            };
+
+            expanded.push_str(&self.resolve_designator(&designator)?);
+            previous = expanded.chars().next_back();
+            found = true;
+        }
+
+        Ok(found.then_some(expanded))
+    }
+
+    fn resolve_designator(&self, designator: &str) -> Result<String> {
+        let entry = match designator {
+            "!" => self.current.last(),
+            "$" => {
+                return self
+                    .last_command()
+                    .and_then(|last| last.split_whitespace().last())
+                    .map(str::to_string)
+                    .ok_or_else(|| Self::event_not_found(designator));
+            }
+            _ => {
+                if let Ok(index) = designator.parse::<usize>() {
+                    self.current.get(index)
+                } else if let Some(offset) = designator.strip_prefix('-')
+                    && let Ok(offset) = offset.parse::<usize>()
+                {
+                    self.current
+                        .len()
+                        .checked_sub(offset)
+                        .and_then(|index| self.current.get(index))
+                } else {
+                    self.current
+                        .iter()
+                        .rev()
+                        .find(|entry| entry.command.starts_with(designator))
+                }
+            }
+        };
+
+        entry
+            .map(|entry| entry.command.clone())
+            .ok_or_else(|| Self::event_not_found(designator))
+    }
+
+    fn event_not_found(designator: &str) -> anyhow::Error {
+        anyhow::Error::new(ShellError {
+            phase: ShellPhase::Expander,
+            command: Some(format!("!{}", designator)),
+            message: "event not found".to_string(),
+        })
+    }
+}
+
+const TAIL_CHUNK: u64 = 64 * 1024;
+
+// Reads the last `entries` lines of the file, returns where they start and the content.
+// Only those are parsed at startup, the rest of the file is loaded when it's needed
+fn read_tail(mut file: &File, end: u64, entries: usize) -> Result<(u64, String)> {
+    let mut chunk = TAIL_CHUNK;
+    loop {
+        let start = end.saturating_sub(chunk);
+        file.seek(SeekFrom::Start(start))?;
+        let mut buffer = Vec::new();
+        file.take(end - start)
+            .read_to_end(&mut buffer)
+            .context("Failed to read history file")?;
+
+        let newlines: Vec<usize> = buffer
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .map(|(i, _)| i)
+            .collect();
+
+        // Without enough lines we need a bigger chunk, unless there's nothing left to read.
+        // When the chunk starts mid-line that partial line is cut off together with the extra ones
+        if newlines.len() > entries || start == 0 {
+            let cut = match newlines.len().checked_sub(entries + 1) {
+                Some(extra) => newlines[extra] + 1,
+                None => 0,
+            };
+            let content =
+                String::from_utf8(buffer.split_off(cut)).context("Failed to read history file")?;
+            return Ok((start + cut as u64, content));
+        }
+        chunk *= 4;
+    }
+}
+
+// The first line of the file, where the encryption header goes
+fn read_header(mut file: &File) -> Result<String> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buffer = Vec::new();
+    file.take(256)
+        .read_to_end(&mut buffer)
+        .context("Failed to read history file")?;
+
+    let line = buffer.split(|&b| b == b'\n').next().unwrap_or_default();
+    Ok(String::from_utf8_lossy(line).into_owned())
+}
+
+/// How many entries to keep, from `HISTSIZE` or else the config file. None keeps them all,
+/// which is also what a negative or invalid `HISTSIZE` means
+pub fn history_size(settings: &HistorySettings) -> Option<usize> {
+    match env::var("HISTSIZE") {
+        Ok(value) => value.trim().parse().ok(),
+        Err(_) => settings.size,
+    }
+}
+
+/// Where the history lives: `$HISTFILE` if set, otherwise `$XDG_DATA_HOME/rshell/history`
+/// which defaults to `~/.local/share/rshell/history`
+pub fn history_path() -> PathBuf {
+    if let Some(path) = env::var_os("HISTFILE").filter(|p| !p.is_empty()) {
+        return PathBuf::from(path);
+    }
+
+    data_dir().join("history")
+}
+
+/// Where the shell keeps its data, `$XDG_DATA_HOME/rshell` or `~/.local/share/rshell`
+pub fn data_dir() -> PathBuf {
+    let data_home = match env::var_os("XDG_DATA_HOME").filter(|p| !p.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => home_dir().join(".local").join("share"),
+    };
+    data_home.join("rshell")
+}
+
+fn home_dir() -> PathBuf {
+    PathBuf::from(env::var("HOME").unwrap_or_else(|_| ".".to_string()))
+}
+
+// Older versions kept the history in ~/.rshell_history, move it over the first time
+fn migrate_legacy_file(path: &Path) -> Result<()> {
+    let legacy = home_dir().join(".rshell_history");
+    if path.exists() || !legacy.exists() {
+        return Ok(());
+    }
+
+    // Renaming fails across filesystems, copying is the fallback
+    if fs::rename(&legacy, path).is_err() {
+        fs::copy(&legacy, path).context("Failed to migrate ~/.rshell_history")?;
+        fs::remove_file(&legacy).context("Failed to remove ~/.rshell_history")?;
+    }
+    Ok(())
+}
+
+/// Identifies this shell process in the history as `<pid>-<start time>`, the start time
+/// keeps it unique once the pid gets reused
+pub fn session_id() -> &'static str {
+    static SESSION: OnceLock<String> = OnceLock::new();
+    SESSION.get_or_init(|| format!("{}-{}", process::id(), now()))
+}
+
+/// Scores how well the query matches the candidate as a case insensitive subsequence, like
+/// fzf. Consecutive characters and matches at the start of words are worth more while gaps
+/// cost a little, `None` means some character of the query is missing
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let mut query = query.chars().filter(|c| !c.is_whitespace()).peekable();
+    let mut score = 0;
+    let mut started = false;
+    let mut consecutive = false;
+    let mut previous: Option<char> = None;
+
+    for c in candidate.chars() {
+        let Some(&wanted) = query.peek() else {
+            break;
+        };
+
+        if c.to_lowercase().eq(wanted.to_lowercase()) {
+            score += 1;
+            if consecutive {
+                score += 5;
+            }
+            if previous.is_none_or(|p| !p.is_alphanumeric()) {
+                score += 3;
+            }
+            if c == wanted {
+                score += 1;
+            }
+            query.next();
+            started = true;
+            consecutive = true;
+        } else {
+            if started {
+                score -= 1;
+            }
+            consecutive = false;
+        }
+        previous = Some(c);
+    }
+
+    match query.peek() {
+        Some(_) => None,
+        None => Some(score),
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats a unix timestamp in local time, entries without one get an empty string
+pub fn format_timestamp(timestamp: u64) -> String {
+    if timestamp == 0 {
+        return String::new();
+    }
+    format_time(timestamp, c"%Y-%m-%d %H:%M")
+}
+
+/// Formats a unix timestamp in local time with a strftime format
+pub fn format_time(timestamp: u64, format: &CStr) -> String {
+    unsafe {
+        let tm = local_time(timestamp);
+        let mut buffer = [0u8; 64];
+        let len = strftime(
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+            format.as_ptr(),
+            &tm,
+        );
+        String::from_utf8_lossy(&buffer[..len]).into_owned()
+    }
+}
+
+/// Parses the value of `--since`: `today`, `yesterday`, a relative amount like
+/// `30m`, `2h` or `3d`, or a plain unix timestamp
+pub fn parse_since(value: &str) -> Option<u64> {
+    let now = now();
+    match value {
+        "today" => return Some(start_of_day(now)),
+        "yesterday" => return Some(start_of_day(now).saturating_sub(24 * 60 * 60)),
+        _ => {}
+    }
+
+    if let Ok(timestamp) = value.parse::<u64>() {
+        return Some(timestamp);
+    }
+
+    let unit = value.chars().last()?;
+    let amount: u64 = value[..value.len() - unit.len_utf8()].parse().ok()?;
+    let unit = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    // Too far back to count is as invalid as a made up unit
+    Some(now.saturating_sub(amount.checked_mul(unit)?))
+}
+
+/// Parses a duration like `500ms`, `5s`, `2m` or `1h` into milliseconds, plain numbers are seconds
+pub fn parse_duration(value: &str) -> Option<u64> {
+    if let Some(millis) = value.strip_suffix("ms") {
+        return millis.parse().ok();
+    }
+    if let Ok(seconds) = value.parse::<u64>() {
+        return seconds.checked_mul(1000);
+    }
+
+    let unit = value.chars().last()?;
+    let amount: u64 = value[..value.len() - unit.len_utf8()].parse().ok()?;
+    let unit = match unit {
+        's' => 1000,
+        'm' => 60 * 1000,
+        'h' => 60 * 60 * 1000,
+        _ => return None,
+    };
+    amount.checked_mul(unit)
+}
+
+/// Formats milliseconds in a compact human readable way, like `850ms`, `3.2s` or `2m05s`
+pub fn format_duration(millis: u64) -> String {
+    let seconds = millis / 1000;
+    if millis < 1000 {
+        format!("{}ms", millis)
+    } else if seconds < 60 {
+        format!("{:.1}s", millis as f64 / 1000.0)
+    } else if seconds < 60 * 60 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else {
+        format!("{}h{:02}m", seconds / 3600, (seconds % 3600) / 60)
+    }
+}
+
+fn start_of_day(timestamp: u64) -> u64 {
+    let mut tm = local_time(timestamp);
+    tm.tm_sec = 0;
+    tm.tm_min = 0;
+    tm.tm_hour = 0;
+    unsafe { mktime(&mut tm) }.max(0) as u64
+}
+
+// A unix timestamp broken down in local time
+fn local_time(timestamp: u64) -> libc::tm {
+    let time = timestamp as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    #[cfg(unix)]
+    unsafe {
+        libc::localtime_r(&time, &mut tm);
+    }
+    #[cfg(windows)]
+    unsafe {
+        libc::localtime_s(&mut tm, &time);
+    }
+    tm
+}
+
+// The C runtime of Windows has them too, libc only leaves them out
+#[cfg(windows)]
+unsafe extern "C" {
+    fn strftime(
+        buffer: *mut libc::c_char,
+        size: usize,
+        format: *const libc::c_char,
+        tm: *const libc::tm,
+    ) -> usize;
+    #[link_name = "_mktime64"]
+    fn mktime(tm: *mut libc::tm) -> libc::time_t;
+}
+
+/// Converts a bash or zsh history file into entries. Bash optionally stores timestamps as
+/// `#<timestamp>` lines before each command, zsh's extended format prefixes commands with
+/// `: <timestamp>:<duration>;` and continues multi-line commands with a trailing backslash
+pub fn parse_foreign(content: &str) -> Vec<HistoryEntry> {
+    let mut entries = Vec::new();
+    let mut timestamp = 0;
+    let mut lines = content.lines();
+
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(stamp) = line.strip_prefix('#')
+            && let Ok(stamp) = stamp.trim().parse::<u64>()
+        {
+            timestamp = stamp;
+            continue;
+        }
+
+        let mut command = line.to_string();
+        if let Some(rest) = line.strip_prefix(": ")
+            && let Some((metadata, zsh_command)) = rest.split_once(';')
+            && let Some((stamp, _duration)) = metadata.split_once(':')
+            && let Ok(stamp) = stamp.parse::<u64>()
+        {
+            timestamp = stamp;
+            command = zsh_command.to_string();
+
+            // Our history is line based, so multi-line commands are joined back together
+            while command.ends_with('\\') {
+                command.pop();
+                match lines.next() {
+                    Some(next) => {
+                        command.push(' ');
+                        command.push_str(next);
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        entries.push(HistoryEntry {
+            command: command.trim().to_string(),
+            timestamp,
+            cwd: PathBuf::new(),
+            exit_code: None,
+            duration: None,
+            session: None,
+        });
+        timestamp = 0;
+    }
+
+    entries
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for character in value.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
 }
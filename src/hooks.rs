@@ -0,0 +1,123 @@
+//hooks.rs
+
+use crate::{context::Context, executor, shell::Shell, terminal::Terminal};
+use anyhow::Result;
+
+/// When a hook runs, named like zsh's hook functions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    /// Before every prompt
+    Precmd,
+    /// Before every command typed at the prompt, with the command as `$1`
+    Preexec,
+    /// After the directory changed, with the old one as `$1` and the new one as `$2`
+    Chpwd,
+}
+
+impl Hook {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "precmd" => Some(Self::Precmd),
+            "preexec" => Some(Self::Preexec),
+            "chpwd" => Some(Self::Chpwd),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Precmd => "precmd",
+            Self::Preexec => "preexec",
+            Self::Chpwd => "chpwd",
+        }
+    }
+}
+
+/// Commands registered with the `hook` builtin. An alias named like the hook (`precmd`,
+/// `preexec` or `chpwd`) is a hook too, run before the registered ones
+#[derive(Clone, Debug, Default)]
+pub struct Hooks {
+    precmd: Vec<String>,
+    preexec: Vec<String>,
+    chpwd: Vec<String>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, hook: Hook) -> &[String] {
+        match hook {
+            Hook::Precmd => &self.precmd,
+            Hook::Preexec => &self.preexec,
+            Hook::Chpwd => &self.chpwd,
+        }
+    }
+
+    pub fn add(&mut self, hook: Hook, command: &str) {
+        self.list(hook).push(command.to_string());
+    }
+
+    /// Removes a command from the hook, or all of them without one. False if there was
+    /// nothing to remove
+    pub fn remove(&mut self, hook: Hook, command: Option<&str>) -> bool {
+        let list = self.list(hook);
+        let before = list.len();
+        match command {
+            Some(command) => list.retain(|registered| registered != command),
+            None => list.clear(),
+        }
+        list.len() != before
+    }
+
+    fn list(&mut self, hook: Hook) -> &mut Vec<String> {
+        match hook {
+            Hook::Precmd => &mut self.precmd,
+            Hook::Preexec => &mut self.preexec,
+            Hook::Chpwd => &mut self.chpwd,
+        }
+    }
+}
+
+/// Runs the commands of a hook with the arguments as the positional parameters. They don't
+/// change `$?` or the positional parameters, and a failing one is reported without stopping
+/// the others
+pub fn run(
+    context: &mut Context,
+    terminal: &mut Terminal,
+    hook: Hook,
+    args: &[&str],
+) -> Result<()> {
+    let mut commands = Vec::new();
+    if context.aliases.get(hook.name()).is_some() {
+        commands.push(hook.name().to_string());
+    }
+    commands.extend(context.hooks.get(hook).iter().cloned());
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    let status = context.last_exit_code;
+    let positional = std::mem::replace(
+        &mut context.positional,
+        args.iter().map(|arg| arg.to_string()).collect(),
+    );
+    let raw = terminal.is_raw();
+    terminal.exit_raw_mode()?;
+
+    for command in commands {
+        let result = Shell::parse_command(context, terminal, &command, true)
+            .and_then(|command| executor::execute(context, terminal, command, None));
+        if let Err(error) = result {
+            eprintln!("{}: {}", hook.name(), error);
+        }
+    }
+
+    if raw {
+        terminal.enter_raw_mode()?;
+    }
+    context.positional = positional;
+    context.last_exit_code = status;
+    Ok(())
+}
@@ -1,6 +1,30 @@
-use crate::terminal::Terminal;
+use crate::{
+    sys::{Pid, RawFd},
+    terminal::Terminal,
+};
 use anyhow::{Ok, Result};
-use std::{collections::HashMap, fmt, os::fd::RawFd};
+#[cfg(unix)]
+use std::collections::HashSet;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Makes a process group the foreground one of the terminal. Without a terminal on stdin, like
+/// a script fed through a pipe or run by cron, there's nothing to hand over
+#[cfg(unix)]
+pub fn give_terminal(pgid: Pid) -> bool {
+    unsafe {
+        libc::isatty(libc::STDIN_FILENO) == 0 || libc::tcsetpgrp(libc::STDIN_FILENO, pgid) != -1
+    }
+}
+
+/// Every program on the Windows console reads from it, there's no foreground group to change
+#[cfg(windows)]
+pub fn give_terminal(_pgid: Pid) -> bool {
+    true
+}
 
 #[derive(Clone)]
 pub enum JobState {
@@ -20,18 +44,32 @@ impl fmt::Display for JobState {
 
 #[derive(Clone)]
 pub struct Job {
-    pub pgid: libc::pid_t,
+    pub pgid: Pid,
     pub remaining: usize,
-    pub pids: Vec<libc::pid_t>,
+    pub pids: Vec<Pid>,
     pub command: String,
     pub state: JobState,
     pub stdout_fd: Option<RawFd>, // Some for background, None for foreground
+    pub started: Instant,
+    /// Set with `disown`, leaving the shell doesn't wait for it or hang it up
+    pub disowned: bool,
+    // Status of the last process of the pipeline, once it's done
+    #[cfg_attr(windows, allow(dead_code))]
+    status: i32,
+}
+
+/// A job that ended while the shell wasn't waiting for it, kept until the shell had a look
+#[derive(Clone, Debug)]
+pub struct Finished {
+    pub command: String,
+    pub status: i32,
+    pub duration: Duration,
 }
 
 impl Job {
     pub fn new(
-        pgid: libc::pid_t,
-        pids: Vec<libc::pid_t>,
+        pgid: Pid,
+        pids: Vec<Pid>,
         command: String,
         state: JobState,
         stdout_fd: Option<RawFd>,
@@ -43,6 +81,9 @@ impl Job {
             command,
             state,
             stdout_fd,
+            started: Instant::now(),
+            disowned: false,
+            status: 0,
         }
     }
 
@@ -57,9 +98,13 @@ impl Job {
 #[derive(Clone)]
 pub struct Jobs {
     pub table: HashMap<usize, Job>,
-    pub pgid_to_id: HashMap<libc::pid_t, usize>,
-    pub pid_to_id: HashMap<libc::pid_t, usize>,
+    pub pgid_to_id: HashMap<Pid, usize>,
+    pub pid_to_id: HashMap<Pid, usize>,
     pub next_job_id: usize,
+    /// Jobs that ended since the shell last took them
+    pub finished: Vec<Finished>,
+    /// Leaving was put off because of the jobs, trying again right after leaves
+    pub exit_warned: bool,
 }
 
 impl Jobs {
@@ -69,6 +114,8 @@ impl Jobs {
             pgid_to_id: HashMap::new(),
             pid_to_id: HashMap::new(),
             next_job_id: 1,
+            finished: Vec::new(),
+            exit_warned: false,
         }
     }
 
@@ -100,43 +147,66 @@ impl Jobs {
         }
     }
 
-    pub fn get_entry(&mut self, pgid: libc::pid_t) -> Option<(usize, &mut Job)> {
+    pub fn get_entry(&mut self, pgid: Pid) -> Option<(usize, &mut Job)> {
         let &id = self.pgid_to_id.get(&pgid)?;
         let job = self.table.get_mut(&id)?;
         Some((id, job))
     }
 
-    pub fn get_entry_by_pid(&mut self, pid: libc::pid_t) -> Option<(usize, &mut Job)> {
+    pub fn get_entry_by_pid(&mut self, pid: Pid) -> Option<(usize, &mut Job)> {
         let &id = self.pid_to_id.get(&pid)?;
         let job = self.table.get_mut(&id)?;
         Some((id, job))
     }
 
+    /// Windows commands are waited for or left to run on their own, none is kept in the table
+    #[cfg(windows)]
+    pub fn update_table(&mut self, _terminal: &mut Terminal) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
     pub fn update_table(&mut self, terminal: &mut Terminal) -> Result<()> {
+        // Only the processes of jobs are waited for, the shell's own helpers (like the git
+        // of the prompt) are waited for by whoever started them
+        let pids: Vec<Pid> = self.pid_to_id.keys().copied().collect();
         unsafe {
-            loop {
+            for pid in pids {
                 let mut status = 0;
 
                 // For understanding this syscall refer here:
                 // https://man7.org/linux/man-pages/man3/wait.3p.html
                 // I would literlly just copy and paste the content otherwise
                 let pid = libc::waitpid(
-                    -1,
+                    pid,
                     &mut status,
                     libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED,
                 );
 
                 if pid <= 0 {
-                    break;
+                    continue;
                 }
 
                 if let Some((id, job)) = self.get_entry_by_pid(pid) {
                     let mut notification: Option<String> = None;
                     if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
                         job.remaining -= 1;
+                        if job.pids.last() == Some(&pid) {
+                            job.status = match libc::WIFEXITED(status) {
+                                true => libc::WEXITSTATUS(status),
+                                false => 128 + libc::WTERMSIG(status),
+                            };
+                        }
                         if job.remaining == 0 {
                             notification = Some(format!("[{}] Done      {}", id, job.command));
+                            tracing::debug!(id, pgid = job.pgid, status = job.status, "Job done");
+                            let finished = Finished {
+                                command: job.command.clone(),
+                                status: job.status,
+                                duration: job.started.elapsed(),
+                            };
                             self.remove(id);
+                            self.finished.push(finished);
                         }
                     } else if libc::WIFSTOPPED(status) {
                         job.state = JobState::Stopped;
@@ -155,6 +225,13 @@ impl Jobs {
         Ok(())
     }
 
+    // Background commands write straight to the console on Windows
+    #[cfg(windows)]
+    pub fn get_background_stdout(&mut self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    #[cfg(unix)]
     pub fn get_background_stdout(&mut self) -> Result<Vec<String>> {
         let mut stdout = Vec::new();
 
@@ -167,8 +244,15 @@ impl Jobs {
         Ok(stdout)
     }
 
+    #[cfg(unix)]
     pub fn job_stdout_from_fd(fd: RawFd) -> Result<String> {
-        let mut output = String::new();
+        Ok(String::from_utf8_lossy(&Self::job_bytes_from_fd(fd)?).into_owned())
+    }
+
+    /// Everything that can be read from the pipe without waiting
+    #[cfg(unix)]
+    pub fn job_bytes_from_fd(fd: RawFd) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
         let mut buf = [0u8; 4096];
 
         unsafe {
@@ -190,26 +274,35 @@ impl Jobs {
                     break; // EOF
                 }
 
-                output.push_str(&String::from_utf8_lossy(&buf[..n as usize]).into_owned());
+                output.extend_from_slice(&buf[..n as usize]);
             }
         }
 
         Ok(output)
     }
 
+    #[cfg(unix)]
     pub fn wait_foreground(
         &mut self,
-        shell_gpid: libc::pid_t,
+        shell_gpid: Pid,
         terminal: &mut Terminal,
-        pgid: libc::pid_t,
+        pgid: Pid,
         command: String,
-        pids: &[libc::pid_t],
+        pids: &[Pid],
         is_new_job: bool,
         is_subshell: bool,
     ) -> Result<i32> {
         let mut exit_code = 0;
         let mut stopped = false;
-        loop {
+        // Stages are waited on in whatever order they finish. Once none is left running the
+        // job is over, or stopped, waiting on stopped ones would block for good. Stages that
+        // finished before a stopped job went on again are long gone
+        let mut running: HashSet<Pid> = pids
+            .iter()
+            .copied()
+            .filter(|&pid| unsafe { libc::kill(pid, 0) } == 0)
+            .collect();
+        while pids.is_empty() || !running.is_empty() {
             let mut status: libc::c_int = 0;
 
             // We use -pgid because as we can read in the docs:
@@ -221,6 +314,7 @@ impl Jobs {
             if pid <= 0 {
                 break;
             }
+            running.remove(&pid);
 
             if libc::WIFEXITED(status) {
                 if !pids.is_empty() && *pids.last().unwrap() == pid {
@@ -236,16 +330,24 @@ impl Jobs {
         }
 
         if stopped {
-            let id;
-
-            if is_new_job {
-                let job = Job::new(pgid, pids.to_vec(), command, JobState::Stopped, None);
-                id = self.add(job);
-            } else {
-                let (job_id, job) = self.get_entry(pgid).unwrap();
-                job.state = JobState::Stopped;
-                id = job_id;
-            }
+            let existing = match is_new_job {
+                true => None,
+                false => self.get_entry(pgid),
+            };
+            let id = match existing {
+                Some((id, job)) => {
+                    job.state = JobState::Stopped;
+                    id
+                }
+                // A new job, or one that left the table while it ran
+                None => self.add(Job::new(
+                    pgid,
+                    pids.to_vec(),
+                    command,
+                    JobState::Stopped,
+                    None,
+                )),
+            };
 
             terminal.println(&format!("\r\n[{}] Stopped {}", id, pgid))?;
             exit_code = 148;
@@ -261,12 +363,57 @@ impl Jobs {
         // so i don't understand the real why behind but it seemed the most logical thing to do
         // and in fact, it worked
         if !is_subshell {
-            unsafe { libc::tcsetpgrp(libc::STDIN_FILENO, shell_gpid) };
+            give_terminal(shell_gpid);
         }
 
+        tracing::debug!(pgid, exit_code, stopped, "Waited");
         Ok(exit_code)
     }
 
+    /// Whether the shell can be left. With jobs still there the first try lists them instead,
+    /// like bash does, and the next one leaves
+    pub fn confirm_exit(&mut self, terminal: &mut Terminal) -> Result<bool> {
+        self.update_table(terminal)?;
+        let mut ids: Vec<usize> = self
+            .table
+            .iter()
+            .filter(|(_, job)| !job.disowned)
+            .map(|(&id, _)| id)
+            .collect();
+        if ids.is_empty() || self.exit_warned {
+            return Ok(true);
+        }
+        ids.sort_unstable();
+
+        terminal.println("There are jobs still running or stopped:")?;
+        for id in ids {
+            let job = &self.table[&id];
+            terminal.println(&format!(
+                "[{}] {:<8} {}",
+                id,
+                job.state.to_string(),
+                job.command
+            ))?;
+        }
+        terminal.println("Leave again (or `exit -f`) to end them")?;
+        self.exit_warned = true;
+        Ok(false)
+    }
+
+    /// Sends SIGHUP to every job as the terminal goes away, stopped ones are woken up to get it.
+    /// Disowned ones are left running
+    pub fn hang_up(&self) {
+        #[cfg(unix)]
+        for job in self.table.values().filter(|job| !job.disowned) {
+            unsafe {
+                libc::kill(-job.pgid, libc::SIGHUP);
+                if matches!(job.state, JobState::Stopped) {
+                    libc::kill(-job.pgid, libc::SIGCONT);
+                }
+            }
+        }
+    }
+
     pub fn get_last_job_id(&self) -> Option<usize> {
         self.table
             .iter()
@@ -0,0 +1,195 @@
+//keymap.rs
+
+use anyhow::{Result, anyhow};
+use crossterm::event::{KeyCode, KeyModifiers};
+use std::collections::HashMap;
+
+/// What the line editor can do in response to a key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    AcceptLine,
+    CancelLine,
+    ClearScreen,
+    FuzzySearch,
+    BackwardChar,
+    ForwardChar,
+    BackwardWord,
+    ForwardWord,
+    BeginningOfLine,
+    EndOfLine,
+    BackwardDeleteChar,
+    BackwardKillWord,
+    PreviousHistory,
+    NextHistory,
+    EditCommandLine,
+    EndOfFile,
+    Complete,
+    KillLine,
+    Yank,
+    CopyLine,
+    CopyKill,
+    PasteClipboard,
+}
+
+// Names used in the config file, the same as readline's where there's one
+const ACTIONS: [(&str, Action); 22] = [
+    ("accept-line", Action::AcceptLine),
+    ("cancel-line", Action::CancelLine),
+    ("clear-screen", Action::ClearScreen),
+    ("fuzzy-search", Action::FuzzySearch),
+    ("backward-char", Action::BackwardChar),
+    ("forward-char", Action::ForwardChar),
+    ("backward-word", Action::BackwardWord),
+    ("forward-word", Action::ForwardWord),
+    ("beginning-of-line", Action::BeginningOfLine),
+    ("end-of-line", Action::EndOfLine),
+    ("backward-delete-char", Action::BackwardDeleteChar),
+    ("backward-kill-word", Action::BackwardKillWord),
+    ("previous-history", Action::PreviousHistory),
+    ("next-history", Action::NextHistory),
+    ("edit-command-line", Action::EditCommandLine),
+    ("end-of-file", Action::EndOfFile),
+    ("complete", Action::Complete),
+    ("kill-line", Action::KillLine),
+    ("yank", Action::Yank),
+    ("copy-line", Action::CopyLine),
+    ("copy-kill", Action::CopyKill),
+    ("paste-clipboard", Action::PasteClipboard),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Self> {
+        ACTIONS
+            .iter()
+            .find(|(action_name, _)| *action_name == name)
+            .map(|(_, action)| *action)
+    }
+}
+
+/// Names of every action, as used in the config file
+pub fn action_names() -> impl Iterator<Item = &'static str> {
+    ACTIONS.iter().map(|(name, _)| *name)
+}
+
+type Key = (KeyCode, KeyModifiers);
+
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    bindings: HashMap<Key, Action>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let none = KeyModifiers::NONE;
+        let control = KeyModifiers::CONTROL;
+        let alt = KeyModifiers::ALT;
+        let defaults = [
+            ((KeyCode::Enter, none), Action::AcceptLine),
+            // What Enter arrives as when typed ahead while a command still had the terminal
+            ((KeyCode::Char('j'), control), Action::AcceptLine),
+            ((KeyCode::Char('c'), control), Action::CancelLine),
+            ((KeyCode::Char('l'), control), Action::ClearScreen),
+            ((KeyCode::Char('r'), control), Action::FuzzySearch),
+            ((KeyCode::Left, none), Action::BackwardChar),
+            ((KeyCode::Right, none), Action::ForwardChar),
+            ((KeyCode::Left, alt), Action::BackwardWord),
+            ((KeyCode::Right, alt), Action::ForwardWord),
+            ((KeyCode::Home, none), Action::BeginningOfLine),
+            ((KeyCode::End, none), Action::EndOfLine),
+            ((KeyCode::Backspace, none), Action::BackwardDeleteChar),
+            ((KeyCode::Backspace, alt), Action::BackwardKillWord),
+            ((KeyCode::Up, none), Action::PreviousHistory),
+            ((KeyCode::Down, none), Action::NextHistory),
+            ((KeyCode::Char('e'), alt), Action::EditCommandLine),
+            ((KeyCode::Char('d'), control), Action::EndOfFile),
+            ((KeyCode::Tab, none), Action::Complete),
+            ((KeyCode::Char('k'), control), Action::KillLine),
+            ((KeyCode::Char('y'), control), Action::Yank),
+            ((KeyCode::Char('w'), alt), Action::CopyLine),
+            ((KeyCode::Char('v'), alt), Action::PasteClipboard),
+        ];
+
+        Self {
+            bindings: defaults.into_iter().collect(),
+        }
+    }
+}
+
+impl Keymap {
+    /// Action bound to a key press, if any
+    pub fn get(&self, code: KeyCode, modifiers: KeyModifiers) -> Option<Action> {
+        self.bindings.get(&normalize(code, modifiers)).copied()
+    }
+
+    /// Binds a key like `ctrl-a`, `alt-b` or `up` to an action by name, `none` unbinds it
+    pub fn bind(&mut self, key: &str, action: &str) -> Result<()> {
+        let key = parse_key(key).ok_or_else(|| anyhow!("Unknown key '{}'", key))?;
+        if action == "none" {
+            self.bindings.remove(&key);
+            return Ok(());
+        }
+
+        let action =
+            Action::from_name(action).ok_or_else(|| anyhow!("Unknown action '{}'", action))?;
+        self.bindings.insert(key, action);
+        Ok(())
+    }
+}
+
+/// Parses keys written as modifiers and a key joined by dashes, like `ctrl-alt-x`
+pub fn parse_key(spec: &str) -> Option<Key> {
+    let mut parts: Vec<&str> = spec.split('-').collect();
+    // `alt--` is alt and the dash key
+    if spec.ends_with("--") {
+        parts.truncate(parts.len() - 2);
+        parts.push("-");
+    }
+    let name = parts.pop()?;
+
+    let mut modifiers = KeyModifiers::NONE;
+    for modifier in parts {
+        modifiers |= match modifier.to_lowercase().as_str() {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" | "meta" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+    }
+
+    let code = match name.to_lowercase().as_str() {
+        "enter" | "return" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "esc" | "escape" => KeyCode::Esc,
+        "space" => KeyCode::Char(' '),
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        _ => {
+            let mut chars = name.chars();
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            KeyCode::Char(c)
+        }
+    };
+    Some(normalize(code, modifiers))
+}
+
+// Terminals report Shift along with uppercase letters, the letter alone says it all. Ctrl
+// combinations don't have a case, Ctrl-R is the same as Ctrl-r
+fn normalize(code: KeyCode, modifiers: KeyModifiers) -> Key {
+    match code {
+        KeyCode::Char(c) if modifiers.contains(KeyModifiers::CONTROL) => (
+            KeyCode::Char(c.to_ascii_lowercase()),
+            modifiers - KeyModifiers::SHIFT,
+        ),
+        KeyCode::Char(c) => (KeyCode::Char(c), modifiers - KeyModifiers::SHIFT),
+        _ => (code, modifiers),
+    }
+}
@@ -1,15 +1,39 @@
 pub mod aliases;
+pub mod arithmetic;
 pub mod builtins;
+pub mod capture;
+pub mod clipboard;
+pub mod completion;
+pub mod conditional;
 pub mod context;
+pub mod correction;
+pub mod crypto;
+pub mod debugger;
+pub mod direnv;
 pub mod editor;
+pub mod encoding;
 pub mod error;
 pub mod executor;
 pub mod expander;
+pub mod frecency;
 pub mod history;
+pub mod hooks;
 pub mod jobs;
+pub mod keymap;
+pub mod logging;
+pub mod notify;
 pub mod parser;
+pub mod path_index;
+pub mod plugins;
 pub mod prompt;
+pub mod record;
+pub mod safety;
+pub mod segments;
+pub mod settings;
 pub mod shell;
 pub mod signals;
+pub mod sys;
 pub mod terminal;
+pub mod theme;
 pub mod tokenizer;
+pub mod variables;
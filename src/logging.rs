@@ -0,0 +1,66 @@
+//logging.rs
+
+use crate::history;
+use anyhow::{Context as AnyhowContext, Result, anyhow};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    path::PathBuf,
+    sync::Mutex,
+};
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+
+/// Where the log is written: `RSHELL_LOG_FILE`, or `rshell.log` next to the history
+pub fn log_path() -> PathBuf {
+    env::var_os("RSHELL_LOG_FILE")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| history::data_dir().join("rshell.log"))
+}
+
+/// What gets logged. `--log-level` takes a level for the whole shell and wins over
+/// `RSHELL_LOG`, which also takes directives like `rshell::parser=trace,rshell=info`.
+/// None when neither is given, logging is off by default
+pub fn filter(level: Option<Level>) -> Result<Option<EnvFilter>> {
+    let directives = match (level, env::var("RSHELL_LOG")) {
+        (Some(level), _) => format!("rshell={}", level.as_str().to_lowercase()),
+        (None, Ok(directives)) if !directives.trim().is_empty() => directives,
+        _ => return Ok(None),
+    };
+    EnvFilter::try_new(&directives)
+        .map(Some)
+        .map_err(|error| anyhow!("Invalid log filter '{}': {}", directives, error))
+}
+
+/// Starts writing the spans and events of the parser, expander, executor and history to the
+/// log file, when asked to. Several shells can share the file, every line has the time and
+/// each session starts with a line giving its pid
+pub fn init(level: Option<Level>) -> Result<()> {
+    let Some(filter) = filter(level)? else {
+        return Ok(());
+    };
+    let path = log_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context("Failed to create log directory")?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open log file {}", path.display()))?;
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(Mutex::new(file))
+        .with_ansi(false)
+        .with_target(true)
+        .try_init()
+        .map_err(|error| anyhow!("Failed to start logging: {}", error))?;
+    tracing::info!(
+        pid = std::process::id(),
+        version = env!("CARGO_PKG_VERSION"),
+        "Started"
+    );
+    Ok(())
+}
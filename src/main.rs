@@ -1,9 +1,33 @@
 //main.rs
 
-use rshell::shell::Shell;
+use rshell::{
+    context::{Input, Mode},
+    encoding, logging,
+    shell::Shell,
+};
+use std::{env, io::IsTerminal};
 
 fn main() {
-    let mut shell = match Shell::new() {
+    // Arguments don't have to be UTF-8, they're read like any other text
+    let args: Vec<String> = env::args_os()
+        .map(|arg| encoding::from_os_str(&arg))
+        .collect();
+    let mode = Mode::from_args(&args, std::io::stdin().is_terminal());
+    let (input, positional) = match Input::from_args(&args) {
+        Ok(input) => input,
+        Err(error) => {
+            eprintln!("rshell: {}", error);
+            // The status sh uses for wrong usage
+            std::process::exit(2)
+        }
+    };
+
+    // The shell works without its log, a problem with it is only worth a mention
+    if let Err(error) = logging::init(mode.log_level) {
+        eprintln!("rshell: {:#}", error);
+    }
+
+    let mut shell = match Shell::new(mode) {
         Ok(shell) => shell,
         Err(error) => {
             eprintln!("Failed to start up RShell: {:?}", error);
@@ -11,8 +35,45 @@ fn main() {
         }
     };
 
-    if let Err(e) = shell.run() {
-        eprintln!("Critical Shell Error: {:?}", e);
-        std::process::exit(1);
+    shell.context.positional = positional;
+
+    match input {
+        Input::Script(path) => match shell.run_script(&path) {
+            Ok(status) => std::process::exit(status),
+            Err(error) => {
+                eprintln!("rshell: {:#}", error);
+                // Like sh, a script that can't be read is as good as a missing command
+                std::process::exit(127)
+            }
+        },
+        Input::Command { command, name } => {
+            if let Some(name) = name {
+                shell.context.name = name;
+            }
+            match shell.run_command(&command) {
+                Ok(status) => std::process::exit(status),
+                Err(error) => {
+                    eprintln!("rshell: {:#}", error);
+                    std::process::exit(1)
+                }
+            }
+        }
+        // Commands piped in, there's no terminal for a prompt
+        Input::Stdin if !shell.context.mode.interactive => match shell.run_batch() {
+            Ok(status) => std::process::exit(status),
+            Err(error) => {
+                eprintln!("rshell: {:#}", error);
+                std::process::exit(1)
+            }
+        },
+        Input::Stdin => {}
+    }
+
+    match shell.run() {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            eprintln!("Critical Shell Error: {:?}", e);
+            std::process::exit(1);
+        }
     }
 }
@@ -0,0 +1,193 @@
+//notify.rs
+
+use crate::{context::Context, history, jobs::Finished, terminal::Terminal};
+use anyhow::{Context as AnyhowContext, Result, anyhow};
+use std::{
+    env,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+/// How long a command has to run to be worth a notification, from `NOTIFY_AFTER` or the
+/// config file. None when notifications are off, which they are by default
+pub fn threshold(context: &Context) -> Option<Duration> {
+    env::var("NOTIFY_AFTER")
+        .ok()
+        .or_else(|| context.settings.notifications.after.clone())
+        .and_then(|value| history::parse_duration(&value))
+        .map(Duration::from_millis)
+}
+
+/// How long a command has to run to have its time reported, from `REPORTTIME` or the config
+/// file. Plain numbers are seconds, like in zsh. None when reports are off, the default
+pub fn report_threshold(context: &Context) -> Option<Duration> {
+    context
+        .variables
+        .get("REPORTTIME")
+        .or_else(|| context.settings.notifications.report_time.clone())
+        .and_then(|value| history::parse_duration(&value))
+        .map(Duration::from_millis)
+}
+
+/// CPU time spent by the shell and the programs it waited for, what a command used is the
+/// difference from before it ran
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Usage {
+    pub user: Duration,
+    pub system: Duration,
+}
+
+impl Usage {
+    #[cfg(unix)]
+    pub fn now() -> Self {
+        let mut usage = Self::default();
+        for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+            let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+            if unsafe { libc::getrusage(who, &mut rusage) } == 0 {
+                usage.user += timeval(rusage.ru_utime);
+                usage.system += timeval(rusage.ru_stime);
+            }
+        }
+        usage
+    }
+
+    // Windows doesn't count the time of the children that were waited for
+    #[cfg(windows)]
+    pub fn now() -> Self {
+        Self::default()
+    }
+
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            user: self.user.saturating_sub(earlier.user),
+            system: self.system.saturating_sub(earlier.system),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn timeval(time: libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+}
+
+/// The line reported for a command that ran longer than `REPORTTIME`, like zsh's
+/// `make  12.31s user 1.02s system 87% cpu 15.2s total`
+pub fn report(command: &str, elapsed: Duration, usage: Usage) -> String {
+    let cpu = (usage.user + usage.system).as_secs_f64();
+    let percent = match elapsed.as_secs_f64() {
+        0.0 => 0.0,
+        total => cpu / total * 100.0,
+    };
+    format!(
+        "{}  {:.2}s user {:.2}s system {:.0}% cpu {} total",
+        command,
+        usage.user.as_secs_f64(),
+        usage.system.as_secs_f64(),
+        percent,
+        history::format_duration(elapsed.as_millis() as u64)
+    )
+}
+
+/// Sends a desktop notification for a command that ran longer than the threshold, unless the
+/// terminal is known to have the focus, in which case it was seen finishing
+pub fn command_finished(
+    context: &Context,
+    terminal: &Terminal,
+    command: &str,
+    status: i32,
+    duration: Duration,
+) {
+    let Some(threshold) = threshold(context) else {
+        return;
+    };
+    if duration < threshold || focused(terminal) == Some(true) {
+        return;
+    }
+
+    let title = match status {
+        0 => String::from("Command finished"),
+        _ => format!("Command failed with status {}", status),
+    };
+    let body = format!(
+        "{}\ntook {}",
+        command,
+        history::format_duration(duration.as_millis() as u64)
+    );
+    // There's no good place to report it, a notification that can't be sent is let go
+    let _ = send(&title, &body);
+}
+
+/// Notifications for the background jobs that ended since the last look
+pub fn jobs_finished(context: &mut Context, terminal: &mut Terminal) -> Result<()> {
+    while !context.jobs.finished.is_empty() {
+        let finished: Vec<Finished> = context.jobs.finished.drain(..).collect();
+        for job in finished {
+            command_finished(context, terminal, &job.command, job.status, job.duration);
+        }
+        // The notifier isn't a job, its end shouldn't look like one finishing
+        if context.signals.drain_child_pipe() {
+            context.jobs.update_table(terminal)?;
+        }
+    }
+    Ok(())
+}
+
+/// Shows a notification with `notify-send` (which goes through D-Bus on Linux and BSD) or
+/// with `osascript` on macOS
+pub fn send(title: &str, body: &str) -> Result<()> {
+    let mut command = match () {
+        _ if in_path("notify-send") => {
+            let mut command = Command::new("notify-send");
+            command.args(["--app-name=rshell", title, body]);
+            command
+        }
+        _ if in_path("osascript") => {
+            let script = format!(
+                "display notification {} with title {}",
+                apple_string(body),
+                apple_string(title)
+            );
+            let mut command = Command::new("osascript");
+            command.args(["-e", &script]);
+            command
+        }
+        _ => return Err(anyhow!("No notify-send or osascript to send notifications")),
+    };
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .context("Failed to send the notification")?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(anyhow!("The notification wasn't sent")),
+    }
+}
+
+// Whether the terminal has the focus, from its focus reports or else from X, where the
+// window the terminal gave in WINDOWID can be compared with the active one
+fn focused(terminal: &Terminal) -> Option<bool> {
+    if let Some(focused) = terminal.focused() {
+        return Some(focused);
+    }
+    let window = env::var("WINDOWID").ok()?;
+    env::var_os("DISPLAY")?;
+    let output = Command::new("xdotool")
+        .arg("getactivewindow")
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).trim() == window.trim())
+}
+
+// A string for AppleScript, which only escapes quotes and backslashes
+fn apple_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn in_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
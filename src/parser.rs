@@ -1,5 +1,6 @@
 //parser.rs
 
+use crate::encoding;
 use crate::error::{ShellError, ShellPhase};
 use crate::tokenizer::Token;
 use anyhow::{Context, Ok, Result};
@@ -7,7 +8,6 @@ use std::ffi::CString;
 use std::{
     borrow::Cow,
     fmt::{self},
-    iter::Peekable,
 };
 
 #[derive(Clone, Debug)]
@@ -21,6 +21,17 @@ impl<'a> EnvVariable<'a> {
         Self { name, value }
     }
 
+    pub fn into_owned(self) -> EnvVariable<'static> {
+        EnvVariable::new(
+            Cow::Owned(self.name.into_owned()),
+            Cow::Owned(self.value.into_owned()),
+        )
+    }
+
+    pub fn borrowed(&self) -> EnvVariable<'_> {
+        EnvVariable::new(Cow::Borrowed(&self.name), Cow::Borrowed(&self.value))
+    }
+
     pub fn strip_quotes_from_value(value: &str) -> &str {
         if (value.starts_with('\'') && value.ends_with('\''))
             || (value.starts_with('"') && value.ends_with('"'))
@@ -33,7 +44,7 @@ impl<'a> EnvVariable<'a> {
 
     pub fn to_cstring(name: &str, value: &str) -> Result<CString> {
         let formatted = format!("{}={}", name, value);
-        CString::new(formatted).context(format!(
+        encoding::to_cstring(&formatted).context(format!(
             "Failed to convert environment variable {}={} to CString",
             name, value
         ))
@@ -44,6 +55,9 @@ impl<'a> EnvVariable<'a> {
 pub enum RedirectTarget<'a> {
     File(Cow<'a, str>),
     FileDescriptor(u8),
+    // The body of a here-document, expand is false once it's expanded or when the word
+    // after `<<` was quoted
+    Text { body: Cow<'a, str>, expand: bool },
 }
 
 impl<'a> fmt::Display for RedirectTarget<'a> {
@@ -51,6 +65,7 @@ impl<'a> fmt::Display for RedirectTarget<'a> {
         match self {
             RedirectTarget::File(path) => write!(f, "{}", path),
             RedirectTarget::FileDescriptor(fd) => write!(f, "&{}", fd),
+            RedirectTarget::Text { body, .. } => write!(f, "{:?}", body),
         }
     }
 }
@@ -61,7 +76,10 @@ pub enum RedirectKind {
     Append,    // >>
     In,        // <
     Err,       // 2>
+    ErrAppend, // 2>>
     ErrAndOut, // 2>&1
+    All,       // &>
+    HereDoc,   // <<
 }
 
 impl fmt::Display for RedirectKind {
@@ -71,7 +89,10 @@ impl fmt::Display for RedirectKind {
             RedirectKind::Append => write!(f, ">>"),
             RedirectKind::In => write!(f, "<"),
             RedirectKind::Err => write!(f, "2>"),
+            RedirectKind::ErrAppend => write!(f, "2>>"),
             RedirectKind::ErrAndOut => write!(f, "2>&1"),
+            RedirectKind::All => write!(f, "&>"),
+            RedirectKind::HereDoc => write!(f, "<<"),
         }
     }
 }
@@ -83,7 +104,9 @@ impl RedirectKind {
             Token::RedirectAppend => Some(RedirectKind::Append),
             Token::RedirectIn => Some(RedirectKind::In),
             Token::RedirectErr => Some(RedirectKind::Err),
+            Token::RedirectErrAppend => Some(RedirectKind::ErrAppend),
             Token::RedirectErrAndOut => Some(RedirectKind::ErrAndOut),
+            Token::RedirectAll => Some(RedirectKind::All),
             _ => None,
         }
     }
@@ -96,6 +119,36 @@ pub struct Redirect<'a> {
 }
 
 impl<'a> Redirect<'a> {
+    pub fn into_owned(self) -> Redirect<'static> {
+        let target = match self.target {
+            RedirectTarget::File(path) => RedirectTarget::File(Cow::Owned(path.into_owned())),
+            RedirectTarget::FileDescriptor(fd) => RedirectTarget::FileDescriptor(fd),
+            RedirectTarget::Text { body, expand } => RedirectTarget::Text {
+                body: Cow::Owned(body.into_owned()),
+                expand,
+            },
+        };
+        Redirect {
+            kind: self.kind,
+            target,
+        }
+    }
+
+    pub fn borrowed(&self) -> Redirect<'_> {
+        let target = match &self.target {
+            RedirectTarget::File(path) => RedirectTarget::File(Cow::Borrowed(path)),
+            RedirectTarget::FileDescriptor(fd) => RedirectTarget::FileDescriptor(*fd),
+            RedirectTarget::Text { body, expand } => RedirectTarget::Text {
+                body: Cow::Borrowed(body),
+                expand: *expand,
+            },
+        };
+        Redirect {
+            kind: self.kind.clone(),
+            target,
+        }
+    }
+
     pub fn get_target_path(&self) -> Option<&str> {
         match &self.target {
             RedirectTarget::File(cow) => Some(cow.as_ref()),
@@ -128,11 +181,32 @@ impl<'a> Arg<'a> {
             Arg::Word(s) | Arg::SingleQuoted(s) | Arg::DoubleQuoted(s) => s.as_ref(),
         }
     }
+
+    pub fn into_owned(self) -> Arg<'static> {
+        match self {
+            Arg::Word(s) => Arg::Word(Cow::Owned(s.into_owned())),
+            Arg::SingleQuoted(s) => Arg::SingleQuoted(Cow::Owned(s.into_owned())),
+            Arg::DoubleQuoted(s) => Arg::DoubleQuoted(Cow::Owned(s.into_owned())),
+        }
+    }
+
+    pub fn borrowed(&self) -> Arg<'_> {
+        match self {
+            Arg::Word(s) => Arg::Word(Cow::Borrowed(s)),
+            Arg::SingleQuoted(s) => Arg::SingleQuoted(Cow::Borrowed(s)),
+            Arg::DoubleQuoted(s) => Arg::DoubleQuoted(Cow::Borrowed(s)),
+        }
+    }
 }
 
 impl<'a> fmt::Display for Arg<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            // A backslash at the end of the line escapes nothing, printed as it is it would
+            // escape what comes after the word
+            Arg::Word(s) if (s.len() - s.trim_end_matches('\\').len()) % 2 == 1 => {
+                write!(f, "{}\\", s)
+            }
             Arg::Word(s) => write!(f, "{}", s),
             Arg::SingleQuoted(s) => write!(f, "'{}'", s),
             Arg::DoubleQuoted(s) => write!(f, "\"{}\"", s),
@@ -177,8 +251,89 @@ pub enum Command<'a> {
     Sequence(Box<Command<'a>>, Box<Command<'a>>),
     Background(Box<Command<'a>>),
     Subshell(Box<Command<'a>>),
+    /// `for name in words; do body; done`, the words and the body are only expanded when the
+    /// loop runs. Without `in` it goes through the positional parameters
+    For {
+        variable: Cow<'a, str>,
+        words: Option<Vec<Arg<'a>>>,
+        body: Box<Command<'a>>,
+    },
+    /// `for ((init; condition; step)); do body; done`
+    ArithmeticFor {
+        init: Cow<'a, str>,
+        condition: Cow<'a, str>,
+        step: Cow<'a, str>,
+        body: Box<Command<'a>>,
+    },
+    /// `case word in pattern) body;; esac`, runs the body of the first item with a pattern
+    /// matching the word
+    Case {
+        word: Arg<'a>,
+        items: Vec<CaseItem<'a>>,
+    },
+    /// `select name in words; do body; done`, shows the words as a numbered menu and runs the
+    /// body with the one picked until a `break`
+    Select {
+        variable: Cow<'a, str>,
+        words: Option<Vec<Arg<'a>>>,
+        body: Box<Command<'a>>,
+    },
+    /// `((expression))`, succeeds when the arithmetic expression isn't 0
+    Arithmetic(Cow<'a, str>),
+    /// `[[ condition ]]`, the words between the brackets. Operators like `&&` and `(` are
+    /// unquoted words, so a quoted `"&&"` is still a plain string
+    Conditional(Vec<Arg<'a>>),
+    /// `name=value` on its own, sets shell variables without running anything
+    Assignment(Vec<EnvVariable<'a>>),
+    /// `name=(values...)` sets an array, `name+=(values...)` adds to the end of it
+    ArrayAssignment {
+        name: Cow<'a, str>,
+        values: Vec<Arg<'a>>,
+        append: bool,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct CaseItem<'a> {
+    /// Glob patterns separated by `|`, any of them can match
+    pub patterns: Vec<Arg<'a>>,
+    pub body: Option<Command<'a>>,
+    /// Ended by `;&` instead of `;;`, the next body runs too without checking its patterns
+    pub fall_through: bool,
 }
 
+impl<'a> CaseItem<'a> {
+    pub fn into_owned(self) -> CaseItem<'static> {
+        CaseItem {
+            patterns: self.patterns.into_iter().map(Arg::into_owned).collect(),
+            body: self.body.map(Command::into_owned),
+            fall_through: self.fall_through,
+        }
+    }
+
+    pub fn borrowed(&self) -> CaseItem<'_> {
+        CaseItem {
+            patterns: self.patterns.iter().map(Arg::borrowed).collect(),
+            body: self.body.as_ref().map(Command::borrowed),
+            fall_through: self.fall_through,
+        }
+    }
+}
+
+impl<'a> fmt::Display for CaseItem<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let patterns: Vec<String> = self.patterns.iter().map(Arg::to_string).collect();
+        write!(f, "{})", patterns.join("|"))?;
+        if let Some(body) = &self.body {
+            write!(f, " {}", body.to_string())?;
+        }
+        write!(f, "{}", if self.fall_through { ";&" } else { ";;" })
+    }
+}
+
+// Words that close a list of commands, they can't be used as a command name
+const LIST_TERMINATORS: [&str; 2] = ["done", "esac"];
+
 impl<'a> Command<'a> {
     pub fn to_string(&self) -> String {
         match self {
@@ -186,9 +341,13 @@ impl<'a> Command<'a> {
                 command,
                 args,
                 redirects,
-                env_vars: _,
+                env_vars,
             } => {
-                let mut result = command.to_string();
+                let mut result = String::new();
+                for variable in env_vars {
+                    result.push_str(&format!("{}={} ", variable.name, variable.value));
+                }
+                result.push_str(command);
 
                 for arg in args {
                     result.push_str(&format!(" {}", arg));
@@ -218,51 +377,338 @@ impl<'a> Command<'a> {
             Command::Subshell(command) => {
                 format!("({})", command.to_string())
             }
+            Command::For {
+                variable,
+                words,
+                body,
+            }
+            | Command::Select {
+                variable,
+                words,
+                body,
+            } => {
+                let keyword = match self {
+                    Command::Select { .. } => "select",
+                    _ => "for",
+                };
+                let mut result = format!("{} {}", keyword, variable);
+                if let Some(words) = words {
+                    result.push_str(" in");
+                    for word in words {
+                        result.push_str(&format!(" {}", word));
+                    }
+                }
+                format!("{}; do {}; done", result, body.to_string())
+            }
+            Command::ArithmeticFor {
+                init,
+                condition,
+                step,
+                body,
+            } => {
+                format!(
+                    "for (({};{};{})); do {}; done",
+                    init,
+                    condition,
+                    step,
+                    body.to_string()
+                )
+            }
+            Command::Case { word, items } => {
+                let mut result = format!("case {} in", word);
+                for item in items {
+                    result.push_str(&format!(" {}", item));
+                }
+                format!("{} esac", result)
+            }
+            Command::Arithmetic(expression) => format!("(({}))", expression),
+            Command::Conditional(args) => {
+                let args: Vec<String> = args.iter().map(Arg::to_string).collect();
+                format!("[[ {} ]]", args.join(" "))
+            }
+            Command::Assignment(variables) => {
+                let variables: Vec<String> = variables
+                    .iter()
+                    .map(|variable| format!("{}={}", variable.name, variable.value))
+                    .collect();
+                variables.join(" ")
+            }
+            Command::ArrayAssignment {
+                name,
+                values,
+                append,
+            } => {
+                let values: Vec<String> = values.iter().map(Arg::to_string).collect();
+                let operator = if *append { "+=" } else { "=" };
+                format!("{}{}({})", name, operator, values.join(" "))
+            }
+        }
+    }
+
+    /// Copies everything the command borrows from the line, without expanding anything
+    pub fn into_owned(self) -> Command<'static> {
+        let owned = |command: Box<Command<'a>>| Box::new(command.into_owned());
+        match self {
+            Command::Simple {
+                command,
+                args,
+                redirects,
+                env_vars,
+            } => Command::Simple {
+                command: Cow::Owned(command.into_owned()),
+                args: args.into_iter().map(Arg::into_owned).collect(),
+                redirects: redirects.into_iter().map(Redirect::into_owned).collect(),
+                env_vars: env_vars.into_iter().map(EnvVariable::into_owned).collect(),
+            },
+            Command::Pipeline(left, right) => Command::Pipeline(owned(left), owned(right)),
+            Command::And(left, right) => Command::And(owned(left), owned(right)),
+            Command::Or(left, right) => Command::Or(owned(left), owned(right)),
+            Command::Sequence(left, right) => Command::Sequence(owned(left), owned(right)),
+            Command::Background(command) => Command::Background(owned(command)),
+            Command::Subshell(command) => Command::Subshell(owned(command)),
+            Command::For {
+                variable,
+                words,
+                body,
+            } => Command::For {
+                variable: Cow::Owned(variable.into_owned()),
+                words: words.map(|words| words.into_iter().map(Arg::into_owned).collect()),
+                body: owned(body),
+            },
+            Command::ArithmeticFor {
+                init,
+                condition,
+                step,
+                body,
+            } => Command::ArithmeticFor {
+                init: Cow::Owned(init.into_owned()),
+                condition: Cow::Owned(condition.into_owned()),
+                step: Cow::Owned(step.into_owned()),
+                body: owned(body),
+            },
+            Command::Case { word, items } => Command::Case {
+                word: word.into_owned(),
+                items: items.into_iter().map(CaseItem::into_owned).collect(),
+            },
+            Command::Select {
+                variable,
+                words,
+                body,
+            } => Command::Select {
+                variable: Cow::Owned(variable.into_owned()),
+                words: words.map(|words| words.into_iter().map(Arg::into_owned).collect()),
+                body: owned(body),
+            },
+            Command::Arithmetic(expression) => {
+                Command::Arithmetic(Cow::Owned(expression.into_owned()))
+            }
+            Command::Conditional(args) => {
+                Command::Conditional(args.into_iter().map(Arg::into_owned).collect())
+            }
+            Command::Assignment(variables) => {
+                Command::Assignment(variables.into_iter().map(EnvVariable::into_owned).collect())
+            }
+            Command::ArrayAssignment {
+                name,
+                values,
+                append,
+            } => Command::ArrayAssignment {
+                name: Cow::Owned(name.into_owned()),
+                values: values.into_iter().map(Arg::into_owned).collect(),
+                append,
+            },
+        }
+    }
+
+    /// The same command borrowing from this one, expanding it doesn't need a copy first
+    pub fn borrowed(&self) -> Command<'_> {
+        fn borrowed<'b>(command: &'b Command<'_>) -> Box<Command<'b>> {
+            Box::new(command.borrowed())
+        }
+        match self {
+            Command::Simple {
+                command,
+                args,
+                redirects,
+                env_vars,
+            } => Command::Simple {
+                command: Cow::Borrowed(command),
+                args: args.iter().map(Arg::borrowed).collect(),
+                redirects: redirects.iter().map(Redirect::borrowed).collect(),
+                env_vars: env_vars.iter().map(EnvVariable::borrowed).collect(),
+            },
+            Command::Pipeline(left, right) => Command::Pipeline(borrowed(left), borrowed(right)),
+            Command::And(left, right) => Command::And(borrowed(left), borrowed(right)),
+            Command::Or(left, right) => Command::Or(borrowed(left), borrowed(right)),
+            Command::Sequence(left, right) => Command::Sequence(borrowed(left), borrowed(right)),
+            Command::Background(command) => Command::Background(borrowed(command)),
+            Command::Subshell(command) => Command::Subshell(borrowed(command)),
+            Command::For {
+                variable,
+                words,
+                body,
+            } => Command::For {
+                variable: Cow::Borrowed(variable),
+                words: words
+                    .as_ref()
+                    .map(|words| words.iter().map(Arg::borrowed).collect()),
+                body: borrowed(body),
+            },
+            Command::ArithmeticFor {
+                init,
+                condition,
+                step,
+                body,
+            } => Command::ArithmeticFor {
+                init: Cow::Borrowed(init),
+                condition: Cow::Borrowed(condition),
+                step: Cow::Borrowed(step),
+                body: borrowed(body),
+            },
+            Command::Case { word, items } => Command::Case {
+                word: word.borrowed(),
+                items: items.iter().map(CaseItem::borrowed).collect(),
+            },
+            Command::Select {
+                variable,
+                words,
+                body,
+            } => Command::Select {
+                variable: Cow::Borrowed(variable),
+                words: words
+                    .as_ref()
+                    .map(|words| words.iter().map(Arg::borrowed).collect()),
+                body: borrowed(body),
+            },
+            Command::Arithmetic(expression) => Command::Arithmetic(Cow::Borrowed(expression)),
+            Command::Conditional(args) => {
+                Command::Conditional(args.iter().map(Arg::borrowed).collect())
+            }
+            Command::Assignment(variables) => {
+                Command::Assignment(variables.iter().map(EnvVariable::borrowed).collect())
+            }
+            Command::ArrayAssignment {
+                name,
+                values,
+                append,
+            } => Command::ArrayAssignment {
+                name: Cow::Borrowed(name),
+                values: values.iter().map(Arg::borrowed).collect(),
+                append: *append,
+            },
         }
     }
 }
 
 pub struct Parser<'a> {
-    tokens: Peekable<std::slice::Iter<'a, Token<'a>>>,
+    tokens: Tokens<'a>,
+}
+
+// The tokens left to parse, remembering the furthest one looked at which is where an error
+// is reported
+#[derive(Clone)]
+struct Tokens<'a> {
+    tokens: &'a [Token<'a>],
+    position: usize,
+    furthest: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn peek(&mut self) -> Option<&'a Token<'a>> {
+        self.furthest = self.furthest.max(self.position);
+        self.tokens.get(self.position)
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.peek()?;
+        self.position += 1;
+        Some(token)
+    }
 }
 
 impl<'a> Parser<'a> {
     pub fn parse(tokens: &'a [Token]) -> Result<Command<'a>> {
-        Self {
-            tokens: tokens.iter().peekable(),
-        }
-        .run()
+        Self::parse_with_position(tokens).map_err(|(error, _)| error)
+    }
+
+    /// Like `parse`, an error also comes with the index of the token it was found at, or the
+    /// number of tokens when the input ended too soon
+    pub fn parse_with_position(
+        tokens: &'a [Token],
+    ) -> std::result::Result<Command<'a>, (anyhow::Error, usize)> {
+        let mut parser = Self {
+            tokens: Tokens {
+                tokens,
+                position: 0,
+                furthest: 0,
+            },
+        };
+        parser
+            .run()
+            .inspect(|command| tracing::trace!(command = %command.to_string(), "Parsed"))
+            .map_err(|error| (error, parser.tokens.furthest.min(tokens.len())))
+            .inspect_err(
+                |(error, position)| tracing::debug!(%error, position, ?tokens, "Failed to parse"),
+            )
     }
 
     pub fn run(&mut self) -> Result<Command<'a>> {
-        if self.tokens.peek().is_some() {
-            self.parse_sequence()
-        } else {
-            Parser::error("Empty input: no tokens found to parse")
+        if self.tokens.peek().is_none() {
+            return Parser::error("Empty input: no tokens found to parse");
+        }
+
+        let command = self.parse_sequence()?;
+        match self.tokens.peek() {
+            Some(token) => Parser::error(&format!("Syntax error: unexpected '{}'", token)),
+            None => Ok(command),
         }
     }
 
     fn parse_sequence(&mut self) -> Result<Command<'a>> {
+        self.skip_newlines();
         let mut left = self.parse_and_or()?;
+        let mut background = ends_in_background(&left);
 
         while let Some(token) = self.tokens.peek() {
             if matches!(token, Token::Semicolon | Token::Newline) {
                 self.tokens.next();
+                self.skip_newlines();
+            } else if !background {
+                break;
+            }
 
-                if self.tokens.peek().is_none() {
-                    break;
-                }
-
-                let right = self.parse_and_or()?;
-                left = Command::Sequence(Box::new(left), Box::new(right));
-            } else {
+            // `a; b; done`, `(a; b)` or `a;;` in a case end the list without anything after
+            // the last `;`
+            if self.at_list_end() {
                 break;
             }
+
+            let right = self.parse_and_or()?;
+            background = ends_in_background(&right);
+            left = Command::Sequence(Box::new(left), Box::new(right));
         }
 
         Ok(left)
     }
 
+    fn at_list_end(&mut self) -> bool {
+        match self.tokens.peek() {
+            None | Some(Token::RightParen | Token::DoubleSemicolon | Token::SemicolonAnd) => true,
+            Some(Token::Word(word)) => LIST_TERMINATORS.contains(word),
+            _ => false,
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while matches!(self.tokens.peek(), Some(Token::Newline)) {
+            self.tokens.next();
+        }
+    }
+
     fn parse_and_or(&mut self) -> Result<Command<'a>> {
         let mut left = self.parse_pipeline()?;
         while let Some(token) = self.tokens.peek() {
@@ -306,20 +752,56 @@ impl<'a> Parser<'a> {
     fn parse_command(&mut self) -> Result<Command<'a>> {
         use Token::*;
 
+        // `name=(` starts an array, not a variable followed by a subshell
+        if let Some(Word(word)) = self.tokens.peek()
+            && let Some(name) = word.strip_suffix('=')
+            && is_name(name.strip_suffix('+').unwrap_or(name))
+            && matches!(self.tokens.clone().nth(1), Some(LeftParen))
+        {
+            return self.parse_array_assignment();
+        }
+
         let env_vars = self.parse_env_vars()?;
 
         if matches!(self.tokens.peek(), Some(LeftParen)) {
             return self.parse_subshell();
         }
 
+        // Variables without a command are set in the shell
+        if !env_vars.is_empty()
+            && self
+                .tokens
+                .peek()
+                .is_none_or(|token| token.is_operator() || matches!(token, RightParen))
+        {
+            return Ok(Command::Assignment(env_vars));
+        }
+
+        if env_vars.is_empty() {
+            match self.tokens.peek() {
+                Some(Word("for")) => return self.parse_for(),
+                Some(Word("select")) => return self.parse_select(),
+                Some(Arithmetic(expression)) => {
+                    self.tokens.next();
+                    return Ok(Command::Arithmetic(Cow::Borrowed(expression)));
+                }
+                Some(Word("[[")) => return self.parse_conditional(),
+                Some(Word("case")) => return self.parse_case(),
+                _ => {}
+            }
+        }
+
         let command = match self.tokens.next() {
+            Some(Word(command_name)) if LIST_TERMINATORS.contains(command_name) => {
+                return Parser::error(&format!("Syntax error: unexpected '{}'", command_name));
+            }
             Some(Word(command_name)) => command_name,
             Some(_) => {
                 return Parser::error(
                     "Syntax error: expected a command name at the start of the expression",
                 );
             }
-            None => return Parser::error("Unexpected end of input: expected a command"),
+            None => return Parser::incomplete("a command"),
         };
 
         let mut args = Vec::new();
@@ -335,7 +817,14 @@ impl<'a> Parser<'a> {
                     let arg = self.tokens.next().unwrap().try_into()?;
                     args.push(arg);
                 }
-                RedirectIn | RedirectOut | RedirectAppend | RedirectErr | RedirectErrAndOut => {
+                RedirectIn
+                | RedirectOut
+                | RedirectAppend
+                | RedirectErr
+                | RedirectErrAppend
+                | RedirectErrAndOut
+                | RedirectAll
+                | HereDoc { .. } => {
                     redirects.push(self.parse_redirect()?);
                 }
                 _ => break,
@@ -360,6 +849,29 @@ impl<'a> Parser<'a> {
             });
         }
 
+        if let Token::HereDoc {
+            delimiter,
+            body,
+            strip_tabs,
+        } = kind_token
+        {
+            let body = match strip_tabs {
+                true => Cow::Owned(
+                    body.lines()
+                        .map(|line| format!("{}\n", line.trim_start_matches('\t')))
+                        .collect(),
+                ),
+                false => Cow::Borrowed(*body),
+            };
+            return Ok(Redirect {
+                kind: RedirectKind::HereDoc,
+                target: RedirectTarget::Text {
+                    body,
+                    expand: !delimiter.contains(['\'', '"', '\\']),
+                },
+            });
+        }
+
         match self.tokens.next() {
             Some(Token::Word(file)) => Ok(Redirect {
                 kind: RedirectKind::from_token(kind_token).unwrap(),
@@ -376,39 +888,141 @@ impl<'a> Parser<'a> {
 
     fn parse_env_vars(&mut self) -> Result<Vec<EnvVariable<'a>>> {
         let mut env_vars: Vec<EnvVariable<'a>> = Vec::new();
-        while let Some(token) = self.tokens.peek() {
-            if let Token::Word(content) = token
-                && content.contains('=')
-            {
-                self.tokens.next();
+        // Only a name before the `=` makes it a variable, `./a=b` or `=b` is a command
+        while let Some(Token::Word(content)) = self.tokens.peek()
+            && let Some((name, value)) = content.split_once('=')
+            && is_name(name)
+        {
+            self.tokens.next();
+            // Quotes in the value are left to the expander
+            env_vars.push(EnvVariable::new(Cow::Borrowed(name), Cow::Borrowed(value)));
+        }
 
-                let mut parts = content.splitn(2, '=');
-                let name = parts.next();
-                let value = parts.next();
-                match (name, value) {
-                    (Some(name), Some(mut value)) => {
-                        if name.trim().is_empty() {
-                            return Parser::error(
-                                "Syntax error: the name of a env variable can't be empty",
-                            );
-                        }
+        Ok(env_vars)
+    }
 
-                        value = EnvVariable::strip_quotes_from_value(value);
+    fn parse_conditional(&mut self) -> Result<Command<'a>> {
+        use Token::*;
 
-                        env_vars.push(EnvVariable::new(Cow::Borrowed(name), Cow::Borrowed(value)));
-                    }
-                    _ => {
-                        return Parser::error(
-                            "Syntax error: an envirorment variable, must be formatted using name=value syntax",
-                        );
-                    }
+        self.tokens.next();
+
+        let mut args = Vec::new();
+        loop {
+            let operator = match self.tokens.next() {
+                Some(Word("]]")) => break,
+                Some(Word("=~")) => {
+                    args.push(Arg::Word(Cow::Borrowed("=~")));
+                    args.push(self.parse_regex()?);
+                    continue;
                 }
-            } else {
-                break;
+                Some(token @ (Word(_) | SingleQuoted(_) | DoubleQuoted(_))) => {
+                    args.push(token.try_into()?);
+                    continue;
+                }
+                Some(Newline) => continue,
+                Some(And) => "&&",
+                Some(Or) => "||",
+                Some(LeftParen) => "(",
+                Some(RightParen) => ")",
+                Some(RedirectIn) => "<",
+                Some(RedirectOut) => ">",
+                Some(token) => {
+                    return Parser::error(&format!(
+                        "Syntax error: unexpected '{}' in [[ ]]",
+                        token
+                    ));
+                }
+                None => return Parser::incomplete("']]'"),
+            };
+            args.push(Arg::Word(Cow::Borrowed(operator)));
+        }
+
+        if args.is_empty() {
+            return Parser::error("Syntax error: expected a condition between [[ and ]]");
+        }
+        Ok(Command::Conditional(args))
+    }
+
+    // The regex after `=~` is a single word in other shells, but here its `(`, `)` and `|` are
+    // tokens of their own, so they're put back together. It ends where the condition goes on
+    fn parse_regex(&mut self) -> Result<Arg<'a>> {
+        use Token::*;
+
+        let mut parts: Vec<Arg<'a>> = Vec::new();
+        let mut depth = 0;
+        loop {
+            let part = match self.tokens.peek() {
+                None | Some(Word("]]")) => break,
+                Some(And | Or) if depth == 0 => break,
+                Some(RightParen) if depth == 0 => break,
+                Some(LeftParen) => {
+                    depth += 1;
+                    Arg::Word(Cow::Borrowed("("))
+                }
+                Some(RightParen) => {
+                    depth -= 1;
+                    Arg::Word(Cow::Borrowed(")"))
+                }
+                Some(Pipe) => Arg::Word(Cow::Borrowed("|")),
+                Some(token @ (Word(_) | SingleQuoted(_) | DoubleQuoted(_))) => token.try_into()?,
+                Some(token) => {
+                    return Parser::error(&format!(
+                        "Syntax error: unexpected '{}' in the regex",
+                        token
+                    ));
+                }
+            };
+            self.tokens.next();
+            parts.push(part);
+        }
+
+        match parts.len() {
+            0 => Parser::error("Syntax error: expected a regex after '=~'"),
+            // Kept as it is so a quoted regex can be told apart
+            1 => Ok(parts.remove(0)),
+            _ => Ok(Arg::Word(Cow::Owned(
+                parts.iter().map(Arg::to_string).collect(),
+            ))),
+        }
+    }
+
+    fn parse_array_assignment(&mut self) -> Result<Command<'a>> {
+        use Token::*;
+
+        let Some(Word(word)) = self.tokens.next() else {
+            unreachable!("An array assignment starts with its name")
+        };
+        let word = &word[..word.len() - 1];
+        let (name, append) = match word.strip_suffix('+') {
+            Some(name) => (name, true),
+            None => (word, false),
+        };
+        self.tokens.next();
+
+        // The values can go over several lines
+        let mut values = Vec::new();
+        loop {
+            match self.tokens.next() {
+                Some(RightParen) => break,
+                Some(Newline) => {}
+                Some(token @ (Word(_) | SingleQuoted(_) | DoubleQuoted(_))) => {
+                    values.push(token.try_into()?)
+                }
+                Some(token) => {
+                    return Parser::error(&format!(
+                        "Syntax error: unexpected '{}' in the values of {}",
+                        token, name
+                    ));
+                }
+                None => return Parser::incomplete("')'"),
             }
         }
 
-        Ok(env_vars)
+        Ok(Command::ArrayAssignment {
+            name: Cow::Borrowed(name),
+            values,
+            append,
+        })
     }
 
     fn parse_subshell(&mut self) -> Result<Command<'a>> {
@@ -427,6 +1041,212 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn parse_for(&mut self) -> Result<Command<'a>> {
+        use Token::*;
+
+        self.tokens.next();
+
+        if let Some(Arithmetic(expression)) = self.tokens.peek() {
+            self.tokens.next();
+            let [init, condition, step] = expression.split(';').collect::<Vec<_>>()[..] else {
+                return Parser::error("Syntax error: expected 'for ((init; condition; step))'");
+            };
+            let body = self.parse_do_group()?;
+            return Ok(Command::ArithmeticFor {
+                init: Cow::Borrowed(init),
+                condition: Cow::Borrowed(condition),
+                step: Cow::Borrowed(step),
+                body: Box::new(body),
+            });
+        }
+
+        let (variable, words, body) = self.parse_word_loop("for")?;
+        Ok(Command::For {
+            variable: Cow::Borrowed(variable),
+            words,
+            body: Box::new(body),
+        })
+    }
+
+    fn parse_select(&mut self) -> Result<Command<'a>> {
+        self.tokens.next();
+
+        let (variable, words, body) = self.parse_word_loop("select")?;
+        Ok(Command::Select {
+            variable: Cow::Borrowed(variable),
+            words,
+            body: Box::new(body),
+        })
+    }
+
+    // `name [in words]; do body; done`, what follows `for` and `select`
+    fn parse_word_loop(
+        &mut self,
+        keyword: &str,
+    ) -> Result<(&'a str, Option<Vec<Arg<'a>>>, Command<'a>)> {
+        use Token::*;
+
+        let variable = match self.tokens.next() {
+            Some(Word(name)) if is_name(name) => name,
+            Some(token) => {
+                return Parser::error(&format!(
+                    "Syntax error: '{}' isn't a valid name for the loop variable",
+                    token
+                ));
+            }
+            None => return Parser::incomplete(&format!("a variable name after '{}'", keyword)),
+        };
+
+        self.skip_newlines();
+        let mut words = None;
+        if matches!(self.tokens.peek(), Some(Word("in"))) {
+            self.tokens.next();
+            let mut list = Vec::new();
+            while let Some(Word(_) | SingleQuoted(_) | DoubleQuoted(_)) = self.tokens.peek() {
+                list.push(self.tokens.next().unwrap().try_into()?);
+            }
+            words = Some(list);
+        }
+
+        let body = self.parse_do_group()?;
+        Ok((variable, words, body))
+    }
+
+    fn parse_case(&mut self) -> Result<Command<'a>> {
+        use Token::*;
+
+        self.tokens.next();
+
+        let word = match self.tokens.next() {
+            Some(token @ (Word(_) | SingleQuoted(_) | DoubleQuoted(_))) => token.try_into()?,
+            Some(token) => {
+                return Parser::error(&format!(
+                    "Syntax error: expected a word after 'case' but found '{}'",
+                    token
+                ));
+            }
+            None => return Parser::incomplete("a word after 'case'"),
+        };
+
+        self.skip_newlines();
+        self.expect_keyword("in")?;
+        self.skip_newlines();
+
+        let mut items = Vec::new();
+        loop {
+            match self.tokens.peek() {
+                Some(Word("esac")) => {
+                    self.tokens.next();
+                    break;
+                }
+                None => return Parser::incomplete("'esac'"),
+                _ => items.push(self.parse_case_item()?),
+            }
+            self.skip_newlines();
+        }
+
+        Ok(Command::Case { word, items })
+    }
+
+    // `pattern | pattern) body ;;`, the `(` before the patterns and the body are optional
+    // and the terminator can be left out before `esac`
+    fn parse_case_item(&mut self) -> Result<CaseItem<'a>> {
+        use Token::*;
+
+        if matches!(self.tokens.peek(), Some(LeftParen)) {
+            self.tokens.next();
+        }
+
+        let mut patterns = Vec::new();
+        loop {
+            match self.tokens.next() {
+                Some(token @ (Word(_) | SingleQuoted(_) | DoubleQuoted(_))) => {
+                    patterns.push(token.try_into()?)
+                }
+                Some(token) => {
+                    return Parser::error(&format!(
+                        "Syntax error: expected a pattern but found '{}'",
+                        token
+                    ));
+                }
+                None => return Parser::incomplete("a pattern"),
+            }
+
+            match self.tokens.next() {
+                Some(Pipe) => continue,
+                Some(RightParen) => break,
+                Some(token) => {
+                    return Parser::error(&format!(
+                        "Syntax error: expected ')' after the pattern but found '{}'",
+                        token
+                    ));
+                }
+                None => return Parser::incomplete("')'"),
+            }
+        }
+
+        self.skip_newlines();
+        let body = match self.tokens.peek() {
+            Some(DoubleSemicolon | SemicolonAnd | Word("esac")) => None,
+            _ => Some(self.parse_sequence()?),
+        };
+
+        let fall_through = match self.tokens.peek() {
+            Some(DoubleSemicolon) => false,
+            Some(SemicolonAnd) => true,
+            Some(Word("esac")) => {
+                return Ok(CaseItem {
+                    patterns,
+                    body,
+                    fall_through: false,
+                });
+            }
+            Some(token) => {
+                return Parser::error(&format!(
+                    "Syntax error: expected ';;' but found '{}'",
+                    token
+                ));
+            }
+            None => return Parser::incomplete("'esac'"),
+        };
+        self.tokens.next();
+
+        Ok(CaseItem {
+            patterns,
+            body,
+            fall_through,
+        })
+    }
+
+    // The `do ...; done` of a loop, after the `;` or newline that ends its header
+    fn parse_do_group(&mut self) -> Result<Command<'a>> {
+        if matches!(self.tokens.peek(), Some(Token::Semicolon)) {
+            self.tokens.next();
+        }
+        self.skip_newlines();
+
+        self.expect_keyword("do")?;
+        let body = self.parse_sequence()?;
+        self.expect_keyword("done")?;
+        Ok(body)
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        match self.tokens.next() {
+            Some(Token::Word(word)) if *word == keyword => Ok(()),
+            Some(token) => Parser::error(&format!(
+                "Syntax error: expected '{}' but found '{}'",
+                keyword, token
+            )),
+            None => Parser::incomplete(&format!("'{}'", keyword)),
+        }
+    }
+
+    // The line ended in the middle of a command, scripts read the next line and try again
+    fn incomplete<T>(expected: &str) -> Result<T> {
+        Parser::error(&format!("Unexpected end of input: expected {}", expected))
+    }
+
     fn error<T>(message: &str) -> Result<T> {
         Err(anyhow::Error::new(ShellError {
             phase: ShellPhase::Parser,
@@ -435,3 +1255,19 @@ impl<'a> Parser<'a> {
         }))
     }
 }
+
+/// Whether the word can be the name of a variable
+pub fn is_name(word: &str) -> bool {
+    !word.is_empty()
+        && !word.starts_with(|c: char| c.is_ascii_digit())
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// A `&` ends a command like `;` does, `sleep 5 & echo started` runs both
+fn ends_in_background(command: &Command) -> bool {
+    match command {
+        Command::Background(_) => true,
+        Command::And(_, right) | Command::Or(_, right) => ends_in_background(right),
+        _ => false,
+    }
+}
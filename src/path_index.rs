@@ -0,0 +1,139 @@
+//path_index.rs
+
+use crate::sys;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::SystemTime,
+};
+
+/// The programs of every directory in PATH, kept between completions since reading them all
+/// and looking at every file adds up with a long PATH. It's refreshed in the background, only
+/// the directories that changed since are read again. Copies of it share the same index
+#[derive(Clone, Default)]
+pub struct PathIndex {
+    index: Arc<Mutex<Index>>,
+    refreshing: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct Index {
+    // The PATH it was built for
+    path: String,
+    // In the order of PATH
+    directories: Vec<Directory>,
+}
+
+#[derive(Clone)]
+struct Directory {
+    path: PathBuf,
+    // Adding or removing a file changes it, None when the directory isn't there
+    modified: Option<SystemTime>,
+    programs: Vec<String>,
+}
+
+impl PathIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Brings the index up to date with PATH without waiting for it, nothing is started
+    /// while a refresh is still going
+    pub fn refresh(&self, path: &str) {
+        if self.refreshing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let index = self.clone();
+        let path = path.to_string();
+        thread::spawn(move || {
+            index.update(&path);
+            index.refreshing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// The programs starting with the word. They come from the index as it is, which is
+    /// refreshed behind it, unless it was built for another PATH
+    pub fn programs(&self, path: &str, word: &str) -> Vec<String> {
+        self.prepare(path);
+        self.lock()
+            .directories
+            .iter()
+            .flat_map(|directory| &directory.programs)
+            .filter(|name| name.starts_with(word))
+            .cloned()
+            .collect()
+    }
+
+    /// Where the program is, in the first directory of PATH having it
+    pub fn find(&self, path: &str, name: &str) -> Option<PathBuf> {
+        self.prepare(path);
+        self.lock()
+            .directories
+            .iter()
+            .find(|directory| directory.programs.iter().any(|program| program == name))
+            .map(|directory| directory.path.join(name))
+    }
+
+    fn prepare(&self, path: &str) {
+        let current = self.lock().path == path;
+        match current {
+            true => self.refresh(path),
+            false => self.update(path),
+        }
+    }
+
+    // Reads the directories that changed again, the lock isn't held meanwhile so lookups
+    // aren't held up by a refresh
+    fn update(&self, path: &str) {
+        let known = self.lock().directories.clone();
+        let directories = env::split_paths(path)
+            .map(|path| {
+                let modified = fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok();
+                let unchanged = known.iter().find(|directory| {
+                    directory.path == path && modified.is_some() && directory.modified == modified
+                });
+                match unchanged {
+                    Some(directory) => directory.clone(),
+                    None => Directory {
+                        programs: programs(&path),
+                        path,
+                        modified,
+                    },
+                }
+            })
+            .collect();
+        *self.lock() = Index {
+            path: path.to_string(),
+            directories,
+        };
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Index> {
+        self.index
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// The executable files of a directory, symlinks are followed since most of what's in PATH
+// can be one
+fn programs(directory: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            fs::metadata(entry.path())
+                .is_ok_and(|metadata| sys::is_executable(&entry.path(), &metadata))
+        })
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect()
+}
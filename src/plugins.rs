@@ -0,0 +1,418 @@
+//plugins.rs
+
+// Plugins are shared libraries loaded with `plugin load`. They register builtins, prompt
+// segments, completion specs and hooks through a C ABI, so they can be written in C, Rust or
+// anything else that exports C functions. `plugins/rshell_plugin.h` describes the ABI
+
+use crate::{completion::Completions, hooks::Hook, settings};
+use anyhow::{Context, Result, anyhow, bail};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(windows)]
+use std::os::windows::ffi::OsStrExt;
+use std::{
+    env::consts::{DLL_PREFIX, DLL_SUFFIX},
+    ffi::{CStr, CString, c_char, c_int, c_long, c_void},
+    path::{self, Path, PathBuf},
+    ptr,
+    rc::Rc,
+};
+#[cfg(windows)]
+use windows_sys::Win32::{
+    Foundation::FreeLibrary,
+    System::LibraryLoader::{GetProcAddress, LoadLibraryW},
+};
+
+/// The version of the ABI given to plugins, raised whenever it changes in a way that would
+/// break plugins built for an older one
+pub const ABI_VERSION: u32 = 1;
+
+// The most a segment can write, longer text is cut
+const SEGMENT_SIZE: usize = 1024;
+
+pub type BuiltinFn =
+    unsafe extern "C" fn(data: *mut c_void, argc: c_int, argv: *const *const c_char) -> c_int;
+pub type SegmentFn =
+    unsafe extern "C" fn(data: *mut c_void, buffer: *mut c_char, size: usize) -> c_long;
+type InitFn = unsafe extern "C" fn(host: *const Host) -> c_int;
+type ExitFn = unsafe extern "C" fn();
+
+/// What `rshell_plugin_init` is given, `struct rshell_host` in the header. Fields are only
+/// ever added at the end, so a plugin built for an older ABI still finds its own
+#[repr(C)]
+pub struct Host {
+    pub abi_version: u32,
+    pub registry: *mut c_void,
+    pub register_builtin:
+        unsafe extern "C" fn(*mut c_void, *const c_char, BuiltinFn, *mut c_void) -> c_int,
+    pub register_segment:
+        unsafe extern "C" fn(*mut c_void, *const c_char, SegmentFn, *mut c_void) -> c_int,
+    pub register_completion:
+        unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> c_int,
+    pub register_hook: unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> c_int,
+}
+
+/// Where `plugin load name` looks for plugins: `~/.config/rshell/plugins/`
+pub fn plugins_dir() -> PathBuf {
+    let config = settings::config_path();
+    config
+        .parent()
+        .map(|dir| dir.join("plugins"))
+        .unwrap_or_else(|| PathBuf::from("plugins"))
+}
+
+// A library opened with dlopen, closed once nothing registered by it is around anymore
+struct Library(*mut c_void);
+
+#[cfg(unix)]
+impl Library {
+    fn open(path: &Path) -> Result<Self> {
+        let name = CString::new(path.as_os_str().as_bytes()).context("Invalid plugin path")?;
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            bail!("{}", dl_error());
+        }
+        Ok(Self(handle))
+    }
+
+    fn symbol(&self, name: &CStr) -> Option<*mut c_void> {
+        let symbol = unsafe { libc::dlsym(self.0, name.as_ptr()) };
+        (!symbol.is_null()).then_some(symbol)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dlclose(self.0);
+        }
+    }
+}
+
+// LoadLibrary is what Windows has for dlopen
+#[cfg(windows)]
+impl Library {
+    fn open(path: &Path) -> Result<Self> {
+        let name: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+        let handle = unsafe { LoadLibraryW(name.as_ptr()) };
+        if handle.is_null() {
+            bail!("{}", std::io::Error::last_os_error());
+        }
+        Ok(Self(handle))
+    }
+
+    fn symbol(&self, name: &CStr) -> Option<*mut c_void> {
+        let symbol = unsafe { GetProcAddress(self.0, name.as_ptr().cast()) }?;
+        Some(symbol as *mut c_void)
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe {
+            FreeLibrary(self.0);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn dl_error() -> String {
+    let error = unsafe { libc::dlerror() };
+    match error.is_null() {
+        true => String::from("Failed to open the library"),
+        false => unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+/// A builtin registered by a plugin
+#[derive(Clone, Debug)]
+pub struct PluginBuiltin {
+    pub name: String,
+    function: BuiltinFn,
+    data: *mut c_void,
+}
+
+impl PluginBuiltin {
+    /// Runs the builtin with the arguments after its name. C's stdout is flushed once it's
+    /// done, so what it printed comes before anything printed next
+    pub fn call(&self, args: &[&str]) -> Result<i32> {
+        let argv = std::iter::once(self.name.as_str())
+            .chain(args.iter().copied())
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Arguments can't hold a NUL byte")?;
+        let mut pointers: Vec<*const c_char> = argv.iter().map(|arg| arg.as_ptr()).collect();
+        pointers.push(ptr::null());
+
+        let status = unsafe { (self.function)(self.data, argv.len() as c_int, pointers.as_ptr()) };
+        unsafe {
+            libc::fflush(ptr::null_mut());
+        }
+        Ok(status)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PluginSegment {
+    name: String,
+    function: SegmentFn,
+    data: *mut c_void,
+}
+
+impl PluginSegment {
+    // A negative length leaves the segment out
+    fn render(&self) -> Option<String> {
+        let mut buffer = vec![0u8; SEGMENT_SIZE];
+        let length =
+            unsafe { (self.function)(self.data, buffer.as_mut_ptr().cast(), buffer.len()) };
+        let length = usize::try_from(length).ok()?.min(buffer.len());
+        Some(String::from_utf8_lossy(&buffer[..length]).into_owned())
+    }
+}
+
+/// A loaded plugin and what it registered
+#[derive(Clone)]
+pub struct Plugin {
+    pub name: String,
+    pub path: PathBuf,
+    pub builtins: Vec<PluginBuiltin>,
+    segments: Vec<PluginSegment>,
+    /// Completion specs, by the command they complete
+    pub completions: Vec<(String, String)>,
+    pub hooks: Vec<(Hook, String)>,
+    library: Rc<Library>,
+}
+
+impl Plugin {
+    pub fn segment_names(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().map(|segment| segment.name.as_str())
+    }
+}
+
+/// The loaded plugins, the last one loaded wins when two register the same name
+#[derive(Clone, Default)]
+pub struct Plugins {
+    loaded: Vec<Plugin>,
+}
+
+impl Plugins {
+    /// Opens the plugin and lets it register what it brings. A name without a `/` is looked
+    /// up in the plugins directory, as it is or as `<name>.so` or `lib<name>.so`
+    pub fn load(&mut self, target: &str) -> Result<&Plugin> {
+        let path = find(target)?;
+        let name = plugin_name(&path);
+        if self.loaded.iter().any(|plugin| plugin.name == name) {
+            bail!("{name} is already loaded");
+        }
+
+        let library = Library::open(&path)?;
+        let init = library
+            .symbol(c"rshell_plugin_init")
+            .ok_or_else(|| anyhow!("{} has no rshell_plugin_init", path.display()))?;
+        let init = unsafe { std::mem::transmute::<*mut c_void, InitFn>(init) };
+
+        let mut registry = Registry::default();
+        let host = Host {
+            abi_version: ABI_VERSION,
+            registry: (&mut registry as *mut Registry).cast(),
+            register_builtin,
+            register_segment,
+            register_completion,
+            register_hook,
+        };
+        let status = unsafe { init(&host) };
+        if status != 0 {
+            bail!("{name} failed to start, rshell_plugin_init gave {status}");
+        }
+
+        self.loaded.push(Plugin {
+            name,
+            path,
+            builtins: registry.builtins,
+            segments: registry.segments,
+            completions: registry.completions,
+            hooks: registry.hooks,
+            library: Rc::new(library),
+        });
+        Ok(self.loaded.last().expect("the plugin was just added"))
+    }
+
+    /// Takes a plugin out, after letting it clean up with `rshell_plugin_exit`
+    pub fn unload(&mut self, name: &str) -> Option<Plugin> {
+        let index = self.loaded.iter().position(|plugin| plugin.name == name)?;
+        let plugin = self.loaded.remove(index);
+        if let Some(exit) = plugin.library.symbol(c"rshell_plugin_exit") {
+            unsafe {
+                std::mem::transmute::<*mut c_void, ExitFn>(exit)();
+            }
+        }
+        Some(plugin)
+    }
+
+    pub fn list(&self) -> &[Plugin] {
+        &self.loaded
+    }
+
+    pub fn builtin(&self, name: &str) -> Option<&PluginBuiltin> {
+        self.loaded
+            .iter()
+            .rev()
+            .flat_map(|plugin| &plugin.builtins)
+            .find(|builtin| builtin.name == name)
+    }
+
+    pub fn builtin_names(&self) -> impl Iterator<Item = &str> {
+        self.loaded
+            .iter()
+            .flat_map(|plugin| &plugin.builtins)
+            .map(|builtin| builtin.name.as_str())
+    }
+
+    /// The text of a segment registered by a plugin, None when no plugin has one of that
+    /// name and Some("") when it's left out
+    pub fn segment(&self, name: &str) -> Option<String> {
+        let segment = self
+            .loaded
+            .iter()
+            .rev()
+            .flat_map(|plugin| &plugin.segments)
+            .find(|segment| segment.name == name)?;
+        Some(segment.render().unwrap_or_default())
+    }
+
+    pub fn completions(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.loaded.iter().flat_map(|plugin| {
+            plugin
+                .completions
+                .iter()
+                .map(|(command, spec)| (command.as_str(), spec.as_str()))
+        })
+    }
+}
+
+fn find(target: &str) -> Result<PathBuf> {
+    if target.contains(['/', path::MAIN_SEPARATOR]) {
+        return Ok(PathBuf::from(target));
+    }
+    let dir = plugins_dir();
+    [
+        target.to_string(),
+        format!("{target}{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{target}{DLL_SUFFIX}"),
+    ]
+    .into_iter()
+    .map(|name| dir.join(name))
+    .find(|path| path.is_file())
+    .ok_or_else(|| anyhow!("No plugin named {} in {}", target, dir.display()))
+}
+
+// `libgreet.so` is the plugin `greet`, so is `greet.dll`
+fn plugin_name(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match stem.strip_prefix("lib") {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => stem,
+    }
+}
+
+// What the plugin registers while `rshell_plugin_init` runs
+#[derive(Default)]
+struct Registry {
+    builtins: Vec<PluginBuiltin>,
+    segments: Vec<PluginSegment>,
+    completions: Vec<(String, String)>,
+    hooks: Vec<(Hook, String)>,
+}
+
+// A string given by a plugin, None when it's null, empty or not UTF-8
+unsafe fn text(pointer: *const c_char) -> Option<String> {
+    if pointer.is_null() {
+        return None;
+    }
+    let text = unsafe { CStr::from_ptr(pointer) }.to_str().ok()?;
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+// Names of builtins and segments are single words
+unsafe fn name(pointer: *const c_char) -> Option<String> {
+    unsafe { text(pointer) }.filter(|name| {
+        !name
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '/' | '{' | '}'))
+    })
+}
+
+unsafe extern "C" fn register_builtin(
+    registry: *mut c_void,
+    name: *const c_char,
+    function: BuiltinFn,
+    data: *mut c_void,
+) -> c_int {
+    let registry = unsafe { &mut *registry.cast::<Registry>() };
+    let Some(name) = (unsafe { self::name(name) }) else {
+        return -1;
+    };
+    registry.builtins.push(PluginBuiltin {
+        name,
+        function,
+        data,
+    });
+    0
+}
+
+unsafe extern "C" fn register_segment(
+    registry: *mut c_void,
+    name: *const c_char,
+    function: SegmentFn,
+    data: *mut c_void,
+) -> c_int {
+    let registry = unsafe { &mut *registry.cast::<Registry>() };
+    let Some(name) = (unsafe { self::name(name) }) else {
+        return -1;
+    };
+    registry.segments.push(PluginSegment {
+        name,
+        function,
+        data,
+    });
+    0
+}
+
+// The spec is checked straight away, so a broken one is the plugin's error and not a
+// problem reported at every Tab
+unsafe extern "C" fn register_completion(
+    registry: *mut c_void,
+    command: *const c_char,
+    spec: *const c_char,
+) -> c_int {
+    let registry = unsafe { &mut *registry.cast::<Registry>() };
+    let (Some(command), Some(spec)) = (unsafe { name(command) }, unsafe { text(spec) }) else {
+        return -1;
+    };
+    if Completions::default().add(&command, &spec).is_err() {
+        return -1;
+    }
+    registry.completions.push((command, spec));
+    0
+}
+
+unsafe extern "C" fn register_hook(
+    registry: *mut c_void,
+    hook: *const c_char,
+    command: *const c_char,
+) -> c_int {
+    let registry = unsafe { &mut *registry.cast::<Registry>() };
+    let hook = unsafe { text(hook) }.and_then(|hook| Hook::from_name(&hook));
+    let (Some(hook), Some(command)) = (hook, unsafe { text(command) }) else {
+        return -1;
+    };
+    registry.hooks.push((hook, command));
+    0
+}
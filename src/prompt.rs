@@ -1,24 +1,469 @@
 //prompt.rs
 
-use std::path::PathBuf;
+use crate::{
+    context::Context,
+    jobs::JobState,
+    segments::{self, PromptSegment, SegmentContext},
+    theme::Theme,
+};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+use unicode_width::UnicodeWidthStr;
+
+/// Used when neither `PROMPT` nor the config file set a template, the directory with the exit code in front after a failure,
+/// the time taken after a long command, the background jobs and user@host over SSH or as root
+pub const DEFAULT_TEMPLATE: &str = "{failure}{duration}{jobs}{login}{cwd} >> ";
+
+/// Marker left in front of submitted lines when `TRANSIENT_PROMPT` is set but empty
+pub const DEFAULT_TRANSIENT: &str = ">> ";
+
+/// Put in front of the prompt while the session is private
+pub const PRIVATE_MARKER: &str = "(private) ";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GitStatus {
+    pub branch: String,
+    pub dirty: bool,
+    pub ahead: u32,
+    pub behind: u32,
+}
+
+impl GitStatus {
+    /// What's known before git answers, or without git
+    pub fn branch_only(branch: String) -> Self {
+        GitStatus {
+            branch,
+            dirty: false,
+            ahead: 0,
+            behind: 0,
+        }
+    }
+
+    /// Parses `git status --porcelain=v2 --branch`
+    pub fn parse(output: &str) -> Self {
+        let mut status = GitStatus {
+            branch: String::new(),
+            dirty: false,
+            ahead: 0,
+            behind: 0,
+        };
+
+        for line in output.lines() {
+            if let Some(head) = line.strip_prefix("# branch.head ") {
+                status.branch = head.to_string();
+            } else if let Some(counts) = line.strip_prefix("# branch.ab ") {
+                for count in counts.split_whitespace() {
+                    if let Some(ahead) = count.strip_prefix('+') {
+                        status.ahead = ahead.parse().unwrap_or(0);
+                    } else if let Some(behind) = count.strip_prefix('-') {
+                        status.behind = behind.parse().unwrap_or(0);
+                    }
+                }
+            } else if !line.starts_with('#') && !line.is_empty() {
+                status.dirty = true;
+            }
+        }
+
+        status
+    }
+
+    /// Renders as `main*↑1↓2`, the star marking uncommitted changes
+    pub fn to_prompt(&self) -> String {
+        let mut output = self.branch.clone();
+        if self.dirty {
+            output.push('*');
+        }
+        if self.ahead > 0 {
+            output.push_str(&format!("↑{}", self.ahead));
+        }
+        if self.behind > 0 {
+            output.push_str(&format!("↓{}", self.behind));
+        }
+        output
+    }
+}
 
 pub struct Prompt {
     pub message: String,
+    /// Shown flush right on the prompt line, empty when `RPROMPT` isn't set
+    pub right: String,
+    /// Replaces the prompt once a line is submitted, from `TRANSIENT_PROMPT`
+    pub transient: Option<String>,
+    // Columns the messages take on screen, escape sequences don't count
+    width: usize,
+    right_width: usize,
+    theme: Theme,
+    segments: Vec<Box<dyn PromptSegment>>,
 }
 
-//TODO: For now we do a static prompt, after i finished everything i will add the ability to customize it
 impl Prompt {
     pub fn new() -> Self {
         Self {
             message: String::new(),
+            right: String::new(),
+            transient: None,
+            width: 0,
+            right_width: 0,
+            theme: Theme::default(),
+            segments: segments::builtin(),
+        }
+    }
+
+    /// Renders the template in `PROMPT` and the one in `RPROMPT`. Without `PROMPT` the
+    /// segments listed in `PROMPT_SEGMENTS` are used, or the template of the config file
+    pub fn update(&mut self, context: &Context) {
+        let settings = &context.settings.prompt;
+        self.theme = Theme::from_settings(&context.settings.colors);
+        let variables = &context.variables;
+        let message = match (variables.get("PROMPT"), variables.get("PROMPT_SEGMENTS")) {
+            (Some(template), _) => self.render(&template, context),
+            (None, Some(names)) => {
+                let names: Vec<&str> = names
+                    .split([',', ' '])
+                    .filter(|name| !name.is_empty())
+                    .collect();
+                format!("{} >> ", self.render_segments(&names, context))
+            }
+            _ => self.render(&settings.template, context),
+        };
+        // Private sessions are marked whatever the template, so it's not forgotten on
+        let message = match context.mode.private {
+            true => format!(
+                "{}{}{}{}",
+                self.theme.tag("warning").unwrap_or_default(),
+                PRIVATE_MARKER,
+                self.theme.tag("reset").unwrap_or_default(),
+                message
+            ),
+            false => message,
+        };
+        self.set(message);
+
+        let right = variables
+            .get("RPROMPT")
+            .unwrap_or_else(|| settings.right.clone());
+        let right = self.render(&right, context);
+        self.set_right(right);
+
+        let transient = variables
+            .get("TRANSIENT_PROMPT")
+            .or_else(|| settings.transient.clone());
+        self.transient = match transient {
+            Some(template) if template.is_empty() => Some(DEFAULT_TRANSIENT.to_string()),
+            Some(template) => Some(self.render(&template, context)),
+            None => None,
+        };
+    }
+
+    /// True when a segment got what it was slow to render, `update` then draws it in
+    pub fn poll(&mut self) -> bool {
+        // Every segment gets polled, not just the first one that's ready
+        let mut ready = false;
+        for segment in &mut self.segments {
+            ready |= segment.poll();
         }
+        ready
+    }
+
+    pub fn set(&mut self, message: String) {
+        self.width = visible_width(&message);
+        self.message = message;
     }
 
-    pub fn update(&mut self, directory: &PathBuf) {
-        self.message = format!("{} >> ", directory.display());
+    pub fn set_right(&mut self, message: String) {
+        self.right_width = visible_width(&message);
+        self.right = message;
     }
 
+    /// Width of the prompt in columns, which is where the typed line starts
     pub fn len(&self) -> usize {
-        self.message.len()
+        self.width
+    }
+
+    /// Column the right prompt starts at on a terminal this wide, None when there's
+    /// no right prompt or the typed line is long enough to run into it
+    pub fn right_column(&self, line_width: usize, columns: usize) -> Option<usize> {
+        if self.right.is_empty() {
+            return None;
+        }
+
+        let start = columns.checked_sub(self.right_width)?;
+        // Keep at least a space between what's typed and the right prompt
+        if self.width + line_width + 1 > start {
+            return None;
+        }
+        Some(start)
+    }
+
+    /// Expands the placeholders of a prompt template, which are the names of the segments.
+    /// The built-in ones are `{cwd}`, `{user}`, `{host}`, `{login}` (user@host, by default
+    /// only over SSH or as root), `{time}`, `{exit_code}`, `{status}` (success/failure
+    /// marker), `{failure}` (exit code after a failure only), `{duration}` (time taken by
+    /// slow commands), `{jobs}` (background job count), `{git_branch}`, `{git}` (branch
+    /// with dirty and ahead/behind markers), `{rust}`, `{node}` and `{python}` (toolchain of
+    /// the project). Colors are set with tags, either roles of the theme like `{primary}` or
+    /// `{error}` or colors like `{red}`, `{bold+blue}` or `{#ff8800}`, and cleared with
+    /// `{reset}`. Unknown placeholders are kept as they are
+    pub fn render(&mut self, template: &str, context: &Context) -> String {
+        let mut output = String::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            output.push_str(&rest[..start]);
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+
+            let name = &rest[start + 1..start + end];
+            match self.placeholder(name, context) {
+                Some(value) => output.push_str(&value),
+                None => output.push_str(&rest[start..=start + end]),
+            }
+            rest = &rest[start + end + 1..];
+        }
+
+        output.push_str(rest);
+        output
+    }
+
+    /// Adds a segment, replacing the one with the same name if there's already one
+    pub fn register(&mut self, segment: Box<dyn PromptSegment>) {
+        self.segments.retain(|s| s.name() != segment.name());
+        self.segments.push(segment);
+    }
+
+    /// Renders the named segments in order separated by a space, skipping the empty ones
+    pub fn render_segments(&mut self, names: &[&str], context: &Context) -> String {
+        let mut parts = Vec::new();
+        for name in names {
+            if let Some(text) = self.segment(name, context) {
+                let text = text.trim_end();
+                if !text.is_empty() {
+                    parts.push(text.to_string());
+                }
+            }
+        }
+        parts.join(" ")
+    }
+
+    fn placeholder(&mut self, name: &str, context: &Context) -> Option<String> {
+        if self.segments.iter().any(|s| s.name() == name) {
+            return Some(self.segment(name, context).unwrap_or_default());
+        }
+        context
+            .plugins
+            .segment(name)
+            .or_else(|| self.theme.tag(name))
+    }
+
+    fn segment(&mut self, name: &str, context: &Context) -> Option<String> {
+        let segment_context = SegmentContext {
+            cwd: &context.directory,
+            last_status: context.last_exit_code,
+            duration: context.last_duration,
+            jobs: context.jobs.table.len(),
+            stopped_jobs: context
+                .jobs
+                .table
+                .values()
+                .filter(|job| matches!(job.state, JobState::Stopped))
+                .count(),
+            theme: &self.theme,
+            settings: &context.settings,
+        };
+
+        match self.segments.iter_mut().find(|s| s.name() == name) {
+            Some(segment) => segment.render(&segment_context),
+            None => context.plugins.segment(name),
+        }
+    }
+}
+
+/// Shortens a path for the prompt according to `PROMPT_CWD`: `full` leaves it alone, `short`
+/// cuts every directory but the last to its first letter (`~/p/s/src`) and a number keeps
+/// only that many trailing components. Anything else just replaces the home directory with `~`
+pub fn abbreviate_path(path: &Path, home: Option<&Path>, style: &str) -> String {
+    if style == "full" {
+        return path.display().to_string();
+    }
+
+    let (prefix, rest) = match home.and_then(|home| path.strip_prefix(home).ok()) {
+        Some(rest) => ("~", rest),
+        None => ("", path.strip_prefix("/").unwrap_or(path)),
+    };
+    let components: Vec<String> = rest
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+
+    if let Ok(keep) = style.parse::<usize>()
+        && keep > 0
+        && components.len() > keep
+    {
+        return format!("…/{}", components[components.len() - keep..].join("/"));
+    }
+
+    let components = if style == "short" {
+        let last = components.len().saturating_sub(1);
+        components
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                if i == last {
+                    return name.clone();
+                }
+                // Hidden directories keep the dot so `.config` doesn't become `.`
+                let take = if name.starts_with('.') { 2 } else { 1 };
+                name.chars().take(take).collect()
+            })
+            .collect()
+    } else {
+        components
+    };
+
+    match (prefix, components.is_empty()) {
+        ("~", true) => "~".to_string(),
+        ("~", false) => format!("~/{}", components.join("/")),
+        _ => format!("/{}", components.join("/")),
+    }
+}
+
+/// Name of the signals commands usually die from, exit codes above 128 carry them
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        #[cfg(unix)]
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        #[cfg(unix)]
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGFPE => "SIGFPE",
+        #[cfg(unix)]
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGSEGV => "SIGSEGV",
+        #[cfg(unix)]
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGTERM => "SIGTERM",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The signal with this name, with or without `SIG` (`TERM`, `SIGKILL`), or number
+pub fn signal_number(name: &str) -> Option<i32> {
+    if let Ok(number) = name.parse::<i32>() {
+        return (1..=64).contains(&number).then_some(number);
+    }
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    (1..32).find(|&signal| signal_name(signal).is_some_and(|known| &known[3..] == name))
+}
+
+/// Width of a string on screen, skipping ANSI escape sequences
+pub fn visible_width(text: &str) -> usize {
+    strip_escapes(text).width()
+}
+
+/// The text without its ANSI escape sequences
+pub fn strip_escapes(text: &str) -> String {
+    let mut plain = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end with a letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+/// Whether we're running on the other end of an SSH connection
+pub fn is_ssh_session() -> bool {
+    ["SSH_CONNECTION", "SSH_CLIENT", "SSH_TTY"]
+        .iter()
+        .any(|name| env::var_os(name).is_some())
+}
+
+#[cfg(unix)]
+pub fn user_name() -> String {
+    if let Ok(user) = env::var("USER") {
+        return user;
+    }
+
+    unsafe {
+        let passwd = libc::getpwuid(libc::getuid());
+        if passwd.is_null() {
+            return String::new();
+        }
+        std::ffi::CStr::from_ptr((*passwd).pw_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(windows)]
+pub fn user_name() -> String {
+    env::var("USERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+pub fn host_name() -> String {
+    let mut buffer = [0u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return String::new();
+    }
+
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+#[cfg(windows)]
+pub fn host_name() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// Name of the checked out branch, or the abbreviated commit when detached. Reads `.git`
+/// directly so it's cheap enough to do for every prompt
+pub fn git_branch(directory: &Path) -> Option<String> {
+    let git_dir = find_git_dir(directory)?;
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+
+    match head.strip_prefix("ref: ") {
+        Some(reference) => Some(
+            reference
+                .strip_prefix("refs/heads/")
+                .unwrap_or(reference)
+                .to_string(),
+        ),
+        None => Some(head.chars().take(7).collect()),
+    }
+}
+
+// Walks up from the directory looking for `.git`, which is a file pointing elsewhere
+// for worktrees and submodules
+pub fn find_git_dir(directory: &Path) -> Option<PathBuf> {
+    for dir in directory.ancestors() {
+        let git = dir.join(".git");
+        if git.is_dir() {
+            return Some(git);
+        }
+        if git.is_file() {
+            let content = fs::read_to_string(&git).ok()?;
+            let target = content.trim().strip_prefix("gitdir: ")?;
+            return Some(dir.join(target));
+        }
     }
+    None
 }
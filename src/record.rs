@@ -0,0 +1,111 @@
+//record.rs
+
+use crate::{context::Context, encoding, parser::Command};
+use std::{env, io, time::Duration};
+
+/// What `--json` tells about a command of a script or `-c` once it's done, written as a line
+/// of JSON so log pipelines can read it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// The command as written
+    pub command: String,
+    /// The arguments after expansion, None for loops, pipelines and the other commands that
+    /// aren't a single program
+    pub argv: Option<Vec<String>>,
+    pub status: i32,
+    pub duration: Duration,
+    /// Where it ran from
+    pub cwd: String,
+    /// Like `script.sh:3`
+    pub location: String,
+}
+
+impl Record {
+    /// A record of a command about to run, its status and duration are filled in after
+    pub fn new(context: &Context, text: &str, command: &Command) -> Self {
+        let argv = match command {
+            Command::Simple { command, args, .. } => Some(
+                std::iter::once(command.to_string())
+                    .chain(args.iter().map(|arg| arg.as_str().to_string()))
+                    .collect(),
+            ),
+            _ => None,
+        };
+        Self {
+            command: text.trim_end().to_string(),
+            argv,
+            status: 0,
+            duration: Duration::ZERO,
+            cwd: env::current_dir()
+                .map(|cwd| encoding::from_os_str(cwd.as_os_str()))
+                .unwrap_or_default(),
+            location: context.location(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let argv = match &self.argv {
+            Some(argv) => format!(
+                "[{}]",
+                argv.iter()
+                    .map(|arg| quote(arg))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            None => String::from("null"),
+        };
+        format!(
+            "{{\"command\":{},\"argv\":{},\"status\":{},\"duration\":{:.6},\"cwd\":{},\"location\":{}}}",
+            quote(&self.command),
+            argv,
+            self.status,
+            self.duration.as_secs_f64(),
+            quote(&self.cwd),
+            quote(&self.location),
+        )
+    }
+
+    /// Writes the record as a line to a file descriptor the shell was given, which stays open
+    #[cfg(unix)]
+    pub fn write(&self, fd: i32) -> io::Result<()> {
+        use std::{fs::File, io::Write, mem::ManuallyDrop, os::fd::FromRawFd};
+        let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+        file.write_all(format!("{}\n", self.to_json()).as_bytes())
+    }
+
+    #[cfg(windows)]
+    pub fn write(&self, fd: i32) -> io::Result<()> {
+        let line = format!("{}\n", self.to_json());
+        let mut bytes = line.as_bytes();
+        while !bytes.is_empty() {
+            let written = unsafe { libc::write(fd, bytes.as_ptr().cast(), bytes.len() as u32) };
+            if written <= 0 {
+                return Err(io::Error::last_os_error());
+            }
+            bytes = &bytes[written as usize..];
+        }
+        Ok(())
+    }
+}
+
+// A JSON string. JSON has to be UTF-8, so bytes of names that aren't become U+FFFD
+fn quote(text: &str) -> String {
+    let text = String::from_utf8_lossy(&encoding::encode(text)).into_owned();
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for character in text.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            control if control.is_control() => {
+                quoted.push_str(&format!("\\u{:04x}", control as u32))
+            }
+            _ => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,77 @@
+//safety.rs
+
+// Commands matching a pattern of `[safety] confirm` in the config file are asked about before
+// they run. They're matched once expanded, so what's asked about is what would run
+
+use crate::{
+    builtins::BuiltIns,
+    context::Context,
+    expander,
+    parser::{Command, RedirectKind},
+    terminal::Terminal,
+};
+use std::io::{self, IsTerminal};
+
+/// A simple command as it's matched against the patterns: its arguments and then its
+/// redirections, like `rm -rf build` or `echo hi > /etc/motd`
+pub fn line(command: &Command) -> Option<String> {
+    let Command::Simple {
+        command,
+        args,
+        redirects,
+        ..
+    } = command
+    else {
+        return None;
+    };
+    let mut line = command.to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(arg.as_str());
+    }
+    for redirect in redirects {
+        match redirect.kind {
+            RedirectKind::ErrAndOut => line.push_str(" 2>&1"),
+            _ => line.push_str(&format!(" {} {}", redirect.kind, redirect.target)),
+        }
+    }
+    Some(line)
+}
+
+/// The first pattern the command matches, with the line it matched. The stages of a pipeline
+/// and background jobs are started together, any of them can match
+pub fn dangerous<'a>(patterns: &'a [String], command: &Command) -> Option<(&'a str, String)> {
+    match command {
+        Command::Pipeline(left, right) => {
+            dangerous(patterns, left).or_else(|| dangerous(patterns, right))
+        }
+        Command::Background(command) => dangerous(patterns, command),
+        command => {
+            let line = line(command)?;
+            let pattern = patterns
+                .iter()
+                .find(|pattern| expander::matches_pattern(pattern, &line))?;
+            Some((pattern, line))
+        }
+    }
+}
+
+/// Whether a command can run, asking first when it matches a pattern. Without someone at a
+/// terminal to answer, like in scripts, commands run as usual
+pub fn allow(context: &Context, terminal: &mut Terminal, command: &Command) -> bool {
+    let patterns = &context.settings.safety.confirm;
+    if patterns.is_empty()
+        || !context.mode.interactive
+        || !context.sources.is_empty()
+        || !io::stdin().is_terminal()
+    {
+        return true;
+    }
+    let Some((pattern, line)) = dangerous(patterns, command) else {
+        return true;
+    };
+    BuiltIns::confirm(
+        terminal,
+        &format!("{line}\nThis matches '{pattern}', run it anyway?"),
+    )
+}
@@ -0,0 +1,445 @@
+//segments.rs
+
+use crate::{
+    history,
+    prompt::{self, GitStatus},
+    settings::Settings,
+    sys,
+    theme::Theme,
+};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Commands faster than this don't get their duration shown, unless `DURATION_THRESHOLD` or
+/// the config file say otherwise
+const DEFAULT_DURATION_THRESHOLD: Duration = Duration::from_secs(5);
+
+// Running git for every prompt is too slow in big repositories, so its answer is reused while
+// the index and HEAD stay the same, for a couple of seconds at most since editing a file
+// doesn't touch either
+const GIT_CACHE_TTL: Duration = Duration::from_secs(2);
+
+// How long the prompt waits for git before it's drawn without its answer, most repositories
+// are done well within it
+const GIT_WAIT: Duration = Duration::from_millis(10);
+
+// Toolchain files rarely change, so what was found for a directory is kept a bit longer
+const TOOLCHAIN_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// What segments get to look at, gathered once per prompt
+pub struct SegmentContext<'a> {
+    pub cwd: &'a Path,
+    pub last_status: i32,
+    pub duration: Option<Duration>,
+    pub jobs: usize,
+    /// How many of the jobs are stopped, the others are running
+    pub stopped_jobs: usize,
+    pub theme: &'a Theme,
+    pub settings: &'a Settings,
+}
+
+impl SegmentContext<'_> {
+    /// Escape sequence of a theme role or color, empty when colors are off
+    pub fn color(&self, name: &str) -> String {
+        self.theme.tag(name).unwrap_or_default()
+    }
+}
+
+/// A piece of the prompt, used in templates as `{name}` or listed in `PROMPT_SEGMENTS`.
+/// The text carries its own colors, None (or an empty string) leaves the segment out
+pub trait PromptSegment {
+    fn name(&self) -> &str;
+    fn render(&mut self, context: &SegmentContext) -> Option<String>;
+
+    /// True when something the segment was waiting for came in since it rendered, so the
+    /// prompt is worth rendering again
+    fn poll(&mut self) -> bool {
+        false
+    }
+}
+
+type RenderFn = fn(&SegmentContext) -> Option<String>;
+
+// Segments without state are just a function
+struct Simple {
+    name: &'static str,
+    render: RenderFn,
+}
+
+impl PromptSegment for Simple {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn render(&mut self, context: &SegmentContext) -> Option<String> {
+        (self.render)(context)
+    }
+}
+
+/// The segments the shell comes with. `failure`, `duration`, `login`, `jobs` and the toolchain
+/// ones (`rust`, `node`, `python`) end with a space since they're meant to go in front of
+/// other segments and often render nothing
+pub fn builtin() -> Vec<Box<dyn PromptSegment>> {
+    let simple: [(&'static str, RenderFn); 11] = [
+        ("cwd", cwd),
+        ("user", |_| Some(prompt::user_name())),
+        ("host", |_| Some(prompt::host_name())),
+        ("login", login),
+        ("time", |_| {
+            Some(history::format_time(history::now(), c"%H:%M:%S"))
+        }),
+        ("exit_code", |context| Some(context.last_status.to_string())),
+        ("status", |context| Some(status(context, true))),
+        ("failure", |context| Some(status(context, false))),
+        ("duration", duration),
+        ("jobs", jobs),
+        ("git_branch", |context| prompt::git_branch(context.cwd)),
+    ];
+
+    let mut segments: Vec<Box<dyn PromptSegment>> = simple
+        .into_iter()
+        .map(|(name, render)| Box::new(Simple { name, render }) as Box<dyn PromptSegment>)
+        .collect();
+    segments.push(Box::new(GitSegment {
+        cache: None,
+        pending: None,
+    }));
+    for (name, detect) in [("rust", rust_toolchain as DetectFn), ("node", node_version)] {
+        segments.push(Box::new(ToolchainSegment {
+            name,
+            detect,
+            cache: HashMap::new(),
+        }));
+    }
+    segments.push(Box::new(Simple {
+        name: "python",
+        render: python_venv,
+    }));
+    segments
+}
+
+fn cwd(context: &SegmentContext) -> Option<String> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let style = env::var("PROMPT_CWD").unwrap_or_else(|_| context.settings.prompt.cwd.clone());
+    Some(prompt::abbreviate_path(
+        context.cwd,
+        home.as_deref(),
+        &style,
+    ))
+}
+
+// A green check after a success and a red cross with the exit code after a failure,
+// `{failure}` only shows the latter and leaves a space after it
+fn status(context: &SegmentContext, show_success: bool) -> String {
+    let reset = context.color("reset");
+    let exit_code = context.last_status;
+    if exit_code == 0 {
+        if !show_success {
+            return String::new();
+        }
+        return format!("{}✔{reset}", context.color("success"));
+    }
+
+    let mut status = format!("{}✘ {exit_code}", context.color("error"));
+    if let Some(signal) = prompt::signal_name(exit_code - 128) {
+        status.push_str(&format!(" {signal}"));
+    }
+    status.push_str(&reset);
+    if !show_success {
+        status.push(' ');
+    }
+    status
+}
+
+// `took 12.3s ` after commands slower than the threshold, nothing otherwise
+fn duration(context: &SegmentContext) -> Option<String> {
+    let threshold = env::var("DURATION_THRESHOLD")
+        .ok()
+        .and_then(|value| history::parse_duration(&value))
+        .or_else(|| history::parse_duration(&context.settings.prompt.duration_threshold))
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_DURATION_THRESHOLD);
+
+    let duration = context.duration.filter(|&duration| duration >= threshold)?;
+    let took = history::format_duration(duration.as_millis() as u64);
+    Some(format!(
+        "{}took {took}{} ",
+        context.color("warning"),
+        context.color("reset")
+    ))
+}
+
+// `[2 jobs] ` while there are background jobs, mentioning the stopped ones since those are
+// the easiest to forget about
+fn jobs(context: &SegmentContext) -> Option<String> {
+    if context.jobs == 0 {
+        return None;
+    }
+
+    let plural = if context.jobs == 1 { "job" } else { "jobs" };
+    let stopped = match context.stopped_jobs {
+        0 => String::new(),
+        n => format!(", {n} stopped"),
+    };
+    Some(format!(
+        "{}[{} {plural}{stopped}]{} ",
+        context.color("accent"),
+        context.jobs,
+        context.color("reset")
+    ))
+}
+
+// `user@host ` when it's worth pointing out, see `PROMPT_LOGIN`. Root is shown as an error
+// and remote hosts as a warning so they stand out
+fn login(context: &SegmentContext) -> Option<String> {
+    let root = sys::is_root();
+    let remote = prompt::is_ssh_session();
+    let show = match env::var("PROMPT_LOGIN").as_deref() {
+        Ok("always") => true,
+        Ok("never") => false,
+        _ => root || remote,
+    };
+    if !show {
+        return None;
+    }
+
+    let reset = context.color("reset");
+    let user_color = match root {
+        true => context.color("bold") + &context.color("error"),
+        false => context.color("success"),
+    };
+    let host_color = match remote {
+        true => context.color("warning"),
+        false => context.color("success"),
+    };
+    Some(format!(
+        "{user_color}{}{reset}@{host_color}{}{reset} ",
+        prompt::user_name(),
+        prompt::host_name()
+    ))
+}
+
+struct GitCache {
+    git_dir: PathBuf,
+    fingerprint: (Option<SystemTime>, Option<SystemTime>),
+    created: Instant,
+    status: Option<GitStatus>,
+}
+
+// A `git status` running on its own thread, for the repository state it was started for
+struct PendingGit {
+    git_dir: PathBuf,
+    fingerprint: (Option<SystemTime>, Option<SystemTime>),
+    receiver: Receiver<Option<GitStatus>>,
+}
+
+/// Branch with dirty and ahead/behind markers, like `main*↑1`. When git takes long the
+/// prompt shows the last answer, or the branch alone, and is drawn again once it's done
+struct GitSegment {
+    cache: Option<GitCache>,
+    pending: Option<PendingGit>,
+}
+
+impl PromptSegment for GitSegment {
+    fn name(&self) -> &str {
+        "git"
+    }
+
+    fn render(&mut self, context: &SegmentContext) -> Option<String> {
+        self.status(context.cwd).map(|status| status.to_prompt())
+    }
+
+    fn poll(&mut self) -> bool {
+        let Some(pending) = &self.pending else {
+            return false;
+        };
+        let status = match pending.receiver.try_recv() {
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Disconnected) => None,
+            Ok(status) => status,
+        };
+        self.finish(status);
+        true
+    }
+}
+
+impl GitSegment {
+    fn status(&mut self, directory: &Path) -> Option<GitStatus> {
+        let git_dir = prompt::find_git_dir(directory)?;
+        let modified = |name: &str| {
+            fs::metadata(git_dir.join(name))
+                .and_then(|m| m.modified())
+                .ok()
+        };
+        let fingerprint = (modified("index"), modified("HEAD"));
+
+        if let Some(cache) = &self.cache
+            && cache.git_dir == git_dir
+            && cache.fingerprint == fingerprint
+            && cache.created.elapsed() < GIT_CACHE_TTL
+        {
+            return cache.status.clone();
+        }
+
+        let started = self.pending.as_ref().is_some_and(|pending| {
+            pending.git_dir == git_dir && pending.fingerprint == fingerprint
+        });
+        if started {
+            self.poll();
+        } else {
+            let receiver = git_status(directory);
+            let arrived = receiver.recv_timeout(GIT_WAIT);
+            self.pending = Some(PendingGit {
+                git_dir: git_dir.clone(),
+                fingerprint,
+                receiver,
+            });
+            if !matches!(arrived, Err(RecvTimeoutError::Timeout)) {
+                self.finish(arrived.ok().flatten());
+            }
+        }
+
+        match &self.cache {
+            Some(cache) if cache.git_dir == git_dir => cache.status.clone(),
+            _ => prompt::git_branch(directory).map(GitStatus::branch_only),
+        }
+    }
+
+    // Keeps what git answered for the repository it was asked about
+    fn finish(&mut self, status: Option<GitStatus>) {
+        if let Some(pending) = self.pending.take() {
+            self.cache = Some(GitCache {
+                git_dir: pending.git_dir,
+                fingerprint: pending.fingerprint,
+                created: Instant::now(),
+                status,
+            });
+        }
+    }
+}
+
+// Runs `git status` on a thread. Without git installed we can still show the branch
+fn git_status(directory: &Path) -> Receiver<Option<GitStatus>> {
+    let (sender, receiver) = mpsc::channel();
+    let directory = directory.to_path_buf();
+    thread::spawn(move || {
+        let status = Command::new("git")
+            .args([
+                "status",
+                "--porcelain=v2",
+                "--branch",
+                "--untracked-files=no",
+            ])
+            .current_dir(&directory)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| GitStatus::parse(&String::from_utf8_lossy(&output.stdout)))
+            .or_else(|| prompt::git_branch(&directory).map(GitStatus::branch_only));
+        // Nobody's waiting anymore when the directory changed in the meantime
+        let _ = sender.send(status);
+    });
+    receiver
+}
+
+type DetectFn = fn(&Path) -> Option<String>;
+
+/// Version of a toolchain the current project asks for, found by looking for its files in
+/// the directory and its parents. The answer is cached per directory
+struct ToolchainSegment {
+    name: &'static str,
+    detect: DetectFn,
+    cache: HashMap<PathBuf, (Instant, Option<String>)>,
+}
+
+impl PromptSegment for ToolchainSegment {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn render(&mut self, context: &SegmentContext) -> Option<String> {
+        let version = match self.cache.get(context.cwd) {
+            Some((created, version)) if created.elapsed() < TOOLCHAIN_CACHE_TTL => version.clone(),
+            _ => {
+                let version = (self.detect)(context.cwd);
+                self.cache
+                    .insert(context.cwd.to_path_buf(), (Instant::now(), version.clone()));
+                version
+            }
+        };
+
+        Some(format!(
+            "{}{}:{}{} ",
+            context.color("secondary"),
+            self.name,
+            version?,
+            context.color("reset")
+        ))
+    }
+}
+
+// First of the files found walking up from the directory
+fn find_upwards(directory: &Path, names: &[&str]) -> Option<PathBuf> {
+    directory
+        .ancestors()
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+// The channel of `rust-toolchain.toml`, the legacy `rust-toolchain` file can also be just
+// the channel name
+fn rust_toolchain(directory: &Path) -> Option<String> {
+    let path = find_upwards(directory, &["rust-toolchain.toml", "rust-toolchain"])?;
+    let content = fs::read_to_string(path).ok()?;
+
+    let channel = content.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "channel").then(|| value.trim().trim_matches(['"', '\'']).to_string())
+    });
+    channel
+        .or_else(|| {
+            let line = content.lines().next()?.trim();
+            (!line.is_empty() && !line.starts_with('[')).then(|| line.to_string())
+        })
+        .filter(|channel| !channel.is_empty())
+}
+
+// The version pinned in `.nvmrc` or `.node-version`, otherwise the installed node for
+// projects with a `package.json`
+fn node_version(directory: &Path) -> Option<String> {
+    if let Some(path) = find_upwards(directory, &[".nvmrc", ".node-version"]) {
+        let content = fs::read_to_string(path).ok()?;
+        let version = content.lines().next()?.trim();
+        return (!version.is_empty()).then(|| version.to_string());
+    }
+
+    find_upwards(directory, &["package.json"])?;
+    let output = Command::new("node")
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!version.is_empty()).then_some(version)
+}
+
+// `(name) ` of the activated virtual environment, a `.venv` that isn't activated doesn't count
+fn python_venv(context: &SegmentContext) -> Option<String> {
+    let venv = env::var_os("VIRTUAL_ENV").filter(|venv| !venv.is_empty())?;
+    let name = Path::new(&venv).file_name()?.to_string_lossy().into_owned();
+    Some(format!(
+        "{}({name}){} ",
+        context.color("secondary"),
+        context.color("reset")
+    ))
+}
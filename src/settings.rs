@@ -0,0 +1,543 @@
+//settings.rs
+
+use crate::{
+    history,
+    keymap::{self, Keymap},
+    prompt,
+    theme::{self, Theme},
+};
+use anyhow::{Context as AnyhowContext, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt, fs, io,
+    path::PathBuf,
+};
+
+// Settings that are None by default, so they don't show up when the defaults are flattened
+const OPTIONAL_SETTINGS: [&str; 4] = [
+    "history.size",
+    "prompt.transient",
+    "notifications.after",
+    "notifications.report_time",
+];
+
+// Tables where any name goes, each entry is checked on its own
+const MAP_SETTINGS: [&str; 2] = ["colors.palette", "keybindings"];
+
+/// Something wrong with a setting of the config file, which then keeps its default
+#[derive(Debug)]
+pub struct Problem {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Problem {}
+
+/// Everything that isn't naturally a shell command, read once at startup from the config
+/// file. Missing values keep their defaults. The environment variables some of these mirror
+/// (`PROMPT`, `PROMPT_THEME`, ...) still win over the file since they can change at runtime
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    pub history: HistorySettings,
+    pub prompt: PromptSettings,
+    pub colors: ColorSettings,
+    pub notifications: NotificationSettings,
+    pub safety: SafetySettings,
+    pub options: Options,
+    /// Keys like `ctrl-r` or `alt-left` mapped to editor actions like `fuzzy-search`
+    pub keybindings: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistorySettings {
+    /// Entries parsed at startup, older ones are read the first time they're needed
+    pub recent_entries: usize,
+    /// Keeps commands starting with a space out of the history, like bash's ignorespace
+    pub ignore_space: bool,
+    /// Most entries kept, the oldest go first. `HISTSIZE` overrides it
+    pub size: Option<usize>,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self {
+            recent_entries: 1000,
+            ignore_space: true,
+            size: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PromptSettings {
+    /// Template used when `PROMPT` and `PROMPT_SEGMENTS` aren't set
+    pub template: String,
+    /// Right prompt template, `RPROMPT` overrides it
+    pub right: String,
+    /// Marker left in front of submitted lines, `TRANSIENT_PROMPT` overrides it
+    pub transient: Option<String>,
+    /// How `{cwd}` is abbreviated, see `PROMPT_CWD`
+    pub cwd: String,
+    /// Commands faster than this don't get their duration shown, like `5s` or `500ms`
+    pub duration_threshold: String,
+}
+
+impl Default for PromptSettings {
+    fn default() -> Self {
+        Self {
+            template: prompt::DEFAULT_TEMPLATE.to_string(),
+            right: String::new(),
+            transient: None,
+            cwd: String::new(),
+            duration_threshold: "5s".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ColorSettings {
+    /// Turns colors off everywhere when false, as does `NO_COLOR`
+    pub enabled: bool,
+    /// One of the built-in themes, `PROMPT_THEME` overrides it
+    pub theme: String,
+    /// Roles of the theme overridden one by one, like `primary = "green"`
+    pub palette: HashMap<String, String>,
+}
+
+impl Default for ColorSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            theme: "default".to_string(),
+            palette: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NotificationSettings {
+    /// Commands that run longer than this, like `30s` or `2m`, get a desktop notification
+    /// when they finish while the terminal isn't looked at. Off when unset, `NOTIFY_AFTER`
+    /// overrides it
+    pub after: Option<String>,
+    /// Commands that run longer than this get their time and CPU usage printed when they
+    /// finish. Off when unset, `REPORTTIME` overrides it
+    pub report_time: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SafetySettings {
+    /// Patterns like `rm -rf *` or `git push --force*`, a command at the prompt matching one
+    /// once expanded is only run after saying yes
+    pub confirm: Vec<String>,
+}
+
+/// Interactive niceties toggled with `shopt`, kept apart from anything that changes how
+/// commands run. The environment variables of the same name (`HISTVERIFY`, ...) turn
+/// them on too
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Options {
+    pub histverify: bool,
+    pub histshare: bool,
+    pub savealiases: bool,
+    pub direnv: bool,
+    pub shellintegration: bool,
+    pub accessible: bool,
+    pub autocd: bool,
+    pub captureoutput: bool,
+}
+
+/// Every option with what it does, listed by `shopt`
+pub const OPTIONS: [(&str, &str); 8] = [
+    (
+        "histverify",
+        "review lines changed by history expansion before running them",
+    ),
+    (
+        "histshare",
+        "show what other sessions ran when browsing the history",
+    ),
+    (
+        "savealiases",
+        "save every alias defined at the prompt to the rc file",
+    ),
+    (
+        "direnv",
+        "load the .shellenv or .env file of the directory cd enters",
+    ),
+    (
+        "shellintegration",
+        "mark prompts and command output for the terminal (OSC 133)",
+    ),
+    (
+        "accessible",
+        "plain prompts and lines for screen readers, with failures said out loud",
+    ),
+    (
+        "autocd",
+        "enter a directory typed on its own, like cd with it",
+    ),
+    (
+        "captureoutput",
+        "keep what the last command printed in LAST_OUTPUT",
+    ),
+];
+
+impl Options {
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "histverify" => Some(self.histverify),
+            "histshare" => Some(self.histshare),
+            "savealiases" => Some(self.savealiases),
+            "direnv" => Some(self.direnv),
+            "shellintegration" => Some(self.shellintegration),
+            "accessible" => Some(self.accessible),
+            "autocd" => Some(self.autocd),
+            "captureoutput" => Some(self.captureoutput),
+            _ => None,
+        }
+    }
+
+    /// Turns an option on or off, returns false if there's no option with that name
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        let option = match name {
+            "histverify" => &mut self.histverify,
+            "histshare" => &mut self.histshare,
+            "savealiases" => &mut self.savealiases,
+            "direnv" => &mut self.direnv,
+            "shellintegration" => &mut self.shellintegration,
+            "accessible" => &mut self.accessible,
+            "autocd" => &mut self.autocd,
+            "captureoutput" => &mut self.captureoutput,
+            _ => return false,
+        };
+        *option = value;
+        true
+    }
+}
+
+impl Settings {
+    /// Reads the config file, a missing file just gives the defaults. Settings with a
+    /// problem keep their default and come back as messages like `config.toml:3: ...`,
+    /// only a file that can't be read or isn't valid TOML at all is an error
+    pub fn load() -> Result<(Self, Vec<String>)> {
+        let path = config_path();
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok((Self::default(), Vec::new()));
+            }
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Failed to read config file {}", path.display()));
+            }
+        };
+
+        let (settings, problems) =
+            Self::check(&content).map_err(|error| anyhow!("{}:{}", path.display(), error))?;
+        let problems = problems
+            .iter()
+            .map(|problem| format!("{}:{}", path.display(), problem))
+            .collect();
+        Ok((settings, problems))
+    }
+
+    /// Like `check`, but any problem is an error
+    pub fn parse(content: &str) -> Result<Self> {
+        let (settings, mut problems) = Self::check(content)?;
+        match problems.is_empty() {
+            true => Ok(settings),
+            false => Err(problems.remove(0).into()),
+        }
+    }
+
+    /// Reads the settings one by one, leaving out the ones with a problem so they keep
+    /// their default. Unknown names, values of the wrong type, colors, themes, durations
+    /// and key bindings are all checked here rather than when they're used
+    pub fn check(content: &str) -> Result<(Self, Vec<Problem>)> {
+        let table: toml::Table = toml::from_str(content).map_err(|error| Problem {
+            line: error
+                .span()
+                .map(|span| line_at(content, span.start))
+                .unwrap_or(1),
+            message: error.message().trim().to_string(),
+        })?;
+
+        let mut leaves = Vec::new();
+        collect_leaves(&mut Vec::new(), &table, &mut leaves);
+
+        let known: Vec<String> = flatten(&Self::default())
+            .into_keys()
+            .chain(OPTIONAL_SETTINGS.iter().map(|name| name.to_string()))
+            .collect();
+        let mut valid = toml::Table::new();
+        let mut problems = Vec::new();
+        for (path, value) in leaves {
+            match check_setting(&path, &value, &known) {
+                Ok(()) => insert(&mut valid, &path, value),
+                Err(message) => problems.push(Problem {
+                    line: line_of(content, &path),
+                    message,
+                }),
+            }
+        }
+
+        let settings = toml::Value::Table(valid).try_into()?;
+        problems.sort_by_key(|problem| problem.line);
+        Ok((settings, problems))
+    }
+
+    /// What differs in other settings, one line per setting like `prompt.cwd = "short" (was "")`
+    /// sorted by name
+    pub fn changes(&self, other: &Settings) -> Vec<String> {
+        let old = flatten(self);
+        let new = flatten(other);
+
+        let mut changes = Vec::new();
+        for (name, value) in &new {
+            match old.get(name) {
+                Some(previous) if previous == value => {}
+                Some(previous) => changes.push(format!("{name} = {value} (was {previous})")),
+                None => changes.push(format!("{name} = {value} (added)")),
+            }
+        }
+        for (name, value) in &old {
+            if !new.contains_key(name) {
+                changes.push(format!("{name} removed (was {value})"));
+            }
+        }
+        changes
+    }
+
+    /// The default key bindings with the ones from the config file on top
+    pub fn keymap(&self) -> Result<Keymap> {
+        let mut keymap = Keymap::default();
+        for (key, action) in &self.keybindings {
+            keymap.bind(key, action)?;
+        }
+        Ok(keymap)
+    }
+}
+
+// Every setting by its dotted name, with its value written as TOML
+fn flatten(settings: &Settings) -> BTreeMap<String, String> {
+    fn walk(prefix: &str, value: &toml::Value, output: &mut BTreeMap<String, String>) {
+        match value {
+            toml::Value::Table(table) => {
+                for (key, value) in table {
+                    let name = match prefix {
+                        "" => key.clone(),
+                        _ => format!("{prefix}.{key}"),
+                    };
+                    walk(&name, value, output);
+                }
+            }
+            _ => {
+                output.insert(prefix.to_string(), value.to_string());
+            }
+        }
+    }
+
+    let mut output = BTreeMap::new();
+    if let Ok(value) = toml::Value::try_from(settings) {
+        walk("", &value, &mut output);
+    }
+    output
+}
+
+// Every value of the file with the names leading to it, tables of settings are walked into
+fn collect_leaves(
+    path: &mut Vec<String>,
+    table: &toml::Table,
+    leaves: &mut Vec<(Vec<String>, toml::Value)>,
+) {
+    for (key, value) in table {
+        path.push(key.clone());
+        match value {
+            toml::Value::Table(table) if !is_map_entry(path) => collect_leaves(path, table, leaves),
+            _ => leaves.push((path.clone(), value.clone())),
+        }
+        path.pop();
+    }
+}
+
+// An entry of one of the tables where any name goes, like `colors.palette.primary`
+fn is_map_entry(path: &[String]) -> bool {
+    path.len() > 1 && MAP_SETTINGS.contains(&path[..path.len() - 1].join(".").as_str())
+}
+
+fn insert(table: &mut toml::Table, path: &[String], value: toml::Value) {
+    let (name, parents) = path.split_last().expect("settings have a name");
+    let mut table = table;
+    for parent in parents {
+        let entry = table
+            .entry(parent.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        table = match entry {
+            toml::Value::Table(inner) => inner,
+            _ => return,
+        };
+    }
+    table.insert(name.clone(), value);
+}
+
+// What's wrong with a single setting, with a suggestion when there's one
+fn check_setting(path: &[String], value: &toml::Value, known: &[String]) -> Result<(), String> {
+    let name = path.join(".");
+    if !is_map_entry(path) && !known.contains(&name) {
+        let hint = suggest(&name, known.iter().map(String::as_str))
+            .map(|suggestion| format!(", did you mean {suggestion}?"))
+            .unwrap_or_default();
+        return Err(format!("Unknown setting {name}{hint}"));
+    }
+
+    // Types are left to serde, with the setting alone so nothing else gets in the way
+    let mut table = toml::Table::new();
+    insert(&mut table, path, value.clone());
+    if let Err(error) = toml::Value::Table(table).try_into::<Settings>() {
+        return Err(format!(
+            "Invalid value for {name}: {}",
+            error.message().trim()
+        ));
+    }
+
+    let text = value.as_str().unwrap_or_default();
+    match path[0].as_str() {
+        "colors" if path.len() == 3 && theme::escape(text).is_none() => {
+            return Err(format!(
+                "Unknown color '{text}' for {name}, use a name like red or bright_blue, \
+                 a number up to 255 or #rrggbb"
+            ));
+        }
+        "colors" if name == "colors.theme" => match Theme::load(text) {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                let themes = Theme::available();
+                let hint = suggest(text, themes.iter().map(String::as_str))
+                    .map(|suggestion| format!(", did you mean {suggestion}?"))
+                    .unwrap_or_else(|| format!(", there's {}", themes.join(", ")));
+                return Err(format!("Unknown theme '{text}'{hint}"));
+            }
+            Err(error) => return Err(format!("{error:#}")),
+        },
+        "prompt" | "notifications"
+            if matches!(
+                name.as_str(),
+                "prompt.duration_threshold" | "notifications.after" | "notifications.report_time"
+            ) && history::parse_duration(text).is_none() =>
+        {
+            return Err(format!(
+                "Invalid duration '{text}' for {name}, use something like 5s or 500ms"
+            ));
+        }
+        "keybindings" => {
+            if let Err(error) = Keymap::default().bind(&path[1], text) {
+                let hint = suggest(text, keymap::action_names())
+                    .map(|suggestion| format!(", did you mean {suggestion}?"))
+                    .unwrap_or_default();
+                return Err(format!("{error} for {name}{hint}"));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The candidate closest to a misspelled word, if one is close enough to be what was meant
+pub fn suggest<'a>(word: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (word.chars().count() / 3).max(1);
+    candidates
+        .map(|candidate| (edit_distance(word, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+// Edits needed to turn one word into the other, swapping two neighbouring characters counts
+// as one since that's the most common typo
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+fn line_at(content: &str, offset: usize) -> usize {
+    content[..offset.min(content.len())].matches('\n').count() + 1
+}
+
+// Line where a setting is written, found by following the table headers. Entries of inline
+// tables get the line of the table
+fn line_of(content: &str, path: &[String]) -> usize {
+    let name = path.join(".");
+    let mut table = String::new();
+    let mut table_line = 1;
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            table = line.trim_matches(['[', ']']).replace(['"', ' '], "");
+            if name.starts_with(&format!("{table}.")) {
+                table_line = number + 1;
+            }
+            continue;
+        }
+        let Some((key, _)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.replace(['"', '\'', ' '], "");
+        let full = match table.as_str() {
+            "" => key,
+            _ => format!("{table}.{key}"),
+        };
+        if full == name {
+            return number + 1;
+        }
+        if name.starts_with(&format!("{full}.")) {
+            table_line = number + 1;
+        }
+    }
+    table_line
+}
+
+/// Where the config file lives: `$XDG_CONFIG_HOME/rshell/config.toml`, which defaults
+/// to `~/.config/rshell/config.toml`
+pub fn config_path() -> PathBuf {
+    let config_home = match env::var_os("XDG_CONFIG_HOME").filter(|p| !p.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var("HOME").unwrap_or_else(|_| ".".to_string())).join(".config"),
+    };
+    config_home.join("rshell").join("config.toml")
+}
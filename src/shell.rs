@@ -1,63 +1,375 @@
 //shell.rs
 
 use crate::{
-    context::Context,
+    builtins::Builtin,
+    capture::Capture,
+    context::{Context, Mode},
     editor::Editor,
+    encoding,
     error::ShellError,
     executor, expander,
+    hooks::{self, Hook},
+    notify,
     parser::{Command, Parser},
-    prompt::Prompt,
-    terminal::Terminal,
+    prompt::{Prompt, signal_name, strip_escapes},
+    sys::{self, Pid},
+    terminal::{Backend, Mark, Terminal},
     tokenizer::Tokenizer,
 };
 use anyhow::Result;
+use std::{env, io, iter, path::Path, time::Instant};
+
+// Failures in a row after which an interactive session gives up, like when the terminal is
+// gone for good
+const MAX_FAILURES: usize = 3;
 
 pub struct Shell {
     pub terminal: Terminal,
     pub context: Context,
 }
 
+/// How to start a shell. Programs embedding one usually want `Mode::default()`, which reads
+/// no startup files
+#[derive(Default)]
+pub struct Config {
+    pub mode: Mode,
+    /// Draws the terminal with another backend than crossterm
+    pub backend: Option<Box<dyn Backend>>,
+    /// Commands of the program embedding the shell, run like the shell's own builtins
+    pub builtins: Vec<(String, Builtin)>,
+}
+
+impl From<Mode> for Config {
+    fn from(mode: Mode) -> Self {
+        Self {
+            mode,
+            ..Self::default()
+        }
+    }
+}
+
+/// What came of the commands given to `Shell::eval`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExecResult {
+    /// Status of the last command
+    pub status: i32,
+    /// Whether they ran `exit`, the shell shouldn't be given anything else to run then
+    pub exited: bool,
+}
+
 impl Shell {
-    pub fn new() -> Result<Shell> {
+    pub fn new(config: impl Into<Config>) -> Result<Shell> {
+        let config = config.into();
+        let mut context = Context::new(config.mode)?;
+        for (name, builtin) in config.builtins {
+            context.builtins.insert(&name, builtin);
+        }
         Ok(Self {
-            terminal: Terminal::new(),
-            context: Context::new()?,
+            terminal: config
+                .backend
+                .map_or_else(Terminal::new, Terminal::with_backend),
+            context,
         })
     }
 
-    pub fn run(&mut self) -> Result<()> {
+    /// Runs commands like a script would, they can span several lines. Errors are reported
+    /// on stderr and give a status of 1 like in scripts, only failing to run at all is an Err
+    pub fn eval(&mut self, commands: &str) -> Result<ExecResult> {
+        let keep_running = Context::source(&mut self.context, Path::new("eval"), commands)?;
+        Ok(ExecResult {
+            status: self.context.last_exit_code,
+            exited: !keep_running,
+        })
+    }
+
+    /// Adds a command of the program embedding the shell, it takes the place of a builtin or
+    /// program with the same name
+    pub fn add_builtin(&mut self, name: &str, builtin: Builtin) {
+        self.context.builtins.insert(name, builtin);
+    }
+
+    /// Reads and runs commands until `exit` or Ctrl-D, giving back the status of the last one
+    pub fn run(&mut self) -> Result<i32> {
         let mut prompt = Prompt::new();
         let mut editor = Editor::new();
+        self.context.signals.catch_ending();
 
-        self.terminal.clear_screen()?;
-        self.terminal.enter_raw_mode()?;
+        // Emacs shells, CI logs and the like get a plain prompt and whole lines instead
+        if !self.terminal.is_dumb() && self.terminal.enter_raw_mode().is_err() {
+            self.terminal.set_dumb();
+        }
+        if !self.terminal.is_dumb() && !self.context.accessible() {
+            self.terminal.clear_screen()?;
+        }
 
-        loop {
-            if self.context.signals.drain_child_pipe() {
-                self.context.jobs.update_table(&mut self.terminal)?;
+        let mut failures = 0;
+        let result = loop {
+            match self.read_and_run(&mut prompt, &mut editor) {
+                Ok(true) => failures = 0,
+                Ok(false) => {
+                    // Jobs go with the session unless they were disowned, a hangup already
+                    // took care of them
+                    if self.context.signals.ending().is_none() {
+                        self.context.jobs.hang_up();
+                    }
+                    break Ok(self.context.last_exit_code);
+                }
+                Err(error) if failures + 1 < MAX_FAILURES => {
+                    failures += 1;
+                    self.recover(&error);
+                }
+                Err(error) => break Err(error),
             }
+        };
 
-            Self::update_prompt(
-                &mut self.context,
-                &mut self.terminal,
-                &mut editor,
-                &mut prompt,
-            )?;
+        // Whatever happened, the terminal is handed back the way it was found
+        let restored = self.terminal.exit_raw_mode();
+        let status = result?;
+        restored?;
+        Ok(status)
+    }
 
-            let line = editor.read_line(&mut self.context, &mut self.terminal, &mut prompt)?;
-            if line.is_empty() {
-                continue;
+    // Reads a line and runs it, false once the session is over
+    fn read_and_run(&mut self, prompt: &mut Prompt, editor: &mut Editor) -> Result<bool> {
+        // It may have come while a command ran
+        if self.hung_up() {
+            return Ok(false);
+        }
+        if self.context.signals.drain_child_pipe() {
+            self.context.jobs.update_table(&mut self.terminal)?;
+        }
+        notify::jobs_finished(&mut self.context, &mut self.terminal)?;
+
+        hooks::run(&mut self.context, &mut self.terminal, Hook::Precmd, &[])?;
+        // Screen readers follow plain lines better than a line redrawn in place, so the
+        // accessible option reads them like a dumb terminal does. It can change at any
+        // prompt with `shopt`
+        let accessible = self.context.accessible();
+        let dumb = self.terminal.is_dumb() || accessible;
+        match dumb {
+            true => self.terminal.exit_raw_mode()?,
+            false => self.terminal.enter_raw_mode()?,
+        }
+        let line = match dumb {
+            true => self.read_plain_line(prompt)?,
+            false => {
+                Self::update_prompt(&mut self.context, &mut self.terminal, editor, prompt)?;
+                editor.read_line(&mut self.context, &mut self.terminal, prompt)?
             }
+        };
+        let Some(mut line) = line else {
+            // Ctrl-D with jobs around lists them first, like `exit`
+            if !self.hung_up() && !self.context.jobs.confirm_exit(&mut self.terminal)? {
+                return Ok(true);
+            }
+            return Ok(false);
+        };
+        if line.is_empty() {
+            return Ok(true);
+        }
 
-            let command = Self::parse_command(&mut self.context, &mut self.terminal, &line, true)?;
-            if !Self::execute_command(&mut self.context, &mut self.terminal, command)?.0 {
-                break;
+        // Designators like !n or !prefix can point anywhere in the history
+        if line.contains('!') {
+            self.context.history.load_all()?;
+        }
+
+        match self.context.history.expand(&line) {
+            Ok(Some(expanded)) => {
+                // Like bash we always echo the expanded line, and with histverify on
+                // we hand it back to the editor instead of running it straight away
+                self.terminal.println(&expanded)?;
+                // The plain prompt has no editor to hand it back to
+                let verify = (self.context.settings.options.histverify
+                    || env::var_os("HISTVERIFY").is_some())
+                    && !dumb;
+                if verify {
+                    editor.preload(&expanded);
+                    return Ok(true);
+                }
+                line = expanded;
+            }
+            Ok(None) => {}
+            Err(error) => {
+                self.terminal.println(&error.to_string())?;
+                return Ok(true);
             }
         }
 
-        self.terminal.exit_raw_mode()?;
+        let line = self.read_rest(editor, dumb, line)?;
+        if !self.context.mode.private {
+            self.context.history.add(&line)?;
+        }
+        hooks::run(
+            &mut self.context,
+            &mut self.terminal,
+            Hook::Preexec,
+            &[&line],
+        )?;
 
-        Ok(())
+        let marks = self.context.shell_integration() && !self.terminal.is_dumb();
+        if marks {
+            self.terminal.mark(Mark::OutputStart)?;
+        }
+        let started = Instant::now();
+        let usage = notify::Usage::now();
+        // Leaving only goes through right after the warning about jobs
+        let warned = self.context.jobs.exit_warned;
+        // A line that doesn't parse or expand is only a mistake, the session goes on
+        // Typed text is UTF-8 already, it's read like a script so it's given back the same
+        let typed = encoding::decode(line.as_bytes());
+        let keep_running =
+            match Self::parse_command(&mut self.context, &mut self.terminal, &typed, true) {
+                Ok(command) => {
+                    Self::execute_command(&mut self.context, &mut self.terminal, command)?.0
+                }
+                Err(error) => {
+                    self.terminal.println(&format!("{:#}", error))?;
+                    self.context.last_exit_code = match error.downcast_ref::<ShellError>() {
+                        Some(error) if error.is_syntax() => 2,
+                        _ => 1,
+                    };
+                    true
+                }
+            };
+        let elapsed = started.elapsed();
+        if notify::report_threshold(&self.context).is_some_and(|threshold| elapsed >= threshold) {
+            let usage = notify::Usage::now().since(&usage);
+            eprintln!("{}", notify::report(&line, elapsed, usage));
+        }
+        if marks {
+            self.terminal
+                .mark(Mark::CommandEnd(self.context.last_exit_code))?;
+        }
+        self.context.last_duration = Some(elapsed);
+        self.context
+            .history
+            .finish(self.context.last_exit_code, elapsed)?;
+        if !keep_running {
+            return Ok(false);
+        }
+        if warned {
+            self.context.jobs.exit_warned = false;
+        }
+        notify::command_finished(
+            &self.context,
+            &self.terminal,
+            &line,
+            self.context.last_exit_code,
+            elapsed,
+        );
+        if accessible {
+            Self::say_status(&mut self.terminal, self.context.last_exit_code)?;
+        }
+        Ok(true)
+    }
+
+    // A hiccup of the terminal, the history file or a hook is told and the session goes on
+    // from a fresh prompt, which puts raw mode back
+    fn recover(&mut self, error: &anyhow::Error) {
+        let _ = self.terminal.exit_raw_mode();
+        eprintln!("rshell: {:#}", error);
+        self.context.last_exit_code = 1;
+    }
+
+    /// Runs a script without a prompt or raw mode, giving back the status of its last command.
+    /// `$0` is the path of the script
+    pub fn run_script(&mut self, path: &Path) -> Result<i32> {
+        self.context.name = path.display().to_string();
+        Context::source_file(&mut self.context, path)?;
+        Ok(self.context.last_exit_code)
+    }
+
+    /// Runs the command string given with `-c`, giving back the status of its last command
+    pub fn run_command(&mut self, command: &str) -> Result<i32> {
+        Context::source(&mut self.context, Path::new("-c"), command)?;
+        Ok(self.context.last_exit_code)
+    }
+
+    /// Runs the commands piped into stdin when it isn't a terminal, like `echo ls | rshell`,
+    /// without raw mode, a prompt or the history
+    pub fn run_batch(&mut self) -> Result<i32> {
+        Context::run_lines(
+            &mut self.context,
+            Path::new("stdin"),
+            iter::from_fn(read_line),
+        )?;
+        Ok(self.context.last_exit_code)
+    }
+
+    // A failure said in words with a bell, instead of only the color of the next prompt
+    fn say_status(terminal: &mut Terminal, status: i32) -> Result<()> {
+        let message = match status {
+            0 => return Ok(()),
+            _ if status > 128 => match signal_name(status - 128) {
+                Some(signal) => format!("Killed by {}, status {}", signal, status),
+                None => format!("Failed with status {}", status),
+            },
+            _ => format!("Failed with status {}", status),
+        };
+        terminal.println(&format!("\x07{}", message))
+    }
+
+    // A line read whole from stdin after a prompt without escape sequences, what a dumb
+    // terminal gets instead of the line editor. None at the end of the input
+    fn read_plain_line(&mut self, prompt: &mut Prompt) -> Result<Option<String>> {
+        let context = &mut self.context;
+        context.update_cwd();
+        prompt.update(context);
+        for line in context.jobs.get_background_stdout()? {
+            self.terminal.print(&line)?;
+        }
+        for notification in std::mem::take(&mut self.terminal.notifications) {
+            self.terminal.println(&notification)?;
+        }
+
+        self.terminal.print(&strip_escapes(&prompt.message))?;
+        let line = read_line();
+        if line.is_none() {
+            self.terminal.println("")?;
+        }
+        Ok(line)
+    }
+
+    // A command that goes on past the line, like a here-document before its terminator or a
+    // `for` without its `done`, gets the lines it's missing from a `PROMPT2` prompt, `> ` by
+    // default. Ctrl-D stops asking and the command fails like in a script
+    fn read_rest(&mut self, editor: &mut Editor, dumb: bool, mut line: String) -> Result<String> {
+        let mut prompt = Prompt::new();
+        let message = self.context.variables.get("PROMPT2");
+        prompt.set(message.unwrap_or_else(|| "> ".to_string()));
+        while is_incomplete(&line) {
+            let more = match dumb {
+                true => {
+                    self.terminal.print(&strip_escapes(&prompt.message))?;
+                    read_line()
+                }
+                false => {
+                    editor.set_prompt(&mut self.terminal)?;
+                    editor.read_line(&mut self.context, &mut self.terminal, &mut prompt)?
+                }
+            };
+            let Some(more) = more else {
+                break;
+            };
+            line.push('\n');
+            line.push_str(&more);
+        }
+        Ok(line)
+    }
+
+    // A hangup or SIGTERM ends the session with the status a program killed by it has, jobs
+    // are hung up with the terminal like bash does
+    fn hung_up(&mut self) -> bool {
+        let Some(signal) = self.context.signals.ending() else {
+            return false;
+        };
+        #[cfg(unix)]
+        if signal == libc::SIGHUP {
+            self.context.jobs.hang_up();
+        }
+        self.context.last_exit_code = 128 + signal;
+        tracing::debug!(signal, "Session ended by a signal");
+        true
     }
 
     fn update_prompt(
@@ -66,7 +378,10 @@ impl Shell {
         editor: &mut Editor,
         prompt: &mut Prompt,
     ) -> Result<()> {
-        prompt.update(context.update_cwd());
+        context.update_cwd();
+        prompt.update(context);
+        // Cheap enough to redo for every prompt, this way `reload` applies to the bindings too
+        editor.keymap = context.settings.keymap()?;
 
         if let Err(e) = editor.set_prompt(terminal) {
             terminal.println(&format!("Terminal Error: {:?}", e))?;
@@ -99,7 +414,8 @@ impl Shell {
                     context, terminal, command, args, redirects, env_vars,
                 )?)
             } else {
-                unreachable!("You can only own simple command")
+                // Like an alias for a pipeline, the caller expands its parts
+                Ok(raw_command.into_owned())
             }
         }
     }
@@ -108,12 +424,24 @@ impl Shell {
         context: &mut Context,
         terminal: &mut Terminal,
         command: Command<'static>,
-    ) -> Result<(bool, libc::pid_t)> {
+    ) -> Result<(bool, Pid)> {
+        let raw = terminal.is_raw();
         terminal.exit_raw_mode()?;
 
+        // Programs see a pipe instead of the terminal meanwhile, so it's only on when asked for
+        let capture = match context.capture_output() {
+            true => Some(Capture::start()?),
+            false => None,
+        };
         let result = executor::execute(context, terminal, command, None);
+        if let Some(capture) = capture {
+            let output = capture.finish();
+            context.variables.assign("LAST_OUTPUT", &output)?;
+        }
 
-        terminal.enter_raw_mode()?;
+        if raw {
+            terminal.enter_raw_mode()?;
+        }
 
         match result {
             Ok((exit_code, pgid)) => {
@@ -132,3 +460,35 @@ impl Shell {
         }
     }
 }
+
+// Whether the line only needs more lines to be a command
+fn is_incomplete(line: &str) -> bool {
+    let error = match Tokenizer::tokenize(line) {
+        Ok(tokens) => match Parser::parse(&tokens) {
+            Ok(_) => return false,
+            Err(error) => error,
+        },
+        Err(error) => error,
+    };
+    error
+        .downcast_ref::<ShellError>()
+        .is_some_and(|error| error.is_incomplete())
+}
+
+/// A line of stdin, None at the end. Read a byte at a time so that nothing past the line is
+/// taken away from the commands, which read the rest of stdin themselves
+pub fn read_line() -> Option<String> {
+    let mut line = Vec::new();
+    let mut byte = 0u8;
+    loop {
+        let read = unsafe { libc::read(sys::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) };
+        match read {
+            1 if byte == b'\n' => break,
+            1 => line.push(byte),
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            _ if line.is_empty() => return None,
+            _ => break,
+        }
+    }
+    Some(encoding::decode(&line))
+}
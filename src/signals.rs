@@ -2,16 +2,57 @@
 
 use crate::error::{ShellError, ShellPhase};
 use anyhow::Result;
+#[cfg(windows)]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(unix)]
 use std::{
     io,
     os::unix::io::RawFd,
-    sync::atomic::{AtomicI32, Ordering},
+    sync::atomic::{AtomicI32, AtomicU64, Ordering},
 };
+#[cfg(windows)]
+use windows_sys::{
+    Win32::System::Console::{
+        CTRL_BREAK_EVENT, CTRL_C_EVENT, CTRL_CLOSE_EVENT, CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT,
+        SetConsoleCtrlHandler,
+    },
+    core::BOOL,
+};
+
+/// The signals an interactive shell ignores, programs it starts get them back. SIGPIPE is
+/// ignored by every Rust program, without it `yes | head` ends with a write error
+#[cfg(unix)]
+pub const IGNORED: [libc::c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGTSTP,
+    libc::SIGTTOU,
+    libc::SIGTTIN,
+    libc::SIGPIPE,
+];
+
+/// What ends an interactive session like `exit` does, so the terminal is left the way it was
+/// found. On a hangup the jobs get SIGHUP too
+#[cfg(unix)]
+pub const ENDING: [libc::c_int; 2] = [libc::SIGHUP, libc::SIGTERM];
+
+/// Windows has no hangup, closing the console or logging off comes as SIGTERM
+#[cfg(windows)]
+pub const ENDING: [libc::c_int; 1] = [libc::SIGTERM];
 
+// Handlers only do what's async-signal-safe: they mark the signal in PENDING and write a byte
+// to the self-pipe, so nothing is allocated, locked or drawn halfway through a redraw. What
+// the signal means is handled once the shell gets back to its loop
+#[cfg(unix)]
 static PIPE_WRITE_END: AtomicI32 = AtomicI32::new(-1);
+static PENDING: AtomicU64 = AtomicU64::new(0);
+#[cfg(unix)]
 static SIGNAL_BYTE: u8 = 1;
 
-extern "C" fn sigchld_handler(_: libc::c_int) {
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    // The code the signal interrupted may look at errno right after, write could change it
+    let errno = unsafe { *libc::__errno_location() };
+    PENDING.fetch_or(1 << signal, Ordering::Relaxed);
     let fd = PIPE_WRITE_END.load(Ordering::Relaxed);
     if fd != -1 {
         let byte = &SIGNAL_BYTE as *const u8 as *const libc::c_void;
@@ -19,20 +60,57 @@ extern "C" fn sigchld_handler(_: libc::c_int) {
             libc::write(fd, byte, 1);
         }
     }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+// Ctrl-C and Ctrl-Break are left to the programs on the console, the shell stays. Children
+// don't inherit a handler, so they still get them
+#[cfg(windows)]
+unsafe extern "system" fn on_console_event(event: u32) -> BOOL {
+    match event {
+        CTRL_C_EVENT | CTRL_BREAK_EVENT => 1,
+        CTRL_CLOSE_EVENT | CTRL_LOGOFF_EVENT | CTRL_SHUTDOWN_EVENT => {
+            PENDING.fetch_or(1 << libc::SIGTERM, Ordering::Relaxed);
+            1
+        }
+        _ => 0,
+    }
 }
 
+#[cfg(unix)]
 #[derive(Clone)]
 pub struct SignalHandler {
     pub sigchld_fd: RawFd,
 }
 
+/// There are no signals between processes on Windows, only the events of the console
+#[cfg(windows)]
+pub struct SignalHandler {
+    installed: bool,
+}
+
+// A copy, like the context of a subshell, leaves the handler to the one that installed it
+#[cfg(windows)]
+impl Clone for SignalHandler {
+    fn clone(&self) -> Self {
+        Self::dummy()
+    }
+}
+
+// Every signal going through the self-pipe
+#[cfg(unix)]
+const CAUGHT: [libc::c_int; 4] = [libc::SIGCHLD, libc::SIGWINCH, libc::SIGHUP, libc::SIGTERM];
+
+#[cfg(unix)]
 impl Drop for SignalHandler {
     fn drop(&mut self) {
         unsafe {
-            // Reset signal handler first to prevent races
+            // Reset signal handlers first to prevent races
             let mut signal_action: libc::sigaction = std::mem::zeroed();
             signal_action.sa_sigaction = libc::SIG_DFL;
-            libc::sigaction(libc::SIGCHLD, &signal_action, std::ptr::null_mut());
+            for signal in CAUGHT {
+                libc::sigaction(signal, &signal_action, std::ptr::null_mut());
+            }
 
             // Clear out the atomic so the handler (if running) sees -1
             let write_fd = PIPE_WRITE_END.swap(-1, Ordering::Relaxed);
@@ -46,6 +124,7 @@ impl Drop for SignalHandler {
     }
 }
 
+#[cfg(unix)]
 impl SignalHandler {
     pub fn new() -> Result<Self> {
         Self::ignore();
@@ -58,15 +137,38 @@ impl SignalHandler {
     }
 
     pub fn drain_child_pipe(&self) -> bool {
+        self.received(libc::SIGCHLD)
+    }
+
+    /// Whether the signal came since the last time this was asked
+    pub fn received(&self, signal: libc::c_int) -> bool {
         let mut buffer = [0u8; 64];
-        let bytes_read = unsafe {
+        while unsafe {
             libc::read(
                 self.sigchld_fd,
                 buffer.as_mut_ptr() as *mut libc::c_void,
-                64,
+                buffer.len(),
             )
-        };
-        bytes_read > 0
+        } > 0
+        {}
+        let bit = 1 << signal;
+        PENDING.fetch_and(!bit, Ordering::Relaxed) & bit != 0
+    }
+
+    /// The signal ending the session, it stays there once it came
+    pub fn ending(&self) -> Option<libc::c_int> {
+        let pending = PENDING.load(Ordering::Relaxed);
+        ENDING
+            .into_iter()
+            .find(|signal| pending & (1 << signal) != 0)
+    }
+
+    /// Lets a hangup or SIGTERM end the session cleanly instead of killing the shell in raw
+    /// mode. Only for the interactive loop, scripts still die by them
+    pub fn catch_ending(&self) {
+        for signal in ENDING {
+            Self::catch(signal);
+        }
     }
 
     pub fn ignore() {
@@ -84,19 +186,24 @@ impl SignalHandler {
     }
 
     pub fn reset(&self) {
-        unsafe {
-            libc::signal(libc::SIGINT, libc::SIG_DFL);
-            libc::signal(libc::SIGTSTP, libc::SIG_DFL);
-            libc::signal(libc::SIGTTOU, libc::SIG_DFL);
-            libc::signal(libc::SIGTTIN, libc::SIG_DFL);
+        Self::restore_defaults();
+    }
+
+    /// Puts back the default dispositions, so the programs we start don't inherit what the
+    /// shell ignores
+    pub fn restore_defaults() {
+        for signal in IGNORED {
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+            }
         }
     }
 
     fn setup_self_pipe_trick() -> Result<RawFd> {
         unsafe {
-            // Create the pipe and make it non blocking
+            // Create the pipe and make it non blocking, programs we start don't get it
             let mut fds = [0i32; 2];
-            if libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK) == -1 {
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) == -1 {
                 return Self::os_error();
             }
 
@@ -106,22 +213,29 @@ impl SignalHandler {
             // signal handler C function callback
             PIPE_WRITE_END.store(write_end, Ordering::Relaxed);
 
-            // Setup the signal action with the sigchld hanlder we created
+            Self::catch(libc::SIGCHLD);
+            // The line is drawn again for the new size
+            Self::catch(libc::SIGWINCH);
+
+            Ok(read_end)
+        }
+    }
+
+    fn catch(signal: libc::c_int) {
+        unsafe {
             let mut signal_action: libc::sigaction = std::mem::zeroed();
 
             // We create a pointer to the function and we cast it to the sighandler_t type
-            signal_action.sa_sigaction = sigchld_handler as *const () as libc::sighandler_t;
+            signal_action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
 
             // Setup the signal mask (the behavior basically)
             // SA_RESTART: makes system calls interrupted by signals automatically restart.
-            // SA_NOCLD_STOP: stops SIGCHLD from arriving when child processes stop. You only get it when they terminate.
+            // SA_NOCLDSTOP: stops SIGCHLD from arriving when child processes stop. You only get it when they terminate.
             libc::sigemptyset(&mut signal_action.sa_mask);
             signal_action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
 
             // Create the signal handler itself with everything we did before
-            libc::sigaction(libc::SIGCHLD, &signal_action, std::ptr::null_mut());
-
-            Ok(read_end)
+            libc::sigaction(signal, &signal_action, std::ptr::null_mut());
         }
     }
 
@@ -137,3 +251,58 @@ impl SignalHandler {
         }))
     }
 }
+
+#[cfg(windows)]
+impl Drop for SignalHandler {
+    fn drop(&mut self) {
+        if self.installed {
+            unsafe { SetConsoleCtrlHandler(Some(on_console_event), 0) };
+        }
+    }
+}
+
+#[cfg(windows)]
+impl SignalHandler {
+    pub fn new() -> Result<Self> {
+        if unsafe { SetConsoleCtrlHandler(Some(on_console_event), 1) } == 0 {
+            return Err(anyhow::Error::new(ShellError {
+                phase: ShellPhase::SignalHandler,
+                command: None,
+                message: std::io::Error::last_os_error().to_string(),
+            }));
+        }
+        Ok(Self { installed: true })
+    }
+
+    pub fn dummy() -> Self {
+        Self { installed: false }
+    }
+
+    /// Children are always waited for on Windows, none ends unnoticed
+    pub fn drain_child_pipe(&self) -> bool {
+        false
+    }
+
+    /// Whether the signal came since the last time this was asked
+    pub fn received(&self, signal: libc::c_int) -> bool {
+        let bit = 1 << signal;
+        PENDING.fetch_and(!bit, Ordering::Relaxed) & bit != 0
+    }
+
+    /// The signal ending the session, it stays there once it came
+    pub fn ending(&self) -> Option<libc::c_int> {
+        let pending = PENDING.load(Ordering::Relaxed);
+        ENDING
+            .into_iter()
+            .find(|signal| pending & (1 << signal) != 0)
+    }
+
+    /// The console events are caught from the start
+    pub fn catch_ending(&self) {}
+
+    pub fn ignore() {}
+
+    pub fn reset(&self) {}
+
+    pub fn restore_defaults() {}
+}
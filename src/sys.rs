@@ -0,0 +1,96 @@
+//sys.rs
+
+// What the rest of the shell shares between Unix and Windows. What only Unix has, like job
+// control, uses libc straight away behind cfg(unix)
+
+#[cfg(windows)]
+use std::env;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[cfg(unix)]
+pub use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+#[cfg(unix)]
+pub use std::os::fd::RawFd;
+
+/// A process id, on Unix also the id of the process group it leads
+#[cfg(unix)]
+pub type Pid = libc::pid_t;
+
+// The C runtime numbers files like Unix does, the standard ones included
+#[cfg(windows)]
+pub type RawFd = libc::c_int;
+#[cfg(windows)]
+pub const STDIN_FILENO: RawFd = 0;
+#[cfg(windows)]
+pub const STDOUT_FILENO: RawFd = 1;
+#[cfg(windows)]
+pub const STDERR_FILENO: RawFd = 2;
+
+#[cfg(windows)]
+pub type Pid = i32;
+
+/// The id of the shell itself
+pub fn pid() -> Pid {
+    std::process::id() as Pid
+}
+
+/// Whether a file can be run. On Windows that's told by its extension, one of `PATHEXT`
+#[cfg(unix)]
+pub fn is_executable(_path: &Path, metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(windows)]
+pub fn is_executable(path: &Path, metadata: &fs::Metadata) -> bool {
+    let Some(extension) = path.extension() else {
+        return false;
+    };
+    metadata.is_file()
+        && extensions().iter().any(|known| {
+            known
+                .strip_prefix('.')
+                .is_some_and(|known| extension.eq_ignore_ascii_case(known))
+        })
+}
+
+/// The file of a program in a directory of `PATH`. Windows adds the extensions of `PATHEXT`
+/// to the name, `git` is `git.exe`
+#[cfg(unix)]
+pub fn program_file(directory: &Path, name: &str) -> Option<PathBuf> {
+    let path = directory.join(name);
+    path.is_file().then_some(path)
+}
+
+#[cfg(windows)]
+pub fn program_file(directory: &Path, name: &str) -> Option<PathBuf> {
+    std::iter::once(name.to_string())
+        .chain(
+            extensions()
+                .iter()
+                .map(|extension| format!("{name}{extension}")),
+        )
+        .map(|name| directory.join(name))
+        .find(|path| fs::metadata(path).is_ok_and(|metadata| is_executable(path, &metadata)))
+}
+
+#[cfg(windows)]
+fn extensions() -> Vec<String> {
+    env::var("PATHEXT")
+        .unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into())
+        .split(';')
+        .filter(|extension| !extension.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether the shell runs as root, never on Windows
+pub fn is_root() -> bool {
+    #[cfg(unix)]
+    return unsafe { libc::geteuid() } == 0;
+    #[cfg(windows)]
+    return false;
+}
@@ -3,129 +3,500 @@
 use anyhow::{Context, Result};
 use crossterm::{
     cursor::{MoveDown, MoveLeft, MoveRight, MoveTo, MoveUp},
-    execute,
+    event::{self, DisableFocusChange, EnableFocusChange, Event, KeyEvent},
+    execute, queue,
     terminal::{Clear, ClearType},
 };
 use std::{
-    io::{Stdout, Write, stdout},
-    os::fd::AsRawFd,
+    env,
+    io::{self, IsTerminal, Stdout, Write, stdout},
+    time::Duration,
 };
+use unicode_width::UnicodeWidthChar;
+
+/// What the shell needs from the terminal it runs in. Colors are escape sequences written
+/// with the rest of the output, so a backend only has to pass them through
+pub trait Backend {
+    fn enable_raw_mode(&mut self) -> Result<()>;
+    fn disable_raw_mode(&mut self) -> Result<()>;
+    /// Writes the output and flushes it
+    fn write(&mut self, output: &str) -> Result<()>;
+    fn move_to(&mut self, column: u16, row: u16) -> Result<()>;
+    /// Moves the cursor by a number of columns and rows, negative ones go left and up
+    fn move_by(&mut self, columns: i16, rows: i16) -> Result<()>;
+    fn clear_screen(&mut self) -> Result<()>;
+    fn clear_current_line(&mut self) -> Result<()>;
+    /// As (columns, rows)
+    fn size(&mut self) -> Result<(u16, u16)>;
+    fn cursor_position(&mut self) -> Result<(u16, u16)>;
+    /// The next key pressed, None when there's none before the timeout
+    fn read_key(&mut self, timeout: Duration) -> Result<Option<KeyEvent>>;
+    /// Whether the terminal window has the focus, None when it's not known
+    fn focused(&self) -> Option<bool> {
+        None
+    }
+    /// Holds back the output until `end_batch`, so a whole redraw reaches the terminal in a
+    /// single write. Backends that don't buffer can leave it out
+    fn begin_batch(&mut self) {}
+    /// Writes out what was held back, in one go
+    fn end_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The backend used by default, on Windows it drives the console
+pub struct CrosstermBackend {
+    stdout: Stdout,
+    // Told by the terminal in raw mode, commands run outside of it may see changes instead
+    focused: Option<bool>,
+    // Output held back until the end of the batch
+    batch: Option<Vec<u8>>,
+}
+
+impl CrosstermBackend {
+    pub fn new() -> Self {
+        Self {
+            stdout: stdout(),
+            focused: None,
+            batch: None,
+        }
+    }
+
+    // Adds to the batch when there's one, otherwise writes straight to the terminal
+    fn output(&mut self, write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> io::Result<()> {
+        if let Some(batch) = self.batch.as_mut() {
+            return write(batch);
+        }
+        let mut output = Vec::new();
+        write(&mut output)?;
+        self.stdout.write_all(&output)?;
+        self.stdout.flush()
+    }
+}
+
+impl Default for CrosstermBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for CrosstermBackend {
+    fn enable_raw_mode(&mut self) -> Result<()> {
+        crossterm::terminal::enable_raw_mode().context("Failed to enable terminal raw mode")?;
+
+        #[cfg(unix)]
+        unsafe {
+            use std::os::fd::AsRawFd;
+
+            let fd = self.stdout.as_raw_fd();
+            let mut termios = std::mem::zeroed();
+            libc::tcgetattr(fd, &mut termios);
+
+            // Keep output processing (OPOST) and force NL to CR-NL (ONLCR)
+            termios.c_oflag |= libc::OPOST | libc::ONLCR;
+
+            libc::tcsetattr(fd, libc::TCSANOW, &termios);
+        }
+        execute!(self.stdout, EnableFocusChange).context("Failed to ask for focus changes")
+    }
+
+    fn disable_raw_mode(&mut self) -> Result<()> {
+        // Commands would get the focus changes as typed text
+        self.focused = None;
+        execute!(self.stdout, DisableFocusChange).context("Failed to stop focus changes")?;
+        crossterm::terminal::disable_raw_mode().context("Failed to disable terminal raw mode")
+    }
+
+    fn write(&mut self, output: &str) -> Result<()> {
+        self.output(|out| out.write_all(output.as_bytes()))
+            .with_context(|| format!("Failed to write output to terminal: {}", output))
+    }
+
+    fn move_to(&mut self, column: u16, row: u16) -> Result<()> {
+        self.output(|out| queue!(out, MoveTo(column, row)))
+            .with_context(|| format!("Failed to move cursor to ({}, {})", column, row))
+    }
+
+    fn move_by(&mut self, columns: i16, rows: i16) -> Result<()> {
+        let (right, down) = (columns.unsigned_abs(), rows.unsigned_abs());
+        self.output(|out| {
+            match columns.signum() {
+                1 => queue!(out, MoveRight(right))?,
+                -1 => queue!(out, MoveLeft(right))?,
+                _ => {}
+            }
+            match rows.signum() {
+                1 => queue!(out, MoveDown(down)),
+                -1 => queue!(out, MoveUp(down)),
+                _ => Ok(()),
+            }
+        })
+        .context("Failed to move cursor")
+    }
+
+    fn clear_screen(&mut self) -> Result<()> {
+        self.output(|out| queue!(out, Clear(ClearType::All), MoveTo(0, 0)))
+            .context("Failed to clear screen and reset cursor position")
+    }
+
+    fn clear_current_line(&mut self) -> Result<()> {
+        self.output(|out| queue!(out, Clear(ClearType::CurrentLine)))
+            .context("Failed to clear line")
+    }
+
+    fn size(&mut self) -> Result<(u16, u16)> {
+        crossterm::terminal::size().context("Failed to retrieve terminal size")
+    }
+
+    fn cursor_position(&mut self) -> Result<(u16, u16)> {
+        crossterm::cursor::position().context("Failed to retrieve cursor position")
+    }
+
+    fn read_key(&mut self, timeout: Duration) -> Result<Option<KeyEvent>> {
+        if !event::poll(timeout)? {
+            return Ok(None);
+        }
+        match event::read().context("Failed to read event")? {
+            Event::Key(key) => Ok(Some(key)),
+            Event::FocusGained => {
+                self.focused = Some(true);
+                Ok(None)
+            }
+            Event::FocusLost => {
+                self.focused = Some(false);
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn focused(&self) -> Option<bool> {
+        self.focused
+    }
+
+    fn begin_batch(&mut self) {
+        self.batch.get_or_insert_with(Vec::new);
+    }
+
+    fn end_batch(&mut self) -> Result<()> {
+        let Some(batch) = self.batch.take() else {
+            return Ok(());
+        };
+        self.stdout
+            .write_all(&batch)
+            .and_then(|_| self.stdout.flush())
+            .context("Failed to write output to terminal")
+    }
+}
+
+/// The marks of OSC 133, which terminals like kitty, WezTerm and iTerm2 use to tell prompts,
+/// commands and their output apart, for jumping between prompts or selecting an output
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mark {
+    PromptStart,
+    /// Where the prompt ends and the command typed starts
+    CommandStart,
+    OutputStart,
+    /// With the status of the command
+    CommandEnd(i32),
+}
+
+impl Mark {
+    pub fn sequence(&self) -> String {
+        match self {
+            Self::PromptStart => String::from("\x1b]133;A\x07"),
+            Self::CommandStart => String::from("\x1b]133;B\x07"),
+            Self::OutputStart => String::from("\x1b]133;C\x07"),
+            Self::CommandEnd(status) => format!("\x1b]133;D;{}\x07", status),
+        }
+    }
+}
 
 pub struct Terminal {
-    pub stdout: Stdout,
+    backend: Box<dyn Backend>,
     pub notifications: Vec<String>,
     is_raw: bool,
+    dumb: bool,
+    // Where the cursor is, followed through everything written and every move so the terminal
+    // only has to be asked when it's not known. A column past the last one is a line about to
+    // wrap, like terminals do
+    cursor: Option<(u16, u16)>,
+    // The size the cursor was followed with, a new one makes the cursor unknown again
+    screen: Option<(u16, u16)>,
+    batching: bool,
 }
 
 impl Terminal {
     pub fn new() -> Self {
+        let mut terminal = Self::with_backend(Box::new(CrosstermBackend::new()));
+        terminal.dumb = matches!(env::var("TERM").as_deref(), Ok("dumb"))
+            || !io::stdin().is_terminal()
+            || !io::stdout().is_terminal();
+        terminal
+    }
+
+    /// A terminal drawn by another backend than crossterm
+    pub fn with_backend(backend: Box<dyn Backend>) -> Self {
         Self {
-            stdout: stdout(),
+            backend,
             is_raw: false,
+            dumb: false,
             notifications: Vec::new(),
+            cursor: None,
+            screen: None,
+            batching: false,
         }
     }
 
-    /// Enter raw mode explicitly
-    pub fn enter_raw_mode(&mut self) -> Result<()> {
-        if !self.is_raw {
-            crossterm::terminal::enable_raw_mode().context("Failed to enable terminal raw mode")?;
-
-            let fd = self.stdout.as_raw_fd();
-            unsafe {
-                let mut termios = std::mem::zeroed();
-                libc::tcgetattr(fd, &mut termios);
-
-                // Keep output processing (OPOST) and force NL to CR-NL (ONLCR)
-                termios.c_oflag |= libc::OPOST | libc::ONLCR;
+    /// Whether it can't do raw mode and escape sequences, like with `TERM=dumb` in Emacs or
+    /// when the output goes to a file. Lines are then read whole, with no editing
+    pub fn is_dumb(&self) -> bool {
+        self.dumb
+    }
 
-                libc::tcsetattr(fd, libc::TCSANOW, &termios);
-            }
+    /// Gives up on raw mode and escape sequences, for a terminal that refused raw mode
+    pub fn set_dumb(&mut self) {
+        self.dumb = true;
+    }
 
+    /// Enter raw mode explicitly, a dumb terminal stays as it is
+    pub fn enter_raw_mode(&mut self) -> Result<()> {
+        if !self.is_raw && !self.dumb {
+            self.backend.enable_raw_mode()?;
             self.is_raw = true;
+            // Commands wrote who knows what in the meantime
+            self.cursor = None;
         }
         Ok(())
     }
 
+    pub fn is_raw(&self) -> bool {
+        self.is_raw
+    }
+
     /// Exit raw mode explicitly
     pub fn exit_raw_mode(&mut self) -> Result<()> {
+        self.flush_batch()?;
         if self.is_raw {
-            crossterm::terminal::disable_raw_mode()
-                .context("Failed to disable terminal raw mode")?;
+            self.backend.disable_raw_mode()?;
             self.is_raw = false;
+            self.cursor = None;
         }
         Ok(())
     }
 
     /// Prints to the screen any output
     pub fn print(&mut self, output: &str) -> Result<()> {
-        write!(self.stdout, "{}", output)
-            .with_context(|| format!("Failed to write output to terminal: {}", output))?;
-        self.stdout
-            .flush()
-            .context("Failed to flush terminal stdout")?;
-        Ok(())
+        self.follow_write(output)?;
+        self.backend.write(output)
     }
 
     /// Prints to the screen any output with and goes to a new line
     pub fn println(&mut self, output: &str) -> Result<()> {
-        write!(self.stdout, "{}\n", output)
-            .with_context(|| format!("Failed to write line to terminal: {}", output))?;
-        self.stdout
-            .flush()
-            .context("Failed to flush terminal stdout")?;
-        Ok(())
+        self.print(&format!("{}\n", output))
     }
 
     /// Moves to the cursor at the specified column and row
     pub fn move_to(&mut self, column: u16, row: u16) -> Result<()> {
-        execute!(self.stdout, MoveTo(column, row))
-            .with_context(|| format!("Failed to move cursor to ({}, {})", column, row))?;
-        Ok(())
+        self.follow_move(|_, (columns, rows)| (column.min(columns - 1), row.min(rows - 1)))?;
+        self.backend.move_to(column, row)
     }
 
     /// Moves the cursor up
     pub fn move_up(&mut self) -> Result<()> {
-        execute!(self.stdout, MoveUp(1)).context("Failed to move cursor up")?;
-        Ok(())
+        self.move_by(0, -1)
     }
 
     /// Moves the cursor down
     pub fn move_down(&mut self) -> Result<()> {
-        execute!(self.stdout, MoveDown(1)).context("Failed to move cursor down")?;
-        Ok(())
+        self.move_by(0, 1)
     }
 
     /// Moves the cursor right
     pub fn move_right(&mut self) -> Result<()> {
-        execute!(self.stdout, MoveRight(1)).context("Failed to move cursor right")?;
-        Ok(())
+        self.move_by(1, 0)
     }
 
     /// Moves the cursor left
     pub fn move_left(&mut self) -> Result<()> {
-        execute!(self.stdout, MoveLeft(1)).context("Failed to move cursor left")?;
-        Ok(())
+        self.move_by(-1, 0)
+    }
+
+    // Moves stop at the edges of the screen
+    fn move_by(&mut self, columns: i16, rows: i16) -> Result<()> {
+        self.follow_move(|(column, row), (width, height)| {
+            let column = column.min(width - 1).saturating_add_signed(columns);
+            let row = row.saturating_add_signed(rows);
+            (column.min(width - 1), row.min(height - 1))
+        })?;
+        self.backend.move_by(columns, rows)
     }
 
     /// Clears the entire terminal screen
     pub fn clear_screen(&mut self) -> Result<()> {
-        execute!(self.stdout, Clear(ClearType::All), MoveTo(0, 0))
-            .context("Failed to clear screen and reset cursor position")?;
+        self.backend.clear_screen()?;
+        self.cursor = Some((0, 0));
         Ok(())
     }
 
     /// Clear the entire line at y height
     pub fn clear_line(&mut self, y: u16) -> Result<()> {
-        execute!(self.stdout, MoveTo(0, y), Clear(ClearType::CurrentLine))
-            .with_context(|| format!("Failed to clear terminal line at height {}", y))?;
-        Ok(())
+        self.move_to(0, y)?;
+        self.backend
+            .clear_current_line()
+            .with_context(|| format!("Failed to clear terminal line at height {}", y))
+    }
+
+    /// Retrieves the terminal size as (columns, rows)
+    pub fn size(&mut self) -> Result<(u16, u16)> {
+        let size = self.backend.size()?;
+        // Terminals rewrap their lines when resized, the cursor could be anywhere
+        if self.screen != Some(size) {
+            self.screen = Some(size);
+            self.cursor = None;
+        }
+        Ok(size)
     }
 
-    /// Retrieves the cursor position
+    /// Retrieves the cursor position. It's followed through what's written, the terminal is
+    /// only asked the first time after commands ran or the window was resized, since the
+    /// round trip is slow and gets mixed up with output written at the same time
     pub fn cursor_pos(&mut self) -> Result<(u16, u16)> {
-        crossterm::cursor::position().context("Failed to retrieve cursor position")
+        if let (Some((columns, _)), Some((column, row))) = (self.screen()?, self.cursor) {
+            return Ok((column.min(columns - 1), row));
+        }
+        // The answer would come before the output it's about
+        self.flush_batch()?;
+        let position = self.backend.cursor_position()?;
+        self.cursor = Some(position);
+        Ok(position)
+    }
+
+    /// Forgets where the cursor is, for when something else may have written to the terminal,
+    /// like a background job. The next `cursor_pos` asks the terminal
+    pub fn forget_cursor(&mut self) {
+        self.cursor = None;
+    }
+
+    /// Writes an OSC 133 mark
+    pub fn mark(&mut self, mark: Mark) -> Result<()> {
+        self.backend.write(&mark.sequence())
+    }
+
+    // The size to follow the cursor with. A terminal that doesn't know its own size, like a
+    // pseudo terminal nobody gave one (0x0), can't be followed and always gets asked
+    fn screen(&mut self) -> Result<Option<(u16, u16)>> {
+        let screen = Some(self.size()?).filter(|&(columns, rows)| columns > 0 && rows > 0);
+        if screen.is_none() {
+            self.cursor = None;
+        }
+        Ok(screen)
+    }
+
+    // Follows the cursor through a move, when it's known
+    fn follow_move(&mut self, to: impl FnOnce((u16, u16), (u16, u16)) -> (u16, u16)) -> Result<()> {
+        if self.cursor.is_some()
+            && let Some(screen) = self.screen()?
+        {
+            self.cursor = self.cursor.map(|cursor| to(cursor, screen));
+        }
+        Ok(())
+    }
+
+    // Follows the cursor through text written, when it's known
+    fn follow_write(&mut self, output: &str) -> Result<()> {
+        if self.cursor.is_some()
+            && let Some(screen) = self.screen()?
+        {
+            self.cursor = self.cursor.map(|cursor| advance(cursor, output, screen));
+        }
+        Ok(())
+    }
+
+    /// Whether the terminal window has the focus, as far as it's known
+    pub fn focused(&self) -> Option<bool> {
+        self.backend.focused()
+    }
+
+    /// Waits for a key for up to the timeout
+    pub fn read_key(&mut self, timeout: Duration) -> Result<Option<KeyEvent>> {
+        self.flush_batch()?;
+        self.backend.read_key(timeout)
+    }
+
+    /// Holds back everything written until `end_batch`, so what's drawn for a keystroke
+    /// reaches the terminal in one write instead of flickering in piece by piece
+    pub fn begin_batch(&mut self) {
+        self.batching = true;
+        self.backend.begin_batch();
+    }
+
+    /// Writes out what was held back since `begin_batch`
+    pub fn end_batch(&mut self) -> Result<()> {
+        self.batching = false;
+        self.backend.end_batch()
+    }
+
+    // Writes out what was held back without ending the batch, for when the terminal has to be
+    // up to date, like before asking it something or handing it to a command
+    fn flush_batch(&mut self) -> Result<()> {
+        if self.batching {
+            self.backend.end_batch()?;
+            self.backend.begin_batch();
+        }
+        Ok(())
+    }
+}
+
+/// Where the cursor ends up after the text is written from a position, the way terminals do
+/// it: escape sequences take no room, tabs stop every 8 columns, a line wraps when a character
+/// comes after its last column and the screen scrolls under the last row
+pub fn advance(cursor: (u16, u16), text: &str, (columns, rows): (u16, u16)) -> (u16, u16) {
+    let (columns, rows) = (columns.max(1), rows.max(1));
+    let (mut column, mut row) = cursor;
+    let mut chars = text.chars().peekable();
+    while let Some(character) = chars.next() {
+        match character {
+            '\n' => {
+                column = 0;
+                row = (row + 1).min(rows - 1);
+            }
+            '\r' => column = 0,
+            '\x08' => column = column.min(columns - 1).saturating_sub(1),
+            '\t' => column = (column / 8 + 1).saturating_mul(8).min(columns - 1),
+            '\x1b' => match chars.next() {
+                // CSI, up to a final byte between @ and ~
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC, up to a bell or ESC \\
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            other => {
+                let width = other.width().unwrap_or(0) as u16;
+                if width == 0 {
+                    continue;
+                }
+                if column + width > columns {
+                    column = 0;
+                    row = (row + 1).min(rows - 1);
+                }
+                column += width;
+            }
+        }
     }
+    (column, row)
 }
 
 /// Important: Drop ensures the user isn't stuck in raw mode if the shell crashes.
@@ -0,0 +1,266 @@
+//theme.rs
+
+use crate::settings::{self, ColorSettings};
+use anyhow::{Context, Result, anyhow};
+use std::{collections::HashMap, env, fs, io, path::PathBuf};
+
+/// Names of the built-in themes, `PROMPT_THEME` picks one
+pub const BUILTIN_THEMES: [&str; 4] = ["default", "ocean", "solarized", "mono"];
+
+/// Where theme files live, `themes` next to the config file. A theme is picked by the name
+/// of its file without the `.toml`
+pub fn themes_dir() -> PathBuf {
+    let config = settings::config_path();
+    config
+        .parent()
+        .map(|dir| dir.join("themes"))
+        .unwrap_or_else(|| PathBuf::from("themes"))
+}
+
+/// Maps the roles prompt templates use (`{primary}`, `{error}`, ...) to colors, so a
+/// template can be restyled by switching theme instead of rewriting it
+#[derive(Clone, Debug)]
+pub struct Theme {
+    pub name: String,
+    palette: HashMap<String, String>,
+    /// False when colors are disabled, every tag then renders as nothing
+    pub colors: bool,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::builtin("default").unwrap_or_else(|| Self {
+            name: "default".to_string(),
+            palette: HashMap::new(),
+            colors: true,
+        })
+    }
+}
+
+impl Theme {
+    pub fn builtin(name: &str) -> Option<Self> {
+        let roles: [(&str, &str); 7] = match name {
+            "default" => [
+                ("primary", "blue"),
+                ("secondary", "cyan"),
+                ("accent", "magenta"),
+                ("success", "green"),
+                ("error", "red"),
+                ("warning", "yellow"),
+                ("muted", "dim"),
+            ],
+            "ocean" => [
+                ("primary", "bold+bright_blue"),
+                ("secondary", "cyan"),
+                ("accent", "bright_cyan"),
+                ("success", "bright_green"),
+                ("error", "bright_red"),
+                ("warning", "bright_yellow"),
+                ("muted", "bright_black"),
+            ],
+            "solarized" => [
+                ("primary", "#268bd2"),
+                ("secondary", "#2aa198"),
+                ("accent", "#d33682"),
+                ("success", "#859900"),
+                ("error", "#dc322f"),
+                ("warning", "#b58900"),
+                ("muted", "#586e75"),
+            ],
+            "mono" => [
+                ("primary", "bold"),
+                ("secondary", "reset"),
+                ("accent", "underline"),
+                ("success", "reset"),
+                ("error", "bold"),
+                ("warning", "bold"),
+                ("muted", "dim"),
+            ],
+            _ => return None,
+        };
+
+        Some(Self {
+            name: name.to_string(),
+            palette: roles
+                .iter()
+                .map(|(role, spec)| (role.to_string(), spec.to_string()))
+                .collect(),
+            colors: true,
+        })
+    }
+
+    /// A theme by name, from the themes directory or else one of the built-in ones. A theme
+    /// file sets roles like `primary = "green"`, with tables for the other parts of the
+    /// shell (`[syntax]`, `[completion]`) giving roles like `syntax.command`. Roles it
+    /// leaves out keep the colors of the default theme
+    pub fn load(name: &str) -> Result<Option<Self>> {
+        let path = themes_dir().join(format!("{name}.toml"));
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::builtin(name));
+            }
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Failed to read theme file {}", path.display()));
+            }
+        };
+
+        let mut theme = Self {
+            name: name.to_string(),
+            ..Self::default()
+        };
+        theme
+            .extend_from_toml(&content)
+            .with_context(|| format!("Failed to read theme file {}", path.display()))?;
+        Ok(Some(theme))
+    }
+
+    /// Names of the themes there are, built-in ones first and then the theme files
+    pub fn available() -> Vec<String> {
+        let mut names: Vec<String> = BUILTIN_THEMES.iter().map(|name| name.to_string()).collect();
+
+        let mut files: Vec<String> = fs::read_dir(themes_dir())
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                let name = path.file_stem()?.to_str()?.to_string();
+                (path.extension()? == "toml").then_some(name)
+            })
+            .filter(|name| !names.contains(name))
+            .collect();
+        files.sort();
+
+        names.extend(files);
+        names
+    }
+
+    fn extend_from_toml(&mut self, content: &str) -> Result<()> {
+        fn walk(theme: &mut Theme, prefix: &str, table: &toml::Table) -> Result<()> {
+            for (key, value) in table {
+                let role = match prefix {
+                    "" => key.clone(),
+                    _ => format!("{prefix}.{key}"),
+                };
+                match value {
+                    toml::Value::Table(table) => walk(theme, &role, table)?,
+                    toml::Value::String(spec) => {
+                        if !theme.set(&role, spec) {
+                            return Err(anyhow!("Invalid color '{spec}' for {role}"));
+                        }
+                    }
+                    _ => return Err(anyhow!("The color of {role} must be a string")),
+                }
+            }
+            Ok(())
+        }
+
+        let table: toml::Table = toml::from_str(content)?;
+        walk(self, "", &table)
+    }
+
+    /// Builds the theme from `PROMPT_THEME` (a built-in name or a theme file) and `PROMPT_PALETTE`, a list
+    /// like `primary=green,error=#ff5555` overriding single roles. Colors are turned off
+    /// when `NO_COLOR` is set or the terminal is dumb
+    pub fn from_env() -> Self {
+        Self::from_settings(&ColorSettings::default())
+    }
+
+    /// Like `from_env`, with the theme and palette of the config file used where the
+    /// environment doesn't say otherwise
+    pub fn from_settings(settings: &ColorSettings) -> Self {
+        let name = env::var("PROMPT_THEME").unwrap_or_else(|_| settings.theme.clone());
+        // A broken theme file shouldn't break the prompt, `theme` reports what's wrong
+        let mut theme = Self::load(&name).ok().flatten().unwrap_or_default();
+
+        for (role, spec) in &settings.palette {
+            theme.set(role, spec);
+        }
+        if let Ok(palette) = env::var("PROMPT_PALETTE") {
+            theme.extend(&palette);
+        }
+
+        theme.colors = settings.enabled && colors_supported();
+        theme
+    }
+
+    /// Adds `role=spec` pairs separated by commas to the palette, invalid pairs are skipped
+    pub fn extend(&mut self, palette: &str) {
+        for pair in palette.split(',') {
+            if let Some((role, spec)) = pair.split_once('=') {
+                self.set(role, spec);
+            }
+        }
+    }
+
+    /// Sets the color of a role, returns false when the spec isn't a valid color
+    pub fn set(&mut self, role: &str, spec: &str) -> bool {
+        let spec = spec.trim();
+        if escape(spec).is_none() {
+            return false;
+        }
+        self.palette
+            .insert(role.trim().to_string(), spec.to_string());
+        true
+    }
+
+    /// Escape sequence of a color tag, either a role of the palette or a color spec like
+    /// `red`, `bold+blue`, `208` or `#ff8800`. Gives back an empty string when colors are off
+    pub fn tag(&self, name: &str) -> Option<String> {
+        let spec = self.palette.get(name).map(String::as_str).unwrap_or(name);
+        let sequence = escape(spec)?;
+        Some(if self.colors { sequence } else { String::new() })
+    }
+}
+
+/// Turns a color spec into an SGR escape sequence, parts are joined with `+`
+pub fn escape(spec: &str) -> Option<String> {
+    let mut codes = Vec::new();
+    for part in spec.split('+') {
+        codes.push(sgr_code(part.trim())?);
+    }
+    Some(format!("\x1b[{}m", codes.join(";")))
+}
+
+fn sgr_code(part: &str) -> Option<String> {
+    const COLORS: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+
+    let code = match part {
+        "reset" => "0".to_string(),
+        "bold" => "1".to_string(),
+        "dim" => "2".to_string(),
+        "italic" => "3".to_string(),
+        "underline" => "4".to_string(),
+        _ => {
+            if let Some(n) = COLORS.iter().position(|&c| c == part) {
+                (30 + n).to_string()
+            } else if let Some(n) = part
+                .strip_prefix("bright_")
+                .and_then(|name| COLORS.iter().position(|&c| c == name))
+            {
+                (90 + n).to_string()
+            } else if let Some(hex) = part.strip_prefix('#') {
+                if hex.len() != 6 {
+                    return None;
+                }
+                let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+                format!("38;2;{};{};{}", channel(0)?, channel(2)?, channel(4)?)
+            } else {
+                format!("38;5;{}", part.parse::<u8>().ok()?)
+            }
+        }
+    };
+    Some(code)
+}
+
+/// Whether the terminal should get colors, following the NO_COLOR convention
+pub fn colors_supported() -> bool {
+    if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        return false;
+    }
+    !matches!(env::var("TERM").as_deref(), Ok("dumb"))
+}
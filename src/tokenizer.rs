@@ -2,6 +2,7 @@
 
 use crate::error::{ShellError, ShellPhase};
 use anyhow::Result;
+use std::fmt;
 
 #[derive(Debug)]
 pub enum Token<'a> {
@@ -18,22 +19,78 @@ pub enum Token<'a> {
     Or,         // ||
     Background, // &
 
+    // Case item terminators
+    DoubleSemicolon, // ;;
+    SemicolonAnd,    // ;&
+
     // Redirection
     RedirectOut,       // >
     RedirectAppend,    // >>
     RedirectIn,        // <
     RedirectErr,       // 2>
+    RedirectErrAppend, // 2>>
     RedirectErrAndOut, // 2>&1
+    RedirectAll,       // &>
+    // `<<word`, or `<<-word` which takes the tabs off the start of the lines. The body is the
+    // lines after the command up to the one saying the word, it's expanded unless the word is
+    // quoted
+    HereDoc {
+        delimiter: &'a str,
+        body: &'a str,
+        strip_tabs: bool,
+    },
 
     // Parenthesis
     LeftParen,
     RightParen,
+
+    // Arithmetic, the expression between `((` and `))`
+    Arithmetic(&'a str),
 }
 
 impl<'a> Token<'a> {
     pub fn is_operator(&self) -> bool {
         use Token::*;
-        matches!(self, Pipe | Semicolon | Newline | And | Or | Background)
+        matches!(
+            self,
+            Pipe | Semicolon | Newline | And | Or | Background | DoubleSemicolon | SemicolonAnd
+        )
+    }
+}
+
+impl<'a> fmt::Display for Token<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::SingleQuoted(string) => write!(f, "'{}'", string),
+            Token::DoubleQuoted(string) => write!(f, "\"{}\"", string),
+            Token::Pipe => write!(f, "|"),
+            Token::Semicolon => write!(f, ";"),
+            Token::Newline => write!(f, "newline"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Background => write!(f, "&"),
+            Token::DoubleSemicolon => write!(f, ";;"),
+            Token::SemicolonAnd => write!(f, ";&"),
+            Token::RedirectOut => write!(f, ">"),
+            Token::RedirectAppend => write!(f, ">>"),
+            Token::RedirectIn => write!(f, "<"),
+            Token::RedirectErr => write!(f, "2>"),
+            Token::RedirectErrAppend => write!(f, "2>>"),
+            Token::RedirectErrAndOut => write!(f, "2>&1"),
+            Token::RedirectAll => write!(f, "&>"),
+            Token::HereDoc {
+                delimiter,
+                strip_tabs,
+                ..
+            } => match strip_tabs {
+                true => write!(f, "<<-{}", delimiter),
+                false => write!(f, "<<{}", delimiter),
+            },
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+            Token::Arithmetic(expression) => write!(f, "(({}))", expression),
+        }
     }
 }
 
@@ -47,23 +104,61 @@ impl<'a> Tokenizer<'a> {
         Self { line, cursor: 0 }.run()
     }
 
+    /// Like `tokenize`, along with the byte offset in the line where each token starts
+    pub fn tokenize_with_offsets(line: &'a str) -> Result<(Vec<Token<'a>>, Vec<usize>)> {
+        Self { line, cursor: 0 }.run_with_offsets()
+    }
+
     pub fn run(&mut self) -> Result<Vec<Token<'a>>> {
+        Ok(self.run_with_offsets()?.0)
+    }
+
+    fn run_with_offsets(&mut self) -> Result<(Vec<Token<'a>>, Vec<usize>)> {
         let mut tokens = Vec::new();
+        let mut offsets = Vec::new();
+        // Here-documents of the line, their bodies start after it
+        let mut here_docs = Vec::new();
         while let Some(current) = self.peek() {
-            if current.is_whitespace() {
+            if current.is_whitespace() && current != '\n' {
                 self.next();
                 continue;
             }
 
-            tokens.push(self.get_token(current)?);
+            offsets.push(self.cursor);
+            let token = self.get_token(current)?;
+            let newline = matches!(token, Token::Newline);
+            if matches!(token, Token::HereDoc { .. }) {
+                here_docs.push(tokens.len());
+            }
+            tokens.push(token);
+            if newline {
+                for index in std::mem::take(&mut here_docs) {
+                    self.read_here_doc(&mut tokens[index])?;
+                }
+            }
+        }
+        if let Some(&index) = here_docs.first() {
+            // The body reads nothing past the end, it only says the terminator is missing
+            self.read_here_doc(&mut tokens[index])?;
         }
 
-        Ok(tokens)
+        Ok((tokens, offsets))
     }
 
     fn get_token(&mut self, current: char) -> Result<Token<'a>> {
         if current == '\'' || current == '"' {
-            Ok(self.parse_string()?)
+            let start = self.cursor;
+            let string = self.parse_string()?;
+            // Glued to more text, like `'*'.rs`, it's part of a word
+            match self.peek() {
+                Some(next) if !next.is_whitespace() && !Self::is_operator(next) => {
+                    self.cursor = start;
+                    self.parse_word()
+                }
+                _ => Ok(string),
+            }
+        } else if current == '(' && self.peek_nth(1) == Some('(') {
+            self.parse_arithmetic()
         } else if self.starts_operator(current) {
             Ok(self.parse_operators()?)
         } else {
@@ -80,6 +175,10 @@ impl<'a> Tokenizer<'a> {
 
             if character == '\'' || character == '"' {
                 self.parse_string()?;
+            } else if character == '\\' {
+                // Escapes the next character, even a space or an operator
+                self.next();
+                self.next();
             } else {
                 self.next();
                 if self.is_subcommand(character) {
@@ -96,6 +195,11 @@ impl<'a> Tokenizer<'a> {
 
         let start = self.cursor;
         while let Some(character) = self.next() {
+            // A backslash can escape the quote between double quotes
+            if character == '\\' && quote == '"' {
+                self.next();
+                continue;
+            }
             if character == quote {
                 let end = self.cursor - character.len_utf8();
                 let content = &self.line[start..end];
@@ -122,6 +226,8 @@ impl<'a> Tokenizer<'a> {
                     } else {
                         self.error("Expected '1' after '2>&'")
                     }
+                } else if self.match_next('>') {
+                    Ok(Token::RedirectErrAppend)
                 } else {
                     Ok(Token::RedirectErr)
                 }
@@ -136,6 +242,8 @@ impl<'a> Tokenizer<'a> {
             '&' => {
                 if self.match_next('&') {
                     Ok(Token::And)
+                } else if self.match_next('>') {
+                    Ok(Token::RedirectAll)
                 } else {
                     Ok(Token::Background)
                 }
@@ -147,8 +255,17 @@ impl<'a> Tokenizer<'a> {
                     Ok(Token::RedirectOut)
                 }
             }
-            ';' => Ok(Token::Semicolon),
+            ';' => {
+                if self.match_next(';') {
+                    Ok(Token::DoubleSemicolon)
+                } else if self.match_next('&') {
+                    Ok(Token::SemicolonAnd)
+                } else {
+                    Ok(Token::Semicolon)
+                }
+            }
             '\n' => Ok(Token::Newline),
+            '<' if self.match_next('<') => self.parse_here_doc(),
             '<' => Ok(Token::RedirectIn),
             '(' => Ok(Token::LeftParen),
             ')' => Ok(Token::RightParen),
@@ -156,6 +273,84 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    // The word after `<<`, the body is read once the line is over
+    fn parse_here_doc(&mut self) -> Result<Token<'a>> {
+        let strip_tabs = self.match_next('-');
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.next();
+        }
+        match self.peek() {
+            Some(next) if !next.is_whitespace() && !Self::is_operator(next) => {}
+            _ => return self.error("Expected a word after '<<'"),
+        }
+        let Token::Word(delimiter) = self.parse_word()? else {
+            unreachable!("parse_word only gives words")
+        };
+        Ok(Token::HereDoc {
+            delimiter,
+            body: "",
+            strip_tabs,
+        })
+    }
+
+    // Takes the lines up to the one saying the delimiter as the body
+    fn read_here_doc(&mut self, token: &mut Token<'a>) -> Result<()> {
+        let Token::HereDoc {
+            delimiter,
+            body,
+            strip_tabs,
+        } = token
+        else {
+            return Ok(());
+        };
+        let terminator = unquote(delimiter);
+        let start = self.cursor;
+        while self.cursor < self.line.len() {
+            let line_start = self.cursor;
+            let line_end = self.line[line_start..]
+                .find('\n')
+                .map_or(self.line.len(), |end| line_start + end);
+            self.cursor = (line_end + 1).min(self.line.len());
+            let line = &self.line[line_start..line_end];
+            let line = match strip_tabs {
+                true => line.trim_start_matches('\t'),
+                false => line,
+            };
+            if line == terminator {
+                *body = &self.line[start..line_start];
+                return Ok(());
+            }
+        }
+        // Like an unfinished `for`, scripts read on and the prompt asks for more lines
+        self.error(&format!(
+            "Unexpected end of input: expected '{}' closing the here-document",
+            terminator
+        ))
+    }
+
+    // Everything up to the `))` closing the `((`, the expression can have parentheses of its own
+    fn parse_arithmetic(&mut self) -> Result<Token<'a>> {
+        self.next();
+        self.next();
+
+        let start = self.cursor;
+        let mut depth = 0;
+        while let Some(character) = self.next() {
+            match character {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ')' if self.peek() == Some(')') => {
+                    let content = &self.line[start..self.cursor - 1];
+                    self.next();
+                    return Ok(Token::Arithmetic(content));
+                }
+                ')' => return self.error("Expected '))' to close the arithmetic expression"),
+                _ => {}
+            }
+        }
+        self.error("Found unclosed arithmetic expression, missing '))'")
+    }
+
     fn parse_subcommand(&mut self) -> Result<Token<'a>> {
         let start = self.cursor;
 
@@ -184,7 +379,7 @@ impl<'a> Tokenizer<'a> {
                     self.next();
                     if paren_depth == 0 {
                         let end = self.cursor - character.len_utf8(); // exclude the final ')'
-                        let content = &self.line[start + 2..end]; // the +2 is for the '$' and '(' character
+                        let content = &self.line[start + 1..end]; // the +1 is for the '(' character, the '$' was read already
                         return Ok(Token::Word(content));
                     }
                 }
@@ -251,3 +446,17 @@ impl<'a> Tokenizer<'a> {
         }))
     }
 }
+
+// The delimiter of a here-document as the line ending it says it, without its quotes
+fn unquote(word: &str) -> String {
+    let mut unquoted = String::with_capacity(word.len());
+    let mut chars = word.chars();
+    while let Some(character) = chars.next() {
+        match character {
+            '\'' | '"' => {}
+            '\\' => unquoted.extend(chars.next()),
+            _ => unquoted.push(character),
+        }
+    }
+    unquoted
+}
@@ -0,0 +1,277 @@
+//variables.rs
+
+use crate::{arithmetic, encoding};
+use anyhow::Result;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    env,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Variables with a value worked out when they're read
+const DYNAMIC: [&str; 3] = ["RANDOM", "SECONDS", "EPOCHSECONDS"];
+
+/// The shell's variables. Those set with `name=value` stay in the shell, exported ones are kept
+/// in the environment, which is what commands get when they're started. Setting a variable
+/// that's already exported changes the exported value, and `export` moves a variable of the
+/// shell to the environment.
+///
+/// `RANDOM`, `SECONDS` and `EPOCHSECONDS` have a new value every time they're read.
+///
+/// Sourced files get a scope of their own for the variables made with `local`, which hide
+/// the ones with the same name until the file is done. Scoping is dynamic: a file sourced
+/// from there sees them too, and setting a variable changes the one that can be seen
+#[derive(Clone, Debug)]
+pub struct Variables {
+    local: HashMap<String, String>,
+    attributes: HashMap<String, Attributes>,
+    // For every scope, the variables `local` hid, to put back when it ends
+    scopes: Vec<Vec<Hidden>>,
+    // When `SECONDS` was 0
+    started: Instant,
+    // State of the generator behind `RANDOM`
+    random: Cell<u64>,
+}
+
+/// What `declare` says about a variable, applied to every value it's assigned
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Attributes {
+    /// `-i`, values are worked out as arithmetic, so `n=2+3` sets 5
+    pub integer: bool,
+    /// `-u`, values are made uppercase
+    pub upper: bool,
+    /// `-l`, values are made lowercase
+    pub lower: bool,
+}
+
+impl Attributes {
+    /// The letters of the flags `declare` takes for them, like `iu`
+    pub fn flags(&self) -> String {
+        [(self.integer, 'i'), (self.upper, 'u'), (self.lower, 'l')]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| *flag)
+            .collect()
+    }
+}
+
+// A variable as it was before `local` hid it
+#[derive(Clone, Debug)]
+struct Hidden {
+    name: String,
+    value: Option<String>,
+    exported: bool,
+    attributes: Attributes,
+}
+
+impl Default for Variables {
+    fn default() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let seed = now.as_nanos() as u64 ^ ((std::process::id() as u64) << 32);
+        Self {
+            local: HashMap::new(),
+            attributes: HashMap::new(),
+            scopes: Vec::new(),
+            started: Instant::now(),
+            random: Cell::new(seed),
+        }
+    }
+}
+
+impl Variables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value of a variable, whether exported or not
+    pub fn get(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.dynamic(name) {
+            return Some(value);
+        }
+        self.local.get(name).cloned().or_else(|| exported(name))
+    }
+
+    pub fn is_set(&self, name: &str) -> bool {
+        DYNAMIC.contains(&name) || self.local.contains_key(name) || env::var_os(name).is_some()
+    }
+
+    fn dynamic(&self, name: &str) -> Option<String> {
+        match name {
+            // Like bash, between 0 and 32767
+            "RANDOM" => Some((self.next_random() % 32768).to_string()),
+            "SECONDS" => Some(self.started.elapsed().as_secs().to_string()),
+            "EPOCHSECONDS" => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|time| time.as_secs().to_string()),
+            _ => None,
+        }
+    }
+
+    // xorshift64*, plenty for scripts that want a dice roll
+    fn next_random(&self) -> u64 {
+        let mut state = self.random.get().max(1);
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        self.random.set(state);
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 33
+    }
+
+    pub fn is_exported(&self, name: &str) -> bool {
+        !self.local.contains_key(name) && env::var_os(name).is_some()
+    }
+
+    /// Sets a variable, it stays in the shell unless it's already exported. Setting `RANDOM`
+    /// seeds it so the numbers can be repeated, and `SECONDS` counts on from the value given
+    pub fn set(&mut self, name: &str, value: &str) {
+        let number = value.trim().parse::<u64>().unwrap_or(0);
+        match name {
+            "RANDOM" => return self.random.set(number),
+            "SECONDS" => {
+                let elapsed = std::time::Duration::from_secs(number);
+                self.started = Instant::now()
+                    .checked_sub(elapsed)
+                    .unwrap_or_else(Instant::now);
+                return;
+            }
+            "EPOCHSECONDS" => return,
+            _ => {}
+        }
+
+        match self.is_exported(name) {
+            true => unsafe { env::set_var(name, encoding::to_os_string(value)) },
+            false => {
+                self.local.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+
+    /// Sets a variable the way `name=value` does, going through its attributes first
+    pub fn assign(&mut self, name: &str, value: &str) -> Result<()> {
+        let attributes = self.attributes(name);
+        let value = match attributes.integer {
+            true => arithmetic::eval_in(self, value)?.to_string(),
+            false => value.to_string(),
+        };
+        let value = match (attributes.upper, attributes.lower) {
+            (true, _) => value.to_uppercase(),
+            (_, true) => value.to_lowercase(),
+            _ => value,
+        };
+        self.set(name, &value);
+        Ok(())
+    }
+
+    pub fn attributes(&self, name: &str) -> Attributes {
+        self.attributes.get(name).copied().unwrap_or_default()
+    }
+
+    /// Gives a variable new attributes, they apply from its next assignment
+    pub fn set_attributes(&mut self, name: &str, attributes: Attributes) {
+        match attributes == Attributes::default() {
+            true => self.attributes.remove(name),
+            false => self.attributes.insert(name.to_string(), attributes),
+        };
+    }
+
+    /// Exports a variable with a new value, or the one it has in the shell. Exporting a
+    /// variable that isn't set does nothing
+    pub fn export(&mut self, name: &str, value: Option<&str>) {
+        let local = self.local.remove(name);
+        if let Some(value) = value.map(str::to_string).or(local) {
+            unsafe {
+                env::set_var(name, encoding::to_os_string(&value));
+            }
+        }
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.local.remove(name);
+        self.attributes.remove(name);
+        unsafe {
+            env::remove_var(name);
+        }
+    }
+
+    /// Takes the variables of the shell, not the exported ones, and their attributes from
+    /// a copy. `RANDOM` and `SECONDS` go on from where they are
+    pub fn restore(&mut self, saved: &Variables) {
+        self.local = saved.local.clone();
+        self.attributes = saved.attributes.clone();
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Ends the innermost scope, putting back the variables its `local`s hid
+    pub fn pop_scope(&mut self) {
+        let Some(hidden) = self.scopes.pop() else {
+            return;
+        };
+        for variable in hidden.into_iter().rev() {
+            self.unset(&variable.name);
+            self.set_attributes(&variable.name, variable.attributes);
+            match (variable.value, variable.exported) {
+                (Some(value), true) => unsafe {
+                    env::set_var(&variable.name, encoding::to_os_string(&value))
+                },
+                (Some(value), false) => {
+                    self.local.insert(variable.name, value);
+                }
+                (None, _) => {}
+            }
+        }
+    }
+
+    /// Makes a variable of the innermost scope, hiding the one with the same name until the
+    /// scope ends. Without a value it starts out unset. False when there's no scope
+    pub fn make_local(&mut self, name: &str, value: Option<&str>) -> Result<bool> {
+        let hidden = Hidden {
+            name: name.to_string(),
+            value: self.local.get(name).cloned().or_else(|| exported(name)),
+            exported: self.is_exported(name),
+            attributes: self.attributes(name),
+        };
+        let Some(scope) = self.scopes.last_mut() else {
+            return Ok(false);
+        };
+
+        // Made local twice in the same scope, it's the same variable
+        if !scope.iter().any(|variable| variable.name == name) {
+            scope.push(hidden);
+            self.unset(name);
+        }
+        if let Some(value) = value {
+            self.assign(name, value)?;
+        }
+        Ok(true)
+    }
+
+    /// The names made local in the innermost scope
+    pub fn locals(&self) -> Vec<String> {
+        self.scopes.last().map_or_else(Vec::new, |scope| {
+            scope.iter().map(|variable| variable.name.clone()).collect()
+        })
+    }
+
+    /// Every variable with its value, sorted by name
+    pub fn all(&self) -> Vec<(String, String)> {
+        let mut variables: HashMap<String, String> = env::vars_os()
+            .map(|(name, value)| (encoding::from_os_str(&name), encoding::from_os_str(&value)))
+            .collect();
+        variables.extend(self.local.clone());
+        let mut variables: Vec<(String, String)> = variables.into_iter().collect();
+        variables.sort();
+        variables
+    }
+}
+
+// Value of an exported variable, which doesn't have to be UTF-8
+fn exported(name: &str) -> Option<String> {
+    env::var_os(name).map(|value| encoding::from_os_str(&value))
+}
@@ -0,0 +1,10 @@
+# Arithmetic expansion
+echo $((1 + 2 * 3))
+n=5
+echo $((n * 2)) $(($n - 1))
+echo $(( (7 % 3) << 2 ))
+echo $((10 / 3)) $((-7 / 2)) $((2 > 1)) $((1 == 2))
+echo $((0x10 + 010))
+i=0
+: $((i = i + 4))
+echo $i
@@ -0,0 +1,11 @@
+# Lists, loops and case
+true && echo and
+false || echo or
+false && echo never
+for word in alpha beta; do echo $word; done
+for word in; do echo never; done
+set -- x y
+for arg; do echo arg $arg; done
+case hello.txt in *.sh) echo script;; *.txt) echo text;; esac
+case b in a|b) echo either;; esac
+case '*' in \*) echo star;; esac
@@ -0,0 +1,12 @@
+# Positional and special parameters
+set -- one 'two three' four
+echo $#
+printf '<%s>\n' "$@"
+printf '<%s>\n' $@
+echo ${1}x $1x
+shift
+echo $1
+shift 2
+echo $#
+name=value
+echo ${name}s $name.txt $name/dir $name:x
@@ -0,0 +1,14 @@
+# Quote removal, and which expansions are split
+x='a  b'
+echo $x
+echo "$x"
+echo '$x'
+echo a"$x"b
+printf '<%s>\n' $x
+printf '<%s>\n' "$x"
+printf '<%s>\n' a$x"c d"
+echo \$x "\$x" '\$x'
+empty=
+printf '[%s]\n' $empty x
+printf '[%s]\n' "$empty"
+printf '[%s]\n' ''
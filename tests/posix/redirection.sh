@@ -0,0 +1,19 @@
+# Redirections
+tmp=/tmp/rshell_posix_$$
+echo one > $tmp
+echo two >> $tmp
+cat < $tmp
+ls /nonexistent_rshell_posix 2> $tmp
+cat $tmp | wc -l | tr -d ' '
+rm $tmp
+name=posix
+cat <<EOF
+hello $name $((2 + 3))
+
+EOF
+cat <<'EOF' | tr a-z A-Z
+$name
+EOF
+	cat <<-END
+		tabs
+	END
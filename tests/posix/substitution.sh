@@ -0,0 +1,10 @@
+# Command substitution and subshells
+x=$(echo hi)
+echo "$x there"
+echo $(printf 'a\n\n')end
+echo $(echo $(echo deep))
+(cd /; pwd)
+false
+echo $?
+(exit 3)
+echo $?
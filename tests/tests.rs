@@ -24,7 +24,7 @@
 //   - We call libc::getpid() directly for pgid/pid (safe, no side effects).
 //   - SignalHandler is constructed via its public ::new(), but we wrap it in
 //     a helper that ignores the tcsetpgrp failure by bypassing setup_pgid().
-//   - History is pointed at a temp file so tests don't pollute ~/.rshell_history.
+//   - History is pointed at a temp file so tests don't pollute the real history.
 //   - Everything else (BuiltIns, Jobs, Aliases) constructs fine with ::new().
 //
 // Only the expander and builtin tests need a Context; tokenizer, parser,
@@ -32,10 +32,21 @@
 // -----------------------------------------------------------------------------
 mod test_helpers {
     use rshell::{
-        aliases::Aliases, builtins::BuiltIns, context::Context, history::History, jobs::Jobs,
-        signals::SignalHandler, terminal::Terminal,
+        aliases::Aliases,
+        builtins::BuiltIns,
+        context::{Context, Mode},
+        direnv::DirEnv,
+        history::History,
+        hooks::Hooks,
+        jobs::Jobs,
+        path_index::PathIndex,
+        plugins::Plugins,
+        settings::Settings,
+        signals::SignalHandler,
+        terminal::Terminal,
+        variables::Variables,
     };
-    use std::{env, path::PathBuf};
+    use std::{collections::HashMap, env, path::PathBuf};
     use tempfile::TempDir;
 
     /// A TempDir that we keep alive alongside the Context so the history
@@ -50,12 +61,16 @@ mod test_helpers {
     ///
     /// Differences from Context::new():
     ///   - Does NOT call tcsetpgrp / setpgid (would fail without a terminal).
-    ///   - History file lives in a fresh TempDir instead of ~/.rshell_history.
+    ///   - History file lives in a fresh TempDir instead of the XDG data dir.
     ///   - All other fields are identical to what Context::new() would produce.
     pub fn make_test_env() -> TestEnv {
         // Point HOME at a temp dir so History::new() writes there.
         let history_dir = tempfile::tempdir().expect("tempdir");
-        unsafe { env::set_var("HOME", history_dir.path()) };
+        unsafe {
+            env::set_var("HOME", history_dir.path());
+            env::remove_var("XDG_DATA_HOME");
+            env::remove_var("HISTFILE");
+        }
 
         let pid = unsafe { libc::getpid() };
 
@@ -75,13 +90,27 @@ mod test_helpers {
             // For now all fields used below are pub.
             pid,
             pgid: pid, // shell is its own pgroup leader in test
+            shared_group: false,
             builtins: BuiltIns::new(),
             jobs: Jobs::new(),
             signals,
             last_exit_code: 0,
+            last_duration: None,
             last_job_pid: None,
             history,
             aliases: Aliases::new(),
+            settings: Settings::default(),
+            mode: Mode::default(),
+            dir_env: DirEnv::new(),
+            positional: Vec::new(),
+            arrays: HashMap::new(),
+            sources: Vec::new(),
+            debugger: None,
+            variables: Variables::new(),
+            hooks: Hooks::new(),
+            plugins: Plugins::default(),
+            path_index: PathIndex::new(),
+            snapshots: HashMap::new(),
             directory: PathBuf::from("/tmp"),
         };
 
@@ -119,6 +148,15 @@ mod tokenizer_tests {
         assert_eq!(word(&tokens[0]), "ls");
     }
 
+    #[test]
+    fn quotes_and_escapes_stay_in_the_word() {
+        let tokens = tok(r#"'*'.rs a\ b\;c "say \"hi\"""#);
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(word(&tokens[0]), "'*'.rs");
+        assert_eq!(word(&tokens[1]), r"a\ b\;c");
+        assert!(matches!(tokens[2], Token::DoubleQuoted(r#"say \"hi\""#)));
+    }
+
     #[test]
     fn multiple_words() {
         let tokens = tok("ls -la /tmp");
@@ -260,6 +298,39 @@ mod tokenizer_tests {
         assert!(matches!(tokens[1], Token::RedirectErrAndOut));
     }
 
+    #[test]
+    fn redirect_stderr_append_and_both() {
+        let tokens = tok("cmd 2>> err.txt &> all.txt");
+        assert!(matches!(tokens[1], Token::RedirectErrAppend));
+        assert!(matches!(tokens[3], Token::RedirectAll));
+    }
+
+    #[test]
+    fn here_doc_body_is_the_lines_after_the_command() {
+        let tokens = tok("cat <<'EOF' | wc -l\nhello\n\n  EOF\nEOF\necho done");
+        assert!(matches!(
+            tokens[1],
+            Token::HereDoc {
+                delimiter: "'EOF'",
+                body: "hello\n\n  EOF\n",
+                strip_tabs: false,
+            }
+        ));
+        assert!(matches!(tokens[2], Token::Pipe));
+        assert!(matches!(tokens[5], Token::Newline));
+        assert_eq!(word(&tokens[6]), "echo");
+
+        let tokens = tok("cat <<-END\n\tindented\n\tEND\n");
+        assert!(matches!(
+            tokens[1],
+            Token::HereDoc {
+                body: "\tindented\n",
+                strip_tabs: true,
+                ..
+            }
+        ));
+    }
+
     // ── Edge cases ────────────────────────────────────────────────────────────
 
     #[test]
@@ -280,6 +351,31 @@ mod tokenizer_tests {
         let tokens = tok("echo héllo");
         assert_eq!(word(&tokens[1]), "héllo");
     }
+
+    #[test]
+    fn newlines_separate_commands() {
+        let tokens = tok("echo a\necho b");
+        assert_eq!(tokens.len(), 5);
+        assert!(matches!(tokens[2], Token::Newline));
+    }
+
+    #[test]
+    fn case_terminators() {
+        let tokens = tok("a) x;; b) y;& esac");
+        assert!(matches!(tokens[3], Token::DoubleSemicolon));
+        assert!(matches!(tokens[7], Token::SemicolonAnd));
+    }
+
+    #[test]
+    fn double_parens_are_arithmetic() {
+        let tokens = tok("for ((i = (1 + 2); i < 10; i++))");
+        assert_eq!(tokens.len(), 2);
+        assert!(matches!(
+            tokens[1],
+            Token::Arithmetic("i = (1 + 2); i < 10; i++")
+        ));
+        assert!(Tokenizer::tokenize("for ((i < 10)").is_err());
+    }
 }
 
 // =============================================================================
@@ -304,6 +400,19 @@ mod parser_tests {
         Parser::parse(&tokens).is_err()
     }
 
+    #[test]
+    fn errors_know_where_they_are() {
+        let (tokens, offsets) = Tokenizer::tokenize_with_offsets("echo a ( b").unwrap();
+        assert_eq!(offsets, vec![0, 5, 7, 9]);
+        assert_eq!(Parser::parse_with_position(&tokens).unwrap_err().1, 2);
+
+        let tokens = Tokenizer::tokenize("for x in a; do echo").unwrap();
+        assert_eq!(
+            Parser::parse_with_position(&tokens).unwrap_err().1,
+            tokens.len()
+        );
+    }
+
     // ── Simple commands ───────────────────────────────────────────────────────
 
     #[test]
@@ -453,6 +562,40 @@ mod parser_tests {
         });
     }
 
+    #[test]
+    fn only_names_make_assignments() {
+        parse!("./a=b c", |cmd| {
+            assert!(matches!(cmd, Command::Simple { command, .. } if command == "./a=b"));
+        });
+        parse!("(u=)", |cmd| {
+            assert!(
+                matches!(cmd, Command::Subshell(inner) if matches!(*inner, Command::Assignment(_)))
+            );
+        });
+    }
+
+    // Found by the fuzzer, printing a command has to give a line meaning the same
+    #[test]
+    fn printed_commands_parse_back_the_same() {
+        for (line, printed) in [
+            ("F= [[", "F= [["),
+            ("echo \\", "echo \\\\"),
+            ("echo $() 2>f \\", "echo $() \\\\ 2>f"),
+        ] {
+            assert_eq!(parse!(line, |cmd: Command| cmd.to_string()), printed);
+            assert_eq!(parse!(printed, |cmd: Command| cmd.to_string()), printed);
+        }
+    }
+
+    #[test]
+    fn borrowed_commands_are_the_same_command() {
+        let line = "for f in *.rs; do case $f in a|b) x=1 echo \"$f\" >> out 2>&1;; esac; done &";
+        parse!(line, |cmd: Command| {
+            let owned = cmd.clone().into_owned();
+            assert_eq!(owned.borrowed().to_string(), cmd.to_string());
+        });
+    }
+
     // ── Operator precedence ───────────────────────────────────────────────────
 
     #[test]
@@ -473,6 +616,200 @@ mod parser_tests {
             assert!(matches!(cmd, Command::Sequence(_, _)));
         });
     }
+
+    // ── Loops ─────────────────────────────────────────────────────────────────
+
+    #[test]
+    fn for_loop() {
+        parse!("for x in a 'b c' *.rs; do echo $x; done", |cmd| {
+            if let Command::For {
+                variable,
+                words,
+                body,
+            } = cmd
+            {
+                assert_eq!(variable.as_ref(), "x");
+                assert_eq!(words.unwrap().len(), 3);
+                assert!(matches!(*body, Command::Simple { .. }));
+            } else {
+                panic!("expected For");
+            }
+        });
+        parse!("for x\ndo\n  echo $x\n  echo done\ndone", |cmd| {
+            if let Command::For { words, body, .. } = cmd {
+                assert!(words.is_none());
+                assert!(matches!(*body, Command::Sequence(_, _)));
+            } else {
+                panic!("expected For");
+            }
+        });
+    }
+
+    #[test]
+    fn select_loop() {
+        parse!("select x in a b\ndo echo $x; break; done", |cmd| {
+            if let Command::Select {
+                variable, words, ..
+            } = cmd
+            {
+                assert_eq!(variable.as_ref(), "x");
+                assert_eq!(words.unwrap().len(), 2);
+            } else {
+                panic!("expected Select");
+            }
+        });
+        assert_eq!(
+            Tokenizer::tokenize("select x; do echo $x; done")
+                .map(|tokens| Parser::parse(&tokens).unwrap().to_string())
+                .unwrap(),
+            "select x; do echo $x; done"
+        );
+        assert!(parse_err("select 1x in a; do echo; done"));
+    }
+
+    #[test]
+    fn arithmetic_command() {
+        parse!("((i++)) && echo yes", |cmd| {
+            if let Command::And(left, _) = cmd
+                && let Command::Arithmetic(expression) = *left
+            {
+                assert_eq!(expression.as_ref(), "i++");
+            } else {
+                panic!("expected Arithmetic");
+            }
+        });
+    }
+
+    #[test]
+    fn conditional() {
+        parse!("[[ -f $x && ( $y == *.rs || $z < b ) ]]", |cmd| {
+            if let Command::Conditional(args) = cmd {
+                let words: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+                assert_eq!(
+                    words,
+                    [
+                        "-f", "$x", "&&", "(", "$y", "==", "*.rs", "||", "$z", "<", "b", ")"
+                    ]
+                );
+            } else {
+                panic!("expected Conditional");
+            }
+        });
+        // The regex is put back together, up to where the condition goes on
+        parse!("[[ $x =~ ^(a|b)+$ && -n $x ]]", |cmd| {
+            if let Command::Conditional(args) = cmd {
+                assert_eq!(args[2].as_str(), "^(a|b)+$");
+                assert_eq!(args.len(), 6);
+            } else {
+                panic!("expected Conditional");
+            }
+        });
+        assert!(parse_err("[[ ]]"));
+        assert!(parse_err("[[ a | b ]]"));
+    }
+
+    #[test]
+    fn arithmetic_for_loop() {
+        parse!("for ((i=0; i<3; i++)); do echo $i; done; echo end", |cmd| {
+            if let Command::Sequence(left, _) = cmd
+                && let Command::ArithmeticFor {
+                    init,
+                    condition,
+                    step,
+                    ..
+                } = *left
+            {
+                assert_eq!(init.as_ref(), "i=0");
+                assert_eq!(condition.as_ref(), " i<3");
+                assert_eq!(step.as_ref(), " i++");
+            } else {
+                panic!("expected a Sequence starting with ArithmeticFor");
+            }
+        });
+    }
+
+    // ── Case ──────────────────────────────────────────────────────────────────
+
+    #[test]
+    fn case_statement() {
+        parse!(
+            "case $x in\n  a|'b c') echo ab;;\n  (*.rs) ;&\n  *)\n    echo other\nesac",
+            |cmd| {
+                if let Command::Case { word, items } = cmd {
+                    assert_eq!(word.as_str(), "$x");
+                    assert_eq!(items.len(), 3);
+                    assert_eq!(items[0].patterns.len(), 2);
+                    assert!(items[1].body.is_none() && items[1].fall_through);
+                    assert!(items[2].body.is_some() && !items[2].fall_through);
+                } else {
+                    panic!("expected Case");
+                }
+            }
+        );
+        assert!(parse_err("case x in a) echo a;; b echo b;; esac"));
+        assert!(parse_err("case x a) echo a;; esac"));
+    }
+
+    #[test]
+    fn array_assignment() {
+        parse!("arr+=(one 'two three'\n  four)", |cmd| {
+            if let Command::ArrayAssignment {
+                name,
+                values,
+                append,
+            } = cmd
+            {
+                assert_eq!(name, "arr");
+                assert_eq!(values.len(), 3);
+                assert!(append);
+            } else {
+                panic!("expected ArrayAssignment");
+            }
+        });
+        assert!(parse_err("arr=(one | two)"));
+    }
+
+    #[test]
+    fn assignment_without_a_command() {
+        parse!("A=1 B='two'; echo $A", |cmd: Command| {
+            assert_eq!(cmd.to_string(), "A=1 B='two'; echo $A");
+        });
+        parse!("A=1 env", |cmd| {
+            assert!(matches!(cmd, Command::Simple { .. }));
+        });
+    }
+
+    #[test]
+    fn unfinished_loop_is_incomplete() {
+        use rshell::error::ShellError;
+
+        let incomplete = |input: &str| {
+            let error = match Tokenizer::tokenize(input) {
+                Ok(tokens) => Parser::parse(&tokens).unwrap_err(),
+                Err(error) => error,
+            };
+            error
+                .downcast_ref::<ShellError>()
+                .is_some_and(|error| error.is_incomplete())
+        };
+        assert!(incomplete("for x in a b"));
+        assert!(incomplete("for x in a b; do\necho $x"));
+        assert!(incomplete("echo a &&"));
+        assert!(incomplete("case $x in\na) echo a;;"));
+        assert!(incomplete("cat <<EOF"));
+        assert!(incomplete("cat <<EOF\nbody\nEOFX"));
+        assert!(!incomplete("for x in a; echo $x; done"));
+        assert!(!incomplete("echo a; done"));
+    }
+
+    #[test]
+    fn leftover_tokens_are_error() {
+        assert!(parse_err("echo a )"));
+        assert!(parse_err("for x in a; do done"));
+        parse!("sleep 1 & echo started", |cmd| {
+            assert!(matches!(cmd, Command::Sequence(_, _)));
+        });
+    }
 }
 
 // =============================================================================
@@ -517,6 +854,21 @@ mod expander_tests {
         assert!(cmd.to_string().contains("world"));
     }
 
+    #[test]
+    fn names_end_where_they_should() {
+        unsafe { std::env::set_var("ENDVAR", "v") };
+        let mut e = make_test_env();
+        let cmd = Shell::parse_command(
+            &mut e.ctx,
+            &mut e.term,
+            "echo $ENDVAR/dir $ENDVAR:x $ENDVAR.txt ${ENDVAR}text $ENDVARtext a$",
+            true,
+        )
+        .unwrap();
+        assert_eq!(cmd.to_string(), "echo v/dir v:x v.txt vtext  a$");
+        assert!(Shell::parse_command(&mut e.ctx, &mut e.term, "echo ${}", true).is_err());
+    }
+
     #[test]
     fn undefined_variable_expands_to_empty() {
         unsafe { std::env::remove_var("UNDEFINED_RSHELL_VAR") };
@@ -534,6 +886,65 @@ mod expander_tests {
         assert!(cmd.to_string().contains("42"));
     }
 
+    #[test]
+    fn positional_parameters_expand() {
+        let mut e = make_test_env();
+        e.ctx.positional = vec!["one".into(), "two".into()];
+        let cmd =
+            Shell::parse_command(&mut e.ctx, &mut e.term, "echo $1x $2 $3 $# $@", true).unwrap();
+        assert_eq!(cmd.to_string(), "echo onex two  2 one two");
+    }
+
+    #[test]
+    fn quotes_inside_words_are_honored() {
+        unsafe { std::env::set_var("QUOTED_VAR", "a b") };
+        let mut e = make_test_env();
+        let cmd = Shell::parse_command(
+            &mut e.ctx,
+            &mut e.term,
+            r#"echo x'$QUOTED_VAR'y --v="$QUOTED_VAR" \$QUOTED_VAR "\$\"" '*'.rs"#,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            cmd.to_string(),
+            r#"echo x$QUOTED_VARy --v=a b $QUOTED_VAR "$"" *.rs"#
+        );
+    }
+
+    #[test]
+    fn only_unquoted_parts_are_split() {
+        use rshell::{expander, parser::Arg};
+        use std::borrow::Cow;
+
+        unsafe { std::env::set_var("SPLIT_VAR", "a b") };
+        let mut e = make_test_env();
+        let words = vec![
+            Arg::Word(Cow::Borrowed("$SPLIT_VAR")),
+            Arg::Word(Cow::Borrowed("x\"$SPLIT_VAR\"y")),
+            Arg::Word(Cow::Borrowed("''")),
+        ];
+        let values = expander::expand_words(&mut e.ctx, &mut e.term, words).unwrap();
+        assert_eq!(values, vec!["a", "b", "xa by", ""]);
+    }
+
+    #[test]
+    fn arithmetic_expands_to_its_value() {
+        unsafe { std::env::set_var("ARITH_K", "4") };
+        let mut e = make_test_env();
+        e.ctx
+            .arrays
+            .insert("nums".into(), vec!["10".into(), "20".into()]);
+        let cmd = Shell::parse_command(
+            &mut e.ctx,
+            &mut e.term,
+            "echo $((1 + 2 * 3)) $(( (ARITH_K + $ARITH_K) * ${nums[1]} ))",
+            true,
+        )
+        .unwrap();
+        assert_eq!(cmd.to_string(), "echo 7 160");
+    }
+
     #[test]
     fn dollar_dollar_expands_to_pid() {
         let mut e = make_test_env();
@@ -590,6 +1001,26 @@ mod expander_tests {
         .unwrap();
         assert!(cmd.to_string().contains("*.xyz"));
     }
+
+    // ── Aliases ───────────────────────────────────────────────────────────────
+
+    #[test]
+    fn alias_naming_itself_expands_once() {
+        let mut e = make_test_env();
+        e.ctx.aliases.add("ls".into(), "ls --color".into());
+        let cmd = Shell::parse_command(&mut e.ctx, &mut e.term, "ls -a", true).unwrap();
+        assert_eq!(cmd.to_string(), "ls --color -a");
+    }
+
+    #[test]
+    fn arguments_of_a_pipeline_alias_go_to_its_last_command() {
+        let mut e = make_test_env();
+        e.ctx
+            .aliases
+            .add("gl".into(), "git log --oneline | head".into());
+        let cmd = Shell::parse_command(&mut e.ctx, &mut e.term, "gl -n 3", true).unwrap();
+        assert_eq!(cmd.to_string(), "git log --oneline | head -n 3");
+    }
 }
 
 // =============================================================================
@@ -626,12 +1057,59 @@ mod builtin_tests {
         assert!(BuiltIns::cd(&["-"], &mut e.ctx, &mut e.term).is_ok());
     }
 
+    #[test]
+    fn cd_keeps_the_path_through_symlinks() {
+        let mut e = make_test_env();
+        let dir = e._history_dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(dir.join("real/sub")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real/sub"), dir.join("link")).unwrap();
+        let link = dir.join("link").display().to_string();
+
+        BuiltIns::cd(&[&link], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(e.ctx.variables.get("PWD").unwrap(), link);
+        assert_eq!(e.ctx.directory.display().to_string(), link);
+        BuiltIns::cd(&[&format!("{link}/..")], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(
+            e.ctx.variables.get("PWD").unwrap(),
+            dir.display().to_string()
+        );
+        assert_eq!(e.ctx.variables.get("OLDPWD").unwrap(), link);
+
+        BuiltIns::cd(&["-P", &link], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(
+            e.ctx.variables.get("PWD").unwrap(),
+            dir.join("real/sub").display().to_string()
+        );
+    }
+
     #[test]
     fn cd_nonexistent_path_is_error() {
         let mut e = make_test_env();
         assert!(BuiltIns::cd(&["/no/such/path/12345"], &mut e.ctx, &mut e.term).is_err());
     }
 
+    #[test]
+    fn cd_loads_allowed_env_files_with_direnv() {
+        let mut e = make_test_env();
+        let project = e._history_dir.path().join("project");
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        let content = "RSHELL_TEST_DIRENV=on\n";
+        std::fs::write(project.join(".env"), content).unwrap();
+        rshell::direnv::allow(&project.join(".env"), content).unwrap();
+        unsafe { std::env::remove_var("RSHELL_TEST_DIRENV") };
+
+        // Off by default
+        BuiltIns::cd(&[project.to_str().unwrap()], &mut e.ctx, &mut e.term).unwrap();
+        assert!(std::env::var("RSHELL_TEST_DIRENV").is_err());
+
+        e.ctx.settings.options.direnv = true;
+        BuiltIns::cd(&["src"], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(std::env::var("RSHELL_TEST_DIRENV").unwrap(), "on");
+
+        BuiltIns::cd(&["/tmp"], &mut e.ctx, &mut e.term).unwrap();
+        assert!(std::env::var("RSHELL_TEST_DIRENV").is_err());
+    }
+
     // ── export ────────────────────────────────────────────────────────────────
 
     #[test]
@@ -641,6 +1119,172 @@ mod builtin_tests {
         assert_eq!(std::env::var("RSHELL_TEST_FOO").unwrap(), "bar");
     }
 
+    #[test]
+    fn export_promotes_shell_variables() {
+        let mut e = make_test_env();
+        e.ctx.variables.set("RSHELL_TEST_LOCAL", "value");
+        assert!(std::env::var("RSHELL_TEST_LOCAL").is_err());
+        assert!(!e.ctx.variables.is_exported("RSHELL_TEST_LOCAL"));
+
+        BuiltIns::export(&["RSHELL_TEST_LOCAL"], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(std::env::var("RSHELL_TEST_LOCAL").unwrap(), "value");
+        // Exported ones stay exported when set again
+        e.ctx.variables.set("RSHELL_TEST_LOCAL", "new");
+        assert_eq!(std::env::var("RSHELL_TEST_LOCAL").unwrap(), "new");
+
+        BuiltIns::unset(&["RSHELL_TEST_LOCAL"], &mut e.ctx, &mut e.term).unwrap();
+        assert!(!e.ctx.variables.is_set("RSHELL_TEST_LOCAL"));
+    }
+
+    #[test]
+    fn hooks_are_added_run_and_removed() {
+        use rshell::hooks::{self, Hook};
+
+        let mut e = make_test_env();
+        let output = e._history_dir.path().join("output");
+        let command = format!("echo \"$# $1\" >> {}", output.display());
+        BuiltIns::hook(&["preexec", &command], &mut e.ctx, &mut e.term).unwrap();
+        assert!(BuiltIns::hook(&["postexec", "ls"], &mut e.ctx, &mut e.term).is_err());
+
+        e.ctx.last_exit_code = 3;
+        e.ctx.positional = vec!["a".into(), "b".into()];
+        hooks::run(&mut e.ctx, &mut e.term, Hook::Preexec, &["ls -la"]).unwrap();
+        hooks::run(&mut e.ctx, &mut e.term, Hook::Precmd, &[]).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "1 ls -la\n");
+        assert_eq!(e.ctx.last_exit_code, 3);
+        assert_eq!(e.ctx.positional, vec!["a", "b"]);
+
+        BuiltIns::hook(&["-d", "preexec", &command], &mut e.ctx, &mut e.term).unwrap();
+        assert!(e.ctx.hooks.get(Hook::Preexec).is_empty());
+        assert!(BuiltIns::hook(&["-d", "preexec", &command], &mut e.ctx, &mut e.term).is_err());
+    }
+
+    #[test]
+    fn type_finds_builtins_aliases_and_programs() {
+        let mut e = make_test_env();
+        BuiltIns::alias(&["ll=ls -l"], &mut e.ctx, &mut e.term).unwrap();
+        let status = |e: &mut crate::test_helpers::TestEnv, name: &str| {
+            BuiltIns::type_of(&[name], &mut e.ctx, &mut e.term).unwrap()
+        };
+        assert_eq!(status(&mut e, "cd"), 0);
+        assert_eq!(status(&mut e, "ll"), 0);
+        assert_eq!(status(&mut e, "sh"), 0);
+        assert_eq!(status(&mut e, "rshell-no-such-program"), 1);
+        assert!(BuiltIns::type_of(&[], &mut e.ctx, &mut e.term).is_err());
+    }
+
+    #[test]
+    fn repeat_runs_the_command_until_told_to_stop() {
+        let mut e = make_test_env();
+        let output = e._history_dir.path().join("output");
+        let command = format!("echo run >> {}", output.display());
+        assert_eq!(
+            BuiltIns::repeat(&["3", "sh", "-c", &command], &mut e.ctx, &mut e.term).unwrap(),
+            0
+        );
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "run\nrun\nrun\n");
+
+        // -e stops at the first failure, its status is the one given back
+        let failing = format!("{}; false", command);
+        let status =
+            BuiltIns::repeat(&["-e", "3", "sh", "-c", &failing], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(status, 1);
+        assert_eq!(std::fs::read_to_string(&output).unwrap().lines().count(), 4);
+        assert!(BuiltIns::repeat(&["often", "true"], &mut e.ctx, &mut e.term).is_err());
+    }
+
+    #[test]
+    fn repeat_and_watch_keep_the_arguments_whole() {
+        let mut e = make_test_env();
+        let dir = e._history_dir.path();
+        let spaced = dir.join("a b");
+        let spaced = spaced.to_str().unwrap();
+        BuiltIns::repeat(&["2", "touch", spaced], &mut e.ctx, &mut e.term).unwrap();
+        assert!(dir.join("a b").exists());
+        assert!(!dir.join("a").exists());
+
+        // Nor are they expanded again
+        let starred = dir.join("*$HOME");
+        let starred = starred.to_str().unwrap();
+        BuiltIns::repeat(&["1", "touch", starred], &mut e.ctx, &mut e.term).unwrap();
+        assert!(dir.join("*$HOME").exists());
+    }
+
+    #[test]
+    fn chpwd_hooks_get_the_old_and_new_directory() {
+        let mut e = make_test_env();
+        let (old, new) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let (old, new) = (old.path().to_str().unwrap(), new.path().to_str().unwrap());
+        let output = e._history_dir.path().join("output");
+        let command = format!("echo \"$1 $2\" >> {}", output.display());
+        BuiltIns::cd(&[old], &mut e.ctx, &mut e.term).unwrap();
+        BuiltIns::hook(&["chpwd", &command], &mut e.ctx, &mut e.term).unwrap();
+
+        BuiltIns::cd(&[new], &mut e.ctx, &mut e.term).unwrap();
+        // Staying in the same directory isn't a change
+        BuiltIns::cd(&["."], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            format!("{} {}\n", old, new)
+        );
+    }
+
+    #[test]
+    fn declare_attributes_change_assigned_values() {
+        let mut e = make_test_env();
+
+        BuiltIns::declare(&["-i", "RSHELL_TEST_INT=2*3"], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(e.ctx.variables.get("RSHELL_TEST_INT").as_deref(), Some("6"));
+        e.ctx
+            .variables
+            .assign("RSHELL_TEST_INT", "RSHELL_TEST_INT + 1")
+            .unwrap();
+        assert_eq!(e.ctx.variables.get("RSHELL_TEST_INT").as_deref(), Some("7"));
+
+        BuiltIns::declare(
+            &["-u", "RSHELL_TEST_CASE=mixed Case"],
+            &mut e.ctx,
+            &mut e.term,
+        )
+        .unwrap();
+        assert_eq!(
+            e.ctx.variables.get("RSHELL_TEST_CASE").as_deref(),
+            Some("MIXED CASE")
+        );
+        BuiltIns::declare(&["-l", "RSHELL_TEST_CASE=Lower"], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(
+            e.ctx.variables.get("RSHELL_TEST_CASE").as_deref(),
+            Some("lower")
+        );
+
+        BuiltIns::declare(&["+l", "RSHELL_TEST_CASE"], &mut e.ctx, &mut e.term).unwrap();
+        e.ctx.variables.assign("RSHELL_TEST_CASE", "Kept").unwrap();
+        assert_eq!(
+            e.ctx.variables.get("RSHELL_TEST_CASE").as_deref(),
+            Some("Kept")
+        );
+        assert!(BuiltIns::declare(&["-ul", "RSHELL_TEST_CASE"], &mut e.ctx, &mut e.term).is_err());
+    }
+
+    #[test]
+    fn envrestore_puts_variables_back() {
+        let mut e = make_test_env();
+        e.ctx.variables.set("RSHELL_TEST_KEPT", "before");
+        BuiltIns::envsave(&["clean"], &mut e.ctx, &mut e.term).unwrap();
+
+        e.ctx.variables.set("RSHELL_TEST_KEPT", "after");
+        BuiltIns::export(&["RSHELL_TEST_POLLUTED=1"], &mut e.ctx, &mut e.term).unwrap();
+        BuiltIns::envrestore(&["clean"], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(
+            e.ctx.variables.get("RSHELL_TEST_KEPT").as_deref(),
+            Some("before")
+        );
+        assert!(std::env::var("RSHELL_TEST_POLLUTED").is_err());
+
+        BuiltIns::envsave(&["-d", "clean"], &mut e.ctx, &mut e.term).unwrap();
+        assert!(BuiltIns::envrestore(&["clean"], &mut e.ctx, &mut e.term).is_err());
+    }
+
     #[test]
     fn export_no_args_is_error() {
         let mut e = make_test_env();
@@ -671,6 +1315,47 @@ mod builtin_tests {
         assert!(BuiltIns::export(&["NAME="], &mut e.ctx, &mut e.term).is_err());
     }
 
+    #[test]
+    fn export_histfile_redirects_history_writes() {
+        let mut e = make_test_env();
+        e.ctx.history.push("before".into()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let other = dir.path().join("other_history");
+        let assignment = format!("HISTFILE={}", other.display());
+        e.ctx.history.add(&format!("export {assignment}")).unwrap();
+        BuiltIns::export(&[&assignment], &mut e.ctx, &mut e.term).unwrap();
+        e.ctx.history.finish(0, std::time::Duration::ZERO).unwrap();
+        e.ctx.history.push("after".into()).unwrap();
+
+        let content = std::fs::read_to_string(&other).unwrap();
+        assert!(content.contains("export HISTFILE="));
+        assert!(content.contains("after"));
+        assert!(!content.contains("before"));
+
+        BuiltIns::unset(&["HISTFILE"], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(e.ctx.history.last_command(), Some("before"));
+    }
+
+    #[test]
+    fn export_histsize_trims_history() {
+        let mut e = make_test_env();
+        for command in ["one", "two", "three"] {
+            e.ctx.history.push(command.into()).unwrap();
+        }
+        BuiltIns::export(&["HISTSIZE=2"], &mut e.ctx, &mut e.term).unwrap();
+        let commands: Vec<&str> = e
+            .ctx
+            .history
+            .current
+            .iter()
+            .map(|h| h.command.as_str())
+            .collect();
+        assert_eq!(commands, ["two", "three"]);
+
+        BuiltIns::unset(&["HISTSIZE"], &mut e.ctx, &mut e.term).unwrap();
+    }
+
     // ── unset ─────────────────────────────────────────────────────────────────
 
     #[test]
@@ -701,13 +1386,87 @@ mod builtin_tests {
         assert!(shell_err.is_exit());
     }
 
-    // ── alias / unalias ───────────────────────────────────────────────────────
+    // ── reload ────────────────────────────────────────────────────────────────
 
     #[test]
-    fn alias_adds_entry() {
+    fn reload_applies_the_config_file() {
         let mut e = make_test_env();
-        BuiltIns::alias(&["ll=ls -la"], &mut e.ctx, &mut e.term).unwrap();
-        assert!(e.ctx.aliases.get("ll").is_some());
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("rshell")).unwrap();
+        let config = dir.path().join("rshell").join("config.toml");
+        unsafe { std::env::set_var("XDG_CONFIG_HOME", dir.path()) };
+
+        std::fs::write(
+            &config,
+            "[prompt]\ntemplate = \"$ \"\n[history]\nignore_space = false\n",
+        )
+        .unwrap();
+        assert_eq!(BuiltIns::reload(&[], &mut e.ctx, &mut e.term).unwrap(), 0);
+        assert_eq!(e.ctx.settings.prompt.template, "$ ");
+        e.ctx.history.add(" hidden").unwrap();
+        assert_eq!(e.ctx.history.last_command(), Some(" hidden"));
+
+        // A broken file leaves the settings alone
+        std::fs::write(&config, "[prompt\n").unwrap();
+        assert!(BuiltIns::reload(&[], &mut e.ctx, &mut e.term).is_err());
+        assert_eq!(e.ctx.settings.prompt.template, "$ ");
+
+        unsafe { std::env::remove_var("XDG_CONFIG_HOME") };
+    }
+
+    // ── theme ─────────────────────────────────────────────────────────────────
+
+    #[test]
+    fn theme_switches_for_the_session() {
+        let mut e = make_test_env();
+        unsafe { std::env::remove_var("PROMPT_THEME") };
+
+        assert_eq!(BuiltIns::theme(&[], &mut e.ctx, &mut e.term).unwrap(), 0);
+        BuiltIns::theme(&["ocean"], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(e.ctx.settings.colors.theme, "ocean");
+
+        assert!(BuiltIns::theme(&["neon"], &mut e.ctx, &mut e.term).is_err());
+        assert!(BuiltIns::theme(&["ocean", "mono"], &mut e.ctx, &mut e.term).is_err());
+        assert_eq!(e.ctx.settings.colors.theme, "ocean");
+    }
+
+    // ── shopt ─────────────────────────────────────────────────────────────────
+
+    #[test]
+    fn shopt_toggles_options() {
+        let mut e = make_test_env();
+        assert_eq!(
+            BuiltIns::shopt(&["-q", "histverify"], &mut e.ctx, &mut e.term).unwrap(),
+            1
+        );
+
+        BuiltIns::shopt(&["-s", "histverify", "histshare"], &mut e.ctx, &mut e.term).unwrap();
+        assert!(e.ctx.settings.options.histverify);
+        assert_eq!(
+            BuiltIns::shopt(&["-q", "histverify", "histshare"], &mut e.ctx, &mut e.term).unwrap(),
+            0
+        );
+
+        BuiltIns::shopt(&["-u", "histshare"], &mut e.ctx, &mut e.term).unwrap();
+        assert!(!e.ctx.settings.options.histshare);
+        assert_eq!(BuiltIns::shopt(&[], &mut e.ctx, &mut e.term).unwrap(), 0);
+    }
+
+    #[test]
+    fn shopt_rejects_unknown_options() {
+        let mut e = make_test_env();
+        assert!(BuiltIns::shopt(&["-s", "nope"], &mut e.ctx, &mut e.term).is_err());
+        assert!(BuiltIns::shopt(&["-x", "histverify"], &mut e.ctx, &mut e.term).is_err());
+        assert!(BuiltIns::shopt(&["-s"], &mut e.ctx, &mut e.term).is_err());
+    }
+
+    // ── alias / unalias ───────────────────────────────────────────────────────
+
+    #[test]
+    fn alias_adds_entry() {
+        let mut e = make_test_env();
+        BuiltIns::alias(&["ll=ls -la"], &mut e.ctx, &mut e.term).unwrap();
+        assert!(e.ctx.aliases.get("ll").is_some());
     }
 
     #[test]
@@ -730,19 +1489,67 @@ mod builtin_tests {
         e.ctx.aliases.add("x".into(), "y".into());
         assert!(BuiltIns::alias(&[], &mut e.ctx, &mut e.term).is_ok());
     }
+
+    #[test]
+    fn alias_save_writes_rc_file() {
+        let mut e = make_test_env();
+        let rc_file = e._history_dir.path().join(".rshellrc");
+        std::fs::write(&rc_file, "export EDITOR=vim\n").unwrap();
+
+        BuiltIns::alias(&["--save", "ll='ls -la'"], &mut e.ctx, &mut e.term).unwrap();
+        BuiltIns::alias(&["--save", "gs='git status'"], &mut e.ctx, &mut e.term).unwrap();
+        BuiltIns::alias(&["--save", "ll='ls -l'"], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(e.ctx.aliases.get("ll").unwrap(), "ls -l");
+
+        let content = std::fs::read_to_string(&rc_file).unwrap();
+        assert!(content.starts_with("export EDITOR=vim\n"));
+        assert!(content.contains("alias ll='ls -l'\n"));
+        assert!(content.contains("alias gs='git status'\n"));
+        assert!(!content.contains("ls -la"));
+
+        BuiltIns::unalias(&["--save", "ll"], &mut e.ctx, &mut e.term).unwrap();
+        let content = std::fs::read_to_string(&rc_file).unwrap();
+        assert!(!content.contains("alias ll="));
+        assert!(content.contains("alias gs="));
+    }
+
+    #[test]
+    fn alias_save_existing_and_savealiases_option() {
+        let mut e = make_test_env();
+        let rc_file = e._history_dir.path().join(".rshellrc");
+
+        BuiltIns::alias(&["x=y"], &mut e.ctx, &mut e.term).unwrap();
+        assert!(!rc_file.exists());
+        BuiltIns::alias(&["--save", "x"], &mut e.ctx, &mut e.term).unwrap();
+        assert!(BuiltIns::alias(&["--save", "ghost"], &mut e.ctx, &mut e.term).is_err());
+
+        e.ctx.settings.options.savealiases = true;
+        BuiltIns::alias(&["z=echo"], &mut e.ctx, &mut e.term).unwrap();
+
+        let content = std::fs::read_to_string(&rc_file).unwrap();
+        assert!(content.contains("alias x='y'\n"));
+        assert!(content.contains("alias z='echo'\n"));
+    }
 }
 
 // =============================================================================
 // history — tests
 // =============================================================================
 mod history_tests {
-    use rshell::history::History;
+    use rshell::history::{
+        self, DirectoryMode, ExportFormat, History, HistoryEntry, HistoryFilter,
+    };
+    use std::{path::PathBuf, time::Duration};
     use tempfile::TempDir;
 
     /// Each test gets its own HOME so history files don't interfere.
     fn make_history() -> (History, TempDir) {
         let dir = tempfile::tempdir().unwrap();
-        unsafe { std::env::set_var("HOME", dir.path()) };
+        unsafe {
+            std::env::set_var("HOME", dir.path());
+            std::env::remove_var("XDG_DATA_HOME");
+            std::env::remove_var("HISTFILE");
+        }
         (History::new().unwrap(), dir)
     }
 
@@ -753,12 +1560,37 @@ mod history_tests {
         assert_eq!(h.row, 0);
     }
 
+    #[test]
+    fn size_limit_drops_the_oldest_entries() {
+        let (mut h, _dir) = make_history();
+        for i in 0..5 {
+            h.push(format!("cmd {i}")).unwrap();
+        }
+        h.older(std::path::Path::new("/"), DirectoryMode::Off)
+            .unwrap();
+        h.older(std::path::Path::new("/"), DirectoryMode::Off)
+            .unwrap();
+
+        // Browsing stays on the same entry
+        h.set_size(Some(3));
+        assert_eq!(h.current.len(), 3);
+        assert_eq!(h.current[0].command, "cmd 2");
+        assert_eq!(h.newer(), Some("cmd 4"));
+
+        // The file keeps everything, HISTSIZE only limits what's loaded
+        unsafe { std::env::set_var("HISTSIZE", "2") };
+        let h2 = History::new().unwrap();
+        unsafe { std::env::remove_var("HISTSIZE") };
+        let commands: Vec<&str> = h2.current.iter().map(|h| h.command.as_str()).collect();
+        assert_eq!(commands, ["cmd 3", "cmd 4"]);
+    }
+
     #[test]
     fn push_adds_entry() {
         let (mut h, _dir) = make_history();
         h.push("ls".into()).unwrap();
         assert_eq!(h.current.len(), 1);
-        assert_eq!(h.current[0], "ls");
+        assert_eq!(h.current[0].command, "ls");
     }
 
     #[test]
@@ -781,8 +1613,8 @@ mod history_tests {
         }
         let h2 = History::new().unwrap();
         assert_eq!(h2.current.len(), 2);
-        assert_eq!(h2.current[0], "first");
-        assert_eq!(h2.current[1], "second");
+        assert_eq!(h2.current[0].command, "first");
+        assert_eq!(h2.current[1].command, "second");
     }
 
     #[test]
@@ -797,6 +1629,519 @@ mod history_tests {
         let h2 = History::new().unwrap();
         assert_eq!(h2.row, 2);
     }
+
+    // ── History expansion ─────────────────────────────────────────────────────
+
+    fn with_entries(entries: &[&str]) -> History {
+        let mut h = History::dummy();
+        for entry in entries {
+            h.add(entry).unwrap();
+        }
+        h
+    }
+
+    #[test]
+    fn add_skips_commands_starting_with_space() {
+        let mut h = with_entries(&["ls", " export TOKEN=secret"]);
+        assert_eq!(h.current.len(), 1);
+        assert_eq!(h.last_command(), Some("ls"));
+        // The hidden command's status must not end up on the previous entry
+        h.finish(1, Duration::ZERO).unwrap();
+        assert_eq!(h.current[0].exit_code, None);
+    }
+
+    #[test]
+    fn add_skips_consecutive_duplicates() {
+        let h = with_entries(&["ls", "ls", "pwd"]);
+        let commands: Vec<&str> = h.current.iter().map(|e| e.command.as_str()).collect();
+        assert_eq!(commands, vec!["ls", "pwd"]);
+        assert_eq!(h.row, 2);
+    }
+
+    #[test]
+    fn line_without_bang_is_not_expanded() {
+        let h = with_entries(&["ls"]);
+        assert!(h.expand("echo hi").unwrap().is_none());
+    }
+
+    #[test]
+    fn bang_bang_expands_to_last_command() {
+        let h = with_entries(&["apt update"]);
+        assert_eq!(h.expand("sudo !!").unwrap().unwrap(), "sudo apt update");
+    }
+
+    #[test]
+    fn bang_dollar_expands_to_last_word() {
+        let h = with_entries(&["mkdir /tmp/project"]);
+        assert_eq!(h.expand("cd !$").unwrap().unwrap(), "cd /tmp/project");
+    }
+
+    #[test]
+    fn bang_number_and_negative_offset() {
+        let h = with_entries(&["first", "second", "third"]);
+        assert_eq!(h.expand("!0").unwrap().unwrap(), "first");
+        assert_eq!(h.expand("!-2").unwrap().unwrap(), "second");
+    }
+
+    #[test]
+    fn bang_prefix_finds_most_recent_match() {
+        let h = with_entries(&["git status", "ls", "git log"]);
+        assert_eq!(h.expand("!git").unwrap().unwrap(), "git log");
+    }
+
+    #[test]
+    fn unknown_event_is_error() {
+        let h = with_entries(&["ls"]);
+        assert!(h.expand("!nothing").is_err());
+    }
+
+    #[test]
+    fn bang_in_single_quotes_and_last_job_pid_are_untouched() {
+        let h = with_entries(&["ls"]);
+        assert!(h.expand("echo '!!' $! a != b").unwrap().is_none());
+    }
+
+    #[test]
+    fn concurrent_sessions_merge_instead_of_clobbering() {
+        let (mut first, _dir) = make_history();
+        let mut second = History::new().unwrap();
+        first.push("from first".into()).unwrap();
+        second.push("from second".into()).unwrap();
+        first.reload().unwrap();
+
+        for h in [&first, &second, &History::new().unwrap()] {
+            let commands: Vec<&str> = h.current.iter().map(|e| e.command.as_str()).collect();
+            assert_eq!(commands, vec!["from first", "from second"]);
+        }
+    }
+
+    #[test]
+    fn history_lives_in_the_xdg_data_dir() {
+        let (_h, dir) = make_history();
+        assert_eq!(
+            history::history_path(),
+            dir.path().join(".local/share/rshell/history")
+        );
+        assert!(history::history_path().exists());
+    }
+
+    #[test]
+    fn legacy_history_file_is_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".rshell_history"), "ls\npwd\n").unwrap();
+        unsafe {
+            std::env::set_var("HOME", dir.path());
+            std::env::remove_var("XDG_DATA_HOME");
+            std::env::remove_var("HISTFILE");
+        }
+
+        let h = History::new().unwrap();
+        assert_eq!(h.current.len(), 2);
+        assert!(!dir.path().join(".rshell_history").exists());
+        assert!(dir.path().join(".local/share/rshell/history").exists());
+    }
+
+    fn with_big_file(lines: usize) -> (History, TempDir) {
+        let (_, dir) = make_history();
+        let content: String = (0..lines).map(|n| format!("cmd {n}\n")).collect();
+        std::fs::write(history::history_path(), content).unwrap();
+        (History::new().unwrap(), dir)
+    }
+
+    #[test]
+    fn only_recent_entries_are_loaded_at_startup() {
+        let (mut h, _dir) = with_big_file(2500);
+        assert_eq!(h.current.len(), 1000);
+        assert_eq!(h.current[0].command, "cmd 1500");
+        assert_eq!(h.last_command(), Some("cmd 2499"));
+
+        h.load_all().unwrap();
+        assert_eq!(h.current.len(), 2500);
+        assert_eq!(h.current[0].command, "cmd 0");
+        assert_eq!(h.current[1500].command, "cmd 1500");
+    }
+
+    #[test]
+    fn browsing_past_the_loaded_entries_loads_the_rest() {
+        let (mut h, _dir) = with_big_file(1200);
+        let cwd = PathBuf::from("/");
+        for _ in 0..1000 {
+            h.older(&cwd, DirectoryMode::Off).unwrap();
+        }
+        assert_eq!(h.current.len(), 1000);
+        assert_eq!(h.older(&cwd, DirectoryMode::Off).unwrap(), Some("cmd 199"));
+        assert_eq!(h.current.len(), 1200);
+        assert_eq!(h.newer(), Some("cmd 200"));
+    }
+
+    #[test]
+    fn truncated_last_line_is_dropped_on_load() {
+        let (mut first, dir) = make_history();
+        first.push("complete".into()).unwrap();
+        let path = dir.path().join(".local/share/rshell/history");
+        let mut content = std::fs::read_to_string(&path).unwrap();
+        content.push_str("1700000000\t0\t/tmp\tcut sh");
+        std::fs::write(&path, content).unwrap();
+
+        let mut h = History::new().unwrap();
+        assert_eq!(h.last_command(), Some("complete"));
+        h.push("after".into()).unwrap();
+
+        let commands: Vec<String> = History::new()
+            .unwrap()
+            .current
+            .into_iter()
+            .map(|e| e.command)
+            .collect();
+        assert_eq!(commands, vec!["complete", "after"]);
+    }
+
+    #[test]
+    fn partial_line_from_crashed_session_is_not_merged() {
+        let (mut h, dir) = make_history();
+        let path = dir.path().join(".local/share/rshell/history");
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| std::io::Write::write_all(&mut f, b"1700000000\t0\t/tmp\tcut"))
+            .unwrap();
+
+        h.push("next".into()).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("cut"));
+        assert!(content.ends_with("\tnext\n"));
+    }
+
+    // ── Import ────────────────────────────────────────────────────────────────
+
+    #[test]
+    fn import_bash_with_timestamps() {
+        let entries = history::parse_foreign("#1700000000\nls -la\ncd /tmp\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].command, "ls -la");
+        assert_eq!(entries[0].timestamp, 1700000000);
+        assert_eq!(entries[1].timestamp, 0);
+    }
+
+    #[test]
+    fn import_zsh_extended_format() {
+        let entries =
+            history::parse_foreign(": 1700000000:0;git status\n: 1700000005:3;echo a \\\nb\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].command, "git status");
+        assert_eq!(entries[1].timestamp, 1700000005);
+        assert_eq!(entries[1].command, "echo a  b");
+    }
+
+    #[test]
+    fn import_appends_and_persists() {
+        let (mut h, _dir) = make_history();
+        h.push("mine".into()).unwrap();
+        assert_eq!(h.import("old one\nold two\n").unwrap(), 2);
+        assert_eq!(History::new().unwrap().current.len(), 3);
+    }
+
+    // ── Browsing ──────────────────────────────────────────────────────────────
+
+    fn in_dirs(entries: &[(&str, &str)]) -> History {
+        let mut h = History::dummy();
+        for (command, cwd) in entries {
+            h.add(command).unwrap();
+            h.current.last_mut().unwrap().cwd = PathBuf::from(cwd);
+        }
+        h.reset_row();
+        h
+    }
+
+    #[test]
+    fn stats_aggregate_commands_directories_and_days() {
+        let mut h = in_dirs(&[
+            ("git status", "/repo"),
+            ("cargo build", "/repo"),
+            ("git push", "/repo"),
+            ("ls", "/tmp"),
+        ]);
+        h.current[1].exit_code = Some(101);
+        h.current[2].exit_code = Some(0);
+        for entry in h.current.iter_mut() {
+            entry.timestamp = 86400 * 365;
+        }
+
+        let stats = h.stats(&HistoryFilter::default());
+        assert_eq!(stats.total, 4);
+        assert_eq!(stats.commands[0].name, "git");
+        assert_eq!(stats.commands[0].runs, 2);
+
+        let cargo = stats.commands.iter().find(|c| c.name == "cargo").unwrap();
+        assert_eq!((cargo.finished, cargo.failures), (1, 1));
+        assert_eq!(cargo.failure_rate(), 1.0);
+        let git = stats.commands.iter().find(|c| c.name == "git").unwrap();
+        assert_eq!(git.failure_rate(), 0.0);
+
+        assert_eq!(stats.directories[0], (PathBuf::from("/repo"), 3));
+        assert_eq!(stats.days.len(), 1);
+        assert_eq!(stats.days[0].1, 4);
+    }
+
+    #[test]
+    fn fuzzy_score_matches_subsequences() {
+        assert!(history::fuzzy_score("gco", "git checkout").is_some());
+        assert!(history::fuzzy_score("GIT", "git status").is_some());
+        assert_eq!(history::fuzzy_score("xyz", "git status"), None);
+        assert_eq!(history::fuzzy_score("", "ls"), Some(0));
+
+        // Consecutive characters beat scattered ones
+        let tight = history::fuzzy_score("stat", "git status").unwrap();
+        let loose = history::fuzzy_score("stat", "sudo tail -n at").unwrap();
+        assert!(tight > loose);
+    }
+
+    #[test]
+    fn fuzzy_ranks_best_match_first_and_dedupes() {
+        let h = with_entries(&["git status", "cargo test", "git stash", "git status"]);
+        let found: Vec<&str> = h
+            .fuzzy("gsta")
+            .iter()
+            .map(|(_, e)| e.command.as_str())
+            .collect();
+        assert_eq!(found, vec!["git status", "git stash"]);
+    }
+
+    #[test]
+    fn fuzzy_prefers_recent_commands_on_ties() {
+        let h = with_entries(&["make build", "make check"]);
+        let found = h.fuzzy("make");
+        assert_eq!(found[0].1.command, "make check");
+        assert_eq!(found[0].0, 1);
+    }
+
+    #[test]
+    fn up_and_down_walk_the_history() {
+        let mut h = with_entries(&["one", "two"]);
+        let cwd = PathBuf::from("/");
+        assert_eq!(h.older(&cwd, DirectoryMode::Off).unwrap(), Some("two"));
+        assert_eq!(h.older(&cwd, DirectoryMode::Off).unwrap(), Some("one"));
+        assert_eq!(h.older(&cwd, DirectoryMode::Off).unwrap(), Some("one"));
+        assert_eq!(h.newer(), Some("two"));
+        assert_eq!(h.newer(), Some(""));
+        assert_eq!(h.newer(), None);
+        assert_eq!(h.row, 2);
+    }
+
+    #[test]
+    fn directory_mode_prefer_and_only() {
+        let h = in_dirs(&[("a", "/p"), ("b", "/q"), ("c", "/p"), ("d", "/q")]);
+        let cwd = PathBuf::from("/p");
+        assert_eq!(h.browse_order(&cwd, DirectoryMode::Off), vec![3, 2, 1, 0]);
+        assert_eq!(
+            h.browse_order(&cwd, DirectoryMode::Prefer),
+            vec![2, 0, 3, 1]
+        );
+        assert_eq!(h.browse_order(&cwd, DirectoryMode::Only), vec![2, 0]);
+    }
+
+    // ── Export ────────────────────────────────────────────────────────────────
+
+    #[test]
+    fn export_plain_has_only_commands() {
+        let h = with_entries(&["ls", "pwd"]);
+        assert_eq!(h.export(ExportFormat::Plain), "ls\npwd\n");
+    }
+
+    #[test]
+    fn export_jsonl_escapes_and_includes_metadata() {
+        let mut h = History::dummy();
+        h.add(r#"echo "hi""#).unwrap();
+        h.finish(0, Duration::ZERO).unwrap();
+        let json = h.export(ExportFormat::Jsonl);
+        assert!(json.contains(r#""command":"echo \"hi\"""#), "got: {json}");
+        assert!(json.contains(r#""exit_code":0"#));
+    }
+
+    #[test]
+    fn export_csv_quotes_fields_with_commas() {
+        let h = with_entries(&["echo a,b"]);
+        let csv = h.export(ExportFormat::Csv);
+        assert!(csv.starts_with("index,timestamp,session,cwd,exit_code,duration_ms,command\n"));
+        assert!(csv.contains(r#""echo a,b""#));
+    }
+
+    // ── Entries & search ──────────────────────────────────────────────────────
+
+    #[test]
+    fn entries_carry_the_session() {
+        let entry = HistoryEntry::new("ls".into());
+        assert_eq!(entry.session.as_deref(), Some(history::session_id()));
+        assert!(history::session_id().starts_with(&format!("{}-", std::process::id())));
+
+        let parsed = HistoryEntry::parse(&entry.to_line());
+        assert_eq!(parsed.session, entry.session);
+
+        // Entries written before sessions existed have none
+        let old = HistoryEntry::parse("1700000000\t0:12\t/tmp\tls");
+        assert_eq!(old.session, None);
+        assert_eq!(old.duration, Some(12));
+    }
+
+    #[test]
+    fn search_by_session() {
+        let mut h = with_entries(&["ls", "pwd"]);
+        h.current[0].session = Some("1-1".into());
+        let filter = HistoryFilter {
+            session: Some(history::session_id().to_string()),
+            ..Default::default()
+        };
+        let found: Vec<&str> = h.search(&filter).map(|(_, e)| e.command.as_str()).collect();
+        assert_eq!(found, vec!["pwd"]);
+    }
+
+    #[test]
+    fn entry_line_round_trip() {
+        let mut entry = HistoryEntry::new("echo a\tb".into());
+        entry.exit_code = Some(2);
+        let parsed = HistoryEntry::parse(&entry.to_line());
+        assert_eq!(parsed.command, "echo a\tb");
+        assert_eq!(parsed.exit_code, Some(2));
+        assert_eq!(parsed.timestamp, entry.timestamp);
+        assert_eq!(parsed.cwd, entry.cwd);
+    }
+
+    #[test]
+    fn multi_line_entries_stay_on_one_line() {
+        let entry = HistoryEntry::new("cat <<EOF\nhi\nEOF".into());
+        let line = entry.to_line();
+        assert!(!line.contains('\n'));
+        assert_eq!(HistoryEntry::parse(&line).command, "cat <<EOF\nhi\nEOF");
+    }
+
+    #[test]
+    fn legacy_line_is_plain_command() {
+        let entry = HistoryEntry::parse("ls -la");
+        assert_eq!(entry.command, "ls -la");
+        assert_eq!(entry.exit_code, None);
+    }
+
+    #[test]
+    fn finish_records_exit_code_and_persists() {
+        let (mut h, _dir) = make_history();
+        h.add("false").unwrap();
+        h.finish(1, Duration::from_millis(1500)).unwrap();
+        let h2 = History::new().unwrap();
+        assert_eq!(h2.current[0].exit_code, Some(1));
+        assert_eq!(h2.current[0].duration, Some(1500));
+    }
+
+    #[test]
+    fn entries_without_duration_still_parse() {
+        let entry = HistoryEntry::parse("1700000000\t0\t/tmp\tls");
+        assert_eq!(entry.exit_code, Some(0));
+        assert_eq!(entry.duration, None);
+        assert_eq!(entry.command, "ls");
+    }
+
+    #[test]
+    fn slower_than_filter_and_duration_helpers() {
+        let mut h = History::dummy();
+        for (command, millis) in [("sleep 5", 5000), ("ls", 3)] {
+            h.add(command).unwrap();
+            h.finish(0, Duration::from_millis(millis)).unwrap();
+        }
+        let filter = HistoryFilter {
+            slower_than: history::parse_duration("2s"),
+            ..Default::default()
+        };
+        let found: Vec<usize> = h.search(&filter).map(|(n, _)| n).collect();
+        assert_eq!(found, vec![0]);
+        assert_eq!(history::parse_duration("500ms"), Some(500));
+        assert_eq!(history::parse_duration("99999999999999999h"), None);
+        assert_eq!(history::parse_duration("99999999999999999"), None);
+        assert_eq!(history::format_duration(850), "850ms");
+        assert_eq!(history::format_duration(3200), "3.2s");
+        assert_eq!(history::format_duration(125_000), "2m05s");
+    }
+
+    #[test]
+    fn search_filters_failed_and_pattern() {
+        let mut h = History::dummy();
+        for (command, code) in [("make", 2), ("make test", 0), ("ls", 1)] {
+            h.add(command).unwrap();
+            h.finish(code, Duration::ZERO).unwrap();
+        }
+        let filter = HistoryFilter {
+            failed: true,
+            pattern: Some("make".into()),
+            ..Default::default()
+        };
+        let found: Vec<usize> = h.search(&filter).map(|(n, _)| n).collect();
+        assert_eq!(found, vec![0]);
+    }
+
+    #[test]
+    fn since_accepts_keywords_and_amounts() {
+        let now = history::now();
+        assert!(history::parse_since("today").unwrap() <= now);
+        assert!(
+            history::parse_since("yesterday").unwrap() < history::parse_since("today").unwrap()
+        );
+        let two_hours_ago = history::parse_since("2h").unwrap();
+        assert!((now - 7200..=now - 7199).contains(&two_hours_ago));
+        assert!(history::parse_since("soon").is_none());
+        assert!(history::parse_since("99999999999999999d").is_none());
+    }
+}
+
+// =============================================================================
+// crypto — tests
+// =============================================================================
+mod crypto_tests {
+    use rshell::crypto::{self, Cipher};
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let cipher = Cipher::from_passphrase(b"secret", b"0123456789abcdef").unwrap();
+        let line = cipher.encrypt("1700000000\t0\t/tmp\tls -la").unwrap();
+        assert!(!line.contains("ls -la"));
+        assert_eq!(
+            cipher.decrypt(&line).unwrap(),
+            "1700000000\t0\t/tmp\tls -la"
+        );
+    }
+
+    #[test]
+    fn test_nonce_is_fresh() {
+        let cipher = Cipher::from_passphrase(b"secret", b"0123456789abcdef").unwrap();
+        assert_ne!(cipher.encrypt("ls").unwrap(), cipher.encrypt("ls").unwrap());
+    }
+
+    #[test]
+    fn test_wrong_passphrase_fails() {
+        let salt = b"0123456789abcdef";
+        let line = Cipher::from_passphrase(b"secret", salt)
+            .unwrap()
+            .encrypt("ls")
+            .unwrap();
+        let other = Cipher::from_passphrase(b"guess", salt).unwrap();
+        assert!(other.decrypt(&line).is_err());
+    }
+
+    #[test]
+    fn test_tampered_line_fails() {
+        let cipher = Cipher::from_passphrase(b"secret", b"0123456789abcdef").unwrap();
+        let mut line = cipher.encrypt("ls").unwrap().into_bytes();
+        let last = line.len() - 3;
+        line[last] = if line[last] == b'A' { b'B' } else { b'A' };
+        assert!(cipher.decrypt(&String::from_utf8(line).unwrap()).is_err());
+        assert!(cipher.decrypt("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_salt_round_trip() {
+        let salt: [u8; crypto::SALT_LEN] = crypto::random_bytes().unwrap();
+        assert_eq!(
+            crypto::decode_salt(&crypto::encode_salt(&salt)).unwrap(),
+            salt.to_vec()
+        );
+    }
 }
 
 // =============================================================================
@@ -900,30 +2245,363 @@ mod buffer_tests {
 }
 
 // =============================================================================
-// aliases — tests
+// clipboard — tests
 // =============================================================================
-mod aliases_tests {
-    use rshell::aliases::Aliases;
+mod clipboard_tests {
+    use rshell::clipboard;
 
     #[test]
-    fn add_and_get() {
-        let mut a = Aliases::new();
-        a.add("ll".into(), "ls -la".into());
-        assert_eq!(a.get("ll").unwrap(), "ls -la");
+    fn copies_are_base64_in_osc_52() {
+        assert_eq!(clipboard::osc52("ls -la"), "\x1b]52;c;bHMgLWxh\x07");
+        assert_eq!(clipboard::osc52(""), "\x1b]52;c;\x07");
     }
+}
 
-    #[test]
-    fn get_nonexistent_returns_none() {
-        assert!(Aliases::new().get("nope").is_none());
-    }
+// =============================================================================
+// completion — tests
+// =============================================================================
+mod completion_tests {
+    use crate::test_helpers::make_test_env;
+    use rshell::completion::{self, Completions};
 
-    #[test]
-    fn remove_alias() {
-        let mut a = Aliases::new();
-        a.add("gs".into(), "git status".into());
-        a.remove("gs");
-        assert!(a.get("gs").is_none());
-    }
+    const SPEC: &str = r#"
+flags = ["--version"]
+args = "none"
+
+[subcommands.remote]
+values = ["show"]
+args = "none"
+
+[subcommands.remote.subcommands.add]
+flags = ["--fetch", "--track"]
+"#;
+
+    #[test]
+    fn specs_walk_down_subcommands() {
+        let e = make_test_env();
+        let mut completions = Completions::default();
+        completions.add("tool", SPEC).unwrap();
+
+        assert_eq!(completions.complete(&e.ctx, "tool --v"), vec!["--version"]);
+        assert_eq!(completions.complete(&e.ctx, "tool re"), vec!["remote"]);
+        assert_eq!(
+            completions.complete(&e.ctx, "tool remote "),
+            vec!["add", "show"]
+        );
+        assert_eq!(
+            completions.complete(&e.ctx, "ls | tool remote add --"),
+            vec!["--fetch", "--track"]
+        );
+        assert!(completions.add("broken", "flags = 3").is_err());
+        assert!(completions.add("unknown", "flag = []").is_err());
+
+        let (bundled, problems) = Completions::load();
+        assert!(
+            !problems
+                .iter()
+                .any(|problem| problem.starts_with("Bundled"))
+        );
+        assert!(
+            bundled
+                .get("git")
+                .is_some_and(|git| git.subcommands.contains_key("commit"))
+        );
+    }
+
+    #[test]
+    fn files_are_escaped_and_directories_get_a_slash() {
+        let e = make_test_env();
+        let directory = e._history_dir.path();
+        std::fs::write(directory.join("my file.txt"), "").unwrap();
+        std::fs::write(directory.join(".hidden"), "").unwrap();
+        std::fs::create_dir(directory.join("my dir")).unwrap();
+
+        let line = format!("cat {}/my", directory.display());
+        let candidates = Completions::default().complete(&e.ctx, &line);
+        let dir = directory.display();
+        assert_eq!(
+            candidates,
+            vec![format!("{dir}/my\\ dir/"), format!("{dir}/my\\ file.txt")]
+        );
+        assert_eq!(
+            completion::common_prefix(&candidates),
+            format!("{dir}/my\\ ")
+        );
+
+        let line = format!("cat {}/my\\ f", directory.display());
+        assert_eq!(completion::current_word(&line), format!("{dir}/my\\ f"));
+        assert_eq!(
+            Completions::default().complete(&e.ctx, &line),
+            vec![format!("{dir}/my\\ file.txt")]
+        );
+    }
+
+    #[test]
+    fn the_first_word_completes_to_commands() {
+        let e = make_test_env();
+        let candidates = Completions::default().complete(&e.ctx, "envre");
+        assert_eq!(candidates, vec!["envrestore"]);
+    }
+}
+
+// =============================================================================
+// aliases — tests
+// =============================================================================
+mod encoding_tests {
+    use rshell::encoding;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn bytes_that_arent_utf8_survive() {
+        let bytes = b"caf\xe9 \xff\xfe ok \xc3\xa9";
+        let text = encoding::decode(bytes);
+        assert!(text.ends_with(" ok é"));
+        assert_eq!(encoding::encode(&text), bytes);
+        assert_eq!(encoding::to_os_string(&text).as_bytes(), bytes);
+        assert_eq!(encoding::to_cstring(&text).unwrap().as_bytes(), bytes);
+    }
+
+    #[test]
+    fn private_use_characters_arent_taken_for_bytes() {
+        let bytes = "b\u{10FFFF} \u{10FF7F}\u{10FF80}".as_bytes();
+        assert_eq!(encoding::encode(&encoding::decode(bytes)), bytes);
+        let mixed = b"\xff\xf4\x8f\xbf\xbf\xfe";
+        assert_eq!(encoding::encode(&encoding::decode(mixed)), mixed);
+    }
+
+    #[test]
+    fn names_with_private_use_characters_can_be_opened() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("b\u{10FFFF}");
+        std::fs::write(&path, "hi\n").unwrap();
+
+        let script = format!("cat {}; ls {}", path.display(), dir.path().display());
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .args(["-c", &script])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\nb\u{10FFFF}\n");
+    }
+
+    #[test]
+    fn globbing_finds_names_that_arent_utf8() {
+        use rshell::{expander, parser::Arg};
+        use std::borrow::Cow;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.bin");
+        std::fs::write(dir.path().join(name), "").unwrap();
+
+        let mut e = crate::test_helpers::make_test_env();
+        let pattern = format!("{}/*.bin", dir.path().display());
+        let found = expander::expand_words(
+            &mut e.ctx,
+            &mut e.term,
+            vec![Arg::Word(Cow::Owned(pattern))],
+        )
+        .unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(
+            encoding::encode(&found[0]).ends_with(b"/caf\xe9.bin"),
+            "{:?}",
+            found
+        );
+    }
+}
+
+// =============================================================================
+// record — tests
+// =============================================================================
+mod record_tests {
+    use rshell::{
+        parser::{Arg, Command},
+        record::Record,
+    };
+    use std::{borrow::Cow, time::Duration};
+
+    #[test]
+    fn records_are_one_line_of_json() {
+        let e = crate::test_helpers::make_test_env();
+        let command = Command::Simple {
+            command: Cow::Borrowed("echo"),
+            args: vec![Arg::Word(Cow::Borrowed("say \"hi\"\n"))],
+            redirects: Vec::new(),
+            env_vars: Vec::new(),
+        };
+        let mut record = Record::new(&e.ctx, "echo \"$greeting\"\n", &command);
+        record.status = 1;
+        record.duration = Duration::from_millis(1500);
+        record.cwd = "/tmp".to_string();
+        assert_eq!(
+            record.to_json(),
+            r#"{"command":"echo \"$greeting\"","argv":["echo","say \"hi\"\n"],"status":1,"duration":1.500000,"cwd":"/tmp","location":""}"#
+        );
+    }
+}
+
+mod terminal_tests {
+    use anyhow::Result;
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use rshell::terminal::{self, Backend, Mark, Terminal};
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
+    // Keeps what the shell asked for, to check it goes through the backend
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Recorder {
+        fn log(&self, call: impl Into<String>) -> Result<()> {
+            self.0.borrow_mut().push(call.into());
+            Ok(())
+        }
+    }
+
+    impl Backend for Recorder {
+        fn enable_raw_mode(&mut self) -> Result<()> {
+            self.log("raw")
+        }
+        fn disable_raw_mode(&mut self) -> Result<()> {
+            self.log("cooked")
+        }
+        fn write(&mut self, output: &str) -> Result<()> {
+            self.log(output)
+        }
+        fn move_to(&mut self, column: u16, row: u16) -> Result<()> {
+            self.log(format!("to {column},{row}"))
+        }
+        fn move_by(&mut self, columns: i16, rows: i16) -> Result<()> {
+            self.log(format!("by {columns},{rows}"))
+        }
+        fn clear_screen(&mut self) -> Result<()> {
+            self.log("clear")
+        }
+        fn clear_current_line(&mut self) -> Result<()> {
+            self.log("clear line")
+        }
+        fn size(&mut self) -> Result<(u16, u16)> {
+            Ok((80, 24))
+        }
+        fn cursor_position(&mut self) -> Result<(u16, u16)> {
+            self.log("position")?;
+            Ok((0, 0))
+        }
+        fn read_key(&mut self, _: Duration) -> Result<Option<KeyEvent>> {
+            Ok(Some(KeyEvent::new(KeyCode::Enter, KeyModifiers::NONE)))
+        }
+        fn begin_batch(&mut self) {
+            let _ = self.log("begin");
+        }
+        fn end_batch(&mut self) -> Result<()> {
+            self.log("end")
+        }
+    }
+
+    #[test]
+    fn terminal_goes_through_its_backend() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut terminal = Terminal::with_backend(Box::new(Recorder(calls.clone())));
+        terminal.enter_raw_mode().unwrap();
+        terminal.enter_raw_mode().unwrap();
+        terminal.println("hi").unwrap();
+        terminal.move_left().unwrap();
+        terminal.clear_line(3).unwrap();
+        assert_eq!(terminal.size().unwrap(), (80, 24));
+        let key = terminal.read_key(Duration::ZERO).unwrap().unwrap();
+        assert_eq!(key.code, KeyCode::Enter);
+        drop(terminal);
+
+        assert_eq!(
+            *calls.borrow(),
+            vec!["raw", "hi\n", "by -1,0", "to 0,3", "clear line", "cooked"]
+        );
+    }
+
+    #[test]
+    fn marks_are_osc_133() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut terminal = Terminal::with_backend(Box::new(Recorder(calls.clone())));
+        terminal.mark(Mark::PromptStart).unwrap();
+        terminal.mark(Mark::CommandEnd(2)).unwrap();
+        assert_eq!(*calls.borrow(), vec!["\x1b]133;A\x07", "\x1b]133;D;2\x07"]);
+        assert_eq!(Mark::OutputStart.sequence(), "\x1b]133;C\x07");
+    }
+
+    #[test]
+    fn cursor_is_followed_instead_of_asked() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut terminal = Terminal::with_backend(Box::new(Recorder(calls.clone())));
+        assert_eq!(terminal.cursor_pos().unwrap(), (0, 0));
+        terminal.print("\x1b[1;31m>> \x1b[0mhi").unwrap();
+        assert_eq!(terminal.cursor_pos().unwrap(), (5, 0));
+        terminal.println("").unwrap();
+        terminal.move_right().unwrap();
+        assert_eq!(terminal.cursor_pos().unwrap(), (1, 1));
+        assert_eq!(
+            calls.borrow().iter().filter(|c| *c == "position").count(),
+            1
+        );
+
+        // Commands run out of raw mode write where they like
+        terminal.enter_raw_mode().unwrap();
+        terminal.cursor_pos().unwrap();
+        assert_eq!(
+            calls.borrow().iter().filter(|c| *c == "position").count(),
+            2
+        );
+    }
+
+    #[test]
+    fn batches_are_flushed_before_the_terminal_is_asked_anything() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut terminal = Terminal::with_backend(Box::new(Recorder(calls.clone())));
+        terminal.begin_batch();
+        terminal.print("a").unwrap();
+        terminal.read_key(Duration::ZERO).unwrap();
+        terminal.print("b").unwrap();
+        terminal.end_batch().unwrap();
+        assert_eq!(
+            *calls.borrow(),
+            vec!["begin", "a", "end", "begin", "b", "end"]
+        );
+    }
+
+    #[test]
+    fn cursor_wraps_and_scrolls_like_a_terminal() {
+        let screen = (10, 3);
+        assert_eq!(terminal::advance((0, 0), "0123456789", screen), (10, 0));
+        assert_eq!(terminal::advance((0, 0), "0123456789a", screen), (1, 1));
+        assert_eq!(terminal::advance((8, 2), "漢", screen), (10, 2));
+        assert_eq!(terminal::advance((9, 2), "漢", screen), (2, 2));
+        assert_eq!(terminal::advance((0, 2), "a\nb\tc", screen), (9, 2));
+        assert_eq!(
+            terminal::advance((0, 0), "\x1b]52;c;aGk=\x07x", screen),
+            (1, 0)
+        );
+        assert_eq!(terminal::advance((4, 1), "\r", screen), (0, 1));
+    }
+}
+
+mod aliases_tests {
+    use rshell::aliases::Aliases;
+
+    #[test]
+    fn add_and_get() {
+        let mut a = Aliases::new();
+        a.add("ll".into(), "ls -la".into());
+        assert_eq!(a.get("ll").unwrap(), "ls -la");
+    }
+
+    #[test]
+    fn get_nonexistent_returns_none() {
+        assert!(Aliases::new().get("nope").is_none());
+    }
+
+    #[test]
+    fn remove_alias() {
+        let mut a = Aliases::new();
+        a.add("gs".into(), "git status".into());
+        a.remove("gs");
+        assert!(a.get("gs").is_none());
+    }
 
     #[test]
     fn overwrite_alias() {
@@ -991,7 +2669,14 @@ mod error_tests {
     #[test]
     fn all_phases_have_non_empty_display() {
         use ShellPhase::*;
-        for phase in [Tokenizer, Parser, Expander, Executor, SignalHandler] {
+        for phase in [
+            Tokenizer,
+            Parser,
+            Expander,
+            Executor,
+            SignalHandler,
+            Arithmetic,
+        ] {
             assert!(!format!("{}", phase).is_empty());
         }
     }
@@ -1001,8 +2686,13 @@ mod error_tests {
 // prompt — tests
 // =============================================================================
 mod prompt_tests {
-    use rshell::prompt::Prompt;
-    use std::path::PathBuf;
+    use crate::test_helpers::make_test_env;
+    use rshell::{
+        jobs::{Job, JobState},
+        prompt::{self, GitStatus, Prompt},
+        segments::{PromptSegment, SegmentContext},
+    };
+    use std::{path::PathBuf, time::Duration};
 
     #[test]
     fn new_prompt_is_empty() {
@@ -1013,108 +2703,2715 @@ mod prompt_tests {
 
     #[test]
     fn update_contains_directory_and_separator() {
+        let mut env = make_test_env();
+        env.ctx.directory = PathBuf::from("/home/user");
         let mut p = Prompt::new();
-        p.update(&PathBuf::from("/home/user"));
+        p.update(&env.ctx);
         assert!(p.message.contains("/home/user"));
         assert!(p.message.ends_with(">> "));
     }
 
     #[test]
     fn len_matches_byte_length_of_message() {
+        let env = make_test_env();
         let mut p = Prompt::new();
-        p.update(&PathBuf::from("/tmp"));
+        let message = p.render("{cwd} >> ", &env.ctx);
+        p.set(message);
         assert_eq!(p.len(), p.message.len());
+
+        // The default prompt may carry colors (root, SSH), which take no room on screen
+        p.update(&env.ctx);
+        assert_eq!(p.len(), prompt::visible_width(&p.message));
     }
-}
 
-// =============================================================================
-// integration — full tokenize → parse → expand → execute round trips
-// =============================================================================
-mod integration_tests {
-    use crate::test_helpers::make_test_env;
-    use rshell::shell::Shell;
+    #[test]
+    fn template_placeholders_are_expanded() {
+        let mut env = make_test_env();
+        env.ctx.last_exit_code = 2;
+        let rendered = Prompt::new().render("[{exit_code}] {cwd} {nope} $ ", &env.ctx);
+        assert_eq!(rendered, "[2] /tmp {nope} $ ");
+    }
 
-    /// Parse and execute a command string, returning the exit code.
-    fn run(input: &str) -> i32 {
-        let mut e = make_test_env();
-        let cmd = Shell::parse_command(&mut e.ctx, &mut e.term, input, true).unwrap();
-        let (still_running, _) = Shell::execute_command(&mut e.ctx, &mut e.term, cmd).unwrap();
-        if still_running {
-            e.ctx.last_exit_code
-        } else {
-            -1
+    #[test]
+    fn status_marks_failures() {
+        let mut env = make_test_env();
+        let mut p = Prompt::new();
+        assert!(p.render("{status}", &env.ctx).contains('✔'));
+        assert_eq!(p.render("{failure}", &env.ctx), "");
+
+        env.ctx.last_exit_code = 130;
+        let status = p.render("{status}", &env.ctx);
+        assert!(status.contains("✘ 130 SIGINT"), "got {status:?}");
+        assert!(status.starts_with("\x1b[31m"));
+        assert!(p.render("{failure}", &env.ctx).ends_with("\x1b[0m "));
+
+        env.ctx.last_exit_code = 1;
+        p.update(&env.ctx);
+        assert!(p.message.contains("✘ 1"));
+    }
+
+    #[test]
+    fn duration_shows_after_slow_commands() {
+        let mut env = make_test_env();
+        let mut p = Prompt::new();
+        assert_eq!(p.render("{duration}", &env.ctx), "");
+
+        env.ctx.last_duration = Some(Duration::from_millis(300));
+        assert_eq!(p.render("{duration}", &env.ctx), "");
+
+        env.ctx.last_duration = Some(Duration::from_secs(65));
+        let duration = p.render("{duration}", &env.ctx);
+        assert!(duration.contains("took 1m05s"), "got {duration:?}");
+    }
+
+    #[test]
+    fn cwd_abbreviation_styles() {
+        let home = PathBuf::from("/home/user");
+        let path = PathBuf::from("/home/user/projects/shell/src");
+        let abbreviate = |path: &PathBuf, style| prompt::abbreviate_path(path, Some(&home), style);
+
+        assert_eq!(abbreviate(&path, ""), "~/projects/shell/src");
+        assert_eq!(abbreviate(&home, ""), "~");
+        assert_eq!(abbreviate(&path, "full"), "/home/user/projects/shell/src");
+        assert_eq!(abbreviate(&path, "short"), "~/p/s/src");
+        assert_eq!(abbreviate(&path, "2"), "…/shell/src");
+        assert_eq!(abbreviate(&path, "5"), "~/projects/shell/src");
+        assert_eq!(
+            abbreviate(&PathBuf::from("/etc/.config/app"), "short"),
+            "/e/.c/app"
+        );
+        assert_eq!(abbreviate(&PathBuf::from("/"), ""), "/");
+    }
+
+    #[test]
+    fn login_shows_over_ssh() {
+        let env = make_test_env();
+        let mut p = Prompt::new();
+        unsafe {
+            std::env::set_var("USER", "alice");
+            std::env::set_var("PROMPT_LOGIN", "never");
+        }
+        assert_eq!(p.render("{login}", &env.ctx), "");
+        assert_eq!(p.render("{user}", &env.ctx), "alice");
+
+        unsafe { std::env::set_var("PROMPT_LOGIN", "always") };
+        let login = p.render("{login}", &env.ctx);
+        assert!(login.contains("alice"), "got {login:?}");
+        assert!(login.ends_with("\x1b[0m "));
+
+        unsafe {
+            std::env::remove_var("PROMPT_LOGIN");
+            std::env::set_var("SSH_CONNECTION", "10.0.0.1 22 10.0.0.2 22");
+        }
+        assert!(prompt::is_ssh_session());
+        assert!(p.render("{login}", &env.ctx).contains("\x1b[33m"));
+        unsafe { std::env::remove_var("SSH_CONNECTION") };
+    }
+
+    struct Venv;
+
+    impl PromptSegment for Venv {
+        fn name(&self) -> &str {
+            "venv"
+        }
+
+        fn render(&mut self, context: &SegmentContext) -> Option<String> {
+            (context.jobs == 0).then(|| format!("({})", context.cwd.display()))
         }
     }
 
     #[test]
-    fn true_exits_zero() {
-        assert_eq!(run("true"), 0);
+    fn custom_segments_can_be_registered() {
+        let env = make_test_env();
+        let mut p = Prompt::new();
+        assert_eq!(p.render("{venv}", &env.ctx), "{venv}");
+
+        p.register(Box::new(Venv));
+        assert_eq!(p.render("{venv} $ ", &env.ctx), "(/tmp) $ ");
     }
 
     #[test]
-    fn false_exits_nonzero() {
-        assert_ne!(run("false"), 0);
+    fn segments_render_in_order_skipping_empty_ones() {
+        let env = make_test_env();
+        let mut p = Prompt::new();
+        p.register(Box::new(Venv));
+        let rendered = p.render_segments(&["failure", "venv", "exit_code", "nope"], &env.ctx);
+        assert_eq!(rendered, "(/tmp) 0");
     }
 
     #[test]
-    fn echo_exits_zero() {
-        assert_eq!(run("echo hello"), 0);
+    fn jobs_segment_counts_running_and_stopped_jobs() {
+        let mut env = make_test_env();
+        let mut p = Prompt::new();
+        assert_eq!(p.render("{jobs}", &env.ctx), "");
+
+        let job = |pgid, state| Job::new(pgid, vec![pgid], "sleep 100".to_string(), state, None);
+        env.ctx.jobs.add(job(90001, JobState::Running));
+        assert!(p.render("{jobs}", &env.ctx).contains("[1 job]"));
+
+        env.ctx.jobs.add(job(90002, JobState::Stopped));
+        let rendered = p.render("{jobs}", &env.ctx);
+        assert!(rendered.contains("[2 jobs, 1 stopped]"));
+        assert!(rendered.ends_with(' '));
     }
 
     #[test]
-    fn and_short_circuits_on_failure() {
-        assert_ne!(run("false && echo unreachable"), 0);
+    fn toolchain_segments_read_project_files() {
+        let mut env = make_test_env();
+        let project = tempfile::tempdir().expect("tempdir");
+        let src = project.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(
+            project.path().join("rust-toolchain.toml"),
+            "[toolchain]\nchannel = \"1.80\"\n",
+        )
+        .unwrap();
+        std::fs::write(project.path().join(".nvmrc"), "v20.11.0\n").unwrap();
+
+        env.ctx.directory = src;
+        let mut p = Prompt::new();
+        assert!(p.render("{rust}", &env.ctx).contains("rust:1.80"));
+        assert!(p.render("{node}", &env.ctx).contains("node:v20.11.0"));
+
+        env.ctx.directory = PathBuf::from("/");
+        assert_eq!(p.render("{rust}{node}", &env.ctx), "");
     }
 
     #[test]
-    fn or_short_circuits_on_success() {
-        assert_eq!(run("true || false"), 0);
+    fn python_segment_shows_active_virtualenv() {
+        let env = make_test_env();
+        let mut p = Prompt::new();
+        unsafe { std::env::set_var("VIRTUAL_ENV", "/home/user/project/.venv") };
+        assert!(p.render("{python}", &env.ctx).contains("(.venv)"));
+
+        unsafe { std::env::remove_var("VIRTUAL_ENV") };
+        assert_eq!(p.render("{python}", &env.ctx), "");
     }
 
     #[test]
-    fn sequence_runs_both_sides() {
-        assert_eq!(run("true; true"), 0);
+    fn transient_prompt_is_rendered_when_enabled() {
+        let env = make_test_env();
+        let mut p = Prompt::new();
+        unsafe { std::env::set_var("TRANSIENT_PROMPT", "") };
+        p.update(&env.ctx);
+        assert_eq!(p.transient.as_deref(), Some(prompt::DEFAULT_TRANSIENT));
+
+        unsafe { std::env::set_var("TRANSIENT_PROMPT", "[{exit_code}] ") };
+        p.update(&env.ctx);
+        assert_eq!(p.transient.as_deref(), Some("[0] "));
+
+        unsafe { std::env::remove_var("TRANSIENT_PROMPT") };
+        p.update(&env.ctx);
+        assert_eq!(p.transient, None);
     }
 
     #[test]
-    fn pipeline_exit_code_is_last_stage() {
-        assert_eq!(run("echo hello | cat"), 0);
+    fn color_tags_do_not_count_towards_width() {
+        let env = make_test_env();
+        let mut p = Prompt::new();
+        let message = p.render("{red}{cwd}{reset} $ ", &env.ctx);
+        p.set(message);
+        assert!(p.message.starts_with("\x1b[31m"));
+        assert_eq!(p.len(), "/tmp $ ".len());
     }
 
     #[test]
-    fn redirect_out_creates_file_with_content() {
-        let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("out.txt");
-        run(&format!("echo hello > {}", path.display()));
-        assert!(path.exists());
-        assert!(std::fs::read_to_string(&path).unwrap().contains("hello"));
+    fn right_prompt_is_flush_right_until_the_line_reaches_it() {
+        let mut p = Prompt::new();
+        p.set("$ ".into());
+        assert_eq!(p.right_column(0, 80), None);
+
+        p.set_right("\x1b[2m12:00\x1b[0m".into());
+        assert_eq!(p.right_column(0, 80), Some(75));
+        assert_eq!(p.right_column(72, 80), Some(75));
+        assert_eq!(p.right_column(73, 80), None);
+        assert_eq!(p.right_column(0, 4), None);
     }
 
     #[test]
-    fn redirect_append_does_not_truncate() {
+    fn git_status_parses_porcelain_output() {
+        let status = GitStatus::parse(
+            "# branch.oid abc\n# branch.head main\n# branch.upstream origin/main\n# branch.ab +2 -1\n1 .M N... 100644 100644 100644 a b src/lib.rs\n",
+        );
+        assert_eq!(status.branch, "main");
+        assert!(status.dirty);
+        assert_eq!((status.ahead, status.behind), (2, 1));
+        assert_eq!(status.to_prompt(), "main*↑2↓1");
+
+        let clean = GitStatus::parse("# branch.head dev\n");
+        assert_eq!(clean.to_prompt(), "dev");
+    }
+
+    #[test]
+    fn git_branch_is_read_from_head() {
         let dir = tempfile::tempdir().unwrap();
-        let path = dir.path().join("log.txt");
-        run(&format!("echo line1 > {}", path.display()));
-        run(&format!("echo line2 >> {}", path.display()));
-        let content = std::fs::read_to_string(&path).unwrap();
-        assert!(content.contains("line1") && content.contains("line2"));
+        std::fs::create_dir_all(dir.path().join(".git")).unwrap();
+        std::fs::create_dir_all(dir.path().join("src/deep")).unwrap();
+        std::fs::write(dir.path().join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+        assert_eq!(
+            prompt::git_branch(&dir.path().join("src/deep")).as_deref(),
+            Some("main")
+        );
+
+        std::fs::write(dir.path().join(".git/HEAD"), "0123456789abcdef\n").unwrap();
+        assert_eq!(prompt::git_branch(dir.path()).as_deref(), Some("0123456"));
     }
 
     #[test]
-    fn subcommand_expansion_in_argument() {
-        let mut e = make_test_env();
-        let cmd =
-            Shell::parse_command(&mut e.ctx, &mut e.term, "echo $(echo inner)", true).unwrap();
-        assert!(cmd.to_string().contains("inner"));
+    fn signals_are_found_by_name_or_number() {
+        assert_eq!(prompt::signal_number("TERM"), Some(libc::SIGTERM));
+        assert_eq!(prompt::signal_number("sigkill"), Some(libc::SIGKILL));
+        assert_eq!(prompt::signal_number("2"), Some(libc::SIGINT));
+        assert_eq!(prompt::signal_number("FOO"), None);
+        assert_eq!(prompt::signal_number("0"), None);
     }
+}
+
+// =============================================================================
+// theme — tests
+// =============================================================================
+mod theme_tests {
+    use rshell::theme::{self, BUILTIN_THEMES, Theme};
 
     #[test]
-    fn builtin_cd_changes_working_directory() {
-        let dir = tempfile::tempdir().unwrap();
-        run(&format!("cd {}", dir.path().display()));
+    fn color_specs_become_sgr_sequences() {
+        assert_eq!(theme::escape("red").as_deref(), Some("\x1b[31m"));
         assert_eq!(
-            std::env::current_dir().unwrap(),
-            dir.path().canonicalize().unwrap()
+            theme::escape("bold+bright_blue").as_deref(),
+            Some("\x1b[1;94m")
         );
+        assert_eq!(theme::escape("208").as_deref(), Some("\x1b[38;5;208m"));
+        assert_eq!(
+            theme::escape("#ff8800").as_deref(),
+            Some("\x1b[38;2;255;136;0m")
+        );
+        assert_eq!(theme::escape("#ff88"), None);
+        assert_eq!(theme::escape("purple"), None);
+    }
+
+    #[test]
+    fn roles_come_from_the_palette() {
+        let mut theme = Theme::default();
+        assert_eq!(theme.tag("error").as_deref(), Some("\x1b[31m"));
+        assert_eq!(theme.tag("green").as_deref(), Some("\x1b[32m"));
+        assert_eq!(theme.tag("nope"), None);
+
+        theme.extend("error=#ff0000, bogus=nothing");
+        assert_eq!(theme.tag("error").as_deref(), Some("\x1b[38;2;255;0;0m"));
+        assert_eq!(theme.tag("bogus"), None);
+    }
+
+    #[test]
+    fn disabled_colors_render_nothing() {
+        let mut theme = Theme::default();
+        theme.colors = false;
+        assert_eq!(theme.tag("primary").as_deref(), Some(""));
+        assert_eq!(theme.tag("nope"), None);
+    }
+
+    #[test]
+    fn builtin_themes_define_every_role() {
+        for name in BUILTIN_THEMES {
+            let theme = Theme::builtin(name).unwrap();
+            for role in [
+                "primary",
+                "secondary",
+                "accent",
+                "success",
+                "error",
+                "warning",
+                "muted",
+            ] {
+                assert!(theme.tag(role).is_some(), "{name} lacks {role}");
+            }
+        }
+        assert!(Theme::builtin("neon").is_none());
+    }
+
+    #[test]
+    fn theme_files_are_loaded_by_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let themes = dir.path().join("rshell").join("themes");
+        std::fs::create_dir_all(&themes).unwrap();
+        unsafe { std::env::set_var("XDG_CONFIG_HOME", dir.path()) };
+
+        std::fs::write(
+            themes.join("neon.toml"),
+            "primary = \"#ff00ff\"\n[syntax]\ncommand = \"bold+green\"\n",
+        )
+        .unwrap();
+        std::fs::write(themes.join("broken.toml"), "primary = \"purple\"\n").unwrap();
+
+        let theme = Theme::load("neon").unwrap().unwrap();
+        assert_eq!(theme.name, "neon");
+        assert_eq!(
+            theme.tag("primary").as_deref(),
+            Some("\x1b[38;2;255;0;255m")
+        );
+        assert_eq!(theme.tag("syntax.command").as_deref(), Some("\x1b[1;32m"));
+        // Roles the file leaves out come from the default theme
+        assert_eq!(theme.tag("error").as_deref(), Some("\x1b[31m"));
+
+        assert!(Theme::load("broken").is_err());
+        assert!(Theme::load("ocean").unwrap().is_some());
+        assert!(Theme::load("missing").unwrap().is_none());
+
+        let available = Theme::available();
+        assert_eq!(&available[..4], &BUILTIN_THEMES);
+        assert_eq!(&available[4..], &["broken", "neon"]);
+
+        unsafe { std::env::remove_var("XDG_CONFIG_HOME") };
+    }
+}
+
+mod settings_tests {
+    use crate::test_helpers::make_test_env;
+    use crossterm::event::{KeyCode, KeyModifiers};
+    use rshell::{
+        history::History,
+        keymap::{self, Action, Keymap},
+        notify,
+        prompt::{self, Prompt},
+        settings::{self, Settings},
+        theme::Theme,
+    };
+    use std::{path::PathBuf, time::Duration};
+
+    #[test]
+    fn missing_values_keep_the_defaults() {
+        let settings = Settings::parse("[history]\nrecent_entries = 50\n").unwrap();
+        assert_eq!(settings.history.recent_entries, 50);
+        assert!(settings.history.ignore_space);
+        assert_eq!(settings.prompt.template, prompt::DEFAULT_TEMPLATE);
+        assert!(settings.colors.enabled);
+        assert!(settings.keybindings.is_empty());
+    }
+
+    #[test]
+    fn full_config_is_parsed() {
+        let settings = Settings::parse(
+            r##"
+            [history]
+            ignore_space = false
+
+            [prompt]
+            template = "{cwd} $ "
+            right = "{git}"
+            cwd = "short"
+            duration_threshold = "2s"
+
+            [colors]
+            theme = "ocean"
+            palette = { primary = "#00ff00" }
+
+            [keybindings]
+            "ctrl-p" = "previous-history"
+            "ctrl-r" = "none"
+            "##,
+        )
+        .unwrap();
+
+        assert!(!settings.history.ignore_space);
+        assert_eq!(settings.prompt.template, "{cwd} $ ");
+        assert_eq!(settings.prompt.cwd, "short");
+        assert_eq!(settings.colors.theme, "ocean");
+        assert_eq!(settings.colors.palette["primary"], "#00ff00");
+
+        let keymap = settings.keymap().unwrap();
+        assert_eq!(
+            keymap.get(KeyCode::Char('p'), KeyModifiers::CONTROL),
+            Some(Action::PreviousHistory)
+        );
+        assert_eq!(keymap.get(KeyCode::Char('r'), KeyModifiers::CONTROL), None);
+    }
+
+    #[test]
+    fn changes_are_listed_by_name() {
+        let old = Settings::default();
+        let new = Settings::parse(
+            "[prompt]\ncwd = \"short\"\n[keybindings]\n\"ctrl-p\" = \"previous-history\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            old.changes(&new),
+            [
+                "keybindings.ctrl-p = \"previous-history\" (added)",
+                "prompt.cwd = \"short\" (was \"\")",
+            ]
+        );
+        assert_eq!(
+            new.changes(&old),
+            [
+                "prompt.cwd = \"\" (was \"short\")",
+                "keybindings.ctrl-p removed (was \"previous-history\")",
+            ]
+        );
+        assert!(old.changes(&Settings::default()).is_empty());
+    }
+
+    #[test]
+    fn options_are_read_from_the_config() {
+        let settings = Settings::parse("[options]\nhistverify = true\n").unwrap();
+        assert_eq!(settings.options.get("histverify"), Some(true));
+        assert_eq!(settings.options.get("histshare"), Some(false));
+        assert_eq!(settings.options.get("nope"), None);
+    }
+
+    #[test]
+    fn bad_config_is_an_error() {
+        assert!(Settings::parse("[history\n").is_err());
+        assert!(Settings::parse("[keybindings]\n\"ctrl-x\" = \"explode\"\n").is_err());
+        assert!(Settings::parse("[keybindings]\n\"hyper-x\" = \"accept-line\"\n").is_err());
+    }
+
+    #[test]
+    fn bad_settings_fall_back_alone() {
+        let (settings, problems) = Settings::check(
+            r##"
+[prompt]
+tempalte = "$ "
+cwd = "short"
+duration_threshold = "soon"
+
+[history]
+recent_entries = "many"
+
+[colors]
+theme = "ocaen"
+palette = { primary = "purple", error = "#ff0000" }
+
+[keybindings]
+"ctrl-p" = "previous-histroy"
+"ctrl-n" = "next-history"
+"##,
+        )
+        .unwrap();
+
+        assert_eq!(settings.prompt.cwd, "short");
+        assert_eq!(settings.prompt.template, prompt::DEFAULT_TEMPLATE);
+        assert_eq!(settings.prompt.duration_threshold, "5s");
+        assert_eq!(settings.history.recent_entries, 1000);
+        assert_eq!(settings.colors.theme, "default");
+        assert_eq!(settings.colors.palette.len(), 1);
+        assert_eq!(settings.keybindings.len(), 1);
+
+        let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(problems.len(), 6, "{problems:?}");
+        assert_eq!(
+            problems[0],
+            "3: Unknown setting prompt.tempalte, did you mean prompt.template?"
+        );
+        assert!(problems[1].starts_with("5: Invalid duration 'soon'"));
+        assert!(problems[2].starts_with("8: Invalid value for history.recent_entries"));
+        assert_eq!(
+            problems[3],
+            "11: Unknown theme 'ocaen', did you mean ocean?"
+        );
+        assert!(problems[4].starts_with("12: Unknown color 'purple'"));
+        assert!(problems[5].starts_with("15: Unknown action 'previous-histroy'"));
+        assert!(problems[5].ends_with("did you mean previous-history?"));
+    }
+
+    #[test]
+    fn notifications_are_off_until_given_a_duration() {
+        let mut env = make_test_env();
+        assert_eq!(notify::threshold(&env.ctx), None);
+
+        env.ctx.settings = Settings::parse("[notifications]\nafter = \"1m\"\n").unwrap();
+        assert_eq!(notify::threshold(&env.ctx), Some(Duration::from_secs(60)));
+        assert_eq!(notify::report_threshold(&env.ctx), None);
+
+        env.ctx.settings = Settings::parse("[notifications]\nreport_time = \"10\"\n").unwrap();
+        assert_eq!(
+            notify::report_threshold(&env.ctx),
+            Some(Duration::from_secs(10))
+        );
+
+        let (_, problems) = Settings::check("[notifications]\nafter = \"later\"\n").unwrap();
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0]
+                .to_string()
+                .starts_with("2: Invalid duration 'later'")
+        );
+    }
+
+    #[test]
+    fn syntax_errors_have_a_line() {
+        let error = Settings::check("[prompt]\ncwd = \"short\"\n[history\n").unwrap_err();
+        assert!(error.to_string().starts_with("3: "), "{error}");
+    }
+
+    #[test]
+    fn suggestions_need_to_be_close() {
+        let names = ["histverify", "histshare"];
+        assert_eq!(
+            settings::suggest("histverfy", names.into_iter()),
+            Some("histverify")
+        );
+        assert_eq!(settings::suggest("colors", names.into_iter()), None);
+    }
+
+    #[test]
+    fn keys_are_parsed_with_modifiers() {
+        let ctrl_alt = KeyModifiers::CONTROL | KeyModifiers::ALT;
+        assert_eq!(
+            keymap::parse_key("ctrl-alt-x"),
+            Some((KeyCode::Char('x'), ctrl_alt))
+        );
+        assert_eq!(
+            keymap::parse_key("Up"),
+            Some((KeyCode::Up, KeyModifiers::NONE))
+        );
+        assert_eq!(keymap::parse_key("super-x"), None);
+
+        // Shift comes along with uppercase letters and Ctrl combinations have no case
+        let keymap = Keymap::default();
+        assert_eq!(
+            keymap.get(
+                KeyCode::Char('R'),
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            ),
+            Some(Action::FuzzySearch)
+        );
+        assert_eq!(keymap.get(KeyCode::Char('R'), KeyModifiers::SHIFT), None);
+    }
+
+    #[test]
+    fn prompt_and_theme_use_the_settings() {
+        let mut env = make_test_env();
+        unsafe {
+            std::env::remove_var("PROMPT");
+            std::env::remove_var("PROMPT_SEGMENTS");
+            std::env::remove_var("PROMPT_THEME");
+            std::env::remove_var("PROMPT_PALETTE");
+        }
+        env.ctx.settings.prompt.template = "[{exit_code}] $ ".to_string();
+        let mut p = Prompt::new();
+        p.update(&env.ctx);
+        assert_eq!(p.message, "[0] $ ");
+
+        // The environment still wins
+        unsafe { std::env::set_var("PROMPT", "{exit_code}> ") };
+        p.update(&env.ctx);
+        assert_eq!(p.message, "0> ");
+        unsafe { std::env::remove_var("PROMPT") };
+
+        env.ctx.settings.colors.theme = "mono".to_string();
+        env.ctx
+            .settings
+            .colors
+            .palette
+            .insert("error".to_string(), "green".to_string());
+        let theme = Theme::from_settings(&env.ctx.settings.colors);
+        assert_eq!(theme.name, "mono");
+        assert_eq!(theme.tag("primary").as_deref(), Some("\x1b[1m"));
+        assert_eq!(theme.tag("error").as_deref(), Some("\x1b[32m"));
+    }
+
+    #[test]
+    fn history_follows_the_settings() {
+        let _env = make_test_env();
+        let mut history_settings = Settings::default().history;
+        history_settings.ignore_space = false;
+        let mut h = History::open(&history_settings).unwrap();
+        h.add(" secret").unwrap();
+        assert_eq!(h.last_command(), Some(" secret"));
+
+        for i in 0..5 {
+            h.push(format!("cmd {i}")).unwrap();
+        }
+        history_settings.recent_entries = 2;
+        let mut h = History::open(&history_settings).unwrap();
+        assert_eq!(h.current.len(), 2);
+        h.load_all().unwrap();
+        assert_eq!(h.current.len(), 6);
+    }
+
+    #[test]
+    fn config_lives_in_xdg_config_home() {
+        unsafe { std::env::set_var("XDG_CONFIG_HOME", "/tmp/config") };
+        assert_eq!(
+            settings::config_path(),
+            PathBuf::from("/tmp/config/rshell/config.toml")
+        );
+
+        unsafe {
+            std::env::remove_var("XDG_CONFIG_HOME");
+            std::env::set_var("HOME", "/home/user");
+        }
+        assert_eq!(
+            settings::config_path(),
+            PathBuf::from("/home/user/.config/rshell/config.toml")
+        );
+    }
+}
+
+mod startup_tests {
+    use crate::test_helpers::make_test_env;
+    use rshell::{
+        builtins::BuiltIns,
+        context::{Context, Input, Mode, Source},
+        shell::Shell,
+    };
+    use std::path::{Path, PathBuf};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn login_shells_are_detected() {
+        assert!(Mode::from_args(&args(&["-rshell"]), true).login);
+        assert!(Mode::from_args(&args(&["rshell", "-l"]), true).login);
+        assert!(Mode::from_args(&args(&["rshell", "--login"]), true).login);
+        assert!(!Mode::from_args(&args(&["rshell"]), true).login);
+        // Options after a script name belong to the script
+        assert!(!Mode::from_args(&args(&["rshell", "script.sh", "-l"]), true).login);
+    }
+
+    #[test]
+    fn interactive_follows_the_terminal_or_flag() {
+        assert!(Mode::from_args(&args(&["rshell"]), true).interactive);
+        assert!(!Mode::from_args(&args(&["rshell"]), false).interactive);
+        assert!(!Mode::from_args(&args(&["rshell", "script.sh"]), true).interactive);
+        assert!(!Mode::from_args(&args(&["rshell", "--help"]), true).login);
+
+        let mode = Mode::from_args(&args(&["rshell", "-il"]), false);
+        assert!(mode.interactive && mode.login);
+    }
+
+    #[test]
+    fn syntax_checks_are_asked_for() {
+        let check = Mode::from_args(&args(&["rshell", "-n", "script.sh"]), true);
+        assert!(check.no_exec && !check.print_ast);
+        let parse_only = Mode::from_args(&args(&["rshell", "--parse-only", "-c", "ls"]), false);
+        assert!(parse_only.no_exec && parse_only.print_ast);
+        assert!(Mode::from_args(&args(&["rshell", "--debug", "script.sh"]), true).debug);
+        // Ignored by an interactive shell
+        assert!(!Mode::from_args(&args(&["rshell", "-n"]), true).no_exec);
+    }
+
+    #[test]
+    fn json_records_go_to_the_fd_asked_for() {
+        assert_eq!(
+            Mode::from_args(&args(&["rshell", "--json", "s.sh"]), true).json,
+            Some(2)
+        );
+        assert_eq!(
+            Mode::from_args(&args(&["rshell", "--json=3", "s.sh"]), true).json,
+            Some(3)
+        );
+        assert_eq!(Mode::from_args(&args(&["rshell", "s.sh"]), true).json, None);
+        assert_eq!(
+            Mode::from_args(&args(&["rshell", "--json"]), true).json,
+            None
+        );
+    }
+
+    #[test]
+    fn log_level_writes_a_log_file() {
+        assert_eq!(
+            Mode::from_args(&args(&["rshell", "--log-level=debug"]), true).log_level,
+            Some(tracing::Level::DEBUG)
+        );
+        assert_eq!(Mode::from_args(&args(&["rshell"]), true).log_level, None);
+
+        let directory = tempfile::TempDir::new().unwrap();
+        let log = directory.path().join("rshell.log");
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .args(["--log-level=trace", "-c", "echo ( x"])
+            .env("HOME", directory.path())
+            .env("XDG_DATA_HOME", directory.path())
+            .env("RSHELL_LOG_FILE", &log)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+
+        let content = std::fs::read_to_string(&log).unwrap();
+        assert!(content.contains("Started"), "{content}");
+        assert!(content.contains("Failed to parse"), "{content}");
+    }
+
+    #[test]
+    fn syntax_checks_run_nothing() {
+        let mut e = make_test_env();
+        let output = e._history_dir.path().join("output");
+        e.ctx.mode.no_exec = true;
+        let script = format!(
+            "touch {}\nfor x in a; do\n  echo ( x\ndone\n",
+            output.display()
+        );
+
+        assert!(Context::source(&mut e.ctx, Path::new("check.sh"), &script).unwrap());
+        assert_eq!(e.ctx.last_exit_code, 2);
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn parse_only_stops_quietly_when_nobody_reads() {
+        use std::os::fd::{FromRawFd, OwnedFd};
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { libc::close(fds[0]) };
+        let closed = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .args(["--parse-only", "-c", "echo a\necho b"])
+            .stdout(closed)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!stderr.contains("panicked"), "{stderr}");
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    #[test]
+    fn startup_files_come_in_order() {
+        let home = Path::new("/home/user");
+        let login = Mode {
+            login: true,
+            interactive: true,
+            ..Mode::default()
+        };
+        assert_eq!(
+            login.startup_files(home),
+            vec![
+                PathBuf::from("/etc/profile"),
+                PathBuf::from("/home/user/.profile"),
+                PathBuf::from("/home/user/.rshellrc"),
+            ]
+        );
+
+        let interactive = Mode {
+            login: false,
+            interactive: true,
+            ..Mode::default()
+        };
+        assert_eq!(
+            interactive.startup_files(home),
+            vec![PathBuf::from("/home/user/.rshellrc")]
+        );
+        assert!(Mode::default().startup_files(home).is_empty());
+
+        // POSIX mode reads the file named by ENV instead of the rc file
+        let posix = Mode::from_args(&args(&["rshell", "--posix"]), true);
+        assert!(posix.posix && posix.interactive);
+        unsafe { std::env::set_var("ENV", "/home/user/.shinit") };
+        assert_eq!(
+            posix.startup_files(home),
+            vec![PathBuf::from("/home/user/.shinit")]
+        );
+        unsafe { std::env::remove_var("ENV") };
+
+        let dry_run = Mode::from_args(&args(&["rshell", "--dry-run", "-c", "rm *"]), false);
+        assert!(dry_run.dry_run && !dry_run.interactive);
+        assert!(Mode::from_args(&args(&["rshell", "--private"]), true).private);
+    }
+
+    #[test]
+    fn commands_without_a_prompt_leave_the_history_alone() {
+        let home = tempfile::tempdir().unwrap();
+        let histfile = home.path().join("history");
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .args(["-c", "echo hi"])
+            .env("HOME", home.path())
+            .env("HISTFILE", &histfile)
+            .env("HISTENCRYPT", "passphrase")
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hi\n");
+        assert!(!histfile.exists());
+    }
+
+    #[test]
+    fn scripts_take_the_arguments_after_them() {
+        assert_eq!(
+            Input::from_args(&args(&["rshell"])).unwrap(),
+            (Input::Stdin, vec![])
+        );
+        assert_eq!(
+            Input::from_args(&args(&["rshell", "-l", "run.sh", "-x", "a"])).unwrap(),
+            (Input::Script(PathBuf::from("run.sh")), args(&["-x", "a"]))
+        );
+        assert_eq!(
+            Input::from_args(&args(&["rshell", "--", "-odd.sh"]))
+                .unwrap()
+                .0,
+            Input::Script(PathBuf::from("-odd.sh"))
+        );
+    }
+
+    #[test]
+    fn command_strings_come_after_c() {
+        assert_eq!(
+            Input::from_args(&args(&["rshell", "-c", "echo $1", "name", "one"])).unwrap(),
+            (
+                Input::Command {
+                    command: "echo $1".into(),
+                    name: Some("name".into())
+                },
+                args(&["one"])
+            )
+        );
+        assert_eq!(
+            Input::from_args(&args(&["rshell", "-lc", "ls"])).unwrap(),
+            (
+                Input::Command {
+                    command: "ls".into(),
+                    name: None
+                },
+                vec![]
+            )
+        );
+        assert!(Input::from_args(&args(&["rshell", "-c"])).is_err());
+        assert!(!Mode::from_args(&args(&["rshell", "-c", "ls"]), true).interactive);
+    }
+
+    #[test]
+    fn scripts_know_their_path_and_source_their_neighbours() {
+        let e = make_test_env();
+        let lib = e._history_dir.path().join("lib");
+        let output = e._history_dir.path().join("output");
+        std::fs::create_dir(&lib).unwrap();
+        std::fs::write(
+            lib.join("helper.sh"),
+            format!("echo $0 $1 $# >> {}\n", output.display()),
+        )
+        .unwrap();
+        std::fs::write(
+            lib.join("main.sh"),
+            format!(
+                "#!/usr/bin/env rshell\nsource helper.sh one\n. ./helper.sh\necho $1 >> {}\n",
+                output.display()
+            ),
+        )
+        .unwrap();
+
+        let mut shell = Shell {
+            terminal: e.term,
+            context: e.ctx,
+        };
+        shell.context.positional = vec!["main".into()];
+        let script = lib.join("main.sh");
+        assert_eq!(shell.run_script(&script).unwrap(), 0);
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            format!("{0} one 1\n{0} main 1\nmain\n", script.display())
+        );
+        assert!(shell.context.sources.is_empty());
+        assert!(shell.run_script(&lib.join("missing.sh")).is_err());
+    }
+
+    #[test]
+    fn sourced_files_return_and_report_where_they_were_sourced() {
+        let e = make_test_env();
+        let lib = e._history_dir.path().join("lib");
+        let output = e._history_dir.path().join("output");
+        std::fs::create_dir(&lib).unwrap();
+        std::fs::write(
+            lib.join("inner.sh"),
+            format!(
+                "echo in >> {0}\nreturn 3\necho after >> {0}\n",
+                output.display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            lib.join("main.sh"),
+            format!("source inner.sh\necho $? >> {}\n", output.display()),
+        )
+        .unwrap();
+
+        let mut shell = Shell {
+            terminal: e.term,
+            context: e.ctx,
+        };
+        assert_eq!(shell.run_script(&lib.join("main.sh")).unwrap(), 0);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "in\n3\n");
+
+        let frame = |name: &str, line| Source {
+            name: PathBuf::from(name),
+            file: None,
+            line,
+        };
+        shell.context.sources = vec![frame("main.sh", 12), frame("lib.sh", 3)];
+        assert_eq!(
+            shell.context.location(),
+            "lib.sh:3 (sourced from main.sh:12)"
+        );
+        shell.context.sources.insert(0, frame("x", 1));
+        assert_eq!(
+            shell.context.location(),
+            "lib.sh:3 (sourced from main.sh:12, from x:1)"
+        );
+    }
+
+    #[test]
+    fn local_variables_last_as_long_as_the_sourced_file() {
+        let mut e = make_test_env();
+        let lib = e._history_dir.path().join("scoped.sh");
+        std::fs::write(
+            &lib,
+            "local RSHELL_TEST_SCOPED=inner\nRSHELL_TEST_LEAKED=$RSHELL_TEST_SCOPED\nreturn 1\n",
+        )
+        .unwrap();
+
+        e.ctx.variables.set("RSHELL_TEST_SCOPED", "outer");
+        let path = lib.to_string_lossy().to_string();
+        BuiltIns::source(&[&path], &mut e.ctx, &mut e.term).unwrap();
+        assert_eq!(e.ctx.last_exit_code, 1);
+        assert_eq!(
+            e.ctx.variables.get("RSHELL_TEST_SCOPED").as_deref(),
+            Some("outer")
+        );
+        assert_eq!(
+            e.ctx.variables.get("RSHELL_TEST_LEAKED").as_deref(),
+            Some("inner")
+        );
+        assert!(BuiltIns::local(&["RSHELL_TEST_SCOPED"], &mut e.ctx, &mut e.term).is_err());
+    }
+
+    #[test]
+    fn scripts_run_to_the_last_status() {
+        let mut e = make_test_env();
+        let script = e._history_dir.path().join("script.sh");
+        let output = e._history_dir.path().join("output");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/usr/bin/env rshell\n# comment\necho $1 $# > {}\nfalse\n",
+                output.display()
+            ),
+        )
+        .unwrap();
+        e.ctx.positional = vec!["hello".into()];
+
+        let content = std::fs::read_to_string(&script).unwrap();
+        assert!(Context::source(&mut e.ctx, &script, &content).unwrap());
+        assert_eq!(e.ctx.last_exit_code, 1);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "hello 1\n");
+    }
+
+    #[test]
+    fn failing_rc_lines_are_skipped() {
+        let mut e = make_test_env();
+        e.ctx.mode.interactive = true;
+        std::fs::write(
+            e._history_dir.path().join(".rshellrc"),
+            "alias broken\nalias ll='ls -la'\n",
+        )
+        .unwrap();
+
+        Context::source_startup_files(&mut e.ctx).unwrap();
+        assert_eq!(e.ctx.aliases.get("ll").unwrap(), "ls -la");
+    }
+
+    #[test]
+    fn piped_lines_run_one_at_a_time() {
+        let mut e = make_test_env();
+        let lines = ["alias ll='ls -la'", "", "exit 3", "alias la='ls -a'"];
+
+        assert!(!Context::run_lines(&mut e.ctx, Path::new("stdin"), lines).unwrap());
+        assert_eq!(e.ctx.aliases.get("ll").unwrap(), "ls -la");
+        assert!(e.ctx.aliases.get("la").is_none());
+    }
+}
+
+// =============================================================================
+// arithmetic — tests
+// =============================================================================
+mod arithmetic_tests {
+    use rshell::arithmetic::eval;
+    use std::env;
+
+    #[test]
+    fn precedence_follows_c() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(eval("2 ** 3 ** 2").unwrap(), 512);
+        assert_eq!(eval("-7 / 2 + 7 % 3").unwrap(), -2);
+        assert_eq!(eval("1 < 2 && 3 >= 3 || 0").unwrap(), 1);
+        assert_eq!(eval("0x10 | 010 ^ 1 << 2").unwrap(), 16 | (8 ^ 4));
+        assert_eq!(eval("!0 ? 5 : 6").unwrap(), 5);
+        assert_eq!(eval("").unwrap(), 0);
+    }
+
+    #[test]
+    fn variables_are_read_and_assigned() {
+        unsafe {
+            env::set_var("ARITH_I", "4");
+            env::remove_var("ARITH_UNSET");
+        }
+
+        assert_eq!(eval("$ARITH_I * 2 + ARITH_UNSET").unwrap(), 8);
+        assert_eq!(eval("ARITH_I++").unwrap(), 4);
+        assert_eq!(eval("++ARITH_I").unwrap(), 6);
+        assert_eq!(eval("ARITH_I += 4, ARITH_I *= 2").unwrap(), 20);
+        assert_eq!(env::var("ARITH_I").unwrap(), "20");
+    }
+
+    #[test]
+    fn skipped_branches_have_no_effect() {
+        unsafe {
+            env::set_var("ARITH_J", "1");
+        }
+
+        assert_eq!(eval("0 && (ARITH_J = 5)").unwrap(), 0);
+        assert_eq!(eval("1 || 1 / 0").unwrap(), 1);
+        assert_eq!(eval("ARITH_J ? 2 : ARITH_J++").unwrap(), 2);
+        assert_eq!(env::var("ARITH_J").unwrap(), "1");
+    }
+
+    #[test]
+    fn mistakes_are_errors() {
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("1 +").is_err());
+        assert!(eval("(1 + 2").is_err());
+        assert!(eval("2 3").is_err());
+        assert!(eval("5++").is_err());
+        assert!(eval("1 @ 2").is_err());
+    }
+}
+
+// =============================================================================
+// control flow — tests
+// =============================================================================
+mod control_flow_tests {
+    use crate::test_helpers::make_test_env;
+    use rshell::{context::Context, parser::Command, shell::Shell};
+    use std::{env, fs, path::Path};
+
+    // Runs a script and gives back what it wrote to `$OUT`
+    fn run(script: &str) -> String {
+        let mut e = make_test_env();
+        let output = e._history_dir.path().join("output");
+        unsafe {
+            env::set_var("OUT", &output);
+        }
+        Context::source(&mut e.ctx, Path::new("test"), script).unwrap();
+        fs::read_to_string(output).unwrap_or_default()
+    }
+
+    #[test]
+    fn for_goes_through_words_globs_and_variables() {
+        let mut e = make_test_env();
+        let dir = e._history_dir.path();
+        fs::write(dir.join("a.txt"), "").unwrap();
+        fs::write(dir.join("b.txt"), "").unwrap();
+        unsafe {
+            env::set_var("LIST", "x  y");
+        }
+
+        let script = format!(
+            "for item in one \"two three\" $LIST {}/*.txt; do echo $item >> {}/output; done",
+            dir.display(),
+            dir.display()
+        );
+        Context::source(&mut e.ctx, Path::new("test"), &script).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("output")).unwrap(),
+            format!(
+                "one\ntwo three\nx\ny\n{0}/a.txt\n{0}/b.txt\n",
+                dir.display()
+            )
+        );
+        assert_eq!(
+            e.ctx.variables.get("item").unwrap(),
+            format!("{}/b.txt", dir.display())
+        );
+    }
+
+    #[test]
+    fn loop_bodies_see_what_the_commands_before_did() {
+        let output = run("for i in 1 2 3; do x=$i; echo \"x=$x\" >> $OUT; done");
+        assert_eq!(output, "x=1\nx=2\nx=3\n");
+    }
+
+    #[test]
+    fn aliases_of_composed_commands_can_use_their_own_name() {
+        let output = run("alias echo='echo first >> $OUT && echo'\n\
+                          echo second >> $OUT");
+        assert_eq!(output, "first\nsecond\n");
+    }
+
+    #[test]
+    fn for_without_in_uses_positional_parameters() {
+        let mut e = make_test_env();
+        let output = e._history_dir.path().join("output");
+        e.ctx.positional = vec!["a".into(), "b".into()];
+
+        let script = format!("for arg; do echo $arg >> {}; done", output.display());
+        Context::source(&mut e.ctx, Path::new("test"), &script).unwrap();
+        assert_eq!(fs::read_to_string(output).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn arithmetic_for_counts() {
+        let output = run("for ((i = 0; i < 3; i++)); do echo $i >> $OUT; done\necho $i >> $OUT");
+        assert_eq!(output, "0\n1\n2\n3\n");
+        // A shell variable, not exported
+        assert!(env::var("i").is_err());
+    }
+
+    #[test]
+    fn shell_variables_stay_in_the_shell() {
+        let output = run("tally=2\n\
+                          ((tally += 3))\n\
+                          echo $tally >> $OUT\n\
+                          sh -c 'echo [$tally]' >> $OUT\n\
+                          export tally\n\
+                          sh -c 'echo [$tally]' >> $OUT");
+        assert_eq!(output, "5\n[]\n[5]\n");
+    }
+
+    #[test]
+    fn dynamic_variables() {
+        let output = run("RANDOM=7\n\
+                          echo $RANDOM >> $OUT\n\
+                          RANDOM=7\n\
+                          echo $RANDOM >> $OUT\n\
+                          echo $LINENO >> $OUT\n\
+                          SECONDS=50\n\
+                          echo $SECONDS >> $OUT");
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], lines[1]);
+        assert!(lines[0].parse::<u32>().unwrap() < 32768);
+        assert_eq!(lines[2], "5");
+        assert!(lines[3] == "50" || lines[3] == "51");
+    }
+
+    #[test]
+    fn loops_can_span_lines_and_nest() {
+        let output = run(
+            "for a in 1 2\ndo\n  # inner loop\n  for b in x y; do\n    echo $a$b >> $OUT\n  done\ndone\n",
+        );
+        assert_eq!(output, "1x\n1y\n2x\n2y\n");
+    }
+
+    #[test]
+    fn here_docs_feed_their_lines_to_stdin() {
+        let output = run("name=world\n\
+                          cat <<EOF >> $OUT\n\
+                          hello $name $((1 + 1))\n\
+                          \n\
+                          # kept\n\
+                          EOF\n\
+                          cat <<'EOF' | tr a-z A-Z >> $OUT\n\
+                          $name\n\
+                          EOF\n\
+                          \tcat <<-END >> $OUT\n\
+                          \t\ttabs\n\
+                          \tEND");
+        assert_eq!(output, "hello world 2\n\n# kept\n$NAME\ntabs\n");
+    }
+
+    #[test]
+    fn loops_can_be_piped() {
+        let output = run("for x in b a; do echo $x; done | sort > $OUT");
+        assert_eq!(output, "a\nb\n");
+    }
+
+    #[test]
+    fn case_bodies_see_what_the_commands_before_did() {
+        let output = run("case a in a) y=1; echo \"y=$y\" > $OUT;; esac");
+        assert_eq!(output, "y=1\n");
+    }
+
+    #[test]
+    fn case_runs_the_first_match() {
+        let script = "for word in main.rs x 'a b' '*' other; do\n\
+                      case $word in\n\
+                        *.rs|x) echo rust-or-x >> $OUT ;;\n\
+                        'a b') echo quoted >> $OUT ;;\n\
+                        '*') echo star >> $OUT ;;\n\
+                        *) echo other >> $OUT ;;\n\
+                      esac\n\
+                      done";
+        assert_eq!(run(script), "rust-or-x\nrust-or-x\nquoted\nstar\nother\n");
+    }
+
+    #[test]
+    fn case_can_fall_through() {
+        let output = run(
+            "case start in s*) echo one >> $OUT ;& x) echo two >> $OUT ;; *) echo three >> $OUT ;; esac",
+        );
+        assert_eq!(output, "one\ntwo\n");
+        assert_eq!(run("case nothing in x) echo x >> $OUT ;; esac"), "");
+    }
+
+    #[test]
+    fn set_and_shift_change_the_positional_parameters() {
+        let script = "set -- 'a b' c d\n\
+                      echo $# >> $OUT\n\
+                      for arg in \"$@\"; do echo \"[$arg]\" >> $OUT; done\n\
+                      shift\n\
+                      echo $1 $# >> $OUT\n\
+                      set --\n\
+                      echo $# >> $OUT";
+        assert_eq!(run(script), "3\n[a b]\n[c]\n[d]\nc 2\n0\n");
+
+        let mut e = make_test_env();
+        e.ctx.positional = vec!["one".into()];
+        let script = "shift 2";
+        Context::source(&mut e.ctx, Path::new("test"), script).unwrap();
+        assert_eq!(e.ctx.last_exit_code, 1);
+        assert_eq!(e.ctx.positional, vec!["one"]);
+    }
+
+    #[test]
+    fn quoted_positional_parameters_stay_whole() {
+        let mut e = make_test_env();
+        e.ctx.positional = vec!["a b".into(), "c".into()];
+        let quoted = Shell::parse_command(&mut e.ctx, &mut e.term, "echo \"$@\"", true).unwrap();
+        let unquoted = Shell::parse_command(&mut e.ctx, &mut e.term, "echo $@", true).unwrap();
+        let args = |command: &Command| match command {
+            Command::Simple { args, .. } => {
+                args.iter().map(|arg| arg.as_str().to_string()).collect()
+            }
+            _ => Vec::new(),
+        };
+        assert_eq!(args(&quoted), vec!["a b", "c"]);
+        assert_eq!(args(&unquoted), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn arithmetic_command_status() {
+        let script = "export count=0\n\
+                      for x in a b c; do ((count += 2)); done\n\
+                      ((count == 6)) && echo six >> $OUT\n\
+                      ((count > 10)) || echo small >> $OUT\n\
+                      ((0))\n\
+                      echo $? >> $OUT";
+        assert_eq!(run(script), "six\nsmall\n1\n");
+    }
+
+    #[test]
+    fn conditional_tests() {
+        let mut e = make_test_env();
+        let dir = e._history_dir.path().display().to_string();
+        let checks = [
+            ("[[ main.rs == *.rs ]]", true),
+            ("[[ main.rs == \"*.rs\" ]]", false),
+            ("[[ main.rs != *.toml && -d DIR ]]", true),
+            ("[[ -f DIR || ( 3 -gt 2 && ! -z x ) ]]", true),
+            ("[[ 010 -eq 8 ]]", true),
+            ("[[ abc > abd ]]", false),
+            ("[[ a.c =~ ^a.c$ ]]", true),
+            ("[[ abc =~ \"a.c\" ]]", false),
+        ];
+        for (condition, expected) in checks {
+            let script = condition.replace("DIR", &dir);
+            Context::source(&mut e.ctx, Path::new("test"), &script).unwrap();
+            assert_eq!(e.ctx.last_exit_code == 0, expected, "{}", condition);
+        }
+
+        let script = "[[ 2024-05 =~ ^([0-9]+)-([0-9]+)(x)?$ ]]";
+        Context::source(&mut e.ctx, Path::new("test"), script).unwrap();
+        assert_eq!(e.ctx.arrays["BASH_REMATCH"], ["2024-05", "2024", "05", ""]);
+    }
+
+    #[test]
+    fn break_and_continue_leave_loops() {
+        let script = "for i in 1 2 3; do\n\
+                      for j in a b c; do\n\
+                      case $j in b) continue ;; esac\n\
+                      case $i in 2) continue 2 ;; 3) break 2 ;; esac\n\
+                      echo $i$j >> $OUT\n\
+                      done\n\
+                      done\n\
+                      for ((n = 0; ; n++)); do\n\
+                      case $n in 1) continue ;; 3) break ;; esac\n\
+                      echo $n >> $OUT\n\
+                      done";
+        assert_eq!(run(script), "1a\n1c\n0\n2\n");
+
+        let mut e = make_test_env();
+        Context::source(&mut e.ctx, Path::new("test"), "break").unwrap();
+        assert_eq!(e.ctx.last_exit_code, 1);
+    }
+
+    #[test]
+    fn arrays_hold_values() {
+        let script = "arr=(one 'two three' four)\n\
+                      arr+=(five)\n\
+                      echo ${#arr[@]} ${arr[1]} $arr ${arr[-1]} ${#arr[0]} >> $OUT\n\
+                      for value in \"${arr[@]}\"; do echo \"[$value]\" >> $OUT; done\n\
+                      for value in ${arr[@]}; do echo \"($value)\" >> $OUT; done\n\
+                      arr=()\n\
+                      echo ${#arr[@]} ${arr[0]}. >> $OUT";
+        assert_eq!(
+            run(script),
+            "4 two three one five 3\n[one]\n[two three]\n[four]\n[five]\n\
+             (one)\n(two)\n(three)\n(four)\n(five)\n0 .\n"
+        );
+    }
+
+    #[test]
+    fn exit_keeps_the_status() {
+        // (script, status it leaves with)
+        let cases = [
+            ("false; exit", 1),
+            ("exit 260", 4),
+            ("(exit 3)", 3),
+            ("(sleep 0.1; exit 2); echo after", 0),
+            ("exit nope", 1),
+        ];
+        for (script, status) in cases {
+            let mut e = make_test_env();
+            Context::source(&mut e.ctx, Path::new("test"), script).unwrap();
+            assert_eq!(e.ctx.last_exit_code, status, "{}", script);
+        }
+    }
+}
+
+// =============================================================================
+// debugger — tests
+// =============================================================================
+mod debugger_tests {
+    use crate::test_helpers::make_test_env;
+    use rshell::{
+        context::Source,
+        debugger::{Breakpoint, Debugger},
+        parser::Parser,
+        tokenizer::Tokenizer,
+    };
+    use std::path::PathBuf;
+
+    #[test]
+    fn breakpoints_are_read() {
+        assert_eq!(
+            Breakpoint::parse("12"),
+            Breakpoint::Line {
+                file: None,
+                line: 12
+            }
+        );
+        assert_eq!(
+            Breakpoint::parse("lib.sh:3"),
+            Breakpoint::Line {
+                file: Some("lib.sh".into()),
+                line: 3
+            }
+        );
+        assert_eq!(
+            Breakpoint::parse("make"),
+            Breakpoint::Command("make".into())
+        );
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut e = make_test_env();
+        let tokens = Tokenizer::tokenize("ls | grep x").unwrap();
+        let command = Parser::parse(&tokens).unwrap();
+        let mut debugger = Debugger::new();
+        assert!(debugger.should_stop(&e.ctx, &command));
+
+        let mut run = |debugger: &mut Debugger, line: &str| {
+            debugger.run(&mut e.ctx, &mut e.term, line).unwrap()
+        };
+        assert_eq!(run(&mut debugger, "c"), Some(true));
+        assert_eq!(run(&mut debugger, "b lib.sh:3"), None);
+        assert_eq!(run(&mut debugger, "b grep"), None);
+        assert_eq!(run(&mut debugger, "b cat"), None);
+        assert_eq!(run(&mut debugger, "d 3"), None);
+        assert_eq!(debugger.breakpoints.len(), 2);
+        assert_eq!(run(&mut debugger, "q"), Some(false));
+
+        // Only the command breakpoint matches until the line is the one of the breakpoint
+        debugger.breakpoints.remove(1);
+        assert!(!debugger.should_stop(&e.ctx, &command));
+        e.ctx.sources.push(Source {
+            name: PathBuf::from("scripts/lib.sh"),
+            file: None,
+            line: 3,
+        });
+        assert!(debugger.should_stop(&e.ctx, &command));
+    }
+}
+
+// =============================================================================
+// direnv — tests
+// =============================================================================
+mod direnv_tests {
+    use crate::test_helpers::make_test_env;
+    use rshell::direnv::{self, DirEnv};
+
+    #[test]
+    fn assignments_are_parsed() {
+        let content = "# comment\nFOO=bar\nexport BAZ='two words'\necho nope\n1X=bad\nEMPTY=\n";
+        assert_eq!(
+            direnv::parse(content),
+            [
+                ("FOO".to_string(), "bar".to_string()),
+                ("BAZ".to_string(), "two words".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nearest_file_applies() {
+        let e = make_test_env();
+        let root = e._history_dir.path();
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::fs::write(root.join(".env"), "").unwrap();
+        std::fs::write(root.join("a/.shellenv"), "").unwrap();
+
+        assert_eq!(
+            direnv::find_env_file(&root.join("a/b")),
+            Some(root.join("a/.shellenv"))
+        );
+        assert_eq!(direnv::find_env_file(root), Some(root.join(".env")));
+    }
+
+    #[test]
+    fn files_are_loaded_once_allowed_and_unloaded_after() {
+        let e = make_test_env();
+        let root = e._history_dir.path();
+        std::fs::create_dir(root.join("project")).unwrap();
+        let file = root.join("project/.env");
+        std::fs::write(&file, "RSHELL_TEST_ENV_A=new\nRSHELL_TEST_ENV_B=1\n").unwrap();
+        unsafe {
+            std::env::set_var("RSHELL_TEST_ENV_A", "old");
+            std::env::remove_var("RSHELL_TEST_ENV_B");
+        }
+
+        // Saying no is remembered for the session
+        let mut dir_env = DirEnv::new();
+        let change = dir_env.update(&root.join("project"), |_| false).unwrap();
+        assert_eq!(change.loaded, None);
+        dir_env.unload();
+        dir_env
+            .update(&root.join("project"), |_| panic!("asked twice"))
+            .unwrap();
+        assert_eq!(std::env::var("RSHELL_TEST_ENV_A").unwrap(), "old");
+
+        let mut dir_env = DirEnv::new();
+        let change = dir_env.update(&root.join("project"), |_| true).unwrap();
+        assert_eq!(change.loaded.as_deref(), Some(file.as_path()));
+        assert_eq!(std::env::var("RSHELL_TEST_ENV_A").unwrap(), "new");
+        assert_eq!(std::env::var("RSHELL_TEST_ENV_B").unwrap(), "1");
+
+        // Allowed now, leaving and coming back doesn't ask
+        dir_env.update(root, |_| false).unwrap();
+        assert_eq!(std::env::var("RSHELL_TEST_ENV_A").unwrap(), "old");
+        assert!(std::env::var("RSHELL_TEST_ENV_B").is_err());
+        dir_env
+            .update(&root.join("project"), |_| panic!("already allowed"))
+            .unwrap();
+        assert_eq!(dir_env.loaded(), Some(file.as_path()));
+
+        // A changed file has to be allowed again
+        dir_env.unload();
+        std::fs::write(&file, "RSHELL_TEST_ENV_A=evil\n").unwrap();
+        dir_env.update(&root.join("project"), |_| false).unwrap();
+        assert_eq!(std::env::var("RSHELL_TEST_ENV_A").unwrap(), "old");
+    }
+}
+
+mod path_index_tests {
+    use rshell::path_index::PathIndex;
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    fn program(directory: &Path, name: &str, mode: u32) {
+        let file = directory.join(name);
+        fs::write(&file, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn programs_are_indexed_and_refreshed() {
+        let (first, second) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        program(first.path(), "alpha-tool", 0o755);
+        program(first.path(), "alpha-notes", 0o644);
+        program(second.path(), "alpha-tool", 0o755);
+        let path = format!("{}:{}", first.path().display(), second.path().display());
+
+        let index = PathIndex::new();
+        assert_eq!(index.programs(&path, "al"), ["alpha-tool", "alpha-tool"]);
+        assert_eq!(
+            index.find(&path, "alpha-tool"),
+            Some(first.path().join("alpha-tool"))
+        );
+        // Another PATH is read straight away
+        let only_second = second.path().display().to_string();
+        assert_eq!(
+            index.find(&only_second, "alpha-tool"),
+            Some(second.path().join("alpha-tool"))
+        );
+
+        // A new program shows up once the refresh behind the lookups saw the directory change
+        program(second.path(), "alpha-new", 0o755);
+        let started = Instant::now();
+        while !index
+            .programs(&only_second, "alpha-n")
+            .contains(&"alpha-new".to_string())
+        {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+mod frecency_tests {
+    use rshell::frecency::Directories;
+    use std::path::Path;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn frequent_and_recent_directories_come_first() {
+        let mut directories = Directories::default();
+        for _ in 0..3 {
+            directories.visit(Path::new("/home/user/old-project"), NOW - 30 * 24 * 60 * 60);
+        }
+        directories.visit(Path::new("/home/user/project"), NOW - 60);
+        directories.visit(Path::new("/home/user/project/src"), NOW - 60);
+
+        let paths = |fragments: &[&str]| -> Vec<String> {
+            directories
+                .matching(fragments, NOW)
+                .iter()
+                .map(|entry| entry.path.display().to_string())
+                .collect()
+        };
+        // The last fragment is in the last part of the path, `src` isn't a project
+        assert_eq!(
+            paths(&["proj"]),
+            ["/home/user/project", "/home/user/old-project"]
+        );
+        assert_eq!(paths(&["user", "src"]), ["/home/user/project/src"]);
+        assert_eq!(paths(&["Proj"]), Vec::<String>::new());
+        assert_eq!(paths(&["PROJECT"]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn the_database_is_saved_and_loaded() {
+        let home = tempfile::TempDir::new().unwrap();
+        let file = home.path().join("rshell/directories");
+        let mut directories = Directories::default();
+        directories.visit(Path::new("/tmp/with\ttab and space"), NOW);
+        directories.visit(Path::new("/tmp/with\ttab and space"), NOW + 5);
+        directories.save(&file).unwrap();
+
+        let loaded = Directories::load(&file);
+        assert_eq!(loaded.entries, directories.entries);
+        assert_eq!(loaded.entries[0].visits, 2.0);
+        assert!(
+            Directories::load(&home.path().join("missing"))
+                .entries
+                .is_empty()
+        );
+    }
+}
+
+mod safety_tests {
+    use rshell::{parser::Parser, safety, tokenizer::Tokenizer};
+
+    fn dangerous(patterns: &[&str], line: &str) -> Option<String> {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        let tokens = Tokenizer::tokenize(line).unwrap();
+        let command = Parser::parse(&tokens).unwrap();
+        safety::dangerous(&patterns, &command).map(|(_, line)| line)
+    }
+
+    #[test]
+    fn any_stage_and_redirection_can_match() {
+        let patterns = ["rm -rf *", "git push --force*", "*> /*"];
+        assert_eq!(dangerous(&patterns, "rm -r build"), None);
+        assert_eq!(
+            dangerous(&patterns, "ls | rm -rf build &"),
+            Some("rm -rf build".to_string())
+        );
+        assert_eq!(
+            dangerous(&patterns, "echo hi > /etc/motd"),
+            Some("echo hi > /etc/motd".to_string())
+        );
+        assert!(dangerous(&patterns, "git push --force-with-lease").is_some());
+        assert_eq!(dangerous(&patterns, "echo hi > motd"), None);
+    }
+}
+
+// =============================================================================
+// integration — full tokenize → parse → expand → execute round trips
+// =============================================================================
+mod integration_tests {
+    use crate::test_helpers::make_test_env;
+    use rshell::shell::{ExecResult, Shell};
+
+    /// Parse and execute a command string, returning the exit code.
+    fn run(input: &str) -> i32 {
+        let mut e = make_test_env();
+        let cmd = Shell::parse_command(&mut e.ctx, &mut e.term, input, true).unwrap();
+        let (still_running, _) = Shell::execute_command(&mut e.ctx, &mut e.term, cmd).unwrap();
+        if still_running {
+            e.ctx.last_exit_code
+        } else {
+            -1
+        }
+    }
+
+    #[test]
+    fn autocd_enters_directories_typed_alone() {
+        let mut e = make_test_env();
+        let directory = tempfile::TempDir::new().unwrap();
+        let target = directory.path().canonicalize().unwrap();
+        let line = target.to_str().unwrap();
+
+        // Off by default, the directory is run like a program and fails
+        let cmd = Shell::parse_command(&mut e.ctx, &mut e.term, line, true).unwrap();
+        Shell::execute_command(&mut e.ctx, &mut e.term, cmd).unwrap();
+        assert_ne!(e.ctx.last_exit_code, 0);
+
+        e.ctx.settings.options.autocd = true;
+        let cmd = Shell::parse_command(&mut e.ctx, &mut e.term, line, true).unwrap();
+        Shell::execute_command(&mut e.ctx, &mut e.term, cmd).unwrap();
+        assert_eq!(e.ctx.last_exit_code, 0);
+        assert_eq!(std::env::current_dir().unwrap(), target);
+        std::env::set_current_dir("/tmp").unwrap();
+    }
+
+    #[test]
+    fn job_updates_leave_other_children_alone() {
+        let mut e = make_test_env();
+        // Like the git of the prompt, started by the shell but not a job
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        e.ctx.jobs.update_table(&mut e.term).unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+
+    /// Every script in tests/posix runs under `--posix` and prints what's in the `.out` file
+    /// next to it, which is what dash prints for it
+    #[test]
+    fn posix_conformance_scripts() {
+        let directory = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/posix");
+        let mut scripts: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "sh"))
+            .collect();
+        scripts.sort();
+        assert!(!scripts.is_empty());
+
+        for script in scripts {
+            let output = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+                .arg("--posix")
+                .arg(&script)
+                .output()
+                .unwrap();
+            let expected = std::fs::read_to_string(script.with_extension("out")).unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                expected,
+                "{} diverges",
+                script.display()
+            );
+        }
+    }
+
+    #[test]
+    fn dry_runs_show_commands_without_running_them() {
+        let directory = tempfile::TempDir::new().unwrap();
+        std::fs::write(directory.path().join("a b.txt"), "").unwrap();
+        std::fs::write(directory.path().join("c.txt"), "").unwrap();
+        let script = directory.path().join("clean.sh");
+        std::fs::write(
+            &script,
+            "x='two  words'\nDEBUG=1 rm -f *.txt \"$x\" > log | cat $(touch ran)\n",
+        )
+        .unwrap();
+
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .arg("--dry-run")
+            .arg(&script)
+            .current_dir(directory.path())
+            .output()
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "rm\n  argv: [\"rm\", \"-f\", \"a b.txt\", \"c.txt\", \"two  words\"]\n  \
+             env: DEBUG=\"1\"\n  redirect: > \"log\"\n\
+             cat\n  argv: [\"cat\", \"$(touch ran)\"]\n"
+        );
+        let mut left: Vec<_> = std::fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["a b.txt", "c.txt", "clean.sh"]);
+    }
+
+    /// The example plugin, built with the C compiler, brings its builtin, segment, completion
+    /// and hook, and takes them away when unloaded
+    #[test]
+    fn plugins_register_through_the_c_abi() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let plugins = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("plugins");
+        let library = directory.path().join("libgreet.so");
+        let built = std::process::Command::new("cc")
+            .args(["-shared", "-fPIC", "-I"])
+            .arg(&plugins)
+            .arg("-o")
+            .arg(&library)
+            .arg(plugins.join("greet.c"))
+            .status()
+            .unwrap();
+        assert!(built.success());
+
+        let script = format!(
+            "plugin load {}\ngreet tests\nplugin\nhook\nplugin unload greet\nhook\ngreet\n",
+            library.display()
+        );
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .args(["-c", &script])
+            .output()
+            .unwrap();
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let expected = format!(
+            "Hello, tests!\n\
+             greet {}\n  builtins: greet\n  segments: greeting\n  completions: greet\n  \
+             precmd: GREET_HOOK=ran\n\
+             precmd GREET_HOOK=ran\n\
+             Goodbye after 1 greetings\n",
+            library.display()
+        );
+        assert!(stdout.starts_with(&expected), "{stdout}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stdout.contains("Command not found") || stderr.contains("Command not found"));
+    }
+
+    /// Without a terminal an interactive shell reads whole lines after a plain prompt, where
+    /// it used to fail to take the terminal
+    #[test]
+    fn interactive_shell_without_a_terminal_reads_plain_lines() {
+        use std::io::Write;
+
+        let home = tempfile::TempDir::new().unwrap();
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .arg("-i")
+            .env("HOME", home.path())
+            .env("XDG_CONFIG_HOME", home.path())
+            .env("XDG_DATA_HOME", home.path())
+            .env("PROMPT", "{primary}$ {reset}")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"echo one\nexit 3\n")
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "$ one\n$ ");
+    }
+
+    #[test]
+    fn z_jumps_to_visited_directories() {
+        use std::io::Write;
+
+        let home = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(home.path().join("work/project/src")).unwrap();
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .arg("-i")
+            .current_dir(home.path())
+            .env("HOME", home.path())
+            .env("XDG_CONFIG_HOME", home.path())
+            .env("XDG_DATA_HOME", home.path())
+            .env("PROMPT", "")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"cd work/project\ncd src\ncd\nz proj\npwd\nz src\npwd\nz nothing\n")
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        let project = home.path().join("work/project");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let jumps = format!("{}\n{}/src\n", project.display(), project.display());
+        assert!(stdout.starts_with(&jumps), "{stdout}");
+        assert!(
+            stdout.contains("nothing: No visited directory matches"),
+            "{stdout}"
+        );
+    }
+
+    #[test]
+    fn slow_commands_report_their_time() {
+        use std::io::Write;
+
+        let home = tempfile::TempDir::new().unwrap();
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .arg("-i")
+            .env("HOME", home.path())
+            .env("XDG_CONFIG_HOME", home.path())
+            .env("XDG_DATA_HOME", home.path())
+            .env("PROMPT", "")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"REPORTTIME=200ms\nsleep 0.3\necho quick\n")
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let report = stderr
+            .lines()
+            .find(|line| line.starts_with("sleep 0.3  "))
+            .unwrap_or_else(|| panic!("no report in {stderr:?}"));
+        assert!(
+            report.contains("s user ") && report.ends_with(" total"),
+            "{report}"
+        );
+        assert!(!stderr.contains("echo quick"), "{stderr}");
+    }
+
+    #[test]
+    fn syntax_errors_dont_end_the_session() {
+        use std::io::Write;
+
+        let home = tempfile::TempDir::new().unwrap();
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .arg("-i")
+            .env("HOME", home.path())
+            .env("XDG_CONFIG_HOME", home.path())
+            .env("XDG_DATA_HOME", home.path())
+            .env("PROMPT", "$ ")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"echo ( x\necho $?\n")
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!(output.status.code(), Some(0));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("Syntax error"), "{stdout}");
+        assert!(stdout.ends_with("$ 2\n$ \n"), "{stdout}");
+    }
+
+    #[test]
+    fn accessible_mode_says_failures() {
+        use std::io::Write;
+
+        let home = tempfile::TempDir::new().unwrap();
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .arg("-i")
+            .env("HOME", home.path())
+            .env("XDG_CONFIG_HOME", home.path())
+            .env("XDG_DATA_HOME", home.path())
+            .env("PROMPT", "$ ")
+            .env("ACCESSIBLE", "1")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"false\ntrue\n")
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "$ \x07Failed with status 1\n$ $ \n"
+        );
+    }
+
+    fn greet(
+        args: &[&str],
+        context: &mut rshell::context::Context,
+        _: &mut rshell::terminal::Terminal,
+    ) -> anyhow::Result<i32> {
+        context
+            .variables
+            .set("RSHELL_TEST_GREETED", args.first().unwrap_or(&"nobody"));
+        Ok(3)
+    }
+
+    #[test]
+    fn eval_runs_commands_and_custom_builtins() {
+        let e = make_test_env();
+        let mut shell = Shell {
+            terminal: e.term,
+            context: e.ctx,
+        };
+        shell.add_builtin("greet", greet);
+
+        let result = shell
+            .eval("RSHELL_TEST_WHO=world\ngreet $RSHELL_TEST_WHO")
+            .unwrap();
+        assert_eq!(
+            result,
+            ExecResult {
+                status: 3,
+                exited: false
+            }
+        );
+        assert_eq!(
+            shell
+                .context
+                .variables
+                .get("RSHELL_TEST_GREETED")
+                .as_deref(),
+            Some("world")
+        );
+
+        // Pipelines run it in a copy of the shell, which has to know it too
+        let result = shell.eval("greet | true; false || greet again").unwrap();
+        assert_eq!(result.status, 3);
+        assert_eq!(
+            shell.eval("exit 4").unwrap(),
+            ExecResult {
+                status: 4,
+                exited: true
+            }
+        );
+    }
+
+    #[test]
+    fn true_exits_zero() {
+        assert_eq!(run("true"), 0);
+    }
+
+    #[test]
+    fn false_exits_nonzero() {
+        assert_ne!(run("false"), 0);
+    }
+
+    #[test]
+    fn echo_exits_zero() {
+        assert_eq!(run("echo hello"), 0);
+    }
+
+    #[test]
+    fn and_short_circuits_on_failure() {
+        assert_ne!(run("false && echo unreachable"), 0);
+    }
+
+    #[test]
+    fn or_short_circuits_on_success() {
+        assert_eq!(run("true || false"), 0);
+    }
+
+    #[test]
+    fn sequence_runs_both_sides() {
+        assert_eq!(run("true; true"), 0);
+    }
+
+    #[test]
+    fn pipeline_exit_code_is_last_stage() {
+        assert_eq!(run("echo hello | cat"), 0);
+    }
+
+    // What `rshell -c` prints for a script on a single line
+    fn one_line(script: &str) -> String {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_rshell"))
+            .args(["-c", script])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    #[test]
+    fn one_line_assignments_are_seen_further_on() {
+        assert_eq!(one_line("x=5; echo \"x=$x\""), "x=5\n");
+    }
+
+    #[test]
+    fn one_line_cd_changes_pwd_further_on() {
+        assert_eq!(one_line("cd /usr && echo $PWD"), "/usr\n");
+    }
+
+    #[test]
+    fn one_line_arithmetic_status_is_seen_further_on() {
+        assert_eq!(one_line("(( 1 > 2 )); echo $?"), "1\n");
+    }
+
+    #[test]
+    fn one_line_arrays_are_seen_further_on() {
+        assert_eq!(one_line("a=(x y z); echo ${a[1]}"), "y\n");
+    }
+
+    #[test]
+    fn one_line_regex_captures_are_seen_further_on() {
+        assert_eq!(
+            one_line("[[ abc =~ (a)(b) ]] && echo ${BASH_REMATCH[2]}"),
+            "b\n"
+        );
+    }
+
+    #[test]
+    fn timeout_kills_what_ignores_the_signal() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let script = format!(
+            "timeout -k 300ms 200ms sh -c 'echo $$ > {}; trap \"\" TERM; while :; do :; done'; echo $?",
+            pid_file.display()
+        );
+        assert_eq!(one_line(&script), "137\n");
+
+        // Nothing of the group is left running
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(std::fs::read_to_string(stat).map_or(true, |stat| stat.contains(") Z ")));
+    }
+
+    #[test]
+    fn redirect_out_creates_file_with_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.txt");
+        run(&format!("echo hello > {}", path.display()));
+        assert!(path.exists());
+        assert!(std::fs::read_to_string(&path).unwrap().contains("hello"));
+    }
+
+    #[test]
+    fn redirect_append_does_not_truncate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.txt");
+        run(&format!("echo line1 > {}", path.display()));
+        run(&format!("echo line2 >> {}", path.display()));
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains("line1") && content.contains("line2"));
+    }
+
+    #[test]
+    fn redirect_stderr_append_and_both_to_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let errors = dir.path().join("err.txt");
+        let all = dir.path().join("all.txt");
+        let missing = dir.path().join("missing");
+        run(&format!(
+            "ls {} 2>> {}",
+            missing.display(),
+            errors.display()
+        ));
+        run(&format!(
+            "ls {} 2>> {}",
+            missing.display(),
+            errors.display()
+        ));
+        let content = std::fs::read_to_string(&errors).unwrap();
+        assert_eq!(content.lines().count(), 2, "{content}");
+
+        run(&format!(
+            "ls {} {} &> {}",
+            errors.display(),
+            missing.display(),
+            all.display()
+        ));
+        let content = std::fs::read_to_string(&all).unwrap();
+        assert!(
+            content.contains("err.txt") && content.contains("missing"),
+            "{content}"
+        );
+    }
+
+    #[test]
+    fn redirect_to_a_file_that_cannot_be_made_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("out.txt");
+        assert_eq!(run(&format!("ls > {}", path.display())), 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn subcommand_expansion_in_argument() {
+        let mut e = make_test_env();
+        let cmd =
+            Shell::parse_command(&mut e.ctx, &mut e.term, "echo $(echo inner)", true).unwrap();
+        assert!(cmd.to_string().contains("inner"));
+    }
+
+    #[test]
+    fn builtin_cd_changes_working_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        run(&format!("cd {}", dir.path().display()));
+        assert_eq!(
+            std::env::current_dir().unwrap(),
+            dir.path().canonicalize().unwrap()
+        );
+    }
+}
+
+// -----------------------------------------------------------------------------
+// The shell as a user sees it: started in a pseudo-terminal, typed at, and
+// read back from the screen with the escape sequences taken out
+// -----------------------------------------------------------------------------
+mod pty_tests {
+    use std::{
+        fs::{self, File},
+        io::{Read, Write},
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        os::unix::process::CommandExt,
+        path::Path,
+        process::{Child, Command, ExitStatus, Stdio},
+        time::{Duration, Instant},
+    };
+    use tempfile::TempDir;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    struct Pty {
+        master: File,
+        child: Child,
+        // Everything the shell wrote, and how much of it the tests went past already
+        output: Vec<u8>,
+        seen: usize,
+        // Cursor position queries answered so far
+        answered: usize,
+        home: TempDir,
+    }
+
+    impl Pty {
+        // A shell of its own in an 80x24 terminal, with a home holding the history given
+        fn spawn(history: &[&str]) -> Pty {
+            let home = tempfile::tempdir().unwrap();
+            let lines: String = history
+                .iter()
+                .enumerate()
+                .map(|(n, command)| format!("{}\t0:0:pty\t/tmp\t{}\n", 1_700_000_000 + n, command))
+                .collect();
+            fs::write(home.path().join("history"), lines).unwrap();
+
+            let (mut master, mut slave) = (0, 0);
+            let size = libc::winsize {
+                ws_row: 24,
+                ws_col: 80,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            let opened = unsafe {
+                libc::openpty(
+                    &mut master,
+                    &mut slave,
+                    std::ptr::null_mut(),
+                    std::ptr::null(),
+                    &size,
+                )
+            };
+            assert_eq!(opened, 0, "no pseudo-terminal");
+            let master = unsafe { File::from_raw_fd(master) };
+            let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+            unsafe {
+                libc::fcntl(master.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+
+            let mut command = Command::new(env!("CARGO_BIN_EXE_rshell"));
+            command
+                .env_clear()
+                .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+                .env("HOME", home.path())
+                .env("HISTFILE", home.path().join("history"))
+                .env("TERM", "xterm-256color")
+                .env("PROMPT", "$ ")
+                .current_dir(home.path())
+                .stdin(Stdio::from(slave.try_clone().unwrap()))
+                .stdout(Stdio::from(slave.try_clone().unwrap()))
+                .stderr(Stdio::from(slave));
+            // The terminal becomes the one the shell controls, like a login gives it
+            unsafe {
+                command.pre_exec(|| {
+                    libc::setsid();
+                    libc::ioctl(0, libc::TIOCSCTTY, 0);
+                    Ok(())
+                });
+            }
+            let child = command.spawn().unwrap();
+
+            let mut pty = Pty {
+                master,
+                child,
+                output: Vec::new(),
+                seen: 0,
+                answered: 0,
+                home,
+            };
+            pty.wait_for("$ ");
+            pty
+        }
+
+        fn send(&mut self, keys: &str) {
+            self.master.write_all(keys.as_bytes()).unwrap();
+        }
+
+        // Reads what the shell wrote in the meantime, answering where the cursor is when
+        // it asks. False once the shell is gone
+        fn read(&mut self, timeout: Duration) -> bool {
+            let mut poll = libc::pollfd {
+                fd: self.master.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as i32) } <= 0 {
+                return true;
+            }
+            let mut buffer = [0; 4096];
+            let read = match self.master.read(&mut buffer) {
+                Ok(0) | Err(_) => return false,
+                Ok(read) => read,
+            };
+            self.output.extend_from_slice(&buffer[..read]);
+
+            let queries = self.output.windows(4).filter(|w| w == b"\x1b[6n").count();
+            for _ in self.answered..queries {
+                self.master.write_all(b"\x1b[1;1R").unwrap();
+            }
+            self.answered = queries;
+            true
+        }
+
+        // Waits for the text to show up after what was waited for before
+        fn wait_for(&mut self, text: &str) {
+            let started = Instant::now();
+            loop {
+                let screen = plain(&self.output[self.seen..]);
+                if let Some(found) = screen.find(text) {
+                    // Back to raw bytes, the escape sequences make them longer
+                    let before = &screen[..found + text.len()];
+                    self.seen += raw_length(&self.output[self.seen..], before);
+                    return;
+                }
+                if started.elapsed() > TIMEOUT || !self.read(Duration::from_millis(50)) {
+                    panic!("{:?} never showed up in {:?}", text, screen);
+                }
+            }
+        }
+
+        fn exit(&mut self) -> ExitStatus {
+            self.send("exit\r");
+            let started = Instant::now();
+            loop {
+                if let Some(status) = self.child.try_wait().unwrap() {
+                    return status;
+                }
+                assert!(
+                    started.elapsed() < TIMEOUT,
+                    "the shell didn't exit: {:?}",
+                    plain(&self.output[self.seen..])
+                );
+                self.read(Duration::from_millis(50));
+            }
+        }
+    }
+
+    impl Drop for Pty {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    // The output without escape sequences, which is near enough to what's on screen since
+    // every redraw starts the line over
+    fn plain(output: &[u8]) -> String {
+        let output = String::from_utf8_lossy(output);
+        let mut text = String::new();
+        let mut chars = output.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('\x1b', Some('[')) => {
+                    chars.next();
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                ('\x1b', Some(']')) => {
+                    for c in chars.by_ref() {
+                        if c == '\x07' || c == '\\' {
+                            break;
+                        }
+                    }
+                }
+                ('\x1b', _) => {}
+                (c, _) => text.push(c),
+            }
+        }
+        text
+    }
+
+    // How many bytes of the output make up the start of its plain text
+    fn raw_length(output: &[u8], text: &str) -> usize {
+        (0..=output.len())
+            .find(|&end| plain(&output[..end]).len() >= text.len())
+            .unwrap_or(output.len())
+    }
+
+    fn history(home: &Path) -> String {
+        fs::read_to_string(home.join("history")).unwrap()
+    }
+
+    #[test]
+    fn typed_lines_run_and_are_kept() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("echo pty-$((1+1))\r");
+        pty.wait_for("pty-2\r\n");
+        assert!(pty.exit().success());
+        assert!(history(pty.home.path()).contains("echo pty-$((1+1))"));
+    }
+
+    #[test]
+    fn arrows_go_through_the_history() {
+        let mut pty = Pty::spawn(&["echo older", "echo newer"]);
+        pty.send("\x1b[A");
+        pty.wait_for("$ echo newer");
+        pty.send("\x1b[A");
+        pty.wait_for("$ echo older");
+        pty.send("\x1b[B\r");
+        pty.wait_for("newer\r\n");
+        pty.exit();
+    }
+
+    #[test]
+    fn ctrl_r_finds_commands_by_fuzzy_search() {
+        let mut pty = Pty::spawn(&["git status", "cargo build --release"]);
+        pty.send("\x12cbr");
+        pty.wait_for("(fuzzy 1/1) cbr: cargo build --release");
+        pty.send("\x03");
+        pty.exit();
+    }
+
+    #[test]
+    fn programs_get_the_terminal_and_their_signals() {
+        let mut pty = Pty::spawn(&[]);
+        // Keys typed before the terminal is handed over would be read raw, so the command
+        // says when it's running
+        pty.send("sh -c 'echo ready; exec cat'\r");
+        pty.wait_for("ready\r\n");
+        pty.send("typed to cat\r");
+        pty.wait_for("typed to cat\r\ntyped to cat\r\n");
+        // Ctrl-C stops cat, not the shell
+        pty.send("\x03");
+        pty.wait_for("$ ");
+        pty.send("echo still here\r");
+        pty.wait_for("still here\r\n");
+        assert!(pty.exit().success());
+    }
+
+    #[test]
+    fn pipelines_end_when_the_reader_does() {
+        let mut pty = Pty::spawn(&[]);
+        // A subshell stage used to hold the end its reader had, so it never got SIGPIPE
+        pty.send("yes | head -n 1; (yes) | head -n 1; echo done\r");
+        pty.wait_for("y\r\ny\r\ndone\r\n");
+        assert!(!plain(&pty.output).contains("Broken pipe"));
+        pty.exit();
+    }
+
+    #[test]
+    fn ctrl_z_stops_a_whole_pipeline() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send(
+            "sh -c 'trap \"echo resumed\" CONT; echo ready; while :; do sleep 1; done' | cat\r",
+        );
+        pty.wait_for("ready\r\n");
+        // Waiting went on after every stage stopped, the shell never came back
+        pty.send("\x1a");
+        pty.wait_for("Stopped");
+        pty.send("jobs\r");
+        pty.wait_for("Stopped");
+        // Back in the foreground until Ctrl-C ends it
+        pty.send("fg\r");
+        pty.wait_for("resumed\r\n");
+        pty.send("\x03");
+        pty.send("echo still here\r");
+        pty.wait_for("still here\r\n");
+        pty.exit();
+    }
+
+    #[test]
+    fn resizing_draws_the_line_again() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("echo resized");
+        pty.wait_for("$ echo resized");
+        let size = libc::winsize {
+            ws_row: 30,
+            ws_col: 100,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        unsafe { libc::ioctl(pty.master.as_raw_fd(), libc::TIOCSWINSZ, &size) };
+        pty.wait_for("$ echo resized");
+        pty.send("\r");
+        pty.wait_for("resized\r\n");
+        pty.exit();
+    }
+
+    #[test]
+    fn sigterm_ends_the_session_out_of_raw_mode() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("half typed");
+        pty.wait_for("$ half typed");
+        unsafe { libc::kill(pty.child.id() as libc::pid_t, libc::SIGTERM) };
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = pty.child.try_wait().unwrap() {
+                break status;
+            }
+            assert!(started.elapsed() < TIMEOUT, "the shell didn't end");
+            pty.read(Duration::from_millis(50));
+        };
+        assert_eq!(status.code(), Some(128 + libc::SIGTERM));
+
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        unsafe { libc::tcgetattr(pty.master.as_raw_fd(), &mut termios) };
+        assert_ne!(termios.c_lflag & libc::ICANON, 0, "left in raw mode");
+        assert_ne!(termios.c_lflag & libc::ECHO, 0, "left in raw mode");
+    }
+
+    #[test]
+    fn unfinished_commands_ask_for_more_lines() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("cat <<EOF\r");
+        pty.wait_for("> ");
+        pty.send("first $((2 * 3))\r");
+        pty.wait_for("> ");
+        pty.send("EOF\r");
+        pty.wait_for("first 6\r\n");
+        pty.send("for x in a b\r");
+        pty.wait_for("> ");
+        pty.send("do echo x$x; done\r");
+        pty.wait_for("xa\r\nxb\r\n");
+
+        // The whole loop comes back from the history, not just its first line
+        pty.send("\x1b[A\r");
+        pty.wait_for("xa\r\nxb\r\n");
+        pty.exit();
+    }
+
+    #[test]
+    fn typos_are_offered_the_closest_match() {
+        let mut pty = Pty::spawn(&[]);
+        fs::create_dir(pty.home.path().join("projects")).unwrap();
+        pty.send("ehco fixed\r");
+        pty.wait_for("did you mean `echo fixed`? [Y/n]");
+        pty.send("y");
+        pty.wait_for("fixed\r\n");
+
+        pty.send("cd projcts\r");
+        pty.wait_for("did you mean projects? [Y/n]");
+        pty.send("\r");
+        pty.send("pwd\r");
+        pty.wait_for("/projects\r\n");
+
+        // Any other key says no
+        pty.send("ehco kept\r");
+        pty.wait_for("[Y/n]");
+        pty.send("n");
+        pty.wait_for("Command not found");
+        pty.exit();
+    }
+
+    #[test]
+    fn dangerous_commands_are_confirmed_once_expanded() {
+        let mut pty = Pty::spawn(&[]);
+        let config = pty.home.path().join(".config/rshell");
+        fs::create_dir_all(&config).unwrap();
+        fs::write(
+            config.join("config.toml"),
+            "[safety]\nconfirm = [\"rm -r*\"]\n",
+        )
+        .unwrap();
+        fs::create_dir(pty.home.path().join("build")).unwrap();
+        pty.send("reload\r");
+        pty.wait_for("safety.confirm = [\"rm -r*\"]");
+
+        pty.send("rm -r bui*\r");
+        pty.wait_for("rm -r build\r\nThis matches 'rm -r*', run it anyway? [y/N] ");
+        pty.send("n\r");
+        pty.send("echo $?\r");
+        pty.wait_for("1\r\n");
+        assert!(pty.home.path().join("build").exists());
+
+        pty.send("rm -r bui*\r");
+        pty.wait_for("run it anyway? [y/N] ");
+        pty.send("y\r");
+        pty.send("echo $((40 + 2))\r");
+        pty.wait_for("42\r\n");
+        assert!(!pty.home.path().join("build").exists());
+        pty.exit();
+    }
+
+    #[test]
+    fn repeat_stops_on_ctrl_c_and_sums_up() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("repeat 3 true\r");
+        pty.wait_for("3 runs in ");
+        pty.wait_for(": 3 passed, 0 failed\r\n");
+        pty.send("repeat 100 sleep 5\r");
+        pty.wait_for("repeat 100 sleep 5\r\n");
+        std::thread::sleep(Duration::from_millis(300));
+        pty.send("\x03");
+        pty.wait_for("1 run in ");
+        pty.wait_for(": 0 passed, 1 failed (status 130 ×1)\r\n");
+        pty.exit();
+    }
+
+    #[test]
+    fn watch_runs_again_until_stopped() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("alias tick='((n++)); echo tick $n'\r");
+        pty.send("watch -n 0.2 tick\r");
+        pty.wait_for("Every 200ms: tick");
+        pty.wait_for("tick 2\r\n");
+        pty.send("q");
+        pty.send("echo $n\r");
+        pty.wait_for("$ echo $n\r\n");
+        pty.read(Duration::from_millis(300));
+        let after: String = plain(&pty.output[pty.seen..]);
+        assert!(!after.contains("Every"), "{after}");
+
+        // Ctrl-C stops the command and the watch with it
+        pty.send("watch sleep 5\r");
+        pty.wait_for("Every 2s: sleep 5");
+        pty.send("\x03");
+        pty.send("echo status $?\r");
+        pty.wait_for("status 130\r\n");
+        pty.exit();
+    }
+
+    #[test]
+    fn timeout_stops_commands_and_builtins() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("timeout 300ms sleep 5\r");
+        pty.send("echo status $?\r");
+        pty.wait_for("status 124\r\n");
+        // A builtin runs in a copy of the shell, which gets the signal too
+        pty.send("timeout 500ms watch -n 0.1 true\r");
+        pty.send("echo status $?\r");
+        pty.wait_for("status 124\r\n");
+        pty.send("timeout 5 sh -c 'exit 3'\r");
+        pty.send("echo status $?\r");
+        pty.wait_for("status 3\r\n");
+
+        // The command has the terminal, Ctrl-C goes to it and the shell gets it back
+        pty.send("timeout 5 sleep 5\r");
+        pty.read(Duration::from_millis(300));
+        pty.send("\x03");
+        pty.send("echo status $?\r");
+        pty.wait_for("status 130\r\n");
+        pty.exit();
+    }
+
+    #[test]
+    fn leaving_with_jobs_asks_again_and_hangs_them_up() {
+        // Whether a process is gone, or only waiting to be reaped
+        let gone = |pid: &str| {
+            fs::read_to_string(format!("/proc/{}/stat", pid))
+                .map_or(true, |stat| stat.contains(") Z "))
+        };
+        let mut pty = Pty::spawn(&[]);
+        pty.send("sleep 100 &\r");
+        pty.wait_for("[1] ");
+        pty.wait_for("\r\n");
+        let line = plain(&pty.output);
+        let kept: String = line.split("[1] ").last().unwrap().trim().to_string();
+        pty.send("disown %1\r");
+        pty.send("sleep 200 &\r");
+        pty.wait_for("[2] ");
+        pty.wait_for("\r\n");
+        let line = plain(&pty.output);
+        let hung_up: String = line.split("[2] ").last().unwrap().trim().to_string();
+
+        pty.send("exit\r");
+        pty.wait_for("There are jobs still running or stopped:\r\n");
+        pty.wait_for("[2] Running  sleep 200 &\r\n");
+        // Another command in between takes the warning back
+        pty.send("echo still here\r");
+        pty.wait_for("still here\r\n");
+        // Before raw mode Ctrl-D would be the terminal's end of file
+        pty.wait_for("$ ");
+        pty.send("\x04");
+        pty.wait_for("There are jobs still running or stopped:\r\n");
+        pty.exit();
+
+        let started = Instant::now();
+        while !gone(&hung_up) && started.elapsed() < TIMEOUT {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(gone(&hung_up));
+        assert!(!gone(&kept));
+        unsafe { libc::kill(kept.parse().unwrap(), libc::SIGKILL) };
+    }
+
+    #[test]
+    fn captured_output_is_kept_in_last_output() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("shopt -s captureoutput\r");
+        // Still shown while it's kept
+        pty.send("printf 'one\\ntwo\\n' | tail -n 1\r");
+        pty.wait_for("two\r\n");
+        pty.send("echo got $LAST_OUTPUT\r");
+        pty.wait_for("got two\r\n");
+        pty.send("echo $LAST_OUTPUT\r");
+        pty.wait_for("got two\r\n");
+        pty.exit();
+    }
+
+    #[test]
+    fn private_sessions_keep_commands_out_of_the_history() {
+        let mut pty = Pty::spawn(&[]);
+        pty.send("private\r");
+        pty.wait_for("(private) $ ");
+        pty.send("echo secret-token\r");
+        pty.wait_for("secret-token\r\n");
+        pty.send("private off\r");
+        pty.send("echo public\r");
+        pty.wait_for("public\r\n");
+        pty.exit();
+
+        let history = fs::read_to_string(pty.home.path().join("history")).unwrap();
+        assert!(history.contains("echo public"), "{history}");
+        assert!(!history.contains("secret-token"), "{history}");
+    }
+
+    #[test]
+    fn tab_completes_file_names() {
+        let mut pty = Pty::spawn(&[]);
+        fs::write(pty.home.path().join("unique-file.txt"), "contents\n").unwrap();
+        pty.send("cat uni\t");
+        pty.wait_for("$ cat unique-file.txt");
+        pty.send("\r");
+        pty.wait_for("contents\r\n");
+        pty.exit();
     }
 }